@@ -1,5 +1,6 @@
 import difflib
 import re
+import socket
 import sys
 import subprocess as sp
 from pathlib import Path
@@ -53,6 +54,54 @@ def get_input(filename: Path):
     return '\n'.join(match.split('~')) + '\n'
 
 
+def get_run_flags(filename: Path):
+    contents = open(filename).read()
+
+    match = re.search(r'// FLAGS: (?P<flags>.*)\n', contents)
+    if not match:
+        return []
+    return match.group('flags').strip().split()
+
+
+def get_exit_code(filename: Path):
+    contents = open(filename).read()
+
+    match = re.search(r'// EXIT CODE: (?P<code>\d+)\n', contents)
+    if not match:
+        return 0
+    return int(match.group('code'))
+
+
+def get_nodes(filename: Path):
+    contents = open(filename).read()
+
+    match = re.search(r'// NODES: (?P<nodes>.*)\n', contents)
+    if not match:
+        return []
+    return match.group('nodes').strip().split()
+
+
+def free_address():
+    with socket.socket() as s:
+        s.bind(('127.0.0.1', 0))
+        return f'127.0.0.1:{s.getsockname()[1]}'
+
+
+def start_nodes(filename: Path):
+    '''
+    Each node is a separate process, only stdout of the coordinator is checked
+    '''
+    nodes = []
+    for name in get_nodes(filename):
+        address = free_address()
+        process = sp.Popen(
+            ['cargo', 'run', '-q', 'run', '--listen', address, f'{filename}.bytecode'],
+            stdout=sp.DEVNULL,
+        )
+        nodes.append((name, address, process))
+    return nodes
+
+
 def run_file(filename):
     print(f"Running {filename}... ")
     res = sp.run(['cargo', 'run', '-q', 'cc', filename])
@@ -62,15 +111,21 @@ def run_file(filename):
     assert res.returncode == 0, f"Error disassembling {filename}: \n{res.stderr}"
 
     file_input = get_input(filename)
+    nodes = start_nodes(filename)
+    node_flags = [flag for name, address, _ in nodes for flag in ('--node', f'{name}={address}')]
     
     res = sp.run(
-        ['cargo', 'run', '-q', 'run', f'{filename}.bytecode'],
+        ['cargo', 'run', '-q', 'run', *get_run_flags(filename), *node_flags, f'{filename}.bytecode'],
         capture_output=True,
         text=True,
         input=file_input,
         timeout=5  # 5 seconds is enough to determine infinite loop 
     )
-    assert res.returncode == 0, f"Error running {filename}: \n{res.stderr}"
+    for name, _, process in nodes:
+        assert process.wait(timeout=5) == 0, f"Node {name} failed while running {filename}"
+
+    expected_code = get_exit_code(filename)
+    assert res.returncode == expected_code, f"Error running {filename}: \n{res.stderr}"
 
     check_output(filename, res.stdout)
 
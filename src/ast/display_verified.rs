@@ -26,7 +26,7 @@ impl fmt::Display for RawFunction {
             body = self
                 .body
                 .iter()
-                .map(|s| s.display_with_ident(true))
+                .map(|s| s.statement.display_with_ident(true))
                 .collect::<Vec<_>>()
                 .join("\n")
         )
@@ -42,12 +42,12 @@ impl VStatement {
                     condition.expr,
                     if_body
                         .iter()
-                        .map(|s| s.display_with_ident(true))
+                        .map(|s| s.statement.display_with_ident(true))
                         .collect::<Vec<_>>()
                         .join("\n"),
                     else_body
                         .iter()
-                        .map(|s| s.display_with_ident(true))
+                        .map(|s| s.statement.display_with_ident(true))
                         .collect::<Vec<_>>()
                         .join("\n")
                 )
@@ -57,7 +57,7 @@ impl VStatement {
                     "\nwhile {} {{\n{}\n}}\n",
                     condition.expr,
                     body.iter()
-                        .map(|s| s.display_with_ident(true))
+                        .map(|s| s.statement.display_with_ident(true))
                         .collect::<Vec<_>>()
                         .join("\n")
                 )
@@ -95,16 +95,17 @@ impl VStatement {
             VStatement::AssignToCurrentActiveField { field, value, .. } => {
                 format!("{}.{} = {};", "@current_active".yellow(), field, value.expr)
             }
-            VStatement::SendMessage { active, receiver, args } => {
+            VStatement::SendMessage { active, receiver, args, delay } => {
                 format!(
-                    "{}({}, {} : ({}));",
+                    "{}({}, {} : ({}){});",
                     "@send".yellow(),
                     active.expr,
                     receiver,
                     args.iter()
                         .map(|e| format!("{}", e.expr))
                         .collect::<Vec<_>>()
-                        .join(", ")
+                        .join(", "),
+                    delay.as_ref().map_or(String::new(), |d| format!(" after {}", d.expr))
                 )
             }
         };
@@ -180,7 +181,7 @@ impl fmt::Display for VExpr {
             VExpr::Allocate { typename } => {
                 write!(f, "new {}", typename)
             }
-            VExpr::Spawn { typename, args } => {
+            VExpr::Spawn { typename, args, node } => {
                 write!(
                     f,
                     "{}({} : {})",
@@ -190,8 +191,28 @@ impl fmt::Display for VExpr {
                         .map(|e| format!("{}", e.expr))
                         .collect::<Vec<_>>()
                         .join(", ")
+                )?;
+                match node {
+                    Some(node) => write!(f, " on {}", node.expr),
+                    None => Ok(()),
+                }
+            }
+            VExpr::AskMessage { active, receiver, args } => {
+                write!(
+                    f,
+                    "{}({}, {} : ({}))",
+                    "@ask".yellow(),
+                    active.expr,
+                    receiver,
+                    args.iter()
+                        .map(|e| format!("{}", e.expr))
+                        .collect::<Vec<_>>()
+                        .join(", ")
                 )
             }
+            VExpr::WaitFuture(future) => write!(f, "{}({})", "@wait".yellow(), future.expr),
+            VExpr::StopActive(active) => write!(f, "{}({})", "@stop".yellow(), active.expr),
+            VExpr::WaitActive(active) => write!(f, "{}({})", "@wait".yellow(), active.expr),
             VExpr::CurrentActive => write!(f, "{}", "@current_active".yellow()),
             VExpr::CurrentActiveField { field, .. } => {
                 write!(f, "{}.{}", "@current_active".yellow(), field)
@@ -32,10 +32,22 @@ pub struct ClassDecl {
     pub pos: usize,
     pub is_active: bool,
     pub name: String,
+    pub options: ActiveOptions,
     pub fields: Vec<TypedItem>,
     pub methods: Vec<FunctionDecl>,
 }
 
+/// Options of active type, e.g. `active Worker(mailbox = 100, overflow = drop_oldest)`,
+/// names of policies and strategies are checked by semantics
+#[derive(Debug, PartialEq, Default)]
+pub struct ActiveOptions {
+    pub mailbox: Option<i64>,
+    pub overflow: Option<String>,
+    pub supervision: Option<String>,
+    pub max_restarts: Option<i64>,
+    pub restart_period: Option<i64>, // milliseconds
+}
+
 #[derive(Debug, PartialEq)]
 pub struct FunctionDecl {
     pub pos: usize,
@@ -81,6 +93,7 @@ pub enum Statement {
         active: ExprWithPos,
         method: String,
         args: Vec<ExprWithPos>,
+        delay: Option<ExprWithPos>, // milliseconds, message is sent right away without it
     },
     // TODO: SWaitMessage
     Expr(ExprWithPos),
@@ -118,9 +131,8 @@ pub enum UnaryOp {
     Negate,
 }
 
-// TODO : exceptions lead to message being discarder + logs!!
-// This means that if we do something like array[-1], we do not handle it, lol
-// maybe save state of the actor before running it? (2x memory for this)
+// NOTE: runtime errors lead to message being discarded + logs
+// With `frisbee run --transactional` state of the actor is also rolled back
 
 #[derive(Debug, PartialEq)]
 pub struct ExprWithPos {
@@ -140,23 +152,64 @@ pub enum Expr {
     This,
     Identifier(String),
 
-    UnaryOp { op: UnaryOp, operand: Box<ExprWithPos> },
-    BinOp { left: Box<ExprWithPos>, right: Box<ExprWithPos>, op: BinaryOp },
+    UnaryOp {
+        op: UnaryOp,
+        operand: Box<ExprWithPos>,
+    },
+    BinOp {
+        left: Box<ExprWithPos>,
+        right: Box<ExprWithPos>,
+        op: BinaryOp,
+    },
 
-    ListAccess { list: Box<ExprWithPos>, index: Box<ExprWithPos> },
+    ListAccess {
+        list: Box<ExprWithPos>,
+        index: Box<ExprWithPos>,
+    },
     ListValue(Vec<ExprWithPos>),
     TupleValue(Vec<ExprWithPos>),
 
-    FunctionCall { function: String, args: Vec<ExprWithPos> },
+    FunctionCall {
+        function: String,
+        args: Vec<ExprWithPos>,
+    },
 
-    MethodCall { object: Box<ExprWithPos>, method: String, args: Vec<ExprWithPos> },
-    FieldAccess { object: Box<ExprWithPos>, field: String },
+    MethodCall {
+        object: Box<ExprWithPos>,
+        method: String,
+        args: Vec<ExprWithPos>,
+    },
+    FieldAccess {
+        object: Box<ExprWithPos>,
+        field: String,
+    },
 
-    OwnMethodCall { method: String, args: Vec<ExprWithPos> },
-    OwnFieldAccess { field: String },
+    OwnMethodCall {
+        method: String,
+        args: Vec<ExprWithPos>,
+    },
+    OwnFieldAccess {
+        field: String,
+    },
 
-    MaybeMethodCall { object: Box<ExprWithPos>, method: String, args: Vec<ExprWithPos> },
+    MaybeMethodCall {
+        object: Box<ExprWithPos>,
+        method: String,
+        args: Vec<ExprWithPos>,
+    },
 
-    NewClassInstance { typename: String, args: Vec<ExprWithPos> },
-    SpawnActive { typename: String, args: Vec<ExprWithPos> },
+    NewClassInstance {
+        typename: String,
+        args: Vec<ExprWithPos>,
+    },
+    SpawnActive {
+        typename: String,
+        args: Vec<ExprWithPos>,
+        node: Option<Box<ExprWithPos>>,
+    },
+    AskMessage {
+        active: Box<ExprWithPos>,
+        method: String,
+        args: Vec<ExprWithPos>,
+    },
 }
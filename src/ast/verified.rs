@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 
 use crate::alias::ModuleAlias;
+use crate::runtime::scheduler::MailboxConfig;
+use crate::runtime::supervision::SupervisorConfig;
 use crate::symbols::{SymbolFunc, SymbolType};
 use crate::types::VerifiedType;
 
@@ -8,6 +10,8 @@ use crate::types::VerifiedType;
 pub struct CustomType {
     pub name: SymbolType,
     pub is_active: bool,
+    pub mailbox: MailboxConfig,
+    pub supervision: Option<SupervisorConfig>,
     pub fields: TypedFields,
 }
 
@@ -16,7 +20,7 @@ pub struct RawFunction {
     pub name: SymbolFunc,
     pub return_type: VerifiedType,
     pub args: TypedFields,
-    pub body: Vec<VStatement>,
+    pub body: Vec<VStatementWithPos>,
     pub locals: Vec<(String, VerifiedType)>,
 
     pub short_name: String,
@@ -35,16 +39,23 @@ pub struct TypedFields {
     pub names: HashMap<usize, String>,
 }
 
+// Position in the source file, used to map bytecode back to the source code
+#[derive(Debug)]
+pub struct VStatementWithPos {
+    pub statement: VStatement,
+    pub pos: usize,
+}
+
 #[derive(Debug)]
 pub enum VStatement {
     IfElse {
         condition: VExprTyped,
-        if_body: Vec<VStatement>,
-        else_body: Vec<VStatement>,
+        if_body: Vec<VStatementWithPos>,
+        else_body: Vec<VStatementWithPos>,
     },
     While {
         condition: VExprTyped,
-        body: Vec<VStatement>,
+        body: Vec<VStatementWithPos>,
     },
     Break,
     Continue,
@@ -84,6 +95,7 @@ pub enum VStatement {
         active: VExprTyped,
         receiver: SymbolFunc,
         args: Vec<VExprTyped>,
+        delay: Option<VExprTyped>,
     },
 }
 
@@ -121,6 +133,7 @@ pub enum RawOperator {
 pub struct VExprTyped {
     pub expr: VExpr,
     pub expr_type: VerifiedType,
+    pub pos: usize, // first symbol of the expression, runtime errors point to it
 }
 
 // DO NOT ADD CLONE as cloning an expression might
@@ -175,12 +188,23 @@ pub enum VExpr {
     Spawn {
         typename: SymbolType,
         args: Vec<VExprTyped>,
+        node: Option<Box<VExprTyped>>, // name of remote node, active object is spawned locally if None
     },
     CurrentActive,
     CurrentActiveField {
         active_type: SymbolType,
         field: String,
     },
+
+    // Like SendMessage, but returns a future for the return value of receiver
+    AskMessage {
+        active: Box<VExprTyped>,
+        receiver: SymbolFunc,
+        args: Vec<VExprTyped>,
+    },
+    WaitFuture(Box<VExprTyped>),
+    StopActive(Box<VExprTyped>),
+    WaitActive(Box<VExprTyped>), // waits until active object is stopped
 }
 
 impl TypedFields {
@@ -191,4 +215,8 @@ impl TypedFields {
     pub fn len(&self) -> usize {
         self.types.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
 }
@@ -1,40 +1,107 @@
 use std::collections::HashMap;
 
-use crate::symbols::SymbolFunc;
+use crate::runtime::header::{self, features, Header};
+use crate::runtime::opcodes::op;
+use crate::symbols::{SymbolFunc, ON_CHILD_FAILED_METHOD_NAME, ON_STOP_METHOD_NAME};
 
 use super::generator::FunctionBytecode;
 use super::metadata::{CustomTypesMetadataTable, ListKindsMetadataTable};
+use super::utils::{to_operand_bytes, too_big_message};
 
 /*
 Bytecode structure:
- - 0xff 0xff : two starting bytes
+ - versioned header (see runtime/header.rs), checksum is filled in the end
  - constants block (see constants.rs::constants_to_bytecode)
     - constants block ends with CONST_END_FLAG byte
+ - types and list kinds info blocks start with amount of entries (2 bytes), each entry is
+   a name, size (2 bytes) and pointer map
+    - pointer map is amount of entries + offset of each pointer (2 bytes each), offsets of
+      active object handles have ACTIVE_HANDLE_FLAG bit set
+ - mailboxes block, for each type: capacity (4 bytes) and overflow policy (1 byte),
+   both are 0 if type does not declare them, then placeholder for `on_stop` method start,
+   0 if there is no such method
+ - all placeholders for function starts are 4 bytes
+    - then supervision: strategy (1 byte, 0 if type is not a supervisor), max restarts
+      (2 bytes), restart period in milliseconds (4 bytes) and placeholder for
+      `on_child_failed` method start
+ - layouts block: tuple of types of fields for each type, then type of items for each list
+   kind, both encoded the same way as types of locals
  - symbols info block, that contains function names
     - each block starts with a string (2 bytes for length + string)
     - then, placeholder for the function start
+ - functions return info block, same as symbols info but with sizes and pointers of return values
+ - stack maps block, for each function:
+    - placeholder for the function start and amount of safepoints
+    - for each safepoint: offset from function start (4 bytes), amount of stack slots (2 bytes)
+      and bitmap of slots that hold pointers (1 bit per slot, lowest bit first)
+ - line table block, for each function:
+    - placeholder for the function start, module name and amount of entries (4 bytes)
+    - for each entry: offset from function start, line and column (4 bytes each)
+ - locals block, for each function:
+    - placeholder for the function start and amount of locals (2 bytes)
+    - for each local: name, offset in the frame (2 bytes) and its type, encoded by debugger
+      (2 bytes for length + bytes)
+ - signatures block, for each function:
+    - placeholder for the function start and index of the active type, if function is its
+      method (2 bytes, u16::MAX otherwise)
+    - amount of arguments (2 bytes), types of arguments and of the return value, encoded
+      the same way as types of locals
  - functions bytecode
 
 */
 
 static HEADER: [u8; 2] = [u8::MAX, u8::MAX];
 
-fn get_by_value<'a, K>(m: &'a HashMap<K, usize>, value: usize) -> &'a K {
+fn get_by_value<K>(m: &HashMap<K, usize>, value: usize) -> &K {
     m.iter().find(|(_, v)| **v == value).unwrap().0
 }
 
-fn push_str(bytecode: &mut Vec<u8>, s: &str) {
-    bytecode.extend((s.len() as u16).to_be_bytes());
+fn push_str(bytecode: &mut Vec<u8>, s: &str) -> Result<(), String> {
+    push_usize_as_u16(bytecode, s.len(), "length of name")?;
     bytecode.extend(s.as_bytes());
+    Ok(())
 }
 
-fn push_pointers_map(bytecode: &mut Vec<u8>, pointers_map: &[usize]) {
-    bytecode.push(pointers_map.len() as u8);
-    bytecode.extend(pointers_map.iter().map(|x| *x as u8));
+fn push_pointers_map(
+    bytecode: &mut Vec<u8>,
+    pointers_map: &[usize],
+    active_map: &[usize],
+) -> Result<(), String> {
+    let amount = pointers_map.len() + active_map.len();
+    push_usize_as_u16(bytecode, amount, "amount of pointers")?;
+    // Highest bit of the offset is taken by the flag
+    let max_offset = op::ACTIVE_HANDLE_FLAG as usize - 1;
+    for (offset, flag) in (pointers_map.iter().map(|x| (*x, 0)))
+        .chain(active_map.iter().map(|x| (*x, op::ACTIVE_HANDLE_FLAG)))
+    {
+        if offset > max_offset {
+            return Err(too_big_message("pointer offset", offset, max_offset));
+        }
+        bytecode.extend((offset as u16 | flag).to_be_bytes());
+    }
+    Ok(())
+}
+
+fn push_usize_as_u16(bytecode: &mut Vec<u8>, value: usize, what: &str) -> Result<(), String> {
+    bytecode.extend(to_operand_bytes::<2>(value, what)?);
+    Ok(())
 }
 
-fn push_usize_as_u16(bytecode: &mut Vec<u8>, value: usize) {
-    bytecode.extend((value as u16).to_be_bytes());
+fn push_usize_as_u32(bytecode: &mut Vec<u8>, value: usize, what: &str) -> Result<(), String> {
+    bytecode.extend(to_operand_bytes::<4>(value, what)?);
+    Ok(())
+}
+
+fn push_stack_map_bitmap(bytecode: &mut Vec<u8>, flags: &[bool]) -> Result<(), String> {
+    push_usize_as_u16(bytecode, flags.len(), "frame size")?;
+    for chunk in flags.chunks(8) {
+        let byte = chunk
+            .iter()
+            .enumerate()
+            .fold(0u8, |acc, (i, f)| acc | ((*f as u8) << i));
+        bytecode.push(byte);
+    }
+    Ok(())
 }
 
 pub fn assemble_chunks(
@@ -43,57 +110,198 @@ pub fn assemble_chunks(
     list_kinds_meta: ListKindsMetadataTable,
     functions: Vec<FunctionBytecode>,
     entry: &SymbolFunc,
-) -> Vec<u8> {
-    // 1. Initial header
-    let mut bytecode: Vec<u8> = HEADER.into();
+) -> Result<Vec<u8>, String> {
+    // 1. Versioned header, with features used by any function or type
+    let mut used_features = functions.iter().fold(0, |acc, f| acc | f.features);
+    if custom_types_meta.metadata.iter().any(|t| t.supervision.is_some()) {
+        used_features |= features::SUPERVISION;
+    }
+    let mut bytecode: Vec<u8> = Header::new(used_features).to_bytes();
 
     // 2. constants block along with trailing header
     bytecode.extend(constants);
     bytecode.extend_from_slice(&HEADER);
 
     // 3. Types info (size + pointer mapping)
-    bytecode.push(custom_types_meta.metadata.len() as u8);
+    let types_amount = custom_types_meta.metadata.len();
+    push_usize_as_u16(&mut bytecode, types_amount, "amount of types")?;
     for (i, type_meta) in custom_types_meta.metadata.iter().enumerate() {
         let type_name = get_by_value(&custom_types_meta.indexes, i);
-        push_str(&mut bytecode, &format!("{}", type_name));
-        push_usize_as_u16(&mut bytecode, type_meta.size as usize);
-        push_pointers_map(&mut bytecode, &type_meta.pointer_mapping);
+        push_str(&mut bytecode, &format!("{}", type_name))?;
+        push_usize_as_u16(&mut bytecode, type_meta.size, "size of type")?;
+        push_pointers_map(
+            &mut bytecode,
+            &type_meta.pointer_mapping,
+            &type_meta.active_mapping,
+        )?;
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 3.1 Mailboxes of types, their stop hooks and supervision, in the same order as types info
+    let mut hook_methods = vec![];
+    for (i, type_meta) in custom_types_meta.metadata.iter().enumerate() {
+        let capacity = type_meta.mailbox.capacity.unwrap_or(0) as u32;
+        bytecode.extend(capacity.to_be_bytes());
+        bytecode.push(type_meta.mailbox.overflow.map_or(0, |p| p.to_byte()));
+
+        let type_name = get_by_value(&custom_types_meta.indexes, i);
+        let mut push_hook = |bytecode: &mut Vec<u8>, hook_name| {
+            let hook = type_name.method(hook_name);
+            if let Some(function_info) = functions.iter().find(|f| f.name == hook) {
+                hook_methods.push((bytecode.len(), &function_info.name));
+            }
+            bytecode.extend([0; 4]);
+        };
+        push_hook(&mut bytecode, ON_STOP_METHOD_NAME);
+
+        match type_meta.supervision {
+            Some(supervision) => {
+                bytecode.push(supervision.strategy.to_byte());
+                bytecode.extend((supervision.max_restarts as u16).to_be_bytes());
+                bytecode.extend((supervision.period.as_millis() as u32).to_be_bytes());
+            }
+            None => bytecode.extend([0; 7]),
+        }
+        push_hook(&mut bytecode, ON_CHILD_FAILED_METHOD_NAME);
     }
     bytecode.extend_from_slice(&HEADER);
 
     // 4. List kinds info (item size + pointer mapping)
-    bytecode.push(list_kinds_meta.metadata.len() as u8);
-    for (i, list_kind_meta) in list_kinds_meta.metadata.iter().enumerate() {
-        let item_type = get_by_value(&list_kinds_meta.indexes, i);
-        push_str(&mut bytecode, &format!("{}", item_type));
-        push_usize_as_u16(&mut bytecode, list_kind_meta.size as usize);
-        push_pointers_map(&mut bytecode, &list_kind_meta.pointer_mapping);
+    let list_kinds_amount = list_kinds_meta.metadata.len();
+    push_usize_as_u16(&mut bytecode, list_kinds_amount, "amount of list kinds")?;
+    for list_kind_meta in list_kinds_meta.metadata.iter() {
+        push_str(&mut bytecode, &format!("{}", list_kind_meta.item_type))?;
+        push_usize_as_u16(&mut bytecode, list_kind_meta.size, "size of list item")?;
+        push_pointers_map(
+            &mut bytecode,
+            &list_kind_meta.pointer_mapping,
+            &list_kind_meta.active_mapping,
+        )?;
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 4.1 Layouts: types of fields of every type, then types of items of every list kind
+    let layouts = custom_types_meta.metadata.iter().map(|t| &t.layout);
+    let item_types = list_kinds_meta.metadata.iter().map(|l| &l.item_type);
+    for value_type in layouts.chain(item_types) {
+        let value_type = custom_types_meta.get_value_type(value_type).to_bytes();
+        push_usize_as_u16(&mut bytecode, value_type.len(), "length of layout type")?;
+        bytecode.extend(value_type);
     }
     bytecode.extend_from_slice(&HEADER);
 
     // 5. Functions info (names + locals sizes + pointer mapping)
-    bytecode.push(functions.len() as u8);
+    push_usize_as_u16(&mut bytecode, functions.len(), "amount of functions")?;
+    for function_info in functions.iter() {
+        push_str(&mut bytecode, &format!("{}", function_info.name))?;
+        push_usize_as_u16(&mut bytecode, function_info.args_size, "arguments size")?;
+        push_pointers_map(
+            &mut bytecode,
+            &function_info.args_pointer_mapping,
+            &function_info.args_active_mapping,
+        )?;
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 6. Functions return info (return value size + pointer mapping), used to send it back
+    push_usize_as_u16(&mut bytecode, functions.len(), "amount of functions")?;
+    for function_info in functions.iter() {
+        push_str(&mut bytecode, &format!("{}", function_info.name))?;
+        push_usize_as_u16(
+            &mut bytecode,
+            function_info.return_size,
+            "return value size",
+        )?;
+        push_pointers_map(
+            &mut bytecode,
+            &function_info.return_pointer_mapping,
+            &function_info.return_active_mapping,
+        )?;
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 7. Function positions (debug info)
+    let mut encoded_symbols_info: HashMap<usize, &SymbolFunc> = hook_methods.into_iter().collect();
     for function_info in functions.iter() {
-        push_str(&mut bytecode, &format!("{}", function_info.name));
-        push_usize_as_u16(&mut bytecode, function_info.args_size);
-        push_pointers_map(&mut bytecode, &function_info.args_pointer_mapping);
+        encoded_symbols_info.insert(bytecode.len(), &function_info.name);
+        bytecode.extend([0; 4]);
     }
     bytecode.extend_from_slice(&HEADER);
 
-    // 6. Function positions (debug info)
-    let mut encoded_symbols_info: HashMap<usize, &SymbolFunc> = HashMap::new();
+    // 8. Stack maps: for each function start placeholder and safepoints amount, then
+    // for each safepoint its position relative to function start + bitmap of pointers
     for function_info in functions.iter() {
         encoded_symbols_info.insert(bytecode.len(), &function_info.name);
-        bytecode.extend([0, 0]);
+        bytecode.extend([0; 4]);
+        let safepoints_amount = function_info.stack_maps.len();
+        push_usize_as_u32(&mut bytecode, safepoints_amount, "amount of safepoints")?;
+        for (pos, flags) in function_info.stack_maps.iter() {
+            push_usize_as_u32(&mut bytecode, *pos, "safepoint offset")?;
+            push_stack_map_bitmap(&mut bytecode, flags)?;
+        }
     }
     bytecode.extend_from_slice(&HEADER);
 
-    // 7. Entry function pointer + header
+    // 9. Line table: source positions of statements, used for stack traces
+    for function_info in functions.iter() {
+        encoded_symbols_info.insert(bytecode.len(), &function_info.name);
+        bytecode.extend([0; 4]);
+        push_str(&mut bytecode, &function_info.module)?;
+        let entries_amount = function_info.line_table.len();
+        push_usize_as_u32(
+            &mut bytecode,
+            entries_amount,
+            "amount of line table entries",
+        )?;
+        for (pos, line, column) in function_info.line_table.iter() {
+            push_usize_as_u32(&mut bytecode, *pos, "statement offset")?;
+            push_usize_as_u32(&mut bytecode, *line, "line")?;
+            push_usize_as_u32(&mut bytecode, *column, "column")?;
+        }
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 10. Locals: names and types of values in the frame, used by debugger
+    for function_info in functions.iter() {
+        encoded_symbols_info.insert(bytecode.len(), &function_info.name);
+        bytecode.extend([0; 4]);
+        push_usize_as_u16(
+            &mut bytecode,
+            function_info.locals.len(),
+            "amount of locals",
+        )?;
+        for local in function_info.locals.iter() {
+            push_str(&mut bytecode, &local.name)?;
+            push_usize_as_u16(&mut bytecode, local.offset, "local offset")?;
+            let value_type = local.value_type.to_bytes();
+            push_usize_as_u16(&mut bytecode, value_type.len(), "length of local type")?;
+            bytecode.extend(value_type);
+        }
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 11. Signatures: types of arguments and return values, used to call functions from host
+    for function_info in functions.iter() {
+        encoded_symbols_info.insert(bytecode.len(), &function_info.name);
+        bytecode.extend([0; 4]);
+        let signature = &function_info.signature;
+        let active_type = signature.active_type.unwrap_or(u16::MAX as usize);
+        bytecode.extend((active_type as u16).to_be_bytes());
+        push_usize_as_u16(&mut bytecode, signature.args.len(), "amount of arguments")?;
+        for value_type in signature.args.iter().chain(std::iter::once(&signature.returns)) {
+            let value_type = value_type.to_bytes();
+            push_usize_as_u16(&mut bytecode, value_type.len(), "length of argument type")?;
+            bytecode.extend(value_type);
+        }
+    }
+    bytecode.extend_from_slice(&HEADER);
+
+    // 12. Entry function pointer + header
     encoded_symbols_info.insert(bytecode.len(), entry);
-    bytecode.extend([0, 0]); // placeholder, will be filled in later
+    bytecode.extend([0; 4]); // placeholder, will be filled in later
     bytecode.extend_from_slice(&HEADER);
 
-    // 8. Functions bytecode, no headers anymore
+    // 13. Functions bytecode, no headers anymore
     let mut functions_start: HashMap<&SymbolFunc, usize> = HashMap::new();
 
     for function_bytecode in functions.iter() {
@@ -104,12 +312,23 @@ pub fn assemble_chunks(
         bytecode.extend_from_slice(&function_bytecode.bytecode);
     }
 
+    // Positions in the program and jumps inside functions are 4 bytes
+    if bytecode.len() > u32::MAX as usize {
+        return Err(too_big_message(
+            "program size",
+            bytecode.len(),
+            u32::MAX as usize,
+        ));
+    }
+
     // BACKTRACKING: fill function pointers in CALL operations and symbol table
     for (pos, called_func) in encoded_symbols_info.iter() {
-        let start = (functions_start[called_func] as u16).to_be_bytes();
-        bytecode[*pos] = start[0];
-        bytecode[*pos + 1] = start[1];
+        let start = (functions_start[called_func] as u32).to_be_bytes();
+        bytecode[*pos..*pos + 4].copy_from_slice(&start);
     }
 
-    bytecode
+    // Checksum covers everything after the header, so it is calculated last
+    header::seal(&mut bytecode);
+
+    Ok(bytecode)
 }
@@ -21,7 +21,7 @@ fn constants_to_bytecode(data: &[Constant]) -> Vec<u8> {
             }
             Constant::String(s) => {
                 res.push(op::CONST_STRING_FLAG);
-                res.extend((s.len() as u16).to_be_bytes());
+                res.extend((s.len() as u32).to_be_bytes());
                 res.extend(s.as_bytes());
             }
         }
@@ -40,13 +40,13 @@ impl ConstantsTable {
         ConstantsTable { table: vec![] }
     }
 
-    pub fn get_constant(&mut self, constant: Constant) -> u8 {
+    pub fn get_constant(&mut self, constant: Constant) -> usize {
         let existing = self.table.iter().enumerate().find(|(_, c)| *c == &constant);
         match existing {
-            Some((pos, _)) => pos as u8,
+            Some((pos, _)) => pos,
             None => {
                 self.table.push(constant);
-                (self.table.len() - 1) as u8
+                self.table.len() - 1
             }
         }
     }
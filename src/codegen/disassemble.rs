@@ -1,8 +1,13 @@
+use crate::runtime::debugger::ValueType;
+use crate::runtime::header::{features, Header};
 use crate::runtime::opcodes::op;
+use crate::runtime::scheduler::OverflowPolicy;
+use crate::runtime::supervision::RestartStrategy;
 use owo_colors::OwoColorize;
 use std::collections::HashMap;
 
 pub struct Disassembler<'a> {
+    program: &'a [u8],
     program_iter: Box<dyn Iterator<Item = (usize, &'a u8)> + 'a>,
     result: Vec<String>,
     function_names: HashMap<usize, String>,
@@ -13,6 +18,7 @@ pub struct Disassembler<'a> {
 impl<'a> Disassembler<'a> {
     pub fn new(program: &'a [u8]) -> Self {
         Disassembler {
+            program,
             program_iter: Box::new(program.iter().enumerate()),
             result: vec![],
             function_names: HashMap::new(),
@@ -21,10 +27,11 @@ impl<'a> Disassembler<'a> {
         }
     }
 
-    pub fn disassemble(&mut self) -> String {
+    /// Text of the program, or why it can not be run by this version of VM
+    pub fn disassemble(&mut self) -> Result<String, String> {
         self.result.clear();
 
-        self.read_header("Initial");
+        self.read_version_header()?;
 
         self.read_constants();
         self.read_header("End of constants");
@@ -35,12 +42,18 @@ impl<'a> Disassembler<'a> {
         }
         self.read_header("End of types metadata");
 
+        self.read_mailboxes();
+        self.read_header("End of mailboxes metadata");
+
         // Read lists metadata
         for (i, item_type) in self.read_info_block() {
             self.list_kind_names.insert(i, item_type);
         }
         self.read_header("End of list types metadata");
 
+        self.read_layouts();
+        self.read_header("End of layouts");
+
         // Read functions metadata
         let mut function_names = vec![];
         for (_, fname) in self.read_info_block() {
@@ -48,18 +61,34 @@ impl<'a> Disassembler<'a> {
         }
         self.read_header("End of function metadata");
 
+        // Return values metadata has the same functions in the same order
+        self.read_info_block();
+        self.read_header("End of function return metadata");
+
         for fname in function_names.into_iter() {
-            let pos = u16::from_be_bytes(self.get_bytes::<2>());
+            let pos = u32::from_be_bytes(self.get_bytes::<4>());
             self.function_names.insert(pos as usize, fname);
         }
         self.read_header("End of function positions");
 
+        self.read_stack_maps();
+        self.read_header("End of stack maps");
+
+        self.read_line_table();
+        self.read_header("End of line table");
+
+        self.read_locals();
+        self.read_header("End of locals");
+
+        self.read_signatures();
+        self.read_header("End of signatures");
+
         self.read_entry();
         self.read_header("Start of functions");
 
         self.read_functions();
 
-        self.result.join("\n")
+        Ok(self.result.join("\n"))
     }
 
     fn get_byte(&mut self) -> (usize, u8) {
@@ -68,7 +97,11 @@ impl<'a> Disassembler<'a> {
     }
 
     fn get_str(&mut self) -> String {
-        let n = u16::from_be_bytes(self.get_bytes::<2>());
+        let n = u16::from_be_bytes(self.get_bytes::<2>()) as usize;
+        self.get_str_of_length(n)
+    }
+
+    fn get_str_of_length(&mut self, n: usize) -> String {
         let mut s = String::new();
         for _ in 0..n {
             s.push(self.get_byte().1 as char);
@@ -84,6 +117,24 @@ impl<'a> Disassembler<'a> {
         bytes
     }
 
+    fn read_version_header(&mut self) -> Result<(), String> {
+        let (header, length) = Header::read(self.program).map_err(|kind| kind.to_string())?;
+        header
+            .check(&self.program[length..])
+            .map_err(|kind| kind.to_string())?;
+        for _ in 0..length {
+            self.get_byte();
+        }
+        self.result.push(format!(
+            "Bytecode format {}, compiled by frisbee {}, features {:?}, checksum {:016x}",
+            header.format_version,
+            header.compiler_version,
+            features::names(header.features),
+            header.checksum
+        ));
+        Ok(())
+    }
+
     fn read_header(&mut self, header_name: &str) {
         let header = self.get_bytes::<2>();
         self.result.push(format!(
@@ -101,7 +152,10 @@ impl<'a> Disassembler<'a> {
             let const_text: String = match self.get_byte().1 {
                 op::CONST_INT_FLAG => i64::from_be_bytes(self.get_bytes::<8>()).to_string(),
                 op::CONST_FLOAT_FLAG => f64::from_be_bytes(self.get_bytes::<8>()).to_string(),
-                op::CONST_STRING_FLAG => format!("{:?}", self.get_str()), // Debug pring escapes string
+                op::CONST_STRING_FLAG => {
+                    let n = u32::from_be_bytes(self.get_bytes::<4>()) as usize;
+                    format!("{:?}", self.get_str_of_length(n)) // Debug print escapes string
+                }
                 op::CONST_END_FLAG => {
                     break;
                 }
@@ -112,16 +166,55 @@ impl<'a> Disassembler<'a> {
         }
     }
 
+    fn read_mailboxes(&mut self) {
+        self.result.push("Mailboxes:".to_string());
+        for i in 0..self.type_names.len() {
+            let capacity = u32::from_be_bytes(self.get_bytes::<4>());
+            let policy = self.get_byte().1;
+            // Hooks are shown along with other methods
+            self.get_bytes::<4>();
+            let strategy = self.get_byte().1;
+            let max_restarts = u16::from_be_bytes(self.get_bytes::<2>());
+            let period = u32::from_be_bytes(self.get_bytes::<4>());
+            self.get_bytes::<4>();
+
+            if let Some(strategy) = RestartStrategy::from_byte(strategy) {
+                self.result.push(format!(
+                    "   {} -> supervision {}, {} restarts in {}ms",
+                    self.type_names[&i],
+                    strategy.name(),
+                    max_restarts,
+                    period
+                ));
+            }
+            if capacity == 0 && policy == 0 {
+                continue;
+            }
+            let capacity = match capacity {
+                0 => "default".to_string(),
+                capacity => capacity.to_string(),
+            };
+            let policy = match OverflowPolicy::from_byte(policy) {
+                Some(policy) => policy.name(),
+                None => "default",
+            };
+            self.result.push(format!(
+                "   {} -> capacity {}, overflow {}",
+                self.type_names[&i], capacity, policy
+            ));
+        }
+    }
+
     fn read_info_block(&mut self) -> Vec<(usize, String)> {
-        let amount = self.get_byte().1;
+        let amount = u16::from_be_bytes(self.get_bytes::<2>());
         let mut res = vec![];
         for _ in 0..amount {
             let name = self.get_str();
 
             // Skip through sizes + pointer mappings as they are not needed for disassembly
             self.get_bytes::<2>();
-            for _ in 0..self.get_byte().1 {
-                self.get_byte();
+            for _ in 0..u16::from_be_bytes(self.get_bytes::<2>()) {
+                self.get_bytes::<2>();
             }
 
             res.push(name);
@@ -129,9 +222,132 @@ impl<'a> Disassembler<'a> {
         res.into_iter().enumerate().collect()
     }
 
+    fn read_stack_maps(&mut self) {
+        self.result.push("Stack maps:".to_string());
+        for _ in 0..self.function_names.len() {
+            let start = u32::from_be_bytes(self.get_bytes::<4>()) as usize;
+            self.result.push(format!("   {}:", self.function_names[&start]));
+
+            for _ in 0..u32::from_be_bytes(self.get_bytes::<4>()) {
+                let pos = u32::from_be_bytes(self.get_bytes::<4>()) as usize + start;
+                let slots = u16::from_be_bytes(self.get_bytes::<2>()) as usize;
+                let bitmap: Vec<u8> = (0..slots.div_ceil(8)).map(|_| self.get_byte().1).collect();
+                let pointers: Vec<usize> =
+                    (0..slots).filter(|i| bitmap[i / 8] & (1 << (i % 8)) != 0).collect();
+                self.result.push(format!(
+                    "      {:>4x?} -> {} slots, pointers at {:?}",
+                    pos.blue(),
+                    slots,
+                    pointers
+                ));
+            }
+        }
+    }
+
+    fn read_line_table(&mut self) {
+        self.result.push("Line table:".to_string());
+        for _ in 0..self.function_names.len() {
+            let start = u32::from_be_bytes(self.get_bytes::<4>()) as usize;
+            let module = self.get_str();
+            self.result.push(format!(
+                "   {} (in {}):",
+                self.function_names[&start], module
+            ));
+
+            for _ in 0..u32::from_be_bytes(self.get_bytes::<4>()) {
+                let pos = u32::from_be_bytes(self.get_bytes::<4>()) as usize + start;
+                let line = u32::from_be_bytes(self.get_bytes::<4>());
+                let column = u32::from_be_bytes(self.get_bytes::<4>());
+                self.result.push(format!(
+                    "      {:>4x?} -> line {}, column {}",
+                    pos.blue(),
+                    line + 1,
+                    column + 1
+                ));
+            }
+        }
+    }
+
+    fn sorted_type_names(&self) -> Vec<String> {
+        let mut type_names: Vec<(usize, String)> =
+            self.type_names.iter().map(|(i, name)| (*i, name.clone())).collect();
+        type_names.sort();
+        type_names.into_iter().map(|(_, name)| name).collect()
+    }
+
+    fn read_locals(&mut self) {
+        self.result.push("Locals:".to_string());
+        let type_names = self.sorted_type_names();
+
+        for _ in 0..self.function_names.len() {
+            let start = u32::from_be_bytes(self.get_bytes::<4>()) as usize;
+            self.result.push(format!("   {}:", self.function_names[&start]));
+
+            for _ in 0..u16::from_be_bytes(self.get_bytes::<2>()) {
+                let name = self.get_str();
+                let offset = u16::from_be_bytes(self.get_bytes::<2>());
+                let type_name = self.read_type(&type_names);
+                self.result.push(format!(
+                    "      {:>4} -> {}: {}",
+                    offset.blue(),
+                    name,
+                    type_name
+                ));
+            }
+        }
+    }
+
+    fn read_type(&mut self, type_names: &[String]) -> String {
+        let type_len = u16::from_be_bytes(self.get_bytes::<2>());
+        let type_bytes: Vec<u8> = (0..type_len).map(|_| self.get_byte().1).collect();
+        ValueType::from_bytes(&type_bytes)
+            .map_or("<unknown type>".to_string(), |t| t.name(type_names))
+    }
+
+    fn read_layouts(&mut self) {
+        self.result.push("Layouts:".to_string());
+        let type_names = self.sorted_type_names();
+        for name in type_names.iter() {
+            let layout = self.read_type(&type_names);
+            self.result.push(format!("   {} -> {}", name, layout));
+        }
+        for i in 0..self.list_kind_names.len() {
+            let item_type = self.read_type(&type_names);
+            self.result.push(format!(
+                "   [{}] -> items {}",
+                self.list_kind_names[&i], item_type
+            ));
+        }
+    }
+
+    fn read_signatures(&mut self) {
+        self.result.push("Signatures:".to_string());
+        let type_names = self.sorted_type_names();
+
+        for _ in 0..self.function_names.len() {
+            let start = u32::from_be_bytes(self.get_bytes::<4>()) as usize;
+            let active_type = u16::from_be_bytes(self.get_bytes::<2>());
+            let args_amount = u16::from_be_bytes(self.get_bytes::<2>());
+            let mut types: Vec<String> =
+                (0..=args_amount).map(|_| self.read_type(&type_names)).collect();
+            let returns = types.pop().unwrap();
+            let owner = match type_names.get(active_type as usize) {
+                Some(name) if active_type != u16::MAX => format!(", runs in {}", name),
+                _ => String::new(),
+            };
+            self.result.push(format!(
+                "   {}({}) -> {}{}",
+                self.function_names[&start],
+                types.join(", "),
+                returns,
+                owner
+            ));
+        }
+    }
+
     fn read_entry(&mut self) {
-        let entry = self.get_bytes::<2>();
-        let entry_name = &self.function_names[&(u16::from_be_bytes(entry) as usize)];
+        let entry = self.get_bytes::<4>();
+        let entry_name = &self.function_names[&(u32::from_be_bytes(entry) as usize)];
         self.result
             .push(format!("Entry point:\n   -> {} {:02x?}", entry_name, entry));
     }
@@ -156,23 +372,32 @@ impl<'a> Disassembler<'a> {
                 args
             );
 
-            // + 3 is added, because jump offset is relative to instruction pointer
-            // but `i` var points to jump opcode, which is 3 steps behind
-            // (1 step for jump itself and 2 for address of jump)
+            let read_u16 = |from: usize| u16::from_be_bytes([args[from], args[from + 1]]) as usize;
+            let read_u32 = |from: usize| {
+                let bytes = [args[from], args[from + 1], args[from + 2], args[from + 3]];
+                u32::from_be_bytes(bytes) as usize
+            };
+
+            // + 5 is added, because jump offset is relative to instruction pointer
+            // but `i` var points to jump opcode, which is 5 steps behind
+            // (1 step for jump itself and 4 for address of jump)
             if *opcode == op::JUMP_IF_FALSE || *opcode == op::JUMP {
-                let x = u16::from_be_bytes([args[0], args[1]]) as usize;
-                op_text = format!("{} (jumps to {:02x?}) ", op_text, x + i + 3);
+                let x = read_u32(0);
+                op_text = format!("{} (jumps to {:02x?}) ", op_text, x + i + 5);
             } else if *opcode == op::JUMP_BACK {
-                let x = u16::from_be_bytes([args[0], args[1]]) as usize;
-                op_text = format!("{} (jumps to {:02x?}) ", op_text, i - x + 3);
+                let x = read_u32(0);
+                op_text = format!("{} (jumps to {:02x?}) ", op_text, i + 5 - x);
             } else if *opcode == op::ALLOCATE {
-                let typename = &self.type_names[&(args[0] as usize)];
+                let typename = &self.type_names[&read_u16(0)];
                 op_text.push_str(&format!(" (type {}) ", typename).yellow().to_string());
             } else if *opcode == op::ALLOCATE_LIST {
-                let typename = &self.list_kind_names[&(args[0] as usize)];
+                let typename = &self.list_kind_names[&read_u16(0)];
                 op_text.push_str(&format!(" (list of {}) ", typename).yellow().to_string());
-            } else if *opcode == op::SPAWN {
-                let typename = &self.type_names[&(args[0] as usize)];
+            } else if [op::SEND_MESSAGE, op::ASK_MESSAGE, op::SEND_MESSAGE_AFTER].contains(opcode) {
+                let name = &self.function_names[&read_u32(0)];
+                op_text.push_str(&format!(" ({}) ", name).yellow().to_string());
+            } else if *opcode == op::SPAWN || *opcode == op::SPAWN_REMOTE {
+                let typename = &self.type_names[&read_u16(0)];
                 op_text.push_str(&format!(" (type {}) ", typename).yellow().to_string());
             }
 
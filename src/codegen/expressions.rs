@@ -2,6 +2,7 @@ use super::constants::Constant;
 use super::generator::BytecodeGenerator;
 use super::utils::{get_tuple_offset, get_tuple_subitem_type, get_type_size, unwrap_type_as};
 use crate::ast::verified::{RawOperator, VExpr, VExprTyped};
+use crate::runtime::header::features;
 use crate::runtime::opcodes::op;
 use crate::runtime::stdlib_runners::STD_RAW_FUNCTION_RUNNERS;
 use crate::symbols::SymbolFunc;
@@ -41,7 +42,7 @@ pub fn match_std_function(symbol: &SymbolFunc) -> u8 {
     let matched_std_function = STD_RAW_FUNCTION_RUNNERS
         .iter()
         .enumerate()
-        .find(|(_, (name, _))| symbol.is_eq_to_str(*name));
+        .find(|(_, (name, _))| symbol.is_eq_to_str(name));
     match matched_std_function {
         Some((index, (_, _))) => index as u8,
         None => panic!("No std function {} found", symbol),
@@ -50,7 +51,17 @@ pub fn match_std_function(symbol: &SymbolFunc) -> u8 {
 
 impl<'a> BytecodeGenerator<'a> {
     pub fn push_expr(&mut self, expr: &VExprTyped) {
-        let VExprTyped { expr, .. } = expr;
+        let stack_size = self.get_stack_size();
+        self.push_expr_value(&expr.expr, expr.pos);
+
+        // Whatever happened during the calculation, only the result is left on stack
+        self.truncate_stack(stack_size);
+        self.track_value_on_stack(&expr.expr_type);
+    }
+
+    // Runtime errors and stack traces point to the operations that can fail and to calls,
+    // so source position is pushed right before them, after their operands are calculated
+    fn push_expr_value(&mut self, expr: &VExpr, pos: usize) {
         match expr {
             VExpr::Int(i) => {
                 if 0 <= *i && *i < 256 {
@@ -58,18 +69,11 @@ impl<'a> BytecodeGenerator<'a> {
                     self.push(op::LOAD_SMALL_INT);
                     self.push(*i as u8);
                 } else {
-                    self.push(op::LOAD_CONST);
-                    self.push_constant(Constant::Int(*i as i64));
+                    self.push_load_constant(Constant::Int(*i));
                 }
             }
-            VExpr::Float(f) => {
-                self.push(op::LOAD_CONST);
-                self.push_constant(Constant::Float(*f as f64));
-            }
-            VExpr::String(s) => {
-                self.push(op::LOAD_CONST);
-                self.push_constant(Constant::String(s.clone()));
-            }
+            VExpr::Float(f) => self.push_load_constant(Constant::Float(*f)),
+            VExpr::String(s) => self.push_load_constant(Constant::String(s.clone())),
             VExpr::Bool(b) if *b => self.push(op::LOAD_TRUE),
             VExpr::Bool(_) => self.push(op::LOAD_FALSE),
 
@@ -77,12 +81,20 @@ impl<'a> BytecodeGenerator<'a> {
                 for operand in operands.iter() {
                     self.push_expr(operand);
                 }
+                if let RawOperator::DivInts = operator {
+                    self.push_source_position(pos);
+                }
                 self.push(match_operator(operator));
+                if let RawOperator::AddStrings = operator {
+                    self.push_stack_map(0);
+                }
             }
             VExpr::TernaryOp { condition, if_true, if_false } => {
+                let stack_size = self.get_stack_size();
                 self.push_expr(condition);
 
                 self.push(op::JUMP_IF_FALSE);
+                self.truncate_stack(stack_size);
                 let placeholder_to_skip_ifbody = self.push_placeholder();
 
                 self.push_expr(if_true);
@@ -90,13 +102,15 @@ impl<'a> BytecodeGenerator<'a> {
                 let placeholder_to_skip_elsebody = self.push_placeholder();
                 self.fill_placeholder(&placeholder_to_skip_ifbody);
 
+                // Only one of branches is executed, so value of `if_true` is not on stack here
+                self.truncate_stack(stack_size);
                 self.push_expr(if_false);
                 self.fill_placeholder(&placeholder_to_skip_elsebody);
             }
             VExpr::GetVar(varname) => {
                 let var_pos = *self.locals.get(varname.as_str()).unwrap();
                 self.push(op::GET_LOCAL);
-                self.push(var_pos);
+                self.push_operand::<1>(var_pos, "local offset");
                 self.push_type_size(self.locals_types[varname.as_str()]);
             }
             VExpr::CallFunction { name, return_type, args } => {
@@ -106,18 +120,21 @@ impl<'a> BytecodeGenerator<'a> {
                 for arg in args.iter() {
                     self.push_expr(arg);
                 }
-                let func_locals_size: u8 =
+                let func_locals_size: usize =
                     args.iter().map(|arg| get_type_size(&arg.expr_type)).sum();
+                self.push_source_position(pos);
 
                 if name.is_std() {
                     self.push(op::CALL_STD);
-                    self.push(func_locals_size);
-                    self.push(0);
+                    self.push_operand::<1>(func_locals_size, "arguments size");
+                    self.push_type_size(return_type);
                     self.push(match_std_function(name));
+                    self.push_stack_map(0);
                 } else {
                     self.push(op::CALL);
-                    self.push(func_locals_size);
+                    self.push_operand::<1>(func_locals_size, "arguments size");
                     self.push_function_placeholder(name);
+                    self.push_stack_map(func_locals_size);
                 }
             }
             VExpr::TupleValue(items) => {
@@ -130,11 +147,13 @@ impl<'a> BytecodeGenerator<'a> {
                     self.push_expr(item);
                 }
 
-                let list_flag = self.list_kinds_meta.get_or_insert(item_type);
+                let list_flag =
+                    self.list_kinds_meta.get_or_insert(item_type, self.custom_types_meta);
 
                 self.push(op::ALLOCATE_LIST);
-                self.push(list_flag as u8);
-                self.push(items.len() as u8);
+                self.push_operand::<2>(list_flag, "list kind index");
+                self.push_operand::<2>(items.len(), "amount of list items");
+                self.push_stack_map(0);
             }
             VExpr::AccessTupleItem { tuple, index } => {
                 let tuple_type = &tuple.as_ref().expr_type;
@@ -145,37 +164,79 @@ impl<'a> BytecodeGenerator<'a> {
                 let offset = get_tuple_offset(tuple_type, &[*index]);
                 self.push(op::GET_TUPLE_ITEM);
                 self.push_type_size(tuple_type);
-                self.push(offset);
+                self.push_operand::<1>(offset, "tuple item offset");
                 self.push_type_size(item_type);
             }
             VExpr::AccessField { object, field } => {
                 let object_type = unwrap_type_as!(&object.expr_type, Type::Custom);
                 self.push_expr(object);
+                let object_meta = self.custom_types_meta.get_meta(object_type);
+                self.push_source_position(pos);
                 self.push(op::GET_OBJ_FIELD);
-                self.push(self.custom_types_meta.get_meta(object_type).field_offsets[field]);
-                self.push(self.custom_types_meta.get_meta(object_type).field_sizes[field]);
+                self.push_operand::<2>(object_meta.field_offsets[field], "field offset");
+                self.push_operand::<1>(object_meta.field_sizes[field], "value size");
             }
             VExpr::AccessListItem { list, index } => {
+                let item_type = unwrap_type_as!(&list.expr_type, Type::List);
                 self.push_expr(index);
                 self.push_expr(list);
+                self.push_source_position(pos);
                 self.push(op::GET_LIST_ITEM);
+                self.push_type_size(item_type);
             }
             VExpr::Allocate { typename } => {
                 self.push(op::ALLOCATE);
-                self.push(self.custom_types_meta.get_index(typename) as u8);
+                self.push_operand::<2>(self.custom_types_meta.get_index(typename), "type index");
+                self.push_stack_map(0);
             }
-            VExpr::Spawn { typename, args } => {
-                self.push(op::RESERVE);
-                self.push(1);
+            VExpr::Spawn { typename, args, node } => {
                 for arg in args {
                     self.push_expr(arg);
                 }
 
                 let constructor_name = typename.constructor();
 
-                self.push(op::SPAWN);
-                self.push(self.custom_types_meta.get_index(typename) as u8);
+                match node {
+                    Some(node) => {
+                        self.push_expr(node);
+                        self.push_source_position(pos);
+                        self.push(op::SPAWN_REMOTE);
+                        self.use_feature(features::REMOTE_SPAWN);
+                    }
+                    None => {
+                        self.push_source_position(pos);
+                        self.push(op::SPAWN);
+                    }
+                }
+                self.push_operand::<2>(self.custom_types_meta.get_index(typename), "type index");
                 self.push_function_placeholder(&constructor_name);
+                self.push_stack_map(0);
+            }
+            VExpr::AskMessage { active, receiver, args } => {
+                self.push_expr(active);
+                for arg in args.iter() {
+                    self.push_expr(arg);
+                }
+                self.push_source_position(pos);
+                self.push(op::ASK_MESSAGE);
+                self.push_function_placeholder(receiver);
+            }
+            VExpr::WaitFuture(future) => {
+                let value_type = unwrap_type_as!(&future.expr_type, Type::Future);
+                self.push_expr(future);
+                self.push_source_position(pos);
+                self.push(op::WAIT_FUTURE);
+                self.push_type_size(value_type);
+            }
+            VExpr::StopActive(active) => {
+                self.push_expr(active);
+                self.push_source_position(pos);
+                self.push(op::STOP_ACTIVE);
+            }
+            VExpr::WaitActive(active) => {
+                self.push_expr(active);
+                self.push_source_position(pos);
+                self.push(op::WAIT_ACTIVE);
             }
             VExpr::Dummy(t) => {
                 self.push_reserve(t);
@@ -184,9 +245,10 @@ impl<'a> BytecodeGenerator<'a> {
                 self.push(op::CURRENT_ACTIVE);
             }
             VExpr::CurrentActiveField { active_type, field } => {
+                let active_meta = self.custom_types_meta.get_meta(active_type);
                 self.push(op::GET_CURRENT_ACTIVE_FIELD);
-                self.push(self.custom_types_meta.get_meta(active_type).field_offsets[field]);
-                self.push(self.custom_types_meta.get_meta(active_type).field_sizes[field]);
+                self.push_operand::<2>(active_meta.field_offsets[field], "field offset");
+                self.push_operand::<1>(active_meta.field_sizes[field], "value size");
             }
         }
     }
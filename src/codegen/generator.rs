@@ -1,23 +1,41 @@
 use std::collections::HashMap;
 
 use crate::ast::verified::RawFunction;
+use crate::runtime::debugger::LocalInfo;
 use crate::runtime::opcodes::op;
-use crate::symbols::SymbolFunc;
+use crate::runtime::values::Signature;
+use crate::symbols::{SymbolFunc, SymbolType};
 use crate::types::VerifiedType;
 
 use super::constants::{Constant, ConstantsTable};
 use super::metadata::{CustomTypesMetadataTable, ListKindsMetadataTable};
-use super::utils::get_type_size;
+use super::utils::{
+    get_active_handles_map_for_type, get_pointers_flags_for_type, get_pointers_map_for_type,
+    get_type_size, to_operand_bytes,
+};
 
 pub type CallPlaceholders = (usize, SymbolFunc);
+// Position right after the safepoint instruction + pointer flags for each slot of the frame
+pub type StackMap = (usize, Vec<bool>);
+pub type LineTableEntry = (usize, usize, usize); // bytecode position, line, column
 
 pub struct FunctionBytecode {
     pub name: SymbolFunc,
     pub bytecode: Vec<u8>,
     pub call_placeholders: Vec<CallPlaceholders>,
-    pub locals_size: usize,
     pub args_size: usize,
     pub args_pointer_mapping: Vec<usize>,
+    pub args_active_mapping: Vec<usize>,
+    pub return_size: usize,
+    pub return_pointer_mapping: Vec<usize>,
+    pub return_active_mapping: Vec<usize>,
+    pub stack_maps: Vec<StackMap>,
+    pub source_positions: Vec<(usize, usize)>, // bytecode position -> position in source file
+    pub module: String,
+    pub line_table: Vec<LineTableEntry>,
+    pub locals: Vec<LocalInfo>,
+    pub signature: Signature,
+    pub features: u32, // features of the VM, that the function relies on
 }
 pub struct JumpPlaceholder {
     position: usize,
@@ -27,12 +45,18 @@ pub struct BytecodeGenerator<'a> {
     pub custom_types_meta: &'a CustomTypesMetadataTable,
     pub list_kinds_meta: &'a mut ListKindsMetadataTable,
     pub constants: &'a mut ConstantsTable,
-    pub locals: HashMap<&'a str, u8>,
-    pub locals_offset: u8,
+    pub locals: HashMap<&'a str, usize>,
+    pub locals_offset: usize,
     pub locals_types: HashMap<&'a str, &'a VerifiedType>,
     pub locals_order: Vec<&'a str>,
+    args_amount: usize,
     pub return_type: &'a VerifiedType,
+    // Pointer flags of values on the stack during execution: locals first, then temporaries
+    stack_layout: Vec<bool>,
     bytecode: FunctionBytecode,
+    source_pos: usize,
+    // First value that did not fit into its operand, generation goes on to keep offsets valid
+    error: Option<(usize, String)>,
 }
 
 impl<'a> BytecodeGenerator<'a> {
@@ -42,16 +66,28 @@ impl<'a> BytecodeGenerator<'a> {
         constants: &'a mut ConstantsTable,
         function: &'a RawFunction,
     ) -> Self {
-        let mut locals: HashMap<&'a str, u8> = HashMap::new();
-        let mut locals_offset: u8 = 0;
+        let mut locals: HashMap<&'a str, usize> = HashMap::new();
+        let mut locals_offset: usize = 0;
         let mut locals_types = HashMap::new();
         let mut locals_order = vec![];
+        let mut stack_layout = vec![];
+        let is_active = |t: &SymbolType| custom_types_meta.is_active(t);
+        let signature = Signature {
+            args: (function.args.types.iter())
+                .map(|t| custom_types_meta.get_value_type(t))
+                .collect(),
+            returns: custom_types_meta.get_value_type(&function.return_type),
+            active_type: (function.method_of.as_ref())
+                .filter(|_| function.is_active_method)
+                .map(|t| custom_types_meta.get_index(t)),
+        };
 
         for (local_name, local_type) in function.args.iter() {
             locals.insert(local_name, locals_offset);
             locals_offset += get_type_size(local_type);
             locals_types.insert(local_name.as_str(), local_type);
             locals_order.push(local_name.as_str());
+            stack_layout.extend(get_pointers_flags_for_type(local_type, &is_active));
         }
 
         BytecodeGenerator {
@@ -61,16 +97,36 @@ impl<'a> BytecodeGenerator<'a> {
             locals,
             locals_offset,
             locals_types,
+            args_amount: function.args.types.len(),
             locals_order,
             return_type: &function.return_type,
+            stack_layout,
             bytecode: FunctionBytecode {
                 name: function.name.clone(),
                 bytecode: vec![],
                 call_placeholders: vec![],
-                locals_size: locals_offset as usize,
-                args_size: function.args.types.iter().map(get_type_size).sum::<u8>() as usize,
+                args_size: function.args.types.iter().map(get_type_size).sum(),
                 args_pointer_mapping: vec![],
+                args_active_mapping: vec![],
+                return_size: get_type_size(&function.return_type),
+                return_pointer_mapping: get_pointers_map_for_type(
+                    &function.return_type,
+                    &is_active,
+                ),
+                return_active_mapping: get_active_handles_map_for_type(
+                    &function.return_type,
+                    &is_active,
+                ),
+                stack_maps: vec![],
+                source_positions: vec![],
+                module: function.defined_at.to_string(),
+                line_table: vec![],
+                locals: vec![],
+                signature,
+                features: 0,
             },
+            source_pos: 0,
+            error: None,
         }
     }
 
@@ -79,19 +135,82 @@ impl<'a> BytecodeGenerator<'a> {
         self.locals_types.insert(varname, t);
         self.locals_offset += get_type_size(t);
         self.locals_order.push(varname);
+        self.stack_layout.extend(self.get_pointers_flags(t));
     }
 
-    pub fn push_constant(&mut self, constant: Constant) {
+    fn get_pointers_flags(&self, t: &VerifiedType) -> Vec<bool> {
+        get_pointers_flags_for_type(t, &|t: &SymbolType| self.custom_types_meta.is_active(t))
+    }
+
+    pub fn get_stack_size(&self) -> usize {
+        self.stack_layout.len()
+    }
+
+    /// Drops tracked values on stack until given size, used when runtime pops values
+    pub fn truncate_stack(&mut self, size: usize) {
+        self.stack_layout.truncate(size);
+    }
+
+    pub fn track_value_on_stack(&mut self, t: &VerifiedType) {
+        self.stack_layout.extend(self.get_pointers_flags(t));
+    }
+
+    pub fn track_reserved_on_stack(&mut self, size: usize) {
+        // Reserved memory is filled with zeros, so it never contains pointers
+        self.stack_layout.extend(vec![false; size]);
+    }
+
+    /// Saves which stack values of the frame are pointers at current position,
+    /// `skip_top` values are excluded (used for args of called function, as they belong to it)
+    pub fn push_stack_map(&mut self, skip_top: usize) {
+        let frame_size = self.stack_layout.len() - skip_top;
+        let stack_map = self.stack_layout[..frame_size].to_vec();
+        self.bytecode.stack_maps.push((self.get_position(), stack_map));
+    }
+
+    pub fn push_source_position(&mut self, source_pos: usize) {
+        self.source_pos = source_pos;
+        let position = self.get_position();
+        let positions = &mut self.bytecode.source_positions;
+        // Nothing is generated since the previous position, so it is not needed
+        if matches!(positions.last(), Some((last, _)) if *last == position) {
+            positions.pop();
+        }
+        positions.push((position, source_pos));
+    }
+
+    pub fn push_load_constant(&mut self, constant: Constant) {
         let constant_pos = self.constants.get_constant(constant);
-        self.push(constant_pos);
+        if constant_pos <= u8::MAX as usize {
+            self.push(op::LOAD_CONST);
+            self.push_operand::<1>(constant_pos, "constant index");
+        } else {
+            self.push(op::LOAD_CONST_WIDE);
+            self.push_operand::<2>(constant_pos, "constant index");
+        }
     }
 
     pub fn push(&mut self, opcode: u8) {
         self.bytecode.bytecode.push(opcode);
     }
 
+    pub fn use_feature(&mut self, feature: u32) {
+        self.bytecode.features |= feature;
+    }
+
+    /// Pushes operand of `N` bytes, value that does not fit fails the generation of function
+    pub fn push_operand<const N: usize>(&mut self, value: usize, what: &str) {
+        match to_operand_bytes::<N>(value, what) {
+            Ok(bytes) => self.bytecode.bytecode.extend(bytes),
+            Err(message) => {
+                self.error.get_or_insert((self.source_pos, message));
+                self.bytecode.bytecode.extend([0; N]);
+            }
+        }
+    }
+
     pub fn push_type_size(&mut self, t: &VerifiedType) {
-        self.push(get_type_size(t))
+        self.push_operand::<1>(get_type_size(t), "value size")
     }
 
     pub fn push_reserve(&mut self, for_type: &VerifiedType) {
@@ -99,6 +218,7 @@ impl<'a> BytecodeGenerator<'a> {
         if reserve_size > 0 {
             self.push(op::RESERVE);
             self.push_type_size(for_type);
+            self.track_reserved_on_stack(reserve_size);
         }
     }
 
@@ -114,13 +234,12 @@ impl<'a> BytecodeGenerator<'a> {
         self.bytecode
             .call_placeholders
             .push((self.bytecode.bytecode.len(), func.clone()));
-        self.push(0);
-        self.push(0);
+        self.bytecode.bytecode.extend([0; 4]);
     }
 
     pub fn push_placeholder(&mut self) -> JumpPlaceholder {
         let placeholder_pos = self.get_position();
-        self.bytecode.bytecode.resize(placeholder_pos + 2, 0);
+        self.bytecode.bytecode.resize(placeholder_pos + 4, 0);
         JumpPlaceholder { position: placeholder_pos }
     }
 
@@ -128,21 +247,50 @@ impl<'a> BytecodeGenerator<'a> {
         self.bytecode.bytecode.len()
     }
 
+    // Size of the whole program is checked to fit into u32, so jumps inside it fit too
+    fn write_placeholder(&mut self, placeholder: &JumpPlaceholder, diff: usize) {
+        let position = placeholder.position;
+        self.bytecode.bytecode[position..position + 4]
+            .copy_from_slice(&(diff as u32).to_be_bytes());
+    }
+
     pub fn fill_placeholder(&mut self, placeholder: &JumpPlaceholder) {
-        // -2 as this is length of placeholder in the program
-        let diff = ((self.get_position() - placeholder.position - 2) as u16).to_be_bytes();
-        self.bytecode.bytecode[placeholder.position] = diff[0];
-        self.bytecode.bytecode[placeholder.position + 1] = diff[1];
+        // -4 as this is length of placeholder in the program
+        let diff = self.get_position() - placeholder.position - 4;
+        self.write_placeholder(placeholder, diff);
     }
 
     pub fn fill_placeholder_backward(&mut self, placeholder: &JumpPlaceholder, jump_to: usize) {
         // placeholder.position is more than jump_to
-        let diff = ((placeholder.position - jump_to + 2) as u16).to_be_bytes();
-        self.bytecode.bytecode[placeholder.position] = diff[0];
-        self.bytecode.bytecode[placeholder.position + 1] = diff[1];
+        let diff = placeholder.position - jump_to + 4;
+        self.write_placeholder(placeholder, diff);
     }
 
-    pub fn get_bytecode(self) -> FunctionBytecode {
-        self.bytecode
+    /// Bytecode of the function, or the first value that did not fit into bytecode along
+    /// with position of the statement in source file
+    pub fn get_bytecode(mut self) -> Result<FunctionBytecode, (usize, String)> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        // Locals are renamed by semantics to be unique within the function, unlike args, so
+        // their source names are sorted by number of declaration, and temporaries are hidden
+        let mut locals: Vec<(&str, usize, &str)> = (self.locals_order.iter().enumerate())
+            .filter(|(_, name)| !name.starts_with('$'))
+            .map(|(i, name)| match name.rsplit_once('_') {
+                Some((source_name, number)) if i >= self.args_amount => {
+                    (source_name, number.parse().unwrap_or(0), *name)
+                }
+                _ => (*name, 0, *name),
+            })
+            .collect();
+        locals.sort_unstable();
+        self.bytecode.locals = (locals.into_iter())
+            .map(|(source_name, _, name)| LocalInfo {
+                name: source_name.to_string(),
+                offset: self.locals[name],
+                value_type: self.custom_types_meta.get_value_type(self.locals_types[name]),
+            })
+            .collect();
+        Ok(self.bytecode)
     }
 }
@@ -1,7 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::ast::verified::CustomType;
+use crate::runtime::debugger::ValueType;
+use crate::runtime::scheduler::MailboxConfig;
 use crate::runtime::stdlib_runners::LIST_OF_INTS_META_FLAG;
+use crate::runtime::supervision::SupervisorConfig;
 use crate::symbols::SymbolType;
 use crate::types::{Type, VerifiedType};
 
@@ -9,25 +12,33 @@ use super::utils;
 
 #[derive(Debug)]
 pub struct CustomTypeMetadata {
-    pub size: u8,
-    pub field_offsets: HashMap<String, u8>,
-    pub field_sizes: HashMap<String, u8>,
+    pub size: usize,
+    pub field_offsets: HashMap<String, usize>,
+    pub field_sizes: HashMap<String, usize>,
     pub field_types: HashMap<String, VerifiedType>,
+    pub layout: VerifiedType, // tuple of types of all fields, in the order of their offsets
     pub pointer_mapping: Vec<usize>,
+    pub active_mapping: Vec<usize>,
+    pub mailbox: MailboxConfig,
+    pub supervision: Option<SupervisorConfig>,
 }
 
 #[derive(Debug)]
 pub struct ListKindMetadata {
-    pub size: u8,
+    pub size: usize,
     pub item_type: VerifiedType,
     pub pointer_mapping: Vec<usize>,
+    pub active_mapping: Vec<usize>,
 }
 
 impl CustomTypeMetadata {
-    pub fn from_custom(definition: &CustomType) -> Self {
+    pub fn from_custom<F>(definition: &CustomType, is_active: &F) -> Self
+    where
+        F: Fn(&SymbolType) -> bool,
+    {
         let fields_sizes = definition.fields.types.iter().map(utils::get_type_size);
-        let type_size: u8 = fields_sizes.clone().sum();
-        let field_sizes: Vec<u8> = fields_sizes.collect();
+        let type_size: usize = fields_sizes.clone().sum();
+        let field_sizes: Vec<usize> = fields_sizes.collect();
 
         let mut field_offsets = vec![0; field_sizes.len()];
         for (i, _) in field_sizes.iter().enumerate().skip(1) {
@@ -42,17 +53,31 @@ impl CustomTypeMetadata {
             field_types: generate_field_names()
                 .zip(definition.fields.types.iter().cloned())
                 .collect(),
-            pointer_mapping: utils::get_pointers_map_for_sequence(&definition.fields.types),
+            layout: Type::Tuple(definition.fields.types.clone()),
+            pointer_mapping: utils::get_pointers_map_for_sequence(
+                &definition.fields.types,
+                is_active,
+            ),
+            active_mapping: utils::get_active_handles_map_for_sequence(
+                &definition.fields.types,
+                is_active,
+            ),
+            mailbox: definition.mailbox,
+            supervision: definition.supervision,
         }
     }
 }
 
 impl ListKindMetadata {
-    pub fn from_item_type(t: &VerifiedType) -> Self {
+    pub fn from_item_type<F>(t: &VerifiedType, is_active: &F) -> Self
+    where
+        F: Fn(&SymbolType) -> bool,
+    {
         Self {
             size: utils::get_type_size(t),
             item_type: t.clone(),
-            pointer_mapping: utils::get_pointers_map_for_type(t),
+            pointer_mapping: utils::get_pointers_map_for_type(t, is_active),
+            active_mapping: utils::get_active_handles_map_for_type(t, is_active),
         }
     }
 }
@@ -61,6 +86,7 @@ impl ListKindMetadata {
 pub struct CustomTypesMetadataTable {
     pub indexes: HashMap<SymbolType, usize>,
     pub metadata: Vec<CustomTypeMetadata>,
+    pub active_types: HashSet<SymbolType>,
 }
 
 #[derive(Debug)]
@@ -71,15 +97,18 @@ pub struct ListKindsMetadataTable {
 
 impl CustomTypesMetadataTable {
     pub fn from_types(types: &[CustomType]) -> Self {
-        let mut table = Self { indexes: HashMap::new(), metadata: vec![] };
+        let active_types: HashSet<SymbolType> =
+            types.iter().filter(|t| t.is_active).map(|t| t.name.clone()).collect();
+        let is_active = |t: &SymbolType| active_types.contains(t);
 
+        let mut indexes = HashMap::new();
+        let mut metadata = vec![];
         for custom_type in types.iter() {
-            let index = table.indexes.len();
-            table.indexes.insert(custom_type.name.clone(), index);
-            table.metadata.push(CustomTypeMetadata::from_custom(custom_type));
+            indexes.insert(custom_type.name.clone(), indexes.len());
+            metadata.push(CustomTypeMetadata::from_custom(custom_type, &is_active));
         }
 
-        table
+        Self { indexes, metadata, active_types }
     }
 
     pub fn get_meta(&self, flag: &SymbolType) -> &CustomTypeMetadata {
@@ -89,6 +118,30 @@ impl CustomTypesMetadataTable {
     pub fn get_index(&self, flag: &SymbolType) -> usize {
         self.indexes[flag]
     }
+
+    /// Values of active types are handles of active objects, not heap pointers
+    pub fn is_active(&self, flag: &SymbolType) -> bool {
+        self.active_types.contains(flag)
+    }
+
+    /// Type of the value as it is described in debug info
+    pub fn get_value_type(&self, t: &VerifiedType) -> ValueType {
+        let inner = |t: &VerifiedType| Box::new(self.get_value_type(t));
+        match t {
+            Type::Int => ValueType::Int,
+            Type::Float => ValueType::Float,
+            Type::Bool => ValueType::Bool,
+            Type::String => ValueType::String,
+            Type::List(item) => ValueType::List(inner(item)),
+            Type::Tuple(items) => {
+                ValueType::Tuple(items.iter().map(|t| self.get_value_type(t)).collect())
+            }
+            Type::Maybe(t) => ValueType::Maybe(inner(t)),
+            Type::Future(t) => ValueType::Future(inner(t)),
+            Type::Custom(c) if self.is_active(c) => ValueType::Active(self.get_index(c)),
+            Type::Custom(c) => ValueType::Object(self.get_index(c)),
+        }
+    }
 }
 
 impl ListKindsMetadataTable {
@@ -96,20 +149,34 @@ impl ListKindsMetadataTable {
         Self {
             // Add std kinds
             indexes: HashMap::from([(Type::Int, LIST_OF_INTS_META_FLAG)]),
-            metadata: vec![ListKindMetadata::from_item_type(&Type::Int)],
+            metadata: vec![ListKindMetadata::from_item_type(&Type::Int, &|_| false)],
         }
     }
 
-    pub fn get_or_insert(&mut self, t: &VerifiedType) -> usize {
+    pub fn get_or_insert(&mut self, t: &VerifiedType, types: &CustomTypesMetadataTable) -> usize {
         if let Some(index) = self.indexes.get(t) {
             *index
         } else {
             let index = self.indexes.len();
-            self.metadata.push(ListKindMetadata::from_item_type(t));
+            let is_active = |t: &SymbolType| types.is_active(t);
+            self.metadata.push(ListKindMetadata::from_item_type(t, &is_active));
             self.indexes.insert(t.clone(), index);
             index
         }
     }
+
+    /// Adds kinds of all lists within the type, even if the program never creates them
+    pub fn insert_nested(&mut self, t: &VerifiedType, types: &CustomTypesMetadataTable) {
+        match t {
+            Type::List(item) => {
+                self.get_or_insert(item, types);
+                self.insert_nested(item, types);
+            }
+            Type::Tuple(items) => items.iter().for_each(|item| self.insert_nested(item, types)),
+            Type::Maybe(inner) | Type::Future(inner) => self.insert_nested(inner, types),
+            _ => {}
+        }
+    }
 }
 
 #[cfg(test)]
@@ -134,9 +201,15 @@ mod test {
             types: field_types,
             names: field_names.into_iter().enumerate().collect(),
         };
-        let custom_type = CustomType { name: gen_symbol_type(), is_active: false, fields };
+        let custom_type = CustomType {
+            name: gen_symbol_type(),
+            is_active: false,
+            mailbox: MailboxConfig::default(),
+            supervision: None,
+            fields,
+        };
 
-        let metadata = CustomTypeMetadata::from_custom(&custom_type);
+        let metadata = CustomTypeMetadata::from_custom(&custom_type, &|_| false);
 
         assert_eq!(metadata.size, 4);
         assert_eq!(metadata.field_offsets.len(), 3);
@@ -1,5 +1,10 @@
+use std::collections::HashMap;
+
+use crate::alias::ModuleAlias;
 use crate::ast::verified::{CustomType, RawFunction};
-use crate::symbols::SymbolFunc;
+use crate::errors::get_position_coordinates;
+use crate::symbols::{SymbolFunc, SymbolType};
+use crate::types::VerifiedType;
 
 use self::generator::FunctionBytecode;
 
@@ -12,7 +17,20 @@ mod metadata;
 mod statements;
 mod utils;
 
-pub fn generate(types: &[CustomType], functions: &[RawFunction], entry: &SymbolFunc) -> Vec<u8> {
+/// Value of the program that does not fit into operands of the bytecode
+#[derive(Debug)]
+pub struct CodegenError {
+    pub module: ModuleAlias,
+    pub pos: usize,
+    pub message: String,
+}
+
+pub fn generate(
+    types: &[CustomType],
+    functions: &[RawFunction],
+    entry: &SymbolFunc,
+    sources: &HashMap<ModuleAlias, &str>,
+) -> Result<Vec<u8>, CodegenError> {
     let mut constants = constants::ConstantsTable::new();
     let custom_types_meta = metadata::CustomTypesMetadataTable::from_types(types);
     let mut list_kinds_meta = metadata::ListKindsMetadataTable::new_empty();
@@ -25,13 +43,44 @@ pub fn generate(types: &[CustomType], functions: &[RawFunction], entry: &SymbolF
             &mut list_kinds_meta,
             &mut constants,
         )
-        .unwrap();
+        .map_err(|(pos, message)| CodegenError {
+            module: raw_function.defined_at.clone(),
+            pos,
+            message,
+        })?;
+        let is_active = |t: &SymbolType| custom_types_meta.is_active(t);
         bytecode.args_pointer_mapping =
-            utils::get_pointers_map_for_sequence(&raw_function.args.types);
+            utils::get_pointers_map_for_sequence(&raw_function.args.types, &is_active);
+        bytecode.args_active_mapping =
+            utils::get_active_handles_map_for_sequence(&raw_function.args.types, &is_active);
+
+        let source = sources[&raw_function.defined_at];
+        for (position, source_pos) in bytecode.source_positions.iter() {
+            let (line, column) = get_position_coordinates(source, *source_pos);
+            bytecode.line_table.push((*position, line, column));
+        }
 
         functions_bytecode.push(bytecode);
     }
 
+    // Host passes lists in and out of functions, so it needs kinds of all of them
+    for raw_function in functions.iter() {
+        let signature_types = raw_function.args.types.iter();
+        for t in signature_types.chain(std::iter::once(&raw_function.return_type)) {
+            list_kinds_meta.insert_nested(t, &custom_types_meta);
+        }
+    }
+    // Objects in messages are checked against types of their fields and items, so kinds of
+    // lists within those types are known as well
+    for type_meta in custom_types_meta.metadata.iter() {
+        list_kinds_meta.insert_nested(&type_meta.layout, &custom_types_meta);
+    }
+    let item_types: Vec<VerifiedType> =
+        list_kinds_meta.metadata.iter().map(|l| l.item_type.clone()).collect();
+    for item_type in item_types.iter() {
+        list_kinds_meta.insert_nested(item_type, &custom_types_meta);
+    }
+
     let constants_bytecode = constants.generate_bytecode();
 
     assemble::assemble_chunks(
@@ -41,9 +90,99 @@ pub fn generate(types: &[CustomType], functions: &[RawFunction], entry: &SymbolF
         functions_bytecode,
         entry,
     )
+    .map_err(|message| {
+        // Limits of the whole program are reported at the start of the entry module
+        let entry_function = functions.iter().find(|f| &f.name == entry).unwrap();
+        CodegenError { module: entry_function.defined_at.clone(), pos: 0, message }
+    })
 }
 
-pub fn disassemble(program: &[u8]) -> String {
+pub fn disassemble(program: &[u8]) -> Result<String, String> {
     let mut d = disassemble::Disassembler::new(program);
     d.disassemble()
 }
+
+#[cfg(test)]
+mod test {
+    use crate::errors::CompileError;
+    use crate::runtime::opcodes::op;
+    use crate::runtime::vm::{Vm, VmOptions};
+    use crate::semantics::aggregate::ProgramAggregate;
+    use crate::tests::helpers::setup_and_load_program;
+
+    use super::*;
+
+    fn compile(program: &str) -> Result<Vec<u8>, CodegenError> {
+        let mut wp = setup_and_load_program(&format!("===== file: main.frisbee\n{}", program));
+        let aggregate = crate::loader::check_and_aggregate(&mut wp)
+            .unwrap_or_else(|err| panic!("Semantic error: {}", err.error.get_message()));
+        let ProgramAggregate { types, functions, entry } = aggregate;
+        let types: Vec<_> = types.into_values().collect();
+        let functions: Vec<_> = functions.into_values().collect();
+        let sources = wp
+            .files
+            .iter()
+            .map(|(alias, f)| (alias.clone(), f.contents.as_str()))
+            .collect();
+        generate(&types, &functions, &entry, &sources)
+    }
+
+    #[test]
+    fn jumps_over_large_body() {
+        // Each statement takes 66 bytes of bytecode
+        let body = format!("x = x{};\n", " + 1".repeat(20)).repeat(1100);
+        let program = format!(
+            "fun void main() {{\n Int x = 0;\n if x == 0 {{\n{}}}\n println(x.to_string());\n}}",
+            body
+        );
+        let bytecode = compile(&program).unwrap();
+        assert!(bytecode.len() > u16::MAX as usize);
+        assert!(Vm::setup(bytecode, VmOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn many_constants_are_loaded_with_wide_index() {
+        let items: Vec<String> = (1000..1300).map(|i| i.to_string()).collect();
+        let program = format!(
+            "fun void main() {{\n [Int] items = [{}];\n println(items.len().to_string());\n}}",
+            items.join(", ")
+        );
+        let bytecode = compile(&program).unwrap();
+        assert!(disassemble(&bytecode)
+            .unwrap()
+            .contains(op::get_display_name(op::LOAD_CONST_WIDE)));
+        assert!(Vm::setup(bytecode, VmOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn too_big_value_is_reported() {
+        let tuple = vec!["0"; 256].join(", ");
+        let program = format!("fun void main() {{\n Int x = 0;\n ({});\n}}", tuple);
+        let err = compile(&program).unwrap_err();
+        assert_eq!(
+            err.message,
+            "value size 256 does not fit into bytecode, at most 255 is supported"
+        );
+        // Position of the statement with the tuple
+        assert_eq!(err.pos, program.find("(0").unwrap());
+    }
+
+    #[test]
+    fn calls_have_own_source_positions() {
+        let program = "fun Int one() {\n return 1;\n}\n\nfun void main() {\n println((one() + one()).to_string());\n}";
+        let bytecode = compile(program).unwrap();
+        let vm = Vm::setup(bytecode, VmOptions::default()).unwrap();
+        let positions: Vec<(usize, usize)> = vm
+            .metadata
+            .line_table
+            .values()
+            .map(|(_, source)| (source.line, source.column))
+            .collect();
+
+        // Both calls are on the same line as the statement, but each of them has a column
+        let second_call = program.rfind("one()").unwrap();
+        assert!(positions.contains(&get_position_coordinates(program, second_call)));
+        let first_call = program.find("(one()").unwrap() + 1;
+        assert!(positions.contains(&get_position_coordinates(program, first_call)));
+    }
+}
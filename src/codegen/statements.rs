@@ -1,4 +1,5 @@
-use crate::ast::verified::{RawFunction, VStatement};
+use crate::ast::verified::{RawFunction, VStatement, VStatementWithPos};
+use crate::runtime::header::features;
 use crate::runtime::opcodes::op;
 use crate::types::Type;
 
@@ -12,30 +13,35 @@ pub fn generate_function_bytecode(
     custom_types_meta: &CustomTypesMetadataTable,
     list_kinds_meta: &mut ListKindsMetadataTable,
     constants: &mut ConstantsTable,
-) -> Result<FunctionBytecode, String> {
+) -> Result<FunctionBytecode, (usize, String)> {
     let mut generator = BytecodeGenerator::new(custom_types_meta, list_kinds_meta, constants, func);
 
     for (local_name, local_type) in func.locals.iter() {
         generator.add_local(local_name, local_type);
     }
-    let total_size: u8 = func.locals.iter().map(|p| get_type_size(&p.1)).sum();
+    let total_size: usize = func.locals.iter().map(|p| get_type_size(&p.1)).sum();
     generator.push(op::RESERVE);
-    generator.push(total_size);
+    generator.push_operand::<1>(total_size, "size of locals");
 
     for statement in func.body.iter() {
         generator.push_statement(statement, None);
     }
 
-    Ok(generator.get_bytecode())
+    generator.get_bytecode()
 }
 
 impl<'a> BytecodeGenerator<'a> {
     pub fn push_statement(
         &mut self,
-        statement: &'a VStatement,
+        statement_with_pos: &'a VStatementWithPos,
         loop_start: Option<usize>,
     ) -> Vec<JumpPlaceholder> {
         let mut outer_break_placeholders = vec![];
+        let VStatementWithPos { statement, pos } = statement_with_pos;
+        self.push_source_position(*pos);
+
+        // Statements are always leaving stack balanced, so there are only locals on it
+        self.truncate_stack(self.locals_offset);
         match statement {
             VStatement::Expression(expr) => {
                 self.push_expr(expr);
@@ -45,12 +51,10 @@ impl<'a> BytecodeGenerator<'a> {
                 self.push_expr(value);
                 let var_pos = *self.locals.get(name.as_str()).unwrap();
                 let offset = get_tuple_offset(self.locals_types[name.as_str()], tuple_indexes);
+                let size = get_tuple_subitem_size(self.locals_types[name.as_str()], tuple_indexes);
                 self.push(op::SET_LOCAL);
-                self.push(var_pos + offset);
-                self.push(get_tuple_subitem_size(
-                    self.locals_types[name.as_str()],
-                    tuple_indexes,
-                ));
+                self.push_operand::<1>(var_pos + offset, "local offset");
+                self.push_operand::<1>(size, "value size");
             }
             VStatement::AssignToField { object, field, tuple_indexes, value } => {
                 let object_type = unwrap_type_as!(&object.expr_type, Type::Custom);
@@ -65,8 +69,9 @@ impl<'a> BytecodeGenerator<'a> {
 
                 let tuple_offset = get_tuple_offset(field_type, tuple_indexes);
 
+                self.push_source_position(*pos);
                 self.push(op::SET_OBJ_FIELD);
-                self.push(field_offset + tuple_offset);
+                self.push_operand::<2>(field_offset + tuple_offset, "field offset");
                 self.push_type_size(&value.expr_type);
             }
             VStatement::AssignToList { list, index, tuple_indexes, value } => {
@@ -78,8 +83,9 @@ impl<'a> BytecodeGenerator<'a> {
 
                 let tuple_offset = get_tuple_offset(list_item_type.as_ref(), tuple_indexes);
 
+                self.push_source_position(*pos);
                 self.push(op::SET_LIST_ITEM);
-                self.push(tuple_offset);
+                self.push_operand::<1>(tuple_offset, "tuple item offset");
                 self.push_type_size(&value.expr_type);
             }
             VStatement::Return(expr) => {
@@ -162,15 +168,26 @@ impl<'a> BytecodeGenerator<'a> {
                 let tuple_offset = get_tuple_offset(&value.expr_type, tuple_indexes);
 
                 self.push(op::SET_CURRENT_ACTIVE_FIELD);
-                self.push(field_offset + tuple_offset);
+                self.push_operand::<2>(field_offset + tuple_offset, "field offset");
                 self.push_type_size(&value.expr_type);
             }
-            VStatement::SendMessage { active, receiver, args } => {
+            VStatement::SendMessage { active, receiver, args, delay } => {
                 self.push_expr(active);
                 for arg in args.iter() {
                     self.push_expr(arg);
                 }
-                self.push(op::SEND_MESSAGE);
+                match delay {
+                    Some(delay) => {
+                        self.push_expr(delay);
+                        self.push_source_position(*pos);
+                        self.push(op::SEND_MESSAGE_AFTER);
+                        self.use_feature(features::DELAYED_MESSAGES);
+                    }
+                    None => {
+                        self.push_source_position(*pos);
+                        self.push(op::SEND_MESSAGE);
+                    }
+                }
                 self.push_function_placeholder(receiver);
             }
         };
@@ -1,6 +1,6 @@
 use crate::types::Type;
 
-pub fn get_type_size<T>(t: &Type<T>) -> u8 {
+pub fn get_type_size<T>(t: &Type<T>) -> usize {
     match t {
         Type::Int => 1,
         Type::Float => 1,
@@ -10,9 +10,28 @@ pub fn get_type_size<T>(t: &Type<T>) -> u8 {
         Type::Tuple(items) => items.iter().map(|t| get_type_size(t)).sum(),
         Type::List(_) => 1,
         Type::Custom(_) => 1,
+        Type::Future(_) => 1,
     }
 }
 
+pub fn too_big_message(what: &str, value: usize, max: usize) -> String {
+    format!(
+        "{} {} does not fit into bytecode, at most {} is supported",
+        what, value, max
+    )
+}
+
+/// Big-endian bytes of the value, if it fits into `N` bytes
+pub fn to_operand_bytes<const N: usize>(value: usize, what: &str) -> Result<[u8; N], String> {
+    let max = u64::MAX >> (64 - 8 * N);
+    if value as u64 > max {
+        return Err(too_big_message(what, value, max as usize));
+    }
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&(value as u64).to_be_bytes()[8 - N..]);
+    Ok(bytes)
+}
+
 macro_rules! unwrap_type_as {
     ($value:expr, $variant:path $(,)?) => {
         match $value {
@@ -27,7 +46,10 @@ macro_rules! unwrap_type_as {
 }
 pub(crate) use unwrap_type_as;
 
-pub fn get_tuple_offset<T: std::fmt::Debug>(tuple_type: &Type<T>, tuple_indexes: &[usize]) -> u8 {
+pub fn get_tuple_offset<T: std::fmt::Debug>(
+    tuple_type: &Type<T>,
+    tuple_indexes: &[usize],
+) -> usize {
     if tuple_indexes.is_empty() {
         return 0;
     }
@@ -44,7 +66,7 @@ pub fn get_tuple_offset<T: std::fmt::Debug>(tuple_type: &Type<T>, tuple_indexes:
     } else {
         let items = unwrap_type_as!(tuple_type, Type::Tuple);
 
-        let current_item_offset: u8 = items.iter().take(current_index).map(get_type_size).sum();
+        let current_item_offset: usize = items.iter().take(current_index).map(get_type_size).sum();
         let offset_inside_current_item = get_tuple_offset(&items[current_index], next_indexes);
 
         current_item_offset + offset_inside_current_item
@@ -63,50 +85,107 @@ pub fn get_tuple_subitem_type<T>(t: &Type<T>, i: usize) -> &Type<T> {
     }
 }
 
-pub fn get_tuple_subitem_size<T>(tuple_type: &Type<T>, tuple_indexes: &[usize]) -> u8 {
+pub fn get_tuple_subitem_size<T>(tuple_type: &Type<T>, tuple_indexes: &[usize]) -> usize {
     if tuple_indexes.is_empty() {
         get_type_size(tuple_type)
     } else {
-        return get_tuple_subitem_size(
+        get_tuple_subitem_size(
             get_tuple_subitem_type(tuple_type, tuple_indexes[0]),
             &tuple_indexes[1..],
-        );
+        )
     }
 }
 
-pub fn get_pointers_map_for_type<T>(t: &Type<T>) -> Vec<usize> {
+/// Values that refer to something outside of the frame or object they are stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReferenceKind {
+    HeapPointer,
+    ActiveHandle, // index of an active object in the VM, is passed by identity
+}
+
+fn get_references_map_for_type<T, F>(t: &Type<T>, kind: ReferenceKind, is_active: &F) -> Vec<usize>
+where
+    F: Fn(&T) -> bool,
+{
     match t {
-        Type::Int | Type::Float | Type::Bool => vec![],
+        // Future is an index in the table of VM futures, not a heap object
+        Type::Int | Type::Float | Type::Bool | Type::Future(_) => vec![],
         Type::Maybe(t) => {
-            let inner = get_pointers_map_for_type(t.as_ref());
+            let inner = get_references_map_for_type(t.as_ref(), kind, is_active);
             inner.into_iter().map(|i| i + 1).collect()
         }
-        Type::List(_) | Type::Custom(_) | Type::String => vec![0],
+        Type::Custom(c) if is_active(c) => match kind {
+            ReferenceKind::ActiveHandle => vec![0],
+            ReferenceKind::HeapPointer => vec![],
+        },
+        Type::List(_) | Type::Custom(_) | Type::String => match kind {
+            ReferenceKind::HeapPointer => vec![0],
+            ReferenceKind::ActiveHandle => vec![],
+        },
 
-        Type::Tuple(items) => get_pointers_map_for_sequence(items),
+        Type::Tuple(items) => get_references_map_for_sequence(items, kind, is_active),
     }
 }
 
-pub fn get_pointers_map_for_sequence<T>(types: &[Type<T>]) -> Vec<usize> {
-    if types.is_empty() {
-        return vec![];
-    }
-    if types.len() == 1 {
-        return get_pointers_map_for_type(&types[0]);
-    }
-
+fn get_references_map_for_sequence<T, F>(
+    types: &[Type<T>],
+    kind: ReferenceKind,
+    is_active: &F,
+) -> Vec<usize>
+where
+    F: Fn(&T) -> bool,
+{
     let mut result = vec![];
     let mut current_offset: usize = 0;
     for t in types {
-        let inner = get_pointers_map_for_type(t);
+        let inner = get_references_map_for_type(t, kind, is_active);
         result.extend(inner.into_iter().map(|i| i + current_offset));
-        current_offset += get_type_size(t) as usize;
+        current_offset += get_type_size(t);
     }
     result.sort_unstable();
 
     result
 }
 
+pub fn get_pointers_map_for_type<T, F>(t: &Type<T>, is_active: &F) -> Vec<usize>
+where
+    F: Fn(&T) -> bool,
+{
+    get_references_map_for_type(t, ReferenceKind::HeapPointer, is_active)
+}
+
+pub fn get_pointers_map_for_sequence<T, F>(types: &[Type<T>], is_active: &F) -> Vec<usize>
+where
+    F: Fn(&T) -> bool,
+{
+    get_references_map_for_sequence(types, ReferenceKind::HeapPointer, is_active)
+}
+
+pub fn get_active_handles_map_for_type<T, F>(t: &Type<T>, is_active: &F) -> Vec<usize>
+where
+    F: Fn(&T) -> bool,
+{
+    get_references_map_for_type(t, ReferenceKind::ActiveHandle, is_active)
+}
+
+pub fn get_active_handles_map_for_sequence<T, F>(types: &[Type<T>], is_active: &F) -> Vec<usize>
+where
+    F: Fn(&T) -> bool,
+{
+    get_references_map_for_sequence(types, ReferenceKind::ActiveHandle, is_active)
+}
+
+pub fn get_pointers_flags_for_type<T, F>(t: &Type<T>, is_active: &F) -> Vec<bool>
+where
+    F: Fn(&T) -> bool,
+{
+    let mut flags = vec![false; get_type_size(t)];
+    for i in get_pointers_map_for_type(t, is_active) {
+        flags[i] = true;
+    }
+    flags
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -169,7 +248,7 @@ mod test {
             ]),
         ];
 
-        let mapping = get_pointers_map_for_sequence(&items);
+        let mapping = get_pointers_map_for_sequence(&items, &|_| false);
         assert_eq!(mapping, vec![2, 3, 4]);
     }
 
@@ -183,7 +262,36 @@ mod test {
             Type::Tuple(vec![Type::Float, Type::Custom("SomeType")]),
         ])));
 
-        let mapping = get_pointers_map_for_type(&test_type);
+        let mapping = get_pointers_map_for_type(&test_type, &|_| false);
         assert_eq!(mapping, vec![4]);
     }
+
+    #[test]
+    fn check_active_handles_are_not_pointers() {
+        // (Actor, (String, Actor?), [Actor])
+        // (actor, string, bool, actor, list)
+
+        let items = vec![
+            Type::Custom("Actor"),
+            Type::Tuple(vec![
+                Type::String,
+                Type::Maybe(Box::new(Type::Custom("Actor"))),
+            ]),
+            Type::List(Box::new(Type::Custom("Actor"))),
+        ];
+        let is_active = |t: &&str| *t == "Actor";
+
+        assert_eq!(
+            get_pointers_map_for_sequence(&items, &is_active),
+            vec![1, 4]
+        );
+        assert_eq!(
+            get_active_handles_map_for_sequence(&items, &is_active),
+            vec![0, 3]
+        );
+        assert_eq!(
+            get_pointers_flags_for_type(&Type::Tuple(items), &is_active),
+            vec![false, true, false, false, true]
+        );
+    }
 }
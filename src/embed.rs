@@ -0,0 +1,463 @@
+//! Running frisbee programs inside of Rust applications.
+//!
+//! Program is loaded into an [`Instance`] either as bytecode or from sources. Host calls its
+//! functions with typed [`Value`]s, spawns active objects and sends messages to them, while
+//! worker threads of the instance run those active objects the same way `frisbee run` does.
+//!
+//! ```
+//! use frisbee::embed::{Instance, Value, VmOptions};
+//!
+//! let source = "
+//!     active Counter {
+//!         Int total;
+//!
+//!         fun Int add(Int amount) {
+//!             @total = @total + amount;
+//!             return @total;
+//!         }
+//!     }
+//!
+//!     fun Int double(Int x) {
+//!         return x * 2;
+//!     }
+//!
+//!     fun void main() {}
+//! ";
+//! let instance = Instance::from_source(source, VmOptions::default()).unwrap();
+//! assert_eq!(instance.call("double", &[Value::Int(21)]), Ok(Value::Int(42)));
+//!
+//! let counter = instance.spawn("Counter", &[Value::Int(1)]).unwrap();
+//! instance.send(&counter, "add", &[Value::Int(2)]).unwrap();
+//! assert_eq!(instance.ask(&counter, "add", &[Value::Int(3)]), Ok(Value::Int(6)));
+//! assert_eq!(instance.finish(), 0);
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::alias::ModuleAlias;
+use crate::codegen;
+use crate::errors::{get_position_coordinates, CompileError};
+use crate::loader::{self, WholeProgram};
+use crate::runtime::errors::{RuntimeError, RuntimeErrorKind};
+use crate::runtime::values::{check_supported, pack_call, unpack_return, Signature};
+use crate::runtime::vm::Vm;
+
+pub use crate::runtime::stdlib_runners::Output;
+pub use crate::runtime::values::{ActiveRef, Value, ValueError};
+pub use crate::runtime::vm::VmOptions;
+
+// Program compiled from a string has a single module
+const SOURCE_MODULE_PATH: &str = "main.frisbee";
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Compile { module: String, line: usize, column: usize, message: String },
+    Load(RuntimeError),
+    Start(String),
+    Runtime(RuntimeError),
+    Exit(i64),
+    UnknownFunction(String),
+    UnknownType(String),
+    UnknownActive(u64),
+    NotActive(String),         // type is spawned, but it is not an active type
+    NeedsActiveObject(String), // method of an active type is called outside of its object
+    ArgumentsAmount { function: String, expected: usize, given: usize },
+    Value(ValueError),
+    NoReply(RuntimeErrorKind),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Compile { module, line, column, message } => {
+                write!(f, "{} (in {}:{}:{})", message, module, line + 1, column + 1)
+            }
+            Error::Load(error) => write!(f, "cannot load program: {}", error),
+            Error::Start(error) => write!(f, "cannot start program: {}", error),
+            Error::Runtime(error) => write!(f, "runtime error: {}", error),
+            Error::Exit(code) => write!(f, "program exited with code {}", code),
+            Error::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            Error::UnknownType(name) => write!(f, "unknown type `{}`", name),
+            Error::UnknownActive(handle) => write!(f, "unknown active object #{}", handle),
+            Error::NotActive(name) => write!(f, "`{}` is not an active type", name),
+            Error::NeedsActiveObject(name) => {
+                write!(f, "`{}` runs only inside of its active object", name)
+            }
+            Error::ArgumentsAmount { function, expected, given } => write!(
+                f,
+                "`{}` takes {} arguments, but {} are given",
+                function, expected, given
+            ),
+            Error::Value(error) => write!(f, "{}", error),
+            Error::NoReply(kind) => write!(f, "no reply to the message: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn compile_error(module: &ModuleAlias, source: &str, error: &dyn CompileError) -> Error {
+    let (start, _) = error.get_position_window();
+    let (line, column) = get_position_coordinates(source, start);
+    Error::Compile { module: module.to_string(), line, column, message: error.get_message() }
+}
+
+fn compile(mut wp: WholeProgram) -> Result<Vec<u8>, Error> {
+    let aggregate = loader::check_and_aggregate(&mut wp)
+        .map_err(|err| compile_error(&err.module, &wp.files[&err.module].contents, &err.error))?;
+
+    let types: Vec<_> = aggregate.types.into_values().collect();
+    let functions: Vec<_> = aggregate.functions.into_values().collect();
+    let sources = wp
+        .files
+        .iter()
+        .map(|(alias, f)| (alias.clone(), f.contents.as_str()))
+        .collect();
+    codegen::generate(&types, &functions, &aggregate.entry, &sources)
+        .map_err(|err| compile_error(&err.module, sources[&err.module], &err))
+}
+
+/// Compiles the program, its imported modules are read from files next to the main one
+pub fn compile_file(path: &Path) -> Result<Vec<u8>, Error> {
+    if path.extension().is_none_or(|extension| extension != "frisbee") {
+        return Err(Error::Compile {
+            module: path.display().to_string(),
+            line: 0,
+            column: 0,
+            message: "Only *.frisbee files can be compiled".into(),
+        });
+    }
+    let wp = loader::load_program(path)
+        .map_err(|(alias, source, error)| compile_error(&alias, &source, error.as_ref()))?;
+    compile(wp)
+}
+
+/// Compiles the program of a single module `main`, it can not import other modules
+pub fn compile_source(source: &str) -> Result<Vec<u8>, Error> {
+    let read_source = |path: &Path| match path == Path::new(SOURCE_MODULE_PATH) {
+        true => Ok(source.to_string()),
+        false => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "only the main module is compiled from source",
+        )),
+    };
+    let wp = loader::load_program_with(Path::new(SOURCE_MODULE_PATH), read_source)
+        .map_err(|(alias, source, error)| compile_error(&alias, &source, error.as_ref()))?;
+    compile(wp)
+}
+
+/// Loaded program with running worker threads. Names of functions and types are either
+/// full, like `main::Worker::process`, or relative to the module of the entry function
+pub struct Instance {
+    vm: Arc<Vm>,
+    main_module: String,
+    finished: bool,
+}
+
+impl Instance {
+    pub fn new(bytecode: Vec<u8>, options: VmOptions) -> Result<Instance, Error> {
+        let vm = Vm::setup(bytecode, options).map_err(Error::Load)?;
+        Vm::start(&vm).map_err(Error::Start)?;
+        let entry = vm.metadata.get_function_name(vm.entry).unwrap_or_default();
+        let main_module = entry.rsplit_once("::").map_or("", |(module, _)| module).to_string();
+        Ok(Instance { vm, main_module, finished: false })
+    }
+
+    /// Sources are used in stack traces, if `source_root` is not given
+    pub fn from_file(path: &Path, options: VmOptions) -> Result<Instance, Error> {
+        let bytecode = compile_file(path)?;
+        let source_root = options.source_root.or_else(|| path.parent().map(PathBuf::from));
+        Instance::new(bytecode, VmOptions { source_root, ..options })
+    }
+
+    pub fn from_source(source: &str, options: VmOptions) -> Result<Instance, Error> {
+        Instance::new(compile_source(source)?, options)
+    }
+
+    /// Runs the entry function and waits until active objects process all messages
+    pub fn run_main(&self) -> Result<(), Error> {
+        self.run_on_entry(self.vm.entry, &[])?;
+        Vm::wait_until_idle(&self.vm);
+        match self.vm.exit_code() {
+            Some(code) => Err(Error::Exit(code as i64)),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls function on the thread of the caller, messages it sends are processed by
+    /// worker threads in the background
+    pub fn call(&self, function: &str, args: &[Value]) -> Result<Value, Error> {
+        let (position, signature) = self.find_function(function)?;
+        if signature.active_type.is_some() {
+            return Err(Error::NeedsActiveObject(self.function_name(position)));
+        }
+        self.run_on_entry(position, args)
+    }
+
+    /// Spawns active object, arguments are passed to its constructor
+    pub fn spawn(&self, type_name: &str, args: &[Value]) -> Result<ActiveRef, Error> {
+        let types_names = &self.vm.metadata.types_names;
+        let type_index = (types_names.iter())
+            .position(|name| *name == type_name)
+            .or_else(|| {
+                let full_name = format!("{}::{}", self.main_module, type_name);
+                types_names.iter().position(|name| *name == full_name)
+            })
+            .ok_or_else(|| Error::UnknownType(type_name.into()))?;
+
+        let full_name = &types_names[type_index];
+        let short_name = full_name.rsplit("::").next().unwrap();
+        let (position, signature) =
+            self.find_function(&format!("{}::{}", full_name, short_name))?;
+        if signature.active_type != Some(type_index) {
+            return Err(Error::NotActive(full_name.clone()));
+        }
+        let message = self.pack_call(position, args)?;
+        let handle = Vm::spawn_new_active(self.vm.clone(), None, type_index, message);
+        Ok(ActiveRef { handle, type_index })
+    }
+
+    /// Sends message to the active object, without waiting for it to be processed
+    pub fn send(&self, active: &ActiveRef, method: &str, args: &[Value]) -> Result<(), Error> {
+        let (_, message) = self.method_call(active, method, args)?;
+        self.vm.send_message(None, active.handle, message);
+        Ok(())
+    }
+
+    /// Sends message to the active object and waits for its return value
+    pub fn ask(&self, active: &ActiveRef, method: &str, args: &[Value]) -> Result<Value, Error> {
+        let (signature, message) = self.method_call(active, method, args)?;
+        let future = self.vm.ask(None, active.handle, message);
+        let reply = Vm::wait_future(&self.vm, future).map_err(|kind| match kind {
+            RuntimeErrorKind::Exit(code) => Error::Exit(code),
+            kind => Error::NoReply(kind),
+        })?;
+        unpack_return(&reply, &signature, &self.vm.metadata).map_err(Error::Value)
+    }
+
+    /// Blocks until active objects process all messages
+    pub fn wait_until_idle(&self) {
+        Vm::wait_until_idle(&self.vm);
+    }
+
+    /// Waits for all messages, stops worker threads and returns exit code of the program,
+    /// the same one `frisbee run` would exit with
+    pub fn finish(mut self) -> i32 {
+        self.finished = true;
+        Vm::finish(&self.vm)
+    }
+
+    fn function_name(&self, position: usize) -> String {
+        self.vm
+            .metadata
+            .get_function_name(position)
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn find_function(&self, name: &str) -> Result<(usize, Signature), Error> {
+        let metadata = &self.vm.metadata;
+        let position = metadata
+            .find_function(name)
+            .or_else(|| metadata.find_function(&format!("{}::{}", self.main_module, name)))
+            .ok_or_else(|| Error::UnknownFunction(name.into()))?;
+        Ok((position, metadata.function_signatures[&position].clone()))
+    }
+
+    // Values of unsupported types are refused before anything is run
+    fn pack_call(&self, position: usize, args: &[Value]) -> Result<Vec<u8>, Error> {
+        let metadata = &self.vm.metadata;
+        let signature = &metadata.function_signatures[&position];
+        if signature.args.len() != args.len() {
+            return Err(Error::ArgumentsAmount {
+                function: self.function_name(position),
+                expected: signature.args.len(),
+                given: args.len(),
+            });
+        }
+        (signature.args.iter().chain(std::iter::once(&signature.returns)))
+            .try_for_each(|value_type| check_supported(value_type, metadata))
+            .and_then(|_| pack_call(position, args, signature, metadata))
+            .map_err(Error::Value)
+    }
+
+    fn method_call(
+        &self,
+        active: &ActiveRef,
+        method: &str,
+        args: &[Value],
+    ) -> Result<(Signature, Vec<u8>), Error> {
+        if self.vm.get_active_type(active.handle) != Some(active.type_index) {
+            return Err(Error::UnknownActive(active.handle));
+        }
+        let type_name = &self.vm.metadata.types_names[active.type_index];
+        let (position, signature) = self.find_function(&format!("{}::{}", type_name, method))?;
+        Ok((signature, self.pack_call(position, args)?))
+    }
+
+    fn run_on_entry(&self, position: usize, args: &[Value]) -> Result<Value, Error> {
+        let message = self.pack_call(position, args)?;
+        let reply = Vm::run_on_entry(&self.vm, message).map_err(|error| match error.kind {
+            RuntimeErrorKind::Exit(code) => Error::Exit(code),
+            _ => Error::Runtime(error),
+        })?;
+        let signature = &self.vm.metadata.function_signatures[&position];
+        unpack_return(&reply, signature, &self.vm.metadata).map_err(Error::Value)
+    }
+}
+
+// Worker threads hold the VM, so they are stopped along with the instance
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if !self.finished {
+            Vm::stop(&self.vm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::helpers::SharedBuffer;
+
+    const PROGRAM: &str = "
+        active Greeter {
+            String greeting;
+
+            fun void greet(String name) {
+                println(@greeting + \", \" + name);
+            }
+
+            fun Int? half(Int number) {
+                if number < 0 {
+                    return nil;
+                }
+                return number / 2;
+            }
+        }
+
+        fun (Int, [String]) split_sum([Int] numbers, String label) {
+            Int total = 0;
+            [String] labels = [];
+            foreach number in numbers {
+                total = total + number;
+                labels.push(label + number.to_string());
+            }
+            return (total, labels);
+        }
+
+        fun Int divide(Int a, Int b) {
+            return a / b;
+        }
+
+        fun void stop(Int code) {
+            exit(code);
+        }
+
+        fun void main() {
+            print(\"main is run\");
+        }
+    ";
+
+    fn load() -> (Instance, SharedBuffer) {
+        let buffer = SharedBuffer::default();
+        let options =
+            VmOptions { stdout: Output::new(Box::new(buffer.clone())), ..VmOptions::default() };
+        (Instance::from_source(PROGRAM, options).unwrap(), buffer)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn functions_are_called_with_typed_values() {
+        let (instance, buffer) = load();
+        let numbers = Value::List(vec![Value::Int(1), Value::Int(20)]);
+        assert_eq!(
+            instance.call("split_sum", &[numbers, string("n")]),
+            Ok(Value::Tuple(vec![
+                Value::Int(21),
+                Value::List(vec![string("n1"), string("n20")])
+            ]))
+        );
+        assert_eq!(instance.run_main(), Ok(()));
+        assert_eq!(buffer.text(), "main is run");
+        assert_eq!(instance.finish(), 0);
+    }
+
+    #[test]
+    fn messages_are_sent_to_active_objects() {
+        let (instance, buffer) = load();
+        let greeter = instance.spawn("main::Greeter", &[string("Hello")]).unwrap();
+        instance.send(&greeter, "greet", &[string("host")]).unwrap();
+        assert_eq!(
+            instance.ask(&greeter, "half", &[Value::Int(8)]),
+            Ok(Value::Maybe(Some(Box::new(Value::Int(4)))))
+        );
+        assert_eq!(
+            instance.ask(&greeter, "half", &[Value::Int(-2)]),
+            Ok(Value::Maybe(None))
+        );
+        assert_eq!(instance.finish(), 0);
+        assert_eq!(buffer.text(), "Hello, host\n");
+    }
+
+    #[test]
+    fn wrong_calls_are_refused() {
+        let (instance, _) = load();
+        assert_eq!(
+            instance.call("missing", &[]),
+            Err(Error::UnknownFunction("missing".into()))
+        );
+        assert_eq!(
+            instance.call("divide", &[Value::Int(1)]),
+            Err(Error::ArgumentsAmount { function: "main::divide".into(), expected: 2, given: 1 })
+        );
+        assert_eq!(
+            instance.call("divide", &[Value::Int(1), Value::Bool(true)]),
+            Err(Error::Value(ValueError::TypeMismatch {
+                expected: "Int".into(),
+                given: Value::Bool(true)
+            }))
+        );
+        assert_eq!(
+            instance.call("Greeter::greet", &[string("host")]),
+            Err(Error::NeedsActiveObject("main::Greeter::greet".into()))
+        );
+        assert_eq!(
+            instance.spawn("Nobody", &[]),
+            Err(Error::UnknownType("Nobody".into()))
+        );
+
+        let stranger = ActiveRef { handle: 1000, type_index: 0 };
+        assert_eq!(
+            instance.send(&stranger, "greet", &[]),
+            Err(Error::UnknownActive(1000))
+        );
+    }
+
+    #[test]
+    fn failures_are_reported_as_errors() {
+        let (instance, _) = load();
+        match instance.call("divide", &[Value::Int(1), Value::Int(0)]) {
+            Err(Error::Runtime(error)) => assert_eq!(error.kind, RuntimeErrorKind::DivisionByZero),
+            result => panic!("Division by zero is not reported, got {:?}", result),
+        }
+        assert_eq!(instance.call("stop", &[Value::Int(3)]), Err(Error::Exit(3)));
+        assert_eq!(instance.finish(), 3);
+
+        assert_eq!(
+            compile_source("fun void main() { Int x = true; }").map(|_| ()),
+            Err(Error::Compile {
+                module: "main".into(),
+                line: 0,
+                column: 26,
+                message: "Expected type `Int` but got `Bool`".into()
+            })
+        );
+    }
+}
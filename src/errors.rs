@@ -1,13 +1,26 @@
 use crate::alias::ModuleAlias;
+use crate::codegen::CodegenError;
+use crate::loader::UnreadableModule;
 use crate::parsing::scanner::ScanningError;
 use crate::parsing::ParseError;
 use crate::semantics::errors::SemanticError;
 
 use owo_colors::OwoColorize;
 
+// Exit codes of `frisbee` itself, program might use any other code with std `exit`
+pub mod exit_codes {
+    pub const SCANNING_ERROR: i32 = 2;
+    pub const PARSE_ERROR: i32 = 3;
+    pub const SEMANTIC_ERROR: i32 = 4;
+    pub const LOAD_ERROR: i32 = 5;
+    pub const RUNTIME_ERROR: i32 = 6;
+    pub const CODEGEN_ERROR: i32 = 7;
+}
+
 pub trait CompileError: std::fmt::Debug {
     fn get_position_window(&self) -> (usize, usize);
     fn get_message(&self) -> String;
+    fn get_exit_code(&self) -> i32;
 }
 
 impl CompileError for ScanningError {
@@ -18,6 +31,10 @@ impl CompileError for ScanningError {
     fn get_message(&self) -> String {
         format!("Scanning error: {}", self.0)
     }
+
+    fn get_exit_code(&self) -> i32 {
+        exit_codes::SCANNING_ERROR
+    }
 }
 
 impl CompileError for ParseError {
@@ -31,6 +48,10 @@ impl CompileError for ParseError {
             None => self.error_msg.to_string(),
         }
     }
+
+    fn get_exit_code(&self) -> i32 {
+        exit_codes::PARSE_ERROR
+    }
 }
 
 impl CompileError for SemanticError {
@@ -49,6 +70,42 @@ impl CompileError for SemanticError {
             SemanticError::TopLevelError { message, .. } => message.clone(),
         }
     }
+
+    fn get_exit_code(&self) -> i32 {
+        exit_codes::SEMANTIC_ERROR
+    }
+}
+
+impl CompileError for CodegenError {
+    fn get_position_window(&self) -> (usize, usize) {
+        (self.pos, self.pos)
+    }
+
+    fn get_message(&self) -> String {
+        format!("Codegen error: {}", self.message)
+    }
+
+    fn get_exit_code(&self) -> i32 {
+        exit_codes::CODEGEN_ERROR
+    }
+}
+
+impl CompileError for UnreadableModule {
+    fn get_position_window(&self) -> (usize, usize) {
+        (self.pos, self.pos)
+    }
+
+    fn get_message(&self) -> String {
+        format!(
+            "Cannot read module {}: {}",
+            self.path.display(),
+            self.reason
+        )
+    }
+
+    fn get_exit_code(&self) -> i32 {
+        exit_codes::LOAD_ERROR
+    }
 }
 
 #[derive(Debug)]
@@ -73,7 +130,7 @@ fn get_lines_length(source: &str) -> Vec<usize> {
     lines_length
 }
 
-fn get_position_coordinates(file_contents: &str, pos: usize) -> (usize, usize) {
+pub fn get_position_coordinates(file_contents: &str, pos: usize) -> (usize, usize) {
     let mut line: usize = 0;
     let mut row: usize = 0;
     let mut counter: usize = 0;
@@ -109,6 +166,37 @@ pub fn adjust_error_window(source: &str, start: usize, end: usize) -> ErrorCoord
     ErrorCoordinates { line: start_line, start: start_offset, end }
 }
 
+/// Lines of code before the window and the line of the window itself, with their numbers
+/// in the sidebar, and `^~~` under the window, followed by the message if there is one
+pub fn render_error_window(
+    contents: &str,
+    pos: &ErrorCoordinates,
+    lines_before: usize,
+    error_msg: &str,
+) -> String {
+    let sidebar_len = (pos.line + 1).to_string().len() + 3; // "<num> | " - 3 chars + line len
+
+    let lines: Vec<&str> = contents.split('\n').collect();
+    let spaces: String = vec![' '; pos.start + sidebar_len].into_iter().collect();
+
+    // -1 for underscored due to ^ taking one place
+    let underscored: String = vec!['~'; pos.end - pos.start - 1].into_iter().collect();
+
+    let mut rendered = vec![];
+    let first_line = pos.line.saturating_sub(lines_before);
+    for (i, line) in lines.iter().enumerate().take(pos.line + 1).skip(first_line) {
+        rendered.push(format!("{}{}", format!("{} | ", i + 1).blue(), line));
+    }
+    rendered.push(format!(
+        "{}",
+        format!("{}^{}", spaces, underscored).yellow()
+    ));
+    if !error_msg.is_empty() {
+        rendered.push(format!("{}{}", spaces, error_msg.yellow()));
+    }
+    rendered.join("\n")
+}
+
 fn show_error(contents: &str, alias: &ModuleAlias, pos: ErrorCoordinates, error_msg: String) {
     // TODO: add path here somehow, maybe just pass as an argument...
     let header = format!("Error at line {} (in {}):", pos.line, alias);
@@ -117,22 +205,8 @@ fn show_error(contents: &str, alias: &ModuleAlias, pos: ErrorCoordinates, error_
     let header_underscore = vec!["="; header.len() - 1];
     println!("{}\n", header_underscore.join(""));
 
-    let sidebar_len = pos.line.to_string().len() + 3; // "<num> | " - 3 chars + line len
-
-    let lines: Vec<&str> = contents.split('\n').collect();
-    let spaces: String = vec![' '; pos.start + sidebar_len].into_iter().collect();
-
-    // -1 for underscored due to ^ taking one place
-    let underscored: String = vec!['~'; pos.end - pos.start - 1].into_iter().collect();
-
     // Print lines of code, 2 if possible
-
-    let first_list = (pos.line as i64 - 2).max(0) as usize;
-    for (i, line) in lines.iter().enumerate().take(pos.line + 1).skip(first_list) {
-        println!("{}{}", format!("{} | ", i + 1).blue(), line);
-    }
-    println!("{}", format!("{}^{}", spaces, underscored).yellow());
-    println!("{}{}\n", spaces, error_msg.yellow());
+    println!("{}\n", render_error_window(contents, &pos, 2, &error_msg));
 }
 
 pub fn show_error_in_file(alias: &ModuleAlias, source: &str, error: Box<dyn CompileError>) {
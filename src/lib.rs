@@ -0,0 +1,16 @@
+//! Frisbee compiler and VM. The `frisbee` binary is built on top of this crate, and
+//! [`embed`] runs programs inside of other Rust applications.
+
+pub mod alias;
+pub mod ast;
+pub mod codegen;
+pub mod embed;
+pub mod errors;
+pub mod loader;
+pub mod parsing;
+pub mod runtime;
+pub mod semantics;
+pub mod stdlib;
+pub mod symbols;
+pub mod tests;
+pub mod types;
@@ -27,22 +27,24 @@ impl WholeProgram {
     }
 }
 
+pub type LoadError = (ModuleAlias, String, Box<dyn CompileError>);
+
+// Module that imports another one and position of the import
+type ImportedAt = Option<(ModuleAlias, usize)>;
+
+/// Module that can not be read, position is of the import that refers to it
+#[derive(Debug)]
+pub struct UnreadableModule {
+    pub pos: usize,
+    pub path: PathBuf,
+    pub reason: String,
+}
+
 fn load_file(
-    workdir: &Path,
+    file_path: PathBuf,
     module_path: &[String],
-) -> Result<LoadedFile, (ModuleAlias, String, Box<dyn CompileError>)> {
-    if module_path.first().unwrap() == "std" {
-        // TODO: do something with this?
-        panic!("Error loading {:?}: std is reserved", module_path);
-    }
-    // TODO: implement logging system for this
-    let mut file_path = workdir.to_owned();
-    for subpath in module_path.iter() {
-        file_path.push(subpath);
-    }
-    file_path.set_extension("frisbee");
-
-    let contents = std::fs::read_to_string(&file_path).expect("Cant read file");
+    contents: String,
+) -> Result<LoadedFile, LoadError> {
     let module_alias = ModuleAlias::new(module_path);
 
     let (tokens, scan_status) = parsing::scanner::scan_tokens(&contents);
@@ -60,9 +62,18 @@ fn load_file(
 }
 
 // TODO:  ensure both windows and Unix are working file
-pub fn load_program(
+pub fn load_program(entry_file_path: &Path) -> Result<WholeProgram, LoadError> {
+    load_program_with(entry_file_path, |path| std::fs::read_to_string(path))
+}
+
+/// Loads the program, taking sources of its modules from `read_source` instead of files
+pub fn load_program_with<R>(
     entry_file_path: &Path,
-) -> Result<WholeProgram, (ModuleAlias, String, Box<dyn CompileError>)> {
+    read_source: R,
+) -> Result<WholeProgram, LoadError>
+where
+    R: Fn(&Path) -> std::io::Result<String>,
+{
     let workdir = entry_file_path.parent().unwrap();
 
     if entry_file_path.extension().unwrap() != "frisbee" {
@@ -82,13 +93,40 @@ pub fn load_program(
         files: HashMap::new(),
     };
 
-    let mut modules_to_load: Vec<Vec<String>> = vec![vec![main_module.to_owned()]];
+    // Each module is loaded along with the import that refers to it, main one has none
+    let mut modules_to_load: Vec<(Vec<String>, ImportedAt)> =
+        vec![(vec![main_module.to_owned()], None)];
 
-    while !modules_to_load.is_empty() {
-        let module_path = modules_to_load.pop().unwrap();
+    while let Some((module_path, imported_at)) = modules_to_load.pop() {
+        if module_path.first().unwrap() == "std" {
+            // TODO: do something with this?
+            panic!("Error loading {:?}: std is reserved", module_path);
+        }
+        // TODO: implement logging system for this
+        let mut file_path = whole_program.workdir.clone();
+        for subpath in module_path.iter() {
+            file_path.push(subpath);
+        }
+        file_path.set_extension("frisbee");
+
+        let contents = match read_source(&file_path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                let (alias, source, pos) = match imported_at {
+                    Some((alias, pos)) => {
+                        let source = whole_program.files[&alias].contents.clone();
+                        (alias, source, pos)
+                    }
+                    None => (ModuleAlias::new(&module_path), String::new(), 0),
+                };
+                let reason = error.to_string();
+                let error = UnreadableModule { pos, path: file_path, reason };
+                return Err((alias, source, Box::new(error)));
+            }
+        };
 
         // TODO: check error reporting over here
-        let loaded_file = load_file(&whole_program.workdir, &module_path)?;
+        let loaded_file = load_file(file_path, &module_path, contents)?;
 
         let alias = ModuleAlias::new(&module_path);
 
@@ -98,12 +136,13 @@ pub fn load_program(
 
         for import in &loaded_file.ast.imports {
             // todo swap [0] to correct path forming
-            let alias = ModuleAlias::new(&import.module_path);
+            let imported_alias = ModuleAlias::new(&import.module_path);
 
-            if whole_program.files.get(&alias).is_none() {
-                modules_to_load.push(import.module_path.clone());
+            if !whole_program.files.contains_key(&imported_alias) {
+                let imported_at = Some((alias.clone(), import.pos));
+                modules_to_load.push((import.module_path.clone(), imported_at));
             } else {
-                println!("Using cache for {}", alias);
+                println!("Using cache for {}", imported_alias);
             }
         }
     }
@@ -132,12 +171,15 @@ mod test {
     use super::*;
 
     #[test]
-    #[should_panic] // TODO: proper error reporting check
     fn import_of_missing_file() {
         let mut files_dir = TestFilesCreator::new();
-        files_dir.set_mainfile("from mod import somefun;");
+        files_dir.set_mainfile("\nfrom mod import somefun;");
 
-        load_program(files_dir.get_main_path()).unwrap();
+        let (alias, source, error) = load_program(files_dir.get_main_path()).unwrap_err();
+        assert_eq!(alias, ModuleAlias::new(&["main".into()]));
+        assert_eq!(source, "\nfrom mod import somefun;");
+        assert_eq!(error.get_position_window(), (1, 1));
+        assert!(error.get_message().starts_with("Cannot read module "));
     }
 
     #[test]
@@ -1,23 +1,16 @@
 use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use argh::FromArgs;
+use frisbee::errors::CompileError;
+use frisbee::runtime::debugger::Debugger;
+use frisbee::runtime::scheduler::OverflowPolicy;
+use frisbee::runtime::stdlib_runners::Output;
+use frisbee::runtime::tracing::Tracer;
+use frisbee::runtime::vm::{Vm, VmOptions};
+use frisbee::{codegen, errors, loader, runtime, semantics};
 use owo_colors::OwoColorize;
-use runtime::vm::Vm;
-
-pub mod alias;
-pub mod ast;
-pub mod codegen;
-pub mod errors;
-pub mod loader;
-pub mod parsing;
-pub mod runtime;
-pub mod semantics;
-pub mod stdlib;
-pub mod symbols;
-pub mod tests;
-pub mod types;
 
 // TODO: color output?
 
@@ -34,6 +27,7 @@ enum FrisbeeSubCommands {
     Cc(CompileCommand),
     Dis(DisCommand),
     Run(RunCommand),
+    Verify(VerifyCommand),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -62,6 +56,15 @@ struct DisCommand {
     program: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// Verify subcommand.
+#[argh(subcommand, name = "verify")]
+struct VerifyCommand {
+    #[argh(positional)]
+    /// path to compiled program
+    program: String,
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// Run subcommand.
 #[argh(subcommand, name = "run")]
@@ -74,18 +77,91 @@ struct RunCommand {
     /// show debug info on each tick
     show_debug_info: bool,
 
-    #[argh(switch, short = 's')]
-    /// execute step by step for debug porposes
-    step_by_step: bool,
+    #[argh(switch, short = 'd')]
+    /// pause before the first statement and run debugger commands from stdin
+    debug: bool,
+
+    #[argh(option, default = "runtime::vm::DEFAULT_MAX_STACK_SIZE")]
+    /// max amount of values on the stack of each active object
+    max_stack_size: usize,
+
+    #[argh(option, default = "runtime::vm::DEFAULT_MAX_CALL_DEPTH")]
+    /// max depth of nested function calls for each active object
+    max_call_depth: usize,
+
+    #[argh(switch)]
+    /// roll back state of active object if message fails, and save message to dead letters
+    transactional: bool,
+
+    #[argh(option, default = "runtime::vm::default_threads_amount()")]
+    /// amount of worker threads to run active objects on, defaults to one per core
+    threads: usize,
+
+    #[argh(option)]
+    /// run as a node on given address, active objects are spawned on it by the coordinator
+    listen: Option<String>,
+
+    #[argh(option, from_str_fn(parse_node))]
+    /// node to spawn active objects on, as `name=host:port`, might be repeated
+    node: Vec<(String, String)>,
+
+    #[argh(option, from_str_fn(parse_capacity))]
+    /// mailbox capacity of active types, that do not declare it, unbounded by default
+    mailbox_capacity: Option<usize>,
+
+    #[argh(option, default = "OverflowPolicy::Block", from_str_fn(parse_overflow))]
+    /// what happens to messages sent to a full mailbox: block, drop-newest or drop-oldest
+    mailbox_overflow: OverflowPolicy,
+
+    #[argh(switch)]
+    /// run all active objects on a single thread in reproducible order, given by `--seed`
+    deterministic: bool,
+
+    #[argh(option)]
+    /// seed for the order of active objects in deterministic mode, 0 by default
+    seed: Option<u64>,
+
+    #[argh(option)]
+    /// write spawns, sends and processed messages to a file as JSON lines
+    trace: Option<String>,
+
+    #[argh(switch)]
+    /// report executed instructions, function timings, allocations and messages on exit
+    profile: bool,
+}
+
+fn parse_capacity(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(capacity) if capacity > 0 => Ok(capacity),
+        _ => Err("expected positive capacity".to_string()),
+    }
+}
+
+fn parse_overflow(value: &str) -> Result<OverflowPolicy, String> {
+    OverflowPolicy::from_name(value)
+        .ok_or_else(|| "expected block, drop-newest or drop-oldest".to_string())
+}
+
+fn parse_node(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, address)) if !name.is_empty() && !address.is_empty() => {
+            Ok((name.into(), address.into()))
+        }
+        _ => Err(format!(
+            "expected node as `name=host:port`, got `{}`",
+            value
+        )),
+    }
 }
 
-fn compile_file(c: CompileCommand) {
+fn compile_file(c: CompileCommand) -> i32 {
     let CompileCommand { mainfile, show_intermediate, run } = c;
     let file_path = Path::new(&mainfile);
 
     let mut wp = loader::load_program(file_path).unwrap_or_else(|(alias, source, error)| {
+        let exit_code = error.get_exit_code();
         errors::show_error_in_file(&alias, &source, error);
-        panic!("See the error above!");
+        std::process::exit(exit_code);
     });
 
     semantics::add_default_constructors(
@@ -97,13 +173,13 @@ fn compile_file(c: CompileCommand) {
 
     let aggregate =
         semantics::perform_semantic_analysis(&modules, &wp.main_module).unwrap_or_else(|err| {
+            let exit_code = err.error.get_exit_code();
             errors::show_error_in_file(
                 &err.module,
                 &wp.files[&err.module].contents,
                 Box::new(err.error),
             );
-            // return 1 exit code instead of panic
-            panic!("See the error above!");
+            std::process::exit(exit_code);
         });
 
     if show_intermediate {
@@ -114,9 +190,19 @@ fn compile_file(c: CompileCommand) {
     }
 
     let semantics::aggregate::ProgramAggregate { types, functions, entry } = aggregate;
-    let types: Vec<_> = types.into_iter().map(|(_, v)| v).collect();
-    let functions: Vec<_> = functions.into_iter().map(|(_, v)| v).collect();
-    let bytecode = codegen::generate(&types, &functions, &entry);
+    let types: Vec<_> = types.into_values().collect();
+    let functions: Vec<_> = functions.into_values().collect();
+    let sources = wp
+        .files
+        .iter()
+        .map(|(alias, f)| (alias.clone(), f.contents.as_str()))
+        .collect();
+    let bytecode = codegen::generate(&types, &functions, &entry, &sources).unwrap_or_else(|err| {
+        let exit_code = err.get_exit_code();
+        let module = err.module.clone();
+        errors::show_error_in_file(&module, sources[&module], Box::new(err));
+        std::process::exit(exit_code);
+    });
 
     let bytecode_path = file_path.with_extension("frisbee.bytecode");
     let mut bytecode_file = File::create(bytecode_path).expect("Cant open file for writing");
@@ -124,34 +210,156 @@ fn compile_file(c: CompileCommand) {
 
     println!("{}", "File compiled successfully!".green());
     if run {
-        let vm = Vm::setup(bytecode, false, false);
-        Vm::setup_entry_and_run(vm)
+        let source_root = file_path.parent().map(PathBuf::from);
+        return run_bytecode(bytecode, VmOptions { source_root, ..VmOptions::default() });
     }
+    0
 }
 
-fn dis_file(c: DisCommand) {
+fn dis_file(c: DisCommand) -> i32 {
     let DisCommand { program } = c;
 
     // xxd is also usefull way to show something inside of the file
-    let bytecode = std::fs::read(program).expect("Cant read file");
-    println!("{}", codegen::disassemble(&bytecode));
+    let bytecode = match std::fs::read(&program) {
+        Ok(bytecode) => bytecode,
+        Err(error) => {
+            eprintln!("{} {}", "Cannot read program:".red(), error);
+            return errors::exit_codes::LOAD_ERROR;
+        }
+    };
+    match codegen::disassemble(&bytecode) {
+        Ok(text) => {
+            println!("{}", text);
+            0
+        }
+        Err(error) => {
+            eprintln!("{} {}", "Cannot disassemble program:".red(), error);
+            errors::exit_codes::LOAD_ERROR
+        }
+    }
+}
+
+// Program is verified each time it is loaded, so loading it is enough
+fn verify_file(c: VerifyCommand) -> i32 {
+    let VerifyCommand { program } = c;
+
+    let bytecode = match std::fs::read(&program) {
+        Ok(bytecode) => bytecode,
+        Err(error) => {
+            eprintln!("{} {}", "Cannot read program:".red(), error);
+            return errors::exit_codes::LOAD_ERROR;
+        }
+    };
+    match Vm::setup(bytecode, VmOptions::default()) {
+        Ok(vm) => {
+            let message = format!(
+                "Bytecode is valid: {} functions, {} constants",
+                vm.metadata.function_names.len(),
+                vm.constants.len()
+            );
+            println!("{}", message.green());
+            0
+        }
+        Err(error) => {
+            eprintln!("{} {}", "Bytecode is invalid:".red(), error);
+            errors::exit_codes::LOAD_ERROR
+        }
+    }
 }
 
-fn run_file(c: RunCommand) {
-    let RunCommand { program, show_debug_info, step_by_step } = c;
+fn run_file(c: RunCommand) -> i32 {
+    let RunCommand {
+        program,
+        show_debug_info,
+        debug,
+        max_stack_size,
+        max_call_depth,
+        transactional,
+        threads,
+        listen,
+        node,
+        mailbox_capacity,
+        mailbox_overflow,
+        deterministic,
+        seed,
+        trace,
+        profile,
+    } = c;
 
-    let bytecode = std::fs::read(program).expect("Cant read file");
+    // Wrong combinations of options exit with the same code, as wrong options for argh
+    if seed.is_some() && !deterministic {
+        eprintln!("{}", "Seed is only used with `--deterministic`".red());
+        return 1;
+    }
+    if deterministic && (listen.is_some() || !node.is_empty()) {
+        eprintln!("{}", "Nodes can not be used with `--deterministic`".red());
+        return 1;
+    }
+
+    let bytecode = match std::fs::read(&program) {
+        Ok(bytecode) => bytecode,
+        Err(error) => {
+            eprintln!("{} {}", "Cannot read program:".red(), error);
+            return errors::exit_codes::LOAD_ERROR;
+        }
+    };
+    let source_root = Path::new(&program).parent().map(PathBuf::from);
+    let trace = match trace.map(File::create).transpose() {
+        Ok(file) => file.map(|file| Tracer::new(Box::new(BufWriter::new(file)))),
+        Err(error) => {
+            eprintln!("{} {}", "Cannot create trace file:".red(), error);
+            return errors::exit_codes::LOAD_ERROR;
+        }
+    };
+
+    let debugger = debug.then(|| {
+        let input = Box::new(BufReader::new(io::stdin()));
+        Debugger::new(input, Box::new(io::stdout()), source_root.clone())
+    });
 
-    let vm = Vm::setup(bytecode, step_by_step, show_debug_info);
-    Vm::setup_entry_and_run(vm)
+    let options = VmOptions {
+        show_debug: show_debug_info,
+        max_stack_size,
+        max_call_depth,
+        transactional,
+        threads,
+        source_root,
+        listen,
+        nodes: node,
+        mailbox_capacity,
+        mailbox_overflow,
+        deterministic_seed: deterministic.then(|| seed.unwrap_or(0)),
+        trace,
+        profile,
+        debugger,
+        stdout: Output::default(),
+    };
+    run_bytecode(bytecode, options)
+}
+
+fn run_bytecode(bytecode: Vec<u8>, options: VmOptions) -> i32 {
+    match Vm::setup(bytecode, options) {
+        Ok(vm) => match vm.options.listen.clone() {
+            Some(address) => Vm::serve_node(vm, &address),
+            None => Vm::setup_entry_and_run(vm),
+        },
+        Err(error) => {
+            eprintln!("{} {}", "Cannot load program:".red(), error);
+            errors::exit_codes::LOAD_ERROR
+        }
+    }
 }
 
 fn main() {
-    // TODO: exit codes?
     let args: TopLevel = argh::from_env();
-    match args.nested {
+    let exit_code = match args.nested {
         FrisbeeSubCommands::Cc(c) => compile_file(c),
         FrisbeeSubCommands::Dis(c) => dis_file(c),
         FrisbeeSubCommands::Run(c) => run_file(c),
-    }
+        FrisbeeSubCommands::Verify(c) => verify_file(c),
+    };
+
+    // Exiting does not flush stdout, and actors might still be printing something
+    std::io::stdout().flush().unwrap();
+    std::process::exit(exit_code);
 }
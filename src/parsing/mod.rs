@@ -1,7 +1,7 @@
 use crate::ast::parsed::FileAst;
 
 mod helpers;
-pub(self) mod parser;
+mod parser;
 pub mod scanner;
 
 mod tests;
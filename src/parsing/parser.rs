@@ -94,7 +94,7 @@ impl<'a> Parser<'a> {
 
     fn full_token(&self, rel_pos: isize) -> &ScannedToken {
         let pos = if rel_pos < 0 {
-            self.position - (rel_pos.abs() as usize)
+            self.position - rel_pos.unsigned_abs()
         } else {
             self.position + (rel_pos as usize)
         };
@@ -221,6 +221,12 @@ impl<'a> Parser<'a> {
                 "Float" => ParsedType::Float,
                 "Bool" => ParsedType::Bool,
                 "String" => ParsedType::String,
+                "Future" => {
+                    consume_and_check!(self, Token::Less);
+                    let inner = self.parse_type()?;
+                    consume_and_check!(self, Token::Greater);
+                    ParsedType::Future(Box::new(inner))
+                }
                 _ => ParsedType::Custom(s.clone()),
             },
             _ => {
@@ -293,6 +299,11 @@ impl<'a> Parser<'a> {
         }
 
         let new_object_name = consume_and_check_type_ident!(self);
+        let options = if is_active && self.rel_token_check(0, Token::LeftParenthesis) {
+            self.parse_active_options()?
+        } else {
+            ActiveOptions::default()
+        };
         let mut fields: Vec<TypedItem> = vec![];
         let mut methods: Vec<FunctionDecl> = vec![];
 
@@ -322,11 +333,40 @@ impl<'a> Parser<'a> {
             pos: declaration_start,
             is_active,
             name: new_object_name,
+            options,
             fields,
             methods,
         })
     }
 
+    fn parse_active_options(&mut self) -> ParseResult<ActiveOptions> {
+        let mut options = ActiveOptions::default();
+
+        consume_and_check!(self, Token::LeftParenthesis);
+        until_closes!(self, Token::RightParenthesis, {
+            let option = consume_and_check_ident!(self);
+            consume_and_check!(self, Token::Equal);
+            let (number_option, name_option) = match option.as_str() {
+                "mailbox" => (Some(&mut options.mailbox), None),
+                "overflow" => (None, Some(&mut options.overflow)),
+                "supervision" => (None, Some(&mut options.supervision)),
+                "max_restarts" => (Some(&mut options.max_restarts), None),
+                "restart_period" => (Some(&mut options.restart_period), None),
+                _ => return perr(self.full_token(-1), "Unknown option of active type"),
+            };
+            match (number_option, name_option, self.consume_token().clone()) {
+                (Some(slot @ None), _, Token::Integer(value)) => *slot = Some(value),
+                (_, Some(slot @ None), Token::Identifier(value)) => *slot = Some(value),
+                _ => return perr(self.full_token(-1), "Wrong or repeated value of option"),
+            }
+            if !self.rel_token_check(0, Token::RightParenthesis) {
+                consume_and_check!(self, Token::Comma);
+            }
+        });
+
+        Ok(options)
+    }
+
     fn parse_statements_in_curly_block(&mut self) -> ParseResult<Vec<StatementWithPos>> {
         let mut statements: Vec<StatementWithPos> = vec![];
         consume_and_check!(self, Token::LeftCurlyBrackets);
@@ -458,8 +498,16 @@ impl<'a> Parser<'a> {
         } else if consume_if_matches_one_of!(self, [Token::Bang]) {
             let method = consume_and_check_ident!(self);
             let args = self.parse_function_call_args()?;
+            let delay = if consume_if_matches_one_of!(self, [Token::After]) {
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
             consume_and_check!(self, Token::Semicolon);
-            self.stmt_with_pos(Statement::SendMessage { active: expr, method, args }, start)
+            self.stmt_with_pos(
+                Statement::SendMessage { active: expr, method, args, delay },
+                start,
+            )
         } else {
             perr_with_expected(
                 self.full_token(0),
@@ -492,7 +540,20 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse_expr(&mut self) -> ParseResult<ExprWithPos> {
-        self.parse_elvis_operators()
+        self.parse_ask_message()
+    }
+
+    fn parse_ask_message(&mut self) -> ParseResult<ExprWithPos> {
+        let start = self.position;
+        let res_expr = self.parse_elvis_operators()?;
+        if consume_if_matches_one_of!(self, [Token::Question]) {
+            let method = consume_and_check_ident!(self);
+            let args = self.parse_function_call_args()?;
+            let inner = Expr::AskMessage { active: Box::new(res_expr), method, args };
+            return self.expr_with_pos(inner, start, self.position - 1);
+        }
+
+        Ok(res_expr)
     }
 
     fn parse_elvis_operators(&mut self) -> ParseResult<ExprWithPos> {
@@ -750,8 +811,14 @@ impl<'a> Parser<'a> {
         consume_and_check!(self, Token::Spawn);
         let typename = consume_and_check_type_ident!(self);
         let args = self.parse_function_call_args()?;
+        let node = if self.rel_token_check(0, Token::On) {
+            self.consume_token();
+            Some(Box::new(self.parse_expr()?))
+        } else {
+            None
+        };
         self.expr_with_pos(
-            Expr::SpawnActive { typename, args },
+            Expr::SpawnActive { typename, args, node },
             start,
             self.position - 1,
         )
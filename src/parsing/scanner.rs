@@ -23,7 +23,7 @@ pub enum Token {
     Integer(i64),
 
     // Keywords
-    Active, Class, Spawn,
+    Active, Class, Spawn, On, After,
     If, Else, Elif,
     While, Foreach, Break, Continue, In,
     Fun,
@@ -50,7 +50,7 @@ struct Scanner<'a> {
 }
 
 impl<'a> Scanner<'a> {
-    fn create(chars: &'a [char]) -> Scanner {
+    fn create(chars: &'a [char]) -> Scanner<'a> {
         Scanner { chars, tokens: Vec::new(), position: 0 }
     }
 
@@ -103,6 +103,8 @@ fn identifier_to_token(s: String) -> Token {
         "active" => Token::Active,
         "class" => Token::Class,
         "spawn" => Token::Spawn,
+        "on" => Token::On,
+        "after" => Token::After,
         "if" => Token::If,
         "else" => Token::Else,
         "elif" => Token::Elif,
@@ -270,15 +272,15 @@ fn scan_and_add_token(scanner: &mut Scanner) -> Result<(), ScanningError> {
         '"' => scan_string(scanner, start, '"')?,
         '\'' => scan_string(scanner, start, '\'')?,
 
-        d if d.is_digit(10) => {
+        d if d.is_ascii_digit() => {
             let mut is_float = false;
-            while scanner.char_ahead(0).is_digit(10) {
+            while scanner.char_ahead(0).is_ascii_digit() {
                 scanner.consume_char();
             }
-            if scanner.check_ahead(0, '.') && scanner.char_ahead(1).is_digit(10) {
+            if scanner.check_ahead(0, '.') && scanner.char_ahead(1).is_ascii_digit() {
                 is_float = true;
                 scanner.consume_char();
-                while scanner.char_ahead(0).is_digit(10) {
+                while scanner.char_ahead(0).is_ascii_digit() {
                     scanner.consume_char();
                 }
             }
@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_closure)]
+
 #[rustfmt::skip] #[cfg(test)] pub mod tests_scanner;
 #[rustfmt::skip] #[cfg(test)] pub mod tests_helpers;
 #[rustfmt::skip] #[cfg(test)] pub mod tests_top_level;
@@ -588,6 +588,33 @@ fn expr_new_class_instance() {
 fn expr_spawn_active() {
     assert_expr_parses(
         "spawn Object()",
-        Expr::SpawnActive { typename: "Object".into(), args: vec![] },
+        Expr::SpawnActive { typename: "Object".into(), args: vec![], node: None },
     );
 }
+
+#[test]
+fn expr_spawn_active_on_node() {
+    assert_expr_parses(
+        "spawn Object(1) on \"worker\"",
+        Expr::SpawnActive {
+            typename: "Object".into(),
+            args: vec![expr_raw(Expr::Int(1), 13, 13)],
+            node: Some(expr(Expr::String("worker".into()), 19, 26)),
+        },
+    );
+    assert_expr_invalid("spawn Object() on");
+}
+
+#[test]
+fn expr_ask_message() {
+    assert_expr_parses(
+        "a ? qwe(1)",
+        Expr::AskMessage {
+            active: expr(Expr::Identifier("a".into()), 0, 0),
+            method: "qwe".into(),
+            args: vec![expr_raw(Expr::Int(1), 8, 8)],
+        },
+    );
+    assert_expr_invalid("a ? qwe");
+    assert_expr_invalid("a ? (qwe())");
+}
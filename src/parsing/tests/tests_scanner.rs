@@ -189,8 +189,8 @@ fn test_own_identifiers() {
 #[test]
 fn test_keywords() {
     assert_eq!(
-        scan_tokens_helper("if else spawn active class"),
-        vec![Token::If, Token::Else, Token::Spawn, Token::Active, Token::Class,]
+        scan_tokens_helper("if else spawn on active class"),
+        vec![Token::If, Token::Else, Token::Spawn, Token::On, Token::Active, Token::Class,]
     );
 }
 
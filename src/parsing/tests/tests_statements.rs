@@ -227,6 +227,23 @@ fn stmt_send_message() {
             ),
             method: "method".into(),
             args: vec![],
+            delay: None,
+        },
+    );
+}
+
+#[test]
+fn stmt_send_message_after_delay() {
+    assert_stmt_invalid("a ! tick() after;");
+    assert_stmt_invalid("a ! tick() after 10");
+
+    assert_stmt_parses(
+        "a ! tick(1) after 500;",
+        Statement::SendMessage {
+            active: expr_raw(Expr::Identifier("a".into()), 0, 0),
+            method: "tick".into(),
+            args: vec![expr_raw(Expr::Int(1), 9, 9)],
+            delay: Some(expr_raw(Expr::Int(500), 18, 20)),
         },
     );
 }
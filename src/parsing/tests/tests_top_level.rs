@@ -126,6 +126,7 @@ fn active_object_and_fields() {
             pos: 0,
             is_active: true,
             name: String::from("Actor"),
+            options: ActiveOptions::default(),
             fields: vec![
                 TypedItem { typename: Type::String, name: "name".into() },
                 TypedItem { typename: Type::Custom(String::from("Actor")), name: "lol".into() },
@@ -146,6 +147,7 @@ fn class_object_and_methods() {
             pos: 0,
             is_active: false,
             name: String::from("Data"),
+            options: ActiveOptions::default(),
             fields: vec![],
             methods: vec![FunctionDecl {
                 pos: 13,
@@ -179,6 +181,7 @@ fn class_object_constructor_method() {
             pos: 0,
             is_active: false,
             name: String::from("Data"),
+            options: ActiveOptions::default(),
             fields: vec![],
             methods: vec![FunctionDecl {
                 pos: 13,
@@ -212,6 +215,7 @@ fn active_object_constructor_method() {
             pos: 0,
             is_active: true,
             name: String::from("Actor"),
+            options: ActiveOptions::default(),
             fields: vec![],
             methods: vec![FunctionDecl {
                 pos: 15,
@@ -232,3 +236,48 @@ fn active_object_constructor_method() {
         "active Actor { fun ActorConstructor() {} }",
     );
 }
+
+#[test]
+fn active_object_options() {
+    assert_eq!(
+        parse_and_unwrap(
+            |p| Parser::parse_object(p, true),
+            "active Worker(mailbox = 100, overflow = drop_oldest) {}"
+        ),
+        ClassDecl {
+            pos: 0,
+            is_active: true,
+            name: String::from("Worker"),
+            options: ActiveOptions {
+                mailbox: Some(100),
+                overflow: Some("drop_oldest".into()),
+                ..ActiveOptions::default()
+            },
+            fields: vec![],
+            methods: vec![],
+        }
+    );
+    assert_eq!(
+        parse_and_unwrap(
+            |p| Parser::parse_object(p, true),
+            "active Pool(supervision = one_for_all, max_restarts = 2, restart_period = 100) {}"
+        )
+        .options,
+        ActiveOptions {
+            supervision: Some("one_for_all".into()),
+            max_restarts: Some(2),
+            restart_period: Some(100),
+            ..ActiveOptions::default()
+        }
+    );
+
+    assert_parsing_fails(|p| Parser::parse_object(p, true), "active Worker(mailbox) {}");
+    assert_parsing_fails(|p| Parser::parse_object(p, true), "active Worker(size = 1) {}");
+    assert_parsing_fails(|p| Parser::parse_object(p, true), "active Worker(mailbox = 1 2) {}");
+    assert_parsing_fails(|p| Parser::parse_object(p, true), "active Worker(mailbox = block) {}");
+    assert_parsing_fails(
+        |p| Parser::parse_object(p, true),
+        "active Worker(mailbox = 1, mailbox = 2) {}",
+    );
+    assert_parsing_fails(|p| Parser::parse_object(p, false), "class Data(mailbox = 1) {}");
+}
@@ -117,3 +117,18 @@ fn very_complex_types() {
         ]))),
     );
 }
+
+#[test]
+fn future_types() {
+    assert_type_parses("Future<Int>", T::Future(Box::new(T::Int)));
+    assert_type_parses(
+        "Future<(String, [Int])>?",
+        T::Maybe(Box::new(T::Future(Box::new(T::Tuple(vec![
+            T::String,
+            T::List(Box::new(T::Int)),
+        ]))))),
+    );
+
+    assert_parsing_fails(|p| Parser::parse_type(p), "Future");
+    assert_parsing_fails(|p| Parser::parse_type(p), "Future<Int");
+}
@@ -0,0 +1,848 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use super::errors::read_source_line;
+use super::heap::{Heap, HeapObject};
+use super::metadata::Metadata;
+use super::utils::u64_to_f64;
+
+const PROMPT: &str = "(debug) ";
+
+// Nested values are shown up to this depth, and lists up to this amount of items
+const MAX_VALUE_DEPTH: usize = 4;
+const MAX_LIST_ITEMS: usize = 16;
+
+const HELP: &str = "Commands:
+    break <function | module:line | line>   stop when the function or the line is reached
+    delete <id>                             remove a breakpoint
+    breakpoints                             list breakpoints
+    step, next, finish                      step into calls, over them or out of the function
+    continue                                run until the next breakpoint
+    bt                                      show frames of the paused actor
+    frame <n>                               select frame to show locals of
+    locals, print <name>                    show values of locals
+    heap [address]                          list objects of the actor or show one of them
+    actors                                  list active objects
+    pause <id>, unpause <id>                stop the active object, when it runs next time
+    quit                                    stop the program
+";
+
+/// Type of a local, saved in the debug info to show its value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueType {
+    Int,
+    Float,
+    Bool,
+    String,
+    List(Box<ValueType>),
+    Tuple(Vec<ValueType>),
+    Maybe(Box<ValueType>),
+    Future(Box<ValueType>),
+    Object(usize), // index of the type, value is a heap pointer
+    Active(usize), // index of the type, value is an active object handle
+}
+
+fn read_u16(bytes: &mut impl Iterator<Item = u8>) -> Option<u16> {
+    Some(u16::from_be_bytes([bytes.next()?, bytes.next()?]))
+}
+
+impl ValueType {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.push_bytes(&mut bytes);
+        bytes
+    }
+
+    fn push_bytes(&self, bytes: &mut Vec<u8>) {
+        match self {
+            ValueType::Int => bytes.push(1),
+            ValueType::Float => bytes.push(2),
+            ValueType::Bool => bytes.push(3),
+            ValueType::String => bytes.push(4),
+            ValueType::List(item) => {
+                bytes.push(5);
+                item.push_bytes(bytes);
+            }
+            ValueType::Tuple(items) => {
+                bytes.push(6);
+                bytes.extend((items.len() as u16).to_be_bytes());
+                for item in items {
+                    item.push_bytes(bytes);
+                }
+            }
+            ValueType::Maybe(inner) => {
+                bytes.push(7);
+                inner.push_bytes(bytes);
+            }
+            ValueType::Future(inner) => {
+                bytes.push(8);
+                inner.push_bytes(bytes);
+            }
+            ValueType::Object(index) => {
+                bytes.push(9);
+                bytes.extend((*index as u16).to_be_bytes());
+            }
+            ValueType::Active(index) => {
+                bytes.push(10);
+                bytes.extend((*index as u16).to_be_bytes());
+            }
+        }
+    }
+
+    /// None if bytes are not a single encoded type
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut bytes = bytes.iter().copied();
+        let value_type = ValueType::read(&mut bytes)?;
+        bytes.next().is_none().then_some(value_type)
+    }
+
+    fn read(bytes: &mut impl Iterator<Item = u8>) -> Option<Self> {
+        Some(match bytes.next()? {
+            1 => ValueType::Int,
+            2 => ValueType::Float,
+            3 => ValueType::Bool,
+            4 => ValueType::String,
+            5 => ValueType::List(Box::new(ValueType::read(bytes)?)),
+            6 => {
+                let amount = read_u16(bytes)?;
+                let items: Option<Vec<ValueType>> =
+                    (0..amount).map(|_| ValueType::read(bytes)).collect();
+                ValueType::Tuple(items?)
+            }
+            7 => ValueType::Maybe(Box::new(ValueType::read(bytes)?)),
+            8 => ValueType::Future(Box::new(ValueType::read(bytes)?)),
+            9 => ValueType::Object(read_u16(bytes)? as usize),
+            10 => ValueType::Active(read_u16(bytes)? as usize),
+            _ => return None,
+        })
+    }
+
+    /// Amount of stack slots, same as the size of the type in codegen
+    pub fn size(&self) -> usize {
+        match self {
+            ValueType::Tuple(items) => items.iter().map(ValueType::size).sum(),
+            ValueType::Maybe(inner) => inner.size() + 1,
+            _ => 1,
+        }
+    }
+
+    pub fn name(&self, types_names: &[String]) -> String {
+        match self {
+            ValueType::Int => "Int".into(),
+            ValueType::Float => "Float".into(),
+            ValueType::Bool => "Bool".into(),
+            ValueType::String => "String".into(),
+            ValueType::List(item) => format!("[{}]", item.name(types_names)),
+            ValueType::Tuple(items) => {
+                let items: Vec<String> = items.iter().map(|i| i.name(types_names)).collect();
+                format!("({})", items.join(", "))
+            }
+            ValueType::Maybe(inner) => format!("{}?", inner.name(types_names)),
+            ValueType::Future(inner) => format!("Future<{}>", inner.name(types_names)),
+            ValueType::Object(index) | ValueType::Active(index) => types_names[*index].clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalInfo {
+    pub name: String,
+    pub offset: usize, // from the start of the frame
+    pub value_type: ValueType,
+}
+
+/// Frame of the paused actor
+pub struct FrameView {
+    pub position: usize, // of the instruction being executed, or of the call in outer frames
+    pub stack_start: usize,
+}
+
+/// State of the active object, that is stopped at the debugger prompt
+pub struct PausedActor<'a> {
+    pub actor: Option<u64>, // None for the entry
+    pub name: String,
+    pub frames: Vec<FrameView>, // innermost first
+    pub stack: &'a [u64],
+    pub heap: &'a Heap,
+    pub metadata: &'a Metadata,
+    pub actors: Vec<(u64, String)>, // all active objects of the VM, for `actors` command
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugAction {
+    Resume,
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Into,        // to the next line, in any function
+    Over(usize), // to the next line in a frame not deeper than that
+    Out(usize),  // to any instruction in a frame less deep than that
+}
+
+struct Breakpoint {
+    id: usize,
+    spec: String,
+    positions: Vec<usize>,
+}
+
+#[derive(Default)]
+struct DebuggerState {
+    breakpoints: Vec<Breakpoint>,
+    breakpoints_added: usize,
+    steps: HashMap<Option<u64>, Step>,
+    pause_requests: HashSet<u64>,
+    detached: bool, // input has ended, so the program runs to the end
+}
+
+struct Console {
+    input: Box<dyn BufRead + Send>,
+    output: Box<dyn Write + Send>,
+}
+
+/// Stops actors on breakpoints and steps, and runs commands for them one at a time.
+/// While one actor is at the prompt, others keep running until they stop too
+pub struct Debugger {
+    console: Mutex<Console>, // held by the actor at the prompt
+    state: Mutex<DebuggerState>,
+    source_root: Option<PathBuf>,
+}
+
+fn value_or_pointer(value: u64, is_pointer: bool) -> String {
+    match is_pointer {
+        true => format!("{:#x}", value),
+        false => (value as i64).to_string(),
+    }
+}
+
+/// Positions of breakpoint by function name or line, lines are numbered from 1
+fn resolve_breakpoint(spec: &str, metadata: &Metadata) -> Vec<usize> {
+    let line_spec = match spec.rsplit_once(':') {
+        Some((module, line)) => line.parse::<usize>().ok().map(|line| (Some(module), line)),
+        None => spec.parse::<usize>().ok().map(|line| (None, line)),
+    };
+
+    // Function stops at its first statement, after the space for locals is reserved,
+    // and the line stops at its first statement in each function
+    let mut positions = vec![];
+    let mut functions_seen = HashSet::new();
+    for (position, (function_start, source)) in metadata.line_table.iter() {
+        let matches = match line_spec {
+            Some((module, line)) => {
+                source.line + 1 == line && module.is_none_or(|module| module == source.module)
+            }
+            None => {
+                let index = metadata.function_positions[function_start];
+                let name = &metadata.function_names[index];
+                name == spec || name.ends_with(&format!("::{}", spec))
+            }
+        };
+        if matches && functions_seen.insert(*function_start) {
+            positions.push(*position);
+        }
+    }
+    positions
+}
+
+impl Debugger {
+    /// Entry stops at its first statement, so breakpoints could be set before running it
+    pub fn new(
+        input: Box<dyn BufRead + Send>,
+        output: Box<dyn Write + Send>,
+        source_root: Option<PathBuf>,
+    ) -> Self {
+        let mut state = DebuggerState::default();
+        state.steps.insert(None, Step::Into);
+        Debugger {
+            console: Mutex::new(Console { input, output }),
+            state: Mutex::new(state),
+            source_root,
+        }
+    }
+
+    /// Checked before each instruction, depth is the amount of frames of the actor
+    pub fn should_pause(
+        &self,
+        actor: Option<u64>,
+        position: usize,
+        depth: usize,
+        metadata: &Metadata,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.detached {
+            return false;
+        }
+        // Actor is paused by breakpoint or request, so it does not need its step anymore
+        let is_breakpoint = state.breakpoints.iter().any(|b| b.positions.contains(&position));
+        let is_requested = actor.is_some_and(|actor| state.pause_requests.remove(&actor));
+        if is_breakpoint || is_requested {
+            state.steps.remove(&actor);
+            return true;
+        }
+        let is_line_start = || metadata.is_line_start(position);
+        let step_done = match state.steps.get(&actor) {
+            Some(Step::Into) => is_line_start(),
+            Some(Step::Over(max_depth)) => depth <= *max_depth && is_line_start(),
+            Some(Step::Out(max_depth)) => depth < *max_depth,
+            None => false,
+        };
+        if step_done {
+            state.steps.remove(&actor);
+        }
+        step_done
+    }
+
+    /// Runs commands for the paused actor, until it is resumed
+    pub fn pause(&self, paused: &PausedActor) -> DebugAction {
+        // Other actors wait here, until the one at the prompt is resumed
+        let mut console = self.console.lock().unwrap();
+
+        let mut output = String::new();
+        {
+            let state = self.state.lock().unwrap();
+            if state.detached {
+                return DebugAction::Resume;
+            }
+            let position = paused.frames[0].position;
+            if let Some(breakpoint) =
+                state.breakpoints.iter().find(|b| b.positions.contains(&position))
+            {
+                write!(output, "Breakpoint {}, ", breakpoint.id).unwrap();
+            }
+        }
+        writeln!(
+            output,
+            "{} paused at {}",
+            paused.name,
+            self.render_frame(paused, 0)
+        )
+        .unwrap();
+
+        let mut selected_frame = 0;
+        loop {
+            output.push_str(PROMPT);
+            let _ = console.output.write_all(output.as_bytes());
+            let _ = console.output.flush();
+            output.clear();
+
+            let mut line = String::new();
+            if !matches!(console.input.read_line(&mut line), Ok(read) if read > 0) {
+                // Nobody is there to resume actors, so the program is left to run on its own
+                let mut state = self.state.lock().unwrap();
+                state.detached = true;
+                state.breakpoints.clear();
+                state.steps.clear();
+                let _ = console.output.write_all(b"\n");
+                return DebugAction::Resume;
+            }
+
+            let action = self.execute(line.trim(), paused, &mut selected_frame, &mut output);
+            if let Some(action) = action {
+                let _ = console.output.write_all(output.as_bytes());
+                let _ = console.output.flush();
+                return action;
+            }
+        }
+    }
+
+    /// Returns action, if the command resumes or stops the actor
+    fn execute(
+        &self,
+        command: &str,
+        paused: &PausedActor,
+        selected_frame: &mut usize,
+        output: &mut String,
+    ) -> Option<DebugAction> {
+        let (command, argument) = match command.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (command, ""),
+        };
+        let metadata = paused.metadata;
+        let depth = paused.frames.len();
+        let mut state = self.state.lock().unwrap();
+
+        match command {
+            "" => {}
+            "break" | "b" => {
+                let positions = resolve_breakpoint(argument, metadata);
+                if positions.is_empty() {
+                    writeln!(output, "No function or line matches `{}`", argument).unwrap();
+                    return None;
+                }
+                state.breakpoints_added += 1;
+                let id = state.breakpoints_added;
+                writeln!(
+                    output,
+                    "Breakpoint {} at {}",
+                    id,
+                    self.render_position(positions[0], metadata)
+                )
+                .unwrap();
+                state
+                    .breakpoints
+                    .push(Breakpoint { id, spec: argument.into(), positions });
+            }
+            "delete" | "d" => {
+                let before = state.breakpoints.len();
+                state.breakpoints.retain(|b| argument.parse() != Ok(b.id));
+                if state.breakpoints.len() == before {
+                    writeln!(output, "No breakpoint `{}`", argument).unwrap();
+                }
+            }
+            "breakpoints" => {
+                if state.breakpoints.is_empty() {
+                    writeln!(output, "No breakpoints").unwrap();
+                }
+                for breakpoint in state.breakpoints.iter() {
+                    writeln!(output, "{}: {}", breakpoint.id, breakpoint.spec).unwrap();
+                }
+            }
+            "step" | "s" => {
+                state.steps.insert(paused.actor, Step::Into);
+                return Some(DebugAction::Resume);
+            }
+            "next" | "n" => {
+                state.steps.insert(paused.actor, Step::Over(depth));
+                return Some(DebugAction::Resume);
+            }
+            "finish" => {
+                // Function of the message returns to nowhere, so the next message is waited for
+                let step = if depth > 1 {
+                    Step::Out(depth)
+                } else {
+                    Step::Into
+                };
+                state.steps.insert(paused.actor, step);
+                return Some(DebugAction::Resume);
+            }
+            "continue" | "c" => return Some(DebugAction::Resume),
+            "bt" | "backtrace" => {
+                for i in 0..depth {
+                    writeln!(output, "#{} {}", i, self.render_frame(paused, i)).unwrap();
+                }
+            }
+            "frame" | "f" => match argument.parse::<usize>() {
+                Ok(frame) if frame < depth => {
+                    *selected_frame = frame;
+                    writeln!(output, "#{} {}", frame, self.render_frame(paused, frame)).unwrap();
+                }
+                _ => writeln!(output, "No frame `{}`, there are {}", argument, depth).unwrap(),
+            },
+            "locals" => {
+                let locals = Debugger::frame_locals(paused, *selected_frame);
+                if locals.is_empty() {
+                    writeln!(output, "No locals").unwrap();
+                }
+                for local in locals {
+                    let value = Debugger::render_local(paused, *selected_frame, local);
+                    writeln!(output, "{}", value).unwrap();
+                }
+            }
+            "print" | "p" => {
+                let locals = Debugger::frame_locals(paused, *selected_frame);
+                // Shadowed locals have the same name, the one declared later is shown
+                match locals.iter().rev().find(|local| local.name == argument) {
+                    Some(local) => {
+                        let value = Debugger::render_local(paused, *selected_frame, local);
+                        writeln!(output, "{}", value).unwrap();
+                    }
+                    None => writeln!(output, "No local `{}`", argument).unwrap(),
+                }
+            }
+            "heap" => Debugger::render_heap(paused, argument, output),
+            "actors" => {
+                if paused.actors.is_empty() {
+                    writeln!(output, "No active objects").unwrap();
+                }
+                for (actor, name) in paused.actors.iter() {
+                    let mark = match state.pause_requests.contains(actor) {
+                        true => " (pause requested)",
+                        false => "",
+                    };
+                    writeln!(output, "{}{}", name, mark).unwrap();
+                }
+            }
+            "pause" | "unpause" => match argument.trim_start_matches('#').parse::<u64>() {
+                Ok(actor) if paused.actors.iter().any(|(id, _)| *id == actor) => {
+                    match command {
+                        "pause" => state.pause_requests.insert(actor),
+                        _ => state.pause_requests.remove(&actor),
+                    };
+                }
+                _ => writeln!(output, "No active object `{}`", argument).unwrap(),
+            },
+            "help" | "h" => output.push_str(HELP),
+            "quit" | "q" => return Some(DebugAction::Quit),
+            _ => writeln!(output, "Unknown command `{}`, see `help`", command).unwrap(),
+        }
+        None
+    }
+
+    fn render_position(&self, position: usize, metadata: &Metadata) -> String {
+        let function = metadata.get_function_name(position).unwrap_or("<unknown>");
+        match metadata.get_source_position(position) {
+            Some(source) => {
+                let mut result = format!(
+                    "{} ({}:{}:{})",
+                    function,
+                    source.module,
+                    source.line + 1,
+                    source.column + 1
+                );
+                if let Some(line) = read_source_line(source, self.source_root.as_deref()) {
+                    write!(result, "\n    {} | {}", source.line + 1, line.trim()).unwrap();
+                }
+                result
+            }
+            None => format!("{} (ip {:#x})", function, position),
+        }
+    }
+
+    fn render_frame(&self, paused: &PausedActor, frame: usize) -> String {
+        self.render_position(paused.frames[frame].position, paused.metadata)
+    }
+
+    fn frame_locals<'a>(paused: &PausedActor<'a>, frame: usize) -> &'a [LocalInfo] {
+        let metadata = paused.metadata;
+        metadata
+            .get_function_start(paused.frames[frame].position)
+            .and_then(|(start, _)| metadata.function_locals.get(&start))
+            .map_or(&[], |locals| locals.as_slice())
+    }
+
+    fn render_local(paused: &PausedActor, frame: usize, local: &LocalInfo) -> String {
+        let types_names = &paused.metadata.types_names;
+        let start = paused.frames[frame].stack_start + local.offset;
+        let value = match paused.stack.get(start..start + local.value_type.size()) {
+            Some(slots) => Debugger::render_value(&local.value_type, slots, paused, 0),
+            // Space for locals is reserved by the first instruction of the function
+            None => "<not reserved yet>".into(),
+        };
+        format!(
+            "{}: {} = {}",
+            local.name,
+            local.value_type.name(types_names),
+            value
+        )
+    }
+
+    fn render_value(
+        value_type: &ValueType,
+        slots: &[u64],
+        paused: &PausedActor,
+        depth: usize,
+    ) -> String {
+        let types_names = &paused.metadata.types_names;
+        let value = slots[0];
+        let is_pointer = matches!(
+            value_type,
+            ValueType::String | ValueType::List(_) | ValueType::Object(_)
+        );
+        // Locals are zeroed before they are assigned, so pointer might be not set yet
+        if is_pointer && value == 0 {
+            return "<not set>".into();
+        }
+
+        match value_type {
+            ValueType::Int => (value as i64).to_string(),
+            ValueType::Float => u64_to_f64(value).to_string(),
+            ValueType::Bool => (value != 0).to_string(),
+            ValueType::String => format!("{:?}", paused.heap.get(value).extract_string()),
+            ValueType::List(_) if depth >= MAX_VALUE_DEPTH => "[...]".into(),
+            ValueType::List(item_type) => {
+                let list = paused.heap.get(value).extract_list();
+                let mut items: Vec<String> = list
+                    .data
+                    .chunks(item_type.size())
+                    .take(MAX_LIST_ITEMS)
+                    .map(|item| Debugger::render_value(item_type, item, paused, depth + 1))
+                    .collect();
+                if list.items_amount > MAX_LIST_ITEMS {
+                    items.push(format!("... {} more", list.items_amount - MAX_LIST_ITEMS));
+                }
+                format!("[{}]", items.join(", "))
+            }
+            ValueType::Tuple(_) if depth >= MAX_VALUE_DEPTH => "(...)".into(),
+            ValueType::Tuple(items) => {
+                let mut offset = 0;
+                let mut rendered = vec![];
+                for item in items {
+                    let item_slots = &slots[offset..offset + item.size()];
+                    rendered.push(Debugger::render_value(item, item_slots, paused, depth + 1));
+                    offset += item.size();
+                }
+                format!("({})", rendered.join(", "))
+            }
+            ValueType::Maybe(_) if value == 0 => "nil".into(),
+            ValueType::Maybe(inner) => Debugger::render_value(inner, &slots[1..], paused, depth),
+            ValueType::Future(_) => format!("future #{}", value),
+            ValueType::Object(index) => format!("{} at {:#x}", types_names[*index], value),
+            ValueType::Active(index) => format!("{} #{}", types_names[*index], value),
+        }
+    }
+
+    fn render_heap(paused: &PausedActor, argument: &str, output: &mut String) {
+        let metadata = paused.metadata;
+        let heap = paused.heap;
+        if argument.is_empty() {
+            writeln!(output, "{} objects in heap of {}", heap.len(), paused.name).unwrap();
+            for pointer in heap.pointers() {
+                let summary = match heap.get(pointer) {
+                    HeapObject::String(s) => format!("String {:?}", s),
+                    HeapObject::List(list) => format!("list of {} items", list.items_amount),
+                    HeapObject::CustomObject(object) => {
+                        metadata.types_names[object.type_index as usize].clone()
+                    }
+                };
+                writeln!(output, "{:#x} {}", pointer, summary).unwrap();
+            }
+            return;
+        }
+
+        let pointer = match u64::from_str_radix(argument.trim_start_matches("0x"), 16) {
+            Ok(pointer) if heap.contains(pointer) => pointer,
+            _ => {
+                writeln!(
+                    output,
+                    "No object at `{}` in heap of {}",
+                    argument, paused.name
+                )
+                .unwrap();
+                return;
+            }
+        };
+        // Only pointers are known for values of objects and lists, so the rest is shown as Int
+        let render_slots = |data: &[u64], pointers: &[usize]| -> Vec<String> {
+            (data.iter().enumerate())
+                .map(|(i, value)| value_or_pointer(*value, pointers.contains(&i)))
+                .collect()
+        };
+        let rendered = match heap.get(pointer) {
+            HeapObject::String(s) => format!("String {:?}", s),
+            HeapObject::List(list) => {
+                let pointers = &metadata.lists_pointer_mapping[list.list_item_type];
+                let items: Vec<String> = list
+                    .data
+                    .chunks(list.item_size.max(1))
+                    .map(|item| render_slots(item, pointers).join(", "))
+                    .map(|item| match list.item_size {
+                        1 => item,
+                        _ => format!("({})", item),
+                    })
+                    .collect();
+                format!("[{}]", items.join(", "))
+            }
+            HeapObject::CustomObject(object) => {
+                let type_index = object.type_index as usize;
+                let pointers = &metadata.types_pointer_mapping[type_index];
+                let fields = render_slots(&object.data, pointers);
+                format!(
+                    "{}({})",
+                    metadata.types_names[type_index],
+                    fields.join(", ")
+                )
+            }
+        };
+        writeln!(output, "{:#x} {}", pointer, rendered).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::metadata::SourcePosition;
+
+    fn test_metadata() -> Metadata {
+        let mut metadata = Metadata {
+            types_names: vec!["main::Point".into(), "main::Worker".into()],
+            types_sizes: vec![2, 1],
+            types_pointer_mapping: vec![vec![], vec![]],
+            list_types_sizes: vec![1],
+            lists_pointer_mapping: vec![vec![]],
+            ..Default::default()
+        };
+        metadata.fill_function_metadata(vec![
+            ("main::main".into(), 0, vec![]),
+            ("main::Worker::fib".into(), 1, vec![]),
+        ]);
+        metadata.function_positions.insert(10, 0);
+        metadata.function_positions.insert(50, 1);
+        let position = |line| SourcePosition { module: "main".into(), line, column: 4 };
+        metadata.line_table.insert(12, (10, position(3)));
+        metadata.line_table.insert(20, (10, position(4)));
+        metadata.line_table.insert(52, (50, position(9)));
+        metadata.line_table.insert(60, (50, position(10)));
+        metadata.function_locals.insert(
+            10,
+            vec![
+                LocalInfo { name: "name".into(), offset: 0, value_type: ValueType::String },
+                LocalInfo {
+                    name: "pair".into(),
+                    offset: 1,
+                    value_type: ValueType::Tuple(vec![
+                        ValueType::Maybe(Box::new(ValueType::Int)),
+                        ValueType::Bool,
+                    ]),
+                },
+                LocalInfo {
+                    name: "items".into(),
+                    offset: 4,
+                    value_type: ValueType::List(Box::new(ValueType::Int)),
+                },
+                LocalInfo { name: "worker".into(), offset: 5, value_type: ValueType::Active(1) },
+            ],
+        );
+        metadata
+    }
+
+    fn debugger() -> Debugger {
+        Debugger::new(Box::new(std::io::empty()), Box::new(std::io::sink()), None)
+    }
+
+    fn run(debugger: &Debugger, paused: &PausedActor, commands: &[&str]) -> String {
+        let mut output = String::new();
+        let mut frame = 0;
+        for command in commands {
+            debugger.execute(command, paused, &mut frame, &mut output);
+        }
+        output
+    }
+
+    #[test]
+    fn value_types_encoded() {
+        let value_type = ValueType::Tuple(vec![
+            ValueType::List(Box::new(ValueType::Maybe(Box::new(ValueType::Object(2))))),
+            ValueType::Future(Box::new(ValueType::Float)),
+            ValueType::Active(300),
+            ValueType::String,
+        ]);
+        let bytes = value_type.to_bytes();
+        assert_eq!(ValueType::from_bytes(&bytes), Some(value_type.clone()));
+        assert_eq!(ValueType::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(ValueType::from_bytes(&[1, 1]), None);
+        assert_eq!(value_type.size(), 4);
+
+        let mut names: Vec<String> = (0..400).map(|i| format!("Type{}", i)).collect();
+        names[2] = "Item".into();
+        names[300] = "Worker".into();
+        assert_eq!(
+            value_type.name(&names),
+            "([Item?], Future<Float>, Worker, String)"
+        );
+    }
+
+    #[test]
+    fn breakpoints_resolved_to_first_statements() {
+        let metadata = test_metadata();
+        assert_eq!(resolve_breakpoint("fib", &metadata), vec![52]);
+        assert_eq!(resolve_breakpoint("Worker::fib", &metadata), vec![52]);
+        assert_eq!(resolve_breakpoint("main::main", &metadata), vec![12]);
+        assert_eq!(resolve_breakpoint("5", &metadata), vec![20]);
+        assert_eq!(resolve_breakpoint("main:11", &metadata), vec![60]);
+        assert!(resolve_breakpoint("other:11", &metadata).is_empty());
+        assert!(resolve_breakpoint("ib", &metadata).is_empty());
+    }
+
+    #[test]
+    fn stops_on_breakpoints_and_steps() {
+        let metadata = test_metadata();
+        let debugger = debugger();
+        // Entry stops on its first statement
+        assert!(!debugger.should_pause(None, 10, 1, &metadata));
+        assert!(debugger.should_pause(None, 12, 1, &metadata));
+
+        let heap = Heap::default();
+        let paused = PausedActor {
+            actor: None,
+            name: "entry".into(),
+            frames: vec![FrameView { position: 12, stack_start: 0 }],
+            stack: &[],
+            heap: &heap,
+            metadata: &metadata,
+            actors: vec![(3, "main::Worker #3".into())],
+        };
+        let output = run(
+            &debugger,
+            &paused,
+            &["break fib", "break nothing", "pause 3"],
+        );
+        assert_eq!(output, "Breakpoint 1 at main::Worker::fib (main:10:5)\nNo function or line matches `nothing`\n");
+
+        assert!(!debugger.should_pause(None, 20, 1, &metadata));
+        assert!(debugger.should_pause(Some(2), 52, 1, &metadata));
+        // Pause request is used up by the first instruction of the actor
+        assert!(debugger.should_pause(Some(3), 70, 1, &metadata));
+        assert!(!debugger.should_pause(Some(3), 71, 1, &metadata));
+
+        let frames = vec![
+            FrameView { position: 52, stack_start: 0 },
+            FrameView { position: 22, stack_start: 0 },
+        ];
+        let paused = PausedActor { frames, ..paused };
+        run(&debugger, &paused, &["delete 1", "next"]);
+        assert!(!debugger.should_pause(None, 60, 3, &metadata));
+        assert!(debugger.should_pause(None, 60, 2, &metadata));
+
+        run(&debugger, &paused, &["finish"]);
+        assert!(!debugger.should_pause(None, 61, 2, &metadata));
+        assert!(debugger.should_pause(None, 61, 1, &metadata));
+    }
+
+    #[test]
+    fn locals_and_heap_shown() {
+        let metadata = test_metadata();
+        let mut heap = Heap::default();
+        let (name, _) = heap.move_string("Anton".into());
+        let (items, _) = heap.allocate_list(0, 3, &[1, 2, 3], &metadata);
+
+        // Outer frame is the first on the stack
+        let stack = [name, 0, 0, 1, 0, 0, 0, 1, 42, 0, items, 3];
+        let paused = PausedActor {
+            actor: None,
+            name: "entry".into(),
+            frames: vec![
+                FrameView { position: 14, stack_start: 6 },
+                FrameView { position: 22, stack_start: 0 },
+            ],
+            stack: &stack,
+            heap: &heap,
+            metadata: &metadata,
+            actors: vec![],
+        };
+
+        let output = run(
+            &debugger(),
+            &paused,
+            &["p pair", "p other", "frame 1", "locals"],
+        );
+        assert_eq!(
+            output,
+            "pair: (Int?, Bool) = (42, false)\n\
+             No local `other`\n\
+             #1 main::main (main:5:5)\n\
+             name: String = \"Anton\"\n\
+             pair: (Int?, Bool) = (nil, true)\n\
+             items: [Int] = <not set>\n\
+             worker: main::Worker = main::Worker #0\n"
+        );
+
+        let output = run(
+            &debugger(),
+            &paused,
+            &["print items", "heap", &format!("heap {:#x}", items)],
+        );
+        let mut pointers = [(name, "String \"Anton\""), (items, "list of 3 items")];
+        pointers.sort();
+        assert_eq!(
+            output,
+            format!(
+                "items: [Int] = [1, 2, 3]\n2 objects in heap of entry\n{:#x} {}\n{:#x} {}\n{:#x} [1, 2, 3]\n",
+                pointers[0].0, pointers[0].1, pointers[1].0, pointers[1].1, items
+            )
+        );
+    }
+}
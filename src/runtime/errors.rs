@@ -0,0 +1,233 @@
+use std::fmt;
+use std::path::Path;
+
+use super::metadata::{Metadata, SourcePosition};
+use super::serialization::WireFormatError;
+use crate::errors::{render_error_window, ErrorCoordinates};
+
+// Deep recursion produces huge traces, only the innermost frames are worth showing
+const MAX_TRACE_FRAMES: usize = 16;
+
+/// Source of the module, if it is found in `source_root`
+fn read_source(module: &str, source_root: Option<&Path>) -> Option<String> {
+    source_root
+        .map(|root| root.join(module.replace('.', "/")).with_extension("frisbee"))
+        .and_then(|path| std::fs::read_to_string(path).ok())
+}
+
+/// Line of the module source, if it is found in `source_root`
+pub fn read_source_line(source: &SourcePosition, source_root: Option<&Path>) -> Option<String> {
+    read_source(&source.module, source_root)
+        .and_then(|contents| contents.lines().nth(source.line).map(String::from))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RuntimeErrorKind {
+    StackOverflow,
+    IndexOutOfBounds { index: i64, size: usize },
+    PopFromEmptyList,
+    DivisionByZero,
+    UnknownOpcode(u8),
+    NotImplemented(&'static str),
+    InvalidBytecode(String),
+    IncompatibleBytecode(String),
+    FailedFuture,
+    FutureAlreadyWaited,
+    BlockingInActiveObject,
+    MalformedMessage(WireFormatError),
+    UnknownNode(String),
+    ActiveNeverStops(u64),
+    OutputFailed(String),
+
+    // Not an error, but stops the message the same way, before the whole program is stopped
+    Exit(i64),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub ip: usize,
+    pub function: Option<String>, // None if error happened outside of any function
+    pub trace: Vec<usize>,        // positions of calls in outer frames, innermost first
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, ip: usize, metadata: &Metadata) -> Self {
+        let function = metadata.get_function_name(ip).map(String::from);
+        Self { kind, ip, function, trace: vec![] }
+    }
+
+    pub fn invalid_bytecode(ip: usize, message: String) -> Self {
+        Self {
+            kind: RuntimeErrorKind::InvalidBytecode(message),
+            ip,
+            function: None,
+            trace: vec![],
+        }
+    }
+
+    /// Renders a frame per line, with a line of source code under it, where the column
+    /// is marked the same way as in compile errors, if the source is found in `source_root`
+    pub fn render_stack_trace(&self, metadata: &Metadata, source_root: Option<&Path>) -> String {
+        let positions: Vec<usize> =
+            std::iter::once(self.ip).chain(self.trace.iter().copied()).collect();
+
+        let mut result = String::from("Stack trace (most recent call first):");
+        for position in positions.iter().take(MAX_TRACE_FRAMES) {
+            let function = metadata.get_function_name(*position).unwrap_or("<unknown>");
+            let source = match metadata.get_source_position(*position) {
+                Some(source) => source,
+                None => {
+                    result.push_str(&format!("\n  at {} (ip {:#x})", function, position));
+                    continue;
+                }
+            };
+            result.push_str(&format!(
+                "\n  at {} ({}:{}:{})",
+                function,
+                source.module,
+                source.line + 1,
+                source.column + 1
+            ));
+
+            if let Some(contents) = read_source(&source.module, source_root) {
+                // Only a column is known, so the window is a single symbol
+                let window = ErrorCoordinates {
+                    line: source.line,
+                    start: source.column,
+                    end: source.column + 1,
+                };
+                for line in render_error_window(&contents, &window, 0, "").lines() {
+                    result.push_str(&format!("\n      {}", line));
+                }
+            }
+        }
+        if positions.len() > MAX_TRACE_FRAMES {
+            result.push_str(&format!(
+                "\n  ... {} more frames",
+                positions.len() - MAX_TRACE_FRAMES
+            ));
+        }
+        result
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::StackOverflow => write!(f, "stack overflow"),
+            RuntimeErrorKind::IndexOutOfBounds { index, size } => {
+                write!(
+                    f,
+                    "index {} is out of bounds for list of size {}",
+                    index, size
+                )
+            }
+            RuntimeErrorKind::PopFromEmptyList => write!(f, "pop from empty list"),
+            RuntimeErrorKind::DivisionByZero => write!(f, "division by zero"),
+            RuntimeErrorKind::UnknownOpcode(opcode) => write!(f, "unknown opcode {:02x}", opcode),
+            RuntimeErrorKind::NotImplemented(name) => write!(f, "{} is not implemented yet", name),
+            RuntimeErrorKind::InvalidBytecode(message) => {
+                write!(f, "invalid bytecode: {}", message)
+            }
+            RuntimeErrorKind::IncompatibleBytecode(message) => {
+                write!(f, "compiled with incompatible version: {}", message)
+            }
+            RuntimeErrorKind::FailedFuture => {
+                write!(f, "asked message failed, so its future has no value")
+            }
+            RuntimeErrorKind::FutureAlreadyWaited => {
+                write!(f, "future is already waited, so its value is taken")
+            }
+            RuntimeErrorKind::BlockingInActiveObject => {
+                write!(
+                    f,
+                    "waiting for a future is not allowed inside of active objects"
+                )
+            }
+            RuntimeErrorKind::MalformedMessage(error) => write!(f, "malformed message: {}", error),
+            RuntimeErrorKind::UnknownNode(name) => write!(f, "unknown node `{}`", name),
+            RuntimeErrorKind::ActiveNeverStops(handle) => {
+                write!(
+                    f,
+                    "active object #{} is waited for, but it is never stopped",
+                    handle
+                )
+            }
+            RuntimeErrorKind::OutputFailed(error) => write!(f, "cannot write output: {}", error),
+            RuntimeErrorKind::Exit(code) => write!(f, "exit with code {}", code),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "{} in {} (ip {:#x})", self.kind, function, self.ip),
+            None => write!(f, "{} (ip {:#x})", self.kind, self.ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::helpers::TestFilesCreator;
+
+    #[test]
+    fn runtime_error_display() {
+        let mut metadata = Metadata::default();
+        metadata.fill_function_metadata(vec![("main".into(), 0, vec![])]);
+        metadata.function_positions.insert(16, 0);
+
+        let error = RuntimeError::new(RuntimeErrorKind::StackOverflow, 40, &metadata);
+        assert_eq!(error.to_string(), "stack overflow in main (ip 0x28)");
+
+        let error = RuntimeError::new(RuntimeErrorKind::DivisionByZero, 3, &metadata);
+        assert_eq!(error.function, None);
+        assert_eq!(error.to_string(), "division by zero (ip 0x3)");
+    }
+
+    #[test]
+    fn stack_trace_without_sources() {
+        let mut metadata = Metadata::default();
+        metadata.fill_function_metadata(vec![
+            ("main".into(), 0, vec![]),
+            ("divide".into(), 2, vec![]),
+        ]);
+        metadata.function_positions.insert(10, 0);
+        metadata.function_positions.insert(30, 1);
+        let position = |line, column| SourcePosition { module: "main".into(), line, column };
+        metadata.line_table.insert(12, (10, position(4, 4)));
+        metadata.line_table.insert(32, (30, position(0, 4)));
+
+        let mut error = RuntimeError::new(RuntimeErrorKind::DivisionByZero, 35, &metadata);
+        error.trace = vec![20, 5];
+        assert_eq!(
+            error.render_stack_trace(&metadata, None),
+            "Stack trace (most recent call first):\n  at divide (main:1:5)\n  at main (main:5:5)\n  at <unknown> (ip 0x5)"
+        );
+    }
+
+    #[test]
+    fn stack_trace_marks_columns_in_sources() {
+        let mut files = TestFilesCreator::new();
+        files.set_mainfile("fun void main() {\n    Int x = 1 / 0;\n}");
+        let source_root = files.get_main_path().parent().unwrap();
+
+        let mut metadata = Metadata::default();
+        metadata.fill_function_metadata(vec![("main".into(), 0, vec![])]);
+        metadata.function_positions.insert(10, 0);
+        let position = SourcePosition { module: "main".into(), line: 1, column: 12 };
+        metadata.line_table.insert(12, (10, position));
+
+        let error = RuntimeError::new(RuntimeErrorKind::DivisionByZero, 14, &metadata);
+        let trace = error.render_stack_trace(&metadata, Some(source_root));
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines[1], "  at main (main:2:13)");
+        assert!(lines[2].ends_with("    Int x = 1 / 0;"));
+        // Caret is under `1`, after the sidebar of the same width as "2 | "
+        assert!(lines[3].contains(&format!("{}^", " ".repeat(12 + 4))));
+        assert_eq!(lines.len(), 4);
+    }
+}
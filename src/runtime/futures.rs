@@ -0,0 +1,185 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use super::errors::RuntimeErrorKind;
+
+// Future is the index of its slot in the lower bits, and the generation of the slot in the
+// upper ones, so a future that is already waited is not mistaken for the next one in its slot.
+// Futures are local parts of global ids, so both take 48 bits
+const SLOT_BITS: u32 = 32;
+const SLOT_MASK: u64 = (1 << SLOT_BITS) - 1;
+const GENERATION_MASK: u64 = (1 << 16) - 1;
+
+enum FutureState {
+    Pending,
+    Resolved(Vec<u8>), // serialized return value
+    Failed,
+}
+
+struct Slot {
+    generation: u64,
+    state: Option<FutureState>, // None if the slot is free
+}
+
+#[derive(Default)]
+struct FuturesTable {
+    slots: Vec<Slot>,
+    free_slots: Vec<usize>, // slots of waited futures, that are reused by new ones
+    exit_code: Option<i64>, // program is stopped, so pending futures will never be resolved
+}
+
+impl FuturesTable {
+    // Futures of other nodes come from the network, so they might be unknown or stale
+    fn get(&self, future: u64) -> Option<&FutureState> {
+        let slot = self.slots.get((future & SLOT_MASK) as usize)?;
+        let is_current = slot.generation == future >> SLOT_BITS;
+        slot.state.as_ref().filter(|_| is_current)
+    }
+
+    fn is_ready(&self, future: u64) -> bool {
+        self.exit_code.is_some() || !matches!(self.get(future), Some(FutureState::Pending))
+    }
+
+    /// Frees the slot of the resolved future and moves its value out
+    fn take(&mut self, future: u64) -> Result<Vec<u8>, RuntimeErrorKind> {
+        let index = (future & SLOT_MASK) as usize;
+        let slot = &mut self.slots[index];
+        slot.generation = (slot.generation + 1) & GENERATION_MASK;
+        let state = slot.state.take();
+        self.free_slots.push(index);
+        match state {
+            Some(FutureState::Resolved(value)) => Ok(value),
+            _ => Err(RuntimeErrorKind::FailedFuture),
+        }
+    }
+}
+
+/// Return values of asked messages, that are waited by the entry
+#[derive(Default)]
+pub struct Futures {
+    table: Mutex<FuturesTable>,
+    resolved: Condvar,
+}
+
+impl Futures {
+    pub fn create(&self) -> u64 {
+        let mut table = self.table.lock().unwrap();
+        let index = match table.free_slots.pop() {
+            Some(index) => index,
+            None => {
+                table.slots.push(Slot { generation: 0, state: None });
+                table.slots.len() - 1
+            }
+        };
+        let slot = &mut table.slots[index];
+        slot.state = Some(FutureState::Pending);
+        (slot.generation << SLOT_BITS) | index as u64
+    }
+
+    /// Value is None if asked message has failed. Futures, that are not pending, are left as is
+    pub fn resolve(&self, future: u64, value: Option<Vec<u8>>) {
+        let mut table = self.table.lock().unwrap();
+        if !matches!(table.get(future), Some(FutureState::Pending)) {
+            return;
+        }
+        table.slots[(future & SLOT_MASK) as usize].state = Some(match value {
+            Some(value) => FutureState::Resolved(value),
+            None => FutureState::Failed,
+        });
+        self.resolved.notify_all();
+    }
+
+    pub fn cancel_all(&self, exit_code: i64) {
+        self.table.lock().unwrap().exit_code = Some(exit_code);
+        self.resolved.notify_all();
+    }
+
+    /// True if waiting for the future would not block
+    pub fn is_ready(&self, future: u64) -> bool {
+        self.table.lock().unwrap().is_ready(future)
+    }
+
+    /// Blocks until the future is ready or the timeout passes, returns true if it is ready
+    pub fn wait_ready(&self, future: u64, timeout: Duration) -> bool {
+        let table = self.table.lock().unwrap();
+        let (table, _) = (self.resolved)
+            .wait_timeout_while(table, timeout, |table| !table.is_ready(future))
+            .unwrap();
+        table.is_ready(future)
+    }
+
+    /// Blocks until future is resolved, then takes its value. The future can not be waited
+    /// again, as its slot is given to a new one
+    pub fn wait(&self, future: u64) -> Result<Vec<u8>, RuntimeErrorKind> {
+        let mut table = self.table.lock().unwrap();
+        loop {
+            match table.get(future) {
+                Some(FutureState::Pending) => {
+                    if let Some(code) = table.exit_code {
+                        return Err(RuntimeErrorKind::Exit(code));
+                    }
+                }
+                Some(_) => return table.take(future),
+                None => return Err(RuntimeErrorKind::FutureAlreadyWaited),
+            }
+            table = self.resolved.wait(table).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn future_resolved_from_other_thread() {
+        let futures = Arc::new(Futures::default());
+        let first = futures.create();
+        let second = futures.create();
+
+        let resolver = {
+            let futures = futures.clone();
+            thread::spawn(move || {
+                futures.resolve(second, None);
+                futures.resolve(first, Some(vec![16, 42]));
+            })
+        };
+
+        assert_eq!(futures.wait(first), Ok(vec![16, 42]));
+        assert_eq!(futures.wait(second), Err(RuntimeErrorKind::FailedFuture));
+        resolver.join().unwrap();
+    }
+
+    #[test]
+    fn waited_slots_are_reused() {
+        let futures = Futures::default();
+        let first = futures.create();
+        futures.resolve(first, Some(vec![1]));
+        assert_eq!(futures.wait(first), Ok(vec![1]));
+
+        // New future takes the same slot, but the waited one does not refer to it anymore
+        let second = futures.create();
+        assert_eq!(second & SLOT_MASK, first & SLOT_MASK);
+        assert_ne!(second, first);
+        assert_eq!(
+            futures.wait(first),
+            Err(RuntimeErrorKind::FutureAlreadyWaited)
+        );
+        futures.resolve(first, Some(vec![2]));
+        assert!(!futures.is_ready(second));
+
+        futures.resolve(second, Some(vec![3]));
+        assert_eq!(futures.wait(second), Ok(vec![3]));
+        assert_eq!(futures.table.lock().unwrap().slots.len(), 1);
+    }
+
+    #[test]
+    fn pending_future_is_cancelled_on_exit() {
+        let futures = Futures::default();
+        let future = futures.create();
+        futures.cancel_all(3);
+        assert_eq!(futures.wait(future), Err(RuntimeErrorKind::Exit(3)));
+    }
+}
@@ -0,0 +1,215 @@
+use std::convert::TryInto;
+
+use super::errors::RuntimeErrorKind;
+use super::network::bytecode_hash;
+
+pub const MAGIC: [u8; 4] = *b"FRSB";
+
+// Changed with every change of the bytecode layout, VM runs only programs of its own format
+pub const FORMAT_VERSION: u16 = 4;
+pub const COMPILER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Programs before the versioned header started with the first block separator
+const LEGACY_START: [u8; 2] = [0xff, 0xff];
+
+/// Features of the VM that the program relies on, programs with unknown ones are not run
+pub mod features {
+    pub const REMOTE_SPAWN: u32 = 1 << 0;
+    pub const DELAYED_MESSAGES: u32 = 1 << 1;
+    pub const SUPERVISION: u32 = 1 << 2;
+
+    pub const SUPPORTED: u32 = REMOTE_SPAWN | DELAYED_MESSAGES | SUPERVISION;
+
+    const NAMES: [(u32, &str); 3] = [
+        (REMOTE_SPAWN, "remote spawn"),
+        (DELAYED_MESSAGES, "delayed messages"),
+        (SUPERVISION, "supervision"),
+    ];
+
+    pub fn names(flags: u32) -> Vec<&'static str> {
+        NAMES
+            .iter()
+            .filter(|(flag, _)| flags & flag != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/*
+Header structure:
+ - magic number, 4 bytes
+ - format version, 2 bytes
+ - version of the compiler, 1 byte for length + string
+ - feature flags, 4 bytes
+ - checksum of the rest of the program (FNV-1a), 8 bytes
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub format_version: u16,
+    pub compiler_version: String,
+    pub features: u32,
+    pub checksum: u64,
+}
+
+impl Header {
+    /// Header of the program made by this compiler, checksum is set by `seal`
+    pub fn new(features: u32) -> Self {
+        Header {
+            format_version: FORMAT_VERSION,
+            compiler_version: COMPILER_VERSION.to_string(),
+            features,
+            checksum: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(self.format_version.to_be_bytes());
+        bytes.push(self.compiler_version.len() as u8);
+        bytes.extend(self.compiler_version.as_bytes());
+        bytes.extend(self.features.to_be_bytes());
+        bytes.extend(self.checksum.to_be_bytes());
+        bytes
+    }
+
+    /// Header from the start of the program and its length in bytes
+    pub fn read(program: &[u8]) -> Result<(Header, usize), RuntimeErrorKind> {
+        if program.starts_with(&LEGACY_START) {
+            return Err(RuntimeErrorKind::IncompatibleBytecode(
+                "program has no version header, so it is made by an older compiler".into(),
+            ));
+        }
+        if !program.starts_with(&MAGIC) {
+            return Err(RuntimeErrorKind::InvalidBytecode(
+                "file is not a frisbee program".into(),
+            ));
+        }
+
+        let cut_off = || RuntimeErrorKind::InvalidBytecode("header is cut off".into());
+        let mut pos = MAGIC.len();
+        let mut take = |n: usize| {
+            let bytes = program.get(pos..pos + n).ok_or_else(cut_off)?;
+            pos += n;
+            Ok(bytes)
+        };
+
+        let format_version = u16::from_be_bytes(take(2)?.try_into().unwrap());
+        let version_len = take(1)?[0] as usize;
+        let compiler_version = String::from_utf8_lossy(take(version_len)?).into_owned();
+        let features = u32::from_be_bytes(take(4)?.try_into().unwrap());
+        let checksum = u64::from_be_bytes(take(8)?.try_into().unwrap());
+
+        let header = Header { format_version, compiler_version, features, checksum };
+        Ok((header, pos))
+    }
+
+    /// Checks that this VM can run the program, `body` is everything after the header
+    pub fn check(&self, body: &[u8]) -> Result<(), RuntimeErrorKind> {
+        if self.format_version != FORMAT_VERSION {
+            return Err(RuntimeErrorKind::IncompatibleBytecode(format!(
+                "frisbee {} made bytecode of format {}, but this VM (frisbee {}) runs format {}",
+                self.compiler_version, self.format_version, COMPILER_VERSION, FORMAT_VERSION
+            )));
+        }
+        let unknown = self.features & !features::SUPPORTED;
+        if unknown != 0 {
+            return Err(RuntimeErrorKind::IncompatibleBytecode(format!(
+                "frisbee {} made program with features {:#x}, \
+                 that this VM (frisbee {}) does not support",
+                self.compiler_version, unknown, COMPILER_VERSION
+            )));
+        }
+        if bytecode_hash(body) != self.checksum {
+            return Err(RuntimeErrorKind::InvalidBytecode(
+                "checksum does not match, program is corrupted".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Sets checksum of the program, that starts with the header
+pub fn seal(program: &mut [u8]) {
+    let (_, header_len) = Header::read(program).expect("Program must start with the header");
+    let checksum = bytecode_hash(&program[header_len..]);
+    program[header_len - 8..header_len].copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sealed_program(header: Header, body: &[u8]) -> Vec<u8> {
+        let mut program = header.to_bytes();
+        program.extend(body);
+        seal(&mut program);
+        program
+    }
+
+    fn check_program(program: &[u8]) -> Result<Header, String> {
+        let (header, len) = Header::read(program).map_err(|kind| kind.to_string())?;
+        header.check(&program[len..]).map_err(|kind| kind.to_string())?;
+        Ok(header)
+    }
+
+    #[test]
+    fn sealed_program_is_accepted() {
+        let program = sealed_program(Header::new(features::SUPERVISION), &[1, 2, 3]);
+        let header = check_program(&program).unwrap();
+        assert_eq!(header.compiler_version, COMPILER_VERSION);
+        assert_eq!(header.features, features::SUPERVISION);
+        assert_eq!(header.checksum, bytecode_hash(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn incompatible_programs_found() {
+        let old_format = Header { format_version: 1, ..Header::new(0) };
+        assert_eq!(
+            check_program(&sealed_program(old_format, &[])),
+            Err(format!(
+                "compiled with incompatible version: frisbee {0} made bytecode of format 1, \
+                 but this VM (frisbee {0}) runs format {1}",
+                COMPILER_VERSION, FORMAT_VERSION
+            ))
+        );
+
+        let from_future = Header::new(features::REMOTE_SPAWN | 1 << 10);
+        assert_eq!(
+            check_program(&sealed_program(from_future, &[])),
+            Err(format!(
+                "compiled with incompatible version: frisbee {0} made program with features \
+                 0x400, that this VM (frisbee {0}) does not support",
+                COMPILER_VERSION
+            ))
+        );
+
+        assert_eq!(
+            check_program(&[0xff, 0xff, 0xff]),
+            Err(
+                "compiled with incompatible version: program has no version header, \
+                 so it is made by an older compiler"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn damaged_programs_found() {
+        let mut program = sealed_program(Header::new(0), &[1, 2, 3]);
+        *program.last_mut().unwrap() = 4;
+        assert_eq!(
+            check_program(&program),
+            Err("invalid bytecode: checksum does not match, program is corrupted".into())
+        );
+
+        let program = sealed_program(Header::new(0), &[]);
+        assert_eq!(
+            check_program(&program[..program.len() - 1]),
+            Err("invalid bytecode: header is cut off".into())
+        );
+        assert_eq!(
+            check_program(b"#!/bin/sh"),
+            Err("invalid bytecode: file is not a frisbee program".into())
+        );
+    }
+}
@@ -1,6 +1,13 @@
+use std::collections::{HashMap, HashSet};
+
+use super::errors::RuntimeErrorKind;
 use super::metadata::Metadata;
 
-#[derive(Debug)]
+// Amount of objects after which first garbage collection is triggered
+// After each collection threshold is adjusted based on the amount of alive objects
+const INITIAL_GC_THRESHOLD: usize = 1024;
+
+#[derive(Debug, Clone)]
 pub struct List {
     pub list_item_type: usize,
     pub item_size: usize,
@@ -8,22 +15,45 @@ pub struct List {
     pub data: Vec<u64>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CustomObject {
     pub type_index: u64,
     pub data: Vec<u64>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HeapObject {
     String(String),
     List(List),
     CustomObject(CustomObject),
 }
 
-#[derive(Debug, Default)]
+/// Amount of objects of each kind, allocated by the heap
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Allocations {
+    pub strings: u64,
+    pub lists: u64,
+    pub objects: u64,
+}
+
+impl Allocations {
+    pub fn add(&mut self, other: Allocations) {
+        self.strings += other.strings;
+        self.lists += other.lists;
+        self.objects += other.objects;
+    }
+}
+
+#[derive(Debug)]
 pub struct Heap {
-    data: Vec<u64>,
+    // All the objects owned by this heap, raw pointers produced by Box::into_raw
+    // Constants are not owned by heap, so they are never collected
+    objects: HashSet<u64>,
+    next_gc_threshold: usize,
+
+    // Used only for profiling, since the last `take_allocations`
+    allocations: Allocations,
+    peak_size: usize,
 }
 // TODO: check performance gains from using unreachable_unchecked or smth like that
 
@@ -60,6 +90,25 @@ impl HeapObject {
     }
 }
 
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: HashSet::new(),
+            next_gc_threshold: INITIAL_GC_THRESHOLD,
+            allocations: Allocations::default(),
+            peak_size: 0,
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for pointer in self.objects.drain() {
+            drop(unsafe { Box::from_raw(pointer as *mut HeapObject) });
+        }
+    }
+}
+
 impl Heap {
     pub fn allocate_custom(
         &mut self,
@@ -69,7 +118,7 @@ impl Heap {
         let obj_size = meta.types_sizes[type_index];
         let obj = Box::new(HeapObject::CustomObject(CustomObject {
             type_index: type_index as u64,
-            data: vec![0; obj_size as usize],
+            data: vec![0; obj_size],
         }));
         let (pos, obj) = self.insert(obj);
         (pos, obj.extract_custom_object())
@@ -113,8 +162,14 @@ impl Heap {
     }
 
     fn insert(&mut self, object: Box<HeapObject>) -> (u64, &mut HeapObject) {
+        match object.as_ref() {
+            HeapObject::String(_) => self.allocations.strings += 1,
+            HeapObject::List(_) => self.allocations.lists += 1,
+            HeapObject::CustomObject(_) => self.allocations.objects += 1,
+        }
         let index = Box::into_raw(object);
-        self.data.push(index as u64);
+        self.objects.insert(index as u64);
+        self.peak_size = self.peak_size.max(self.objects.len());
 
         // TODO: this is kinda lol, need to get rid of all of this unsafe
         (index as u64, unsafe { &mut *index })
@@ -129,9 +184,112 @@ impl Heap {
         unsafe { &*q }
     }
 
+    /// Constants and objects of other heaps are not owned, even though they are valid pointers
+    pub fn contains(&self, pointer: u64) -> bool {
+        self.objects.contains(&pointer)
+    }
+
+    /// Pointers to all objects of the heap, in order of their addresses
+    pub fn pointers(&self) -> Vec<u64> {
+        let mut pointers: Vec<u64> = self.objects.iter().copied().collect();
+        pointers.sort_unstable();
+        pointers
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn take_allocations(&mut self) -> Allocations {
+        std::mem::take(&mut self.allocations)
+    }
+
+    /// The most objects this heap has ever held at once
+    pub fn peak_size(&self) -> usize {
+        self.peak_size
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_gc_threshold
+    }
+
+    /// Roots are values that are known to be pointers (based on pointer mappings),
+    /// but they still might be nil (0) or point to non-owned objects (like constants),
+    /// so only values that are owned by this heap are followed.
+    fn mark_reachable<I>(&self, roots: I, meta: &Metadata) -> HashSet<u64>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let mut marked: HashSet<u64> = HashSet::new();
+        let mut to_visit: Vec<u64> = roots.into_iter().collect();
+
+        while let Some(pointer) = to_visit.pop() {
+            if !self.objects.contains(&pointer) || !marked.insert(pointer) {
+                continue;
+            }
+
+            match self.get(pointer) {
+                HeapObject::String(_) => {}
+                HeapObject::List(l) => {
+                    let item_map = &meta.lists_pointer_mapping[l.list_item_type];
+                    for i in 0..l.items_amount {
+                        for pos in item_map {
+                            to_visit.push(l.data[pos + l.item_size * i]);
+                        }
+                    }
+                }
+                HeapObject::CustomObject(obj) => {
+                    let type_map = &meta.types_pointer_mapping[obj.type_index as usize];
+                    to_visit.extend(type_map.iter().map(|pos| obj.data[*pos]));
+                }
+            }
+        }
+        marked
+    }
+
+    /// Precise mark-and-sweep collection, returns amount of freed objects.
+    pub fn collect_garbage<I>(&mut self, roots: I, meta: &Metadata) -> usize
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let marked = self.mark_reachable(roots, meta);
+
+        let total_before = self.objects.len();
+        self.objects.retain(|pointer| {
+            let is_alive = marked.contains(pointer);
+            if !is_alive {
+                drop(unsafe { Box::from_raw(*pointer as *mut HeapObject) });
+            }
+            is_alive
+        });
+
+        self.next_gc_threshold = INITIAL_GC_THRESHOLD.max(self.objects.len() * 2);
+        total_before - self.objects.len()
+    }
+
+    /// Copies all the objects reachable from roots, so they can be restored later
+    pub fn snapshot<I>(&self, roots: I, meta: &Metadata) -> HashMap<u64, HeapObject>
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let reachable = self.mark_reachable(roots, meta);
+        reachable.into_iter().map(|p| (p, self.get(p).clone())).collect()
+    }
+
+    /// Objects of the snapshot must still be alive, so they should be used as GC roots
+    pub fn restore(&mut self, snapshot: HashMap<u64, HeapObject>) {
+        for (pointer, object) in snapshot {
+            *self.get_mut(pointer) = object;
+        }
+    }
+
     pub fn simple_debug_view(&self) -> String {
         let mut s = String::from("HEAP STATE: \n");
-        for pointer in self.data.iter() {
+        for pointer in self.objects.iter() {
             let obj = self.get(*pointer);
             s.push_str(format!("\t{:x} => {:?}\n", pointer, obj).as_str());
         }
@@ -144,24 +302,13 @@ impl List {
         &mut self.data[index * self.item_size..]
     }
 
-    pub fn normalize_index(&self, index: i64) -> usize {
-        if index < 0 {
-            if index.abs() > self.items_amount as i64 {
-                panic!(
-                    "Negative out of bounds: list of size {} but {} requested",
-                    self.items_amount, index
-                );
-            }
-            (self.items_amount as i64 + index) as usize
-        } else {
-            if index >= self.items_amount as i64 {
-                panic!(
-                    "Out of bounds: list of size {} but {} requested",
-                    self.items_amount, index
-                );
-            }
-            index as usize
+    pub fn normalize_index(&self, index: i64) -> Result<usize, RuntimeErrorKind> {
+        let size = self.items_amount as i64;
+        let normalized = if index < 0 { size + index } else { index };
+        if normalized < 0 || normalized >= size {
+            return Err(RuntimeErrorKind::IndexOutOfBounds { index, size: self.items_amount });
         }
+        Ok(normalized as usize)
     }
 }
 
@@ -169,27 +316,110 @@ impl List {
 mod test {
     use super::*;
 
+    fn gc_test_metadata() -> Metadata {
+        // type 0: (String, Int), type 1: (Type1?) -> (flag, pointer)
+        // list kind 0: [Int], list kind 1: [Type0]
+        Metadata {
+            types_sizes: vec![2, 2],
+            types_pointer_mapping: vec![vec![0], vec![1]],
+            list_types_sizes: vec![1, 1],
+            lists_pointer_mapping: vec![vec![], vec![0]],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gc_keeps_reachable_objects() {
+        let meta = gc_test_metadata();
+        let mut heap = Heap::default();
+
+        let (string_pos, _) = heap.move_string("reachable".into());
+        let (obj_pos, obj) = heap.allocate_custom(0, &meta);
+        obj.data[0] = string_pos;
+        let (list_pos, _) = heap.allocate_list(1, 1, &[obj_pos], &meta);
+
+        heap.move_string("garbage".into());
+        heap.allocate_list(0, 3, &[1, 2, 3], &meta);
+        assert_eq!(heap.len(), 5);
+
+        let freed = heap.collect_garbage([list_pos], &meta);
+        assert_eq!(freed, 2);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.get(string_pos).extract_string(), "reachable");
+    }
+
+    #[test]
+    fn gc_frees_unreachable_cycles() {
+        let meta = gc_test_metadata();
+        let mut heap = Heap::default();
+
+        let (first, _) = heap.allocate_custom(1, &meta);
+        let (second, second_obj) = heap.allocate_custom(1, &meta);
+        second_obj.data = vec![1, first];
+        heap.get_mut(first).extract_custom_object().data = vec![1, second];
+
+        assert_eq!(heap.collect_garbage([first], &meta), 0);
+        assert_eq!(heap.collect_garbage([], &meta), 2);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn gc_ignores_values_not_owned_by_heap() {
+        let meta = gc_test_metadata();
+        let mut heap = Heap::default();
+        let (obj_pos, obj) = heap.allocate_custom(0, &meta);
+
+        // nil and active object index are not heap pointers
+        obj.data[0] = 0;
+        assert_eq!(heap.collect_garbage([obj_pos, 0, 3], &meta), 0);
+        assert_eq!(heap.len(), 1);
+    }
+
+    #[test]
+    fn snapshot_restores_reachable_objects() {
+        let meta = gc_test_metadata();
+        let mut heap = Heap::default();
+
+        let (string_pos, _) = heap.move_string("before".into());
+        let (obj_pos, obj) = heap.allocate_custom(0, &meta);
+        obj.data[0] = string_pos;
+        obj.data[1] = 1;
+        heap.move_string("not reachable".into());
+
+        let snapshot = heap.snapshot([obj_pos], &meta);
+        assert_eq!(snapshot.len(), 2);
+
+        let (new_string_pos, _) = heap.move_string("after".into());
+        heap.get_mut(obj_pos).extract_custom_object().data = vec![new_string_pos, 2];
+        heap.get_mut(string_pos).extract_string_mut().push_str(" changed");
+
+        heap.restore(snapshot);
+        let restored = heap.get_mut(obj_pos).extract_custom_object();
+        assert_eq!(restored.data, vec![string_pos, 1]);
+        assert_eq!(heap.get(string_pos).extract_string(), "before");
+    }
+
     #[test]
     fn test_normalize_index() {
         let l = List { list_item_type: 0, item_size: 1, items_amount: 10, data: vec![0; 10] };
 
-        assert_eq!(l.normalize_index(0), 0);
-        assert_eq!(l.normalize_index(1), 1);
-        assert_eq!(l.normalize_index(-1), 9);
-        assert_eq!(l.normalize_index(-10), 0);
+        assert_eq!(l.normalize_index(0), Ok(0));
+        assert_eq!(l.normalize_index(1), Ok(1));
+        assert_eq!(l.normalize_index(-1), Ok(9));
+        assert_eq!(l.normalize_index(-10), Ok(0));
     }
 
     #[test]
-    #[should_panic(expected = "Out of bounds: list of size 10 but 10 requested")]
-    fn too_big_index_panics() {
+    fn too_big_index_is_error() {
         let l = List { list_item_type: 0, item_size: 1, items_amount: 10, data: vec![0; 10] };
-        l.normalize_index(10);
+        let expected = RuntimeErrorKind::IndexOutOfBounds { index: 10, size: 10 };
+        assert_eq!(l.normalize_index(10), Err(expected));
     }
 
     #[test]
-    #[should_panic(expected = "Negative out of bounds: list of size 10 but -11 requested")]
-    fn too_small_index_panics() {
+    fn too_small_index_is_error() {
         let l = List { list_item_type: 0, item_size: 1, items_amount: 10, data: vec![0; 10] };
-        l.normalize_index(-11);
+        let expected = RuntimeErrorKind::IndexOutOfBounds { index: -11, size: 10 };
+        assert_eq!(l.normalize_index(-11), Err(expected));
     }
 }
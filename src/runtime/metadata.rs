@@ -1,42 +1,377 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-pub type MetadataBlock = Vec<(usize, Vec<u8>)>;
+use super::debugger::{LocalInfo, ValueType};
+use super::opcodes::op;
+use super::scheduler::MailboxConfig;
+use super::supervision::SupervisorConfig;
+use super::values::Signature;
+
+pub type MetadataBlock = Vec<(String, usize, Vec<u16>)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcePosition {
+    pub module: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Heap object, that a pointer refers to according to its type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PointerKind {
+    String,
+    List(usize),   // kind of the list
+    Object(usize), // index of the type
+}
 
 #[derive(Default)]
 pub struct Metadata {
+    pub types_names: Vec<String>,
     pub types_sizes: Vec<usize>,
+    pub types_mailboxes: Vec<MailboxConfig>,
+    pub types_on_stop: Vec<Option<usize>>, // position of the method, run after object is stopped
+    pub types_supervision: Vec<Option<SupervisorConfig>>,
+    pub types_on_child_failed: Vec<Option<usize>>, // position of the method, run by supervisor
+    pub list_types_names: Vec<String>,             // names of item types
     pub list_types_sizes: Vec<usize>,
     pub function_args_sizes: Vec<usize>,
+    pub function_names: Vec<String>,
+    pub function_return_sizes: Vec<usize>,
 
     pub types_pointer_mapping: Vec<Vec<usize>>,
     pub lists_pointer_mapping: Vec<Vec<usize>>,
     pub functions_pointer_mapping: Vec<Vec<usize>>,
+    pub functions_return_pointer_mapping: Vec<Vec<usize>>,
+
+    // Offsets of active object handles, they are passed by identity and are not heap pointers
+    pub types_active_mapping: Vec<Vec<usize>>,
+    pub lists_active_mapping: Vec<Vec<usize>>,
+    pub functions_active_mapping: Vec<Vec<usize>>,
+    pub functions_return_active_mapping: Vec<Vec<usize>>,
+
+    // Kinds of objects behind pointers, in the same order as offsets in pointer mappings
+    pub types_pointer_kinds: Vec<Vec<PointerKind>>,
+    pub lists_pointer_kinds: Vec<Vec<PointerKind>>,
+    pub functions_pointer_kinds: Vec<Vec<PointerKind>>,
+    pub functions_return_pointer_kinds: Vec<Vec<PointerKind>>,
 
     pub function_positions: HashMap<usize, usize>, // bytecode position -> function index
+    pub stack_maps: HashMap<usize, Vec<usize>>,    // position after safepoint -> pointers in frame
+    pub line_table: BTreeMap<usize, (usize, SourcePosition)>, // position -> (function start, source)
+    pub function_locals: HashMap<usize, Vec<LocalInfo>>,      // function start -> its locals
+    pub function_signatures: HashMap<usize, Signature>,       // function start -> its types
+}
+
+/// Separates heap pointers from active object handles, that are tagged with ACTIVE_HANDLE_FLAG
+fn split_pointer_mapping(mapping: Vec<u16>) -> (Vec<usize>, Vec<usize>) {
+    let (actives, pointers): (Vec<u16>, Vec<u16>) =
+        mapping.into_iter().partition(|x| x & op::ACTIVE_HANDLE_FLAG != 0);
+    (
+        pointers.into_iter().map(|x| x as usize).collect(),
+        actives
+            .into_iter()
+            .map(|x| (x & !op::ACTIVE_HANDLE_FLAG) as usize)
+            .collect(),
+    )
 }
 
 impl Metadata {
     pub fn fill_types_metadata(&mut self, types_metadata: MetadataBlock) {
-        for (size, mapping) in types_metadata {
+        for (name, size, mapping) in types_metadata {
+            self.types_names.push(name);
             self.types_sizes.push(size);
-            self.types_pointer_mapping
-                .push(mapping.into_iter().map(|x| x as usize).collect());
+            let (pointers, actives) = split_pointer_mapping(mapping);
+            self.types_pointer_mapping.push(pointers);
+            self.types_active_mapping.push(actives);
         }
     }
 
     pub fn fill_lists_metadata(&mut self, lists_metadata: MetadataBlock) {
-        for (size, mapping) in lists_metadata {
+        for (name, size, mapping) in lists_metadata {
+            self.list_types_names.push(name);
             self.list_types_sizes.push(size);
-            self.lists_pointer_mapping
-                .push(mapping.into_iter().map(|x| x as usize).collect());
+            let (pointers, actives) = split_pointer_mapping(mapping);
+            self.lists_pointer_mapping.push(pointers);
+            self.lists_active_mapping.push(actives);
         }
     }
 
     pub fn fill_function_metadata(&mut self, funcs_metadata: MetadataBlock) {
-        for (size, mapping) in funcs_metadata {
+        for (name, size, mapping) in funcs_metadata {
+            self.function_names.push(name);
             self.function_args_sizes.push(size);
-            self.functions_pointer_mapping
-                .push(mapping.into_iter().map(|x| x as usize).collect());
+            let (pointers, actives) = split_pointer_mapping(mapping);
+            self.functions_pointer_mapping.push(pointers);
+            self.functions_active_mapping.push(actives);
+        }
+    }
+
+    pub fn fill_function_return_metadata(&mut self, returns_metadata: MetadataBlock) {
+        for (_, size, mapping) in returns_metadata {
+            self.function_return_sizes.push(size);
+            let (pointers, actives) = split_pointer_mapping(mapping);
+            self.functions_return_pointer_mapping.push(pointers);
+            self.functions_return_active_mapping.push(actives);
+        }
+    }
+
+    /// Fills kinds of pointers from types of fields, list items and signatures. Kinds must
+    /// match pointer mappings, so signatures and all of the mappings are loaded before
+    pub fn fill_pointer_kinds(
+        &mut self,
+        types_layouts: &[ValueType],
+        list_item_types: &[ValueType],
+    ) -> Result<(), String> {
+        let get_kinds = |value_type: &ValueType, mapping: &[usize], name: &str| {
+            let mut kinds = vec![];
+            self.push_pointer_kinds(value_type, &mut kinds)
+                .filter(|_| kinds.len() == mapping.len())
+                .ok_or_else(|| format!("pointers of {} do not match its type", name))?;
+            Ok::<_, String>(kinds)
+        };
+
+        let mut types_kinds = vec![];
+        for (i, layout) in types_layouts.iter().enumerate() {
+            types_kinds.push(get_kinds(
+                layout,
+                &self.types_pointer_mapping[i],
+                &self.types_names[i],
+            )?);
+        }
+        let mut lists_kinds = vec![];
+        for (i, item_type) in list_item_types.iter().enumerate() {
+            let name = format!("[{}]", self.list_types_names[i]);
+            lists_kinds.push(get_kinds(item_type, &self.lists_pointer_mapping[i], &name)?);
+        }
+
+        let mut functions_kinds = vec![vec![]; self.function_names.len()];
+        let mut returns_kinds = vec![vec![]; self.function_names.len()];
+        for (start, index) in self.function_positions.iter() {
+            let name = &self.function_names[*index];
+            let signature = (self.function_signatures.get(start))
+                .ok_or_else(|| format!("no signature of {}", name))?;
+            let args = ValueType::Tuple(signature.args.clone());
+            functions_kinds[*index] =
+                get_kinds(&args, &self.functions_pointer_mapping[*index], name)?;
+            returns_kinds[*index] = get_kinds(
+                &signature.returns,
+                &self.functions_return_pointer_mapping[*index],
+                name,
+            )?;
+        }
+
+        self.types_pointer_kinds = types_kinds;
+        self.lists_pointer_kinds = lists_kinds;
+        self.functions_pointer_kinds = functions_kinds;
+        self.functions_return_pointer_kinds = returns_kinds;
+        Ok(())
+    }
+
+    fn push_pointer_kinds(
+        &self,
+        value_type: &ValueType,
+        kinds: &mut Vec<PointerKind>,
+    ) -> Option<()> {
+        match value_type {
+            ValueType::Int | ValueType::Float | ValueType::Bool => {}
+            // Futures and active objects are not on the heap
+            ValueType::Future(_) | ValueType::Active(_) => {}
+            ValueType::String => kinds.push(PointerKind::String),
+            ValueType::List(item) => kinds.push(PointerKind::List(self.find_list_kind(item)?)),
+            ValueType::Object(index) => kinds.push(PointerKind::Object(*index)),
+            ValueType::Maybe(inner) => self.push_pointer_kinds(inner, kinds)?,
+            ValueType::Tuple(items) => {
+                for item in items {
+                    self.push_pointer_kinds(item, kinds)?;
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Kinds of lists are named after the type of their items
+    pub fn find_list_kind(&self, item_type: &ValueType) -> Option<usize> {
+        let name = item_type.name(&self.types_names);
+        self.list_types_names.iter().position(|list_name| *list_name == name)
+    }
+
+    /// Finds function that contains given bytecode position, which is the closest one before it
+    pub fn get_function_start(&self, position: usize) -> Option<(usize, usize)> {
+        self.function_positions
+            .iter()
+            .filter(|(start, _)| **start <= position)
+            .max_by_key(|(start, _)| **start)
+            .map(|(start, index)| (*start, *index))
+    }
+
+    /// Start of the function with given full name, e.g. `main::Worker::process`
+    pub fn find_function(&self, name: &str) -> Option<usize> {
+        self.function_positions
+            .iter()
+            .find(|(_, index)| self.function_names[**index] == name)
+            .map(|(start, _)| *start)
+    }
+
+    pub fn get_function_name(&self, position: usize) -> Option<&str> {
+        self.get_function_start(position)
+            .map(|(_, index)| self.function_names[index].as_str())
+    }
+
+    /// Source position of the statement or the call, that contains given bytecode position
+    pub fn get_source_position(&self, position: usize) -> Option<&SourcePosition> {
+        let (function_start, _) = self.get_function_start(position)?;
+        match self.line_table.range(..=position).next_back() {
+            Some((_, (start, source))) if *start == function_start => Some(source),
+            _ => None,
         }
     }
+
+    /// Calls and other expressions have their own positions, but only the first one
+    /// of them on each line starts that line
+    pub fn is_line_start(&self, position: usize) -> bool {
+        let (function_start, source) = match self.line_table.get(&position) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        match self.line_table.range(..position).next_back() {
+            Some((_, (start, previous))) => start != function_start || previous.line != source.line,
+            None => true,
+        }
+    }
+
+    pub fn add_stack_map(&mut self, position: usize, slots_amount: usize, bitmap: &[u8]) {
+        let pointers = (0..slots_amount).filter(|i| bitmap[i / 8] & (1 << (i % 8)) != 0);
+        self.stack_maps.insert(position, pointers.collect());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::values::Signature;
+
+    #[test]
+    fn active_handles_split_from_pointers() {
+        let mapping = vec![0, 2, 1 | op::ACTIVE_HANDLE_FLAG, 300, 4 | op::ACTIVE_HANDLE_FLAG];
+        assert_eq!(
+            split_pointer_mapping(mapping),
+            (vec![0, 2, 300], vec![1, 4])
+        );
+    }
+
+    #[test]
+    fn pointer_kinds_follow_types() {
+        let mut metadata = Metadata::default();
+        metadata.fill_types_metadata(vec![("main::Node".into(), 3, vec![1, 2])]);
+        metadata.fill_lists_metadata(vec![
+            ("Int".into(), 1, vec![]),
+            ("main::Node".into(), 1, vec![0]),
+        ]);
+        metadata.fill_function_metadata(vec![("main::visit".into(), 2, vec![1])]);
+        metadata.fill_function_return_metadata(vec![("main::visit".into(), 1, vec![0])]);
+        metadata.function_positions.insert(10, 0);
+        let node = ValueType::Object(0);
+        let signature = Signature {
+            args: vec![ValueType::Active(0), ValueType::List(Box::new(node.clone()))],
+            returns: ValueType::String,
+            active_type: None,
+        };
+        metadata.function_signatures.insert(10, signature);
+
+        // Node is (Int, [Int], Node?)
+        let maybe_node = ValueType::Maybe(Box::new(node.clone()));
+        let int_list = ValueType::List(Box::new(ValueType::Int));
+        let layouts = [ValueType::Tuple(vec![ValueType::Int, int_list, maybe_node])];
+        metadata
+            .fill_pointer_kinds(&layouts, &[ValueType::Int, node])
+            .unwrap();
+        assert_eq!(
+            metadata.types_pointer_kinds,
+            vec![vec![PointerKind::List(0), PointerKind::Object(0)]]
+        );
+        assert_eq!(
+            metadata.lists_pointer_kinds,
+            vec![vec![], vec![PointerKind::Object(0)]]
+        );
+        assert_eq!(
+            metadata.functions_pointer_kinds,
+            vec![vec![PointerKind::List(1)]]
+        );
+        assert_eq!(
+            metadata.functions_return_pointer_kinds,
+            vec![vec![PointerKind::String]]
+        );
+
+        // Item type without pointers does not match the pointer map of the list
+        assert_eq!(
+            metadata.fill_pointer_kinds(&layouts, &[ValueType::Int, ValueType::Int]),
+            Err("pointers of [main::Node] do not match its type".to_string())
+        );
+    }
+
+    #[test]
+    fn stack_map_bitmap_decoded() {
+        let mut metadata = Metadata::default();
+        metadata.add_stack_map(10, 11, &[0b1000_0101, 0b0000_0100]);
+        metadata.add_stack_map(20, 0, &[]);
+
+        assert_eq!(metadata.stack_maps[&10], vec![0, 2, 7, 10]);
+        assert!(metadata.stack_maps[&20].is_empty());
+    }
+
+    #[test]
+    fn function_name_found_by_position() {
+        let mut metadata = Metadata::default();
+        metadata.fill_function_metadata(vec![
+            ("main".into(), 0, vec![]),
+            ("helper".into(), 1, vec![]),
+        ]);
+        metadata.function_positions.insert(100, 1);
+        metadata.function_positions.insert(40, 0);
+
+        assert_eq!(metadata.get_function_name(40), Some("main"));
+        assert_eq!(metadata.get_function_name(99), Some("main"));
+        assert_eq!(metadata.get_function_name(100), Some("helper"));
+        assert_eq!(metadata.get_function_name(250), Some("helper"));
+        assert_eq!(metadata.get_function_name(10), None);
+    }
+
+    #[test]
+    fn source_position_found_within_function() {
+        let mut metadata = Metadata::default();
+        metadata.fill_function_metadata(vec![
+            ("main".into(), 0, vec![]),
+            ("helper".into(), 1, vec![]),
+        ]);
+        metadata.function_positions.insert(10, 0);
+        metadata.function_positions.insert(50, 1);
+
+        let position = |line| SourcePosition { module: "main".into(), line, column: 4 };
+        metadata.line_table.insert(12, (10, position(1)));
+        metadata.line_table.insert(30, (10, position(2)));
+        metadata.line_table.insert(55, (50, position(7)));
+
+        assert_eq!(metadata.get_source_position(11), None);
+        assert_eq!(metadata.get_source_position(12), Some(&position(1)));
+        assert_eq!(metadata.get_source_position(49), Some(&position(2)));
+        // Start of `helper` is before its first statement, so nothing is found
+        assert_eq!(metadata.get_source_position(52), None);
+        assert_eq!(metadata.get_source_position(60), Some(&position(7)));
+    }
+
+    #[test]
+    fn calls_do_not_start_lines() {
+        let mut metadata = Metadata::default();
+        let position = |line, column| SourcePosition { module: "main".into(), line, column };
+        metadata.line_table.insert(12, (10, position(1, 4)));
+        metadata.line_table.insert(20, (10, position(1, 12))); // call within the statement
+        metadata.line_table.insert(30, (10, position(2, 4)));
+        metadata.line_table.insert(55, (50, position(2, 4)));
+
+        assert!(metadata.is_line_start(12));
+        assert!(!metadata.is_line_start(20));
+        assert!(!metadata.is_line_start(25));
+        assert!(metadata.is_line_start(30));
+        // Same line in another function is started again
+        assert!(metadata.is_line_start(55));
+    }
 }
@@ -1,8 +1,20 @@
+pub mod debugger;
+pub mod errors;
+mod futures;
+pub mod header;
 mod heap;
 mod metadata;
+mod network;
 pub mod opcodes;
+mod profiler;
+pub mod scheduler;
 mod serialization;
 pub mod stdlib_runners;
+pub mod supervision;
+mod timers;
+pub mod tracing;
 mod utils;
+pub mod values;
+mod verifier;
 pub mod vm;
 mod worker;
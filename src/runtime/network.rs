@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/*
+Nodes are connected as a star: coordinator runs the entry and connects to every node,
+frames between two other nodes are forwarded through it.
+Coordinator also counts messages of the whole program, so nodes report every message
+they send and process. Reports go through the same connection as messages, so the
+coordinator always counts a message as sent before it is reported as processed.
+
+Frame is a u32 length followed by the frame kind byte and its fields,
+numbers are 8 bytes big-endian, byte strings are prefixed with their u32 length.
+*/
+
+// Active object handles and futures keep the id of their node in the highest bits,
+// so they are unique in the whole program and might be sent to other nodes as they are
+const NODE_ID_SHIFT: u32 = 48;
+pub const COORDINATOR_NODE: u64 = 0;
+
+const HELLO: u8 = 1;
+const WELCOME: u8 = 2;
+const SPAWN: u8 = 3;
+const MESSAGE: u8 = 4;
+const REPLY: u8 = 5;
+const MESSAGE_SENT: u8 = 6;
+const MESSAGE_PROCESSED: u8 = 7;
+const MESSAGE_FAILED: u8 = 8;
+const EXIT: u8 = 9;
+const SHUTDOWN: u8 = 10;
+const STOP: u8 = 11;
+const WAIT_STOPPED: u8 = 12;
+const WAIT_FUTURE: u8 = 13;
+
+// Missing future of a message, u64::MAX is never a valid future
+const NONE: u64 = u64::MAX;
+
+// Nodes are usually started together with the coordinator, so they might be not ready yet
+const CONNECT_ATTEMPTS: usize = 50;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Length is read before the frame itself, so a broken peer must not make us allocate gigabytes
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+pub fn node_of(id: u64) -> u64 {
+    id >> NODE_ID_SHIFT
+}
+
+pub fn local_part(id: u64) -> u64 {
+    id & ((1 << NODE_ID_SHIFT) - 1)
+}
+
+pub fn global_id(node: u64, local: u64) -> u64 {
+    (node << NODE_ID_SHIFT) | local
+}
+
+/// FNV-1a, nodes must run exactly the same bytecode, as they send positions and indexes
+pub fn bytecode_hash(program: &[u8]) -> u64 {
+    program.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    // Handshake: coordinator assigns an id to the node and tells names of all nodes
+    Hello { node_id: u64, bytecode_hash: u64, nodes: Vec<(String, u64)> },
+    Welcome { bytecode_hash: u64 },
+
+    // Spawned handle is sent back as a reply, encoded as 8 bytes
+    Spawn { node: u64, type_index: u64, reply_to: u64, data: Vec<u8> },
+    Message { receiver: u64, reply_to: Option<u64>, data: Vec<u8> },
+    Reply { future: u64, value: Option<Vec<u8>> },
+
+    // Stop message and the request to wait for the stop are counted by their sender.
+    // Waits are replied with an empty value once the active object is stopped, or with
+    // the value of the future
+    Stop { handle: u64 },
+    WaitStopped { handle: u64, reply_to: u64 },
+    WaitFuture { future: u64, reply_to: u64 },
+
+    // Sent by nodes to the coordinator
+    MessageSent,
+    MessageProcessed,
+    MessageFailed,
+    Exit(i64),
+
+    // Sent by the coordinator when the program is finished
+    Shutdown,
+}
+
+impl Frame {
+    /// Node, that must handle the frame
+    pub fn destination(&self) -> u64 {
+        match self {
+            Frame::Spawn { node, .. } => *node,
+            Frame::Message { receiver, .. } => node_of(*receiver),
+            Frame::Reply { future, .. } => node_of(*future),
+            Frame::Stop { handle } | Frame::WaitStopped { handle, .. } => node_of(*handle),
+            Frame::WaitFuture { future, .. } => node_of(*future),
+            _ => COORDINATOR_NODE,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let push_u64 = |bytes: &mut Vec<u8>, value: u64| bytes.extend(value.to_be_bytes());
+        let push_bytes = |bytes: &mut Vec<u8>, data: &[u8]| {
+            bytes.extend((data.len() as u32).to_be_bytes());
+            bytes.extend(data);
+        };
+
+        match self {
+            Frame::Hello { node_id, bytecode_hash, nodes } => {
+                bytes.push(HELLO);
+                push_u64(&mut bytes, *node_id);
+                push_u64(&mut bytes, *bytecode_hash);
+                push_u64(&mut bytes, nodes.len() as u64);
+                for (name, id) in nodes {
+                    push_bytes(&mut bytes, name.as_bytes());
+                    push_u64(&mut bytes, *id);
+                }
+            }
+            Frame::Welcome { bytecode_hash } => {
+                bytes.push(WELCOME);
+                push_u64(&mut bytes, *bytecode_hash);
+            }
+            Frame::Spawn { node, type_index, reply_to, data } => {
+                bytes.push(SPAWN);
+                push_u64(&mut bytes, *node);
+                push_u64(&mut bytes, *type_index);
+                push_u64(&mut bytes, *reply_to);
+                push_bytes(&mut bytes, data);
+            }
+            Frame::Message { receiver, reply_to, data } => {
+                bytes.push(MESSAGE);
+                push_u64(&mut bytes, *receiver);
+                push_u64(&mut bytes, reply_to.unwrap_or(NONE));
+                push_bytes(&mut bytes, data);
+            }
+            Frame::Reply { future, value } => {
+                bytes.push(REPLY);
+                push_u64(&mut bytes, *future);
+                match value {
+                    Some(value) => {
+                        bytes.push(1);
+                        push_bytes(&mut bytes, value);
+                    }
+                    None => bytes.push(0),
+                }
+            }
+            Frame::Stop { handle } => {
+                bytes.push(STOP);
+                push_u64(&mut bytes, *handle);
+            }
+            Frame::WaitStopped { handle, reply_to } => {
+                bytes.push(WAIT_STOPPED);
+                push_u64(&mut bytes, *handle);
+                push_u64(&mut bytes, *reply_to);
+            }
+            Frame::WaitFuture { future, reply_to } => {
+                bytes.push(WAIT_FUTURE);
+                push_u64(&mut bytes, *future);
+                push_u64(&mut bytes, *reply_to);
+            }
+            Frame::MessageSent => bytes.push(MESSAGE_SENT),
+            Frame::MessageProcessed => bytes.push(MESSAGE_PROCESSED),
+            Frame::MessageFailed => bytes.push(MESSAGE_FAILED),
+            Frame::Exit(code) => {
+                bytes.push(EXIT);
+                push_u64(&mut bytes, *code as u64);
+            }
+            Frame::Shutdown => bytes.push(SHUTDOWN),
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Frame> {
+        let mut reader = FrameReader { bytes, position: 0 };
+        let frame = match reader.read_bytes(1)?[0] {
+            HELLO => {
+                let node_id = reader.read_u64()?;
+                let bytecode_hash = reader.read_u64()?;
+                let mut nodes = vec![];
+                for _ in 0..reader.read_u64()? {
+                    let name = String::from_utf8(reader.read_sized()?.to_vec())
+                        .map_err(|_| invalid_data("node name is not utf-8"))?;
+                    nodes.push((name, reader.read_u64()?));
+                }
+                Frame::Hello { node_id, bytecode_hash, nodes }
+            }
+            WELCOME => Frame::Welcome { bytecode_hash: reader.read_u64()? },
+            SPAWN => Frame::Spawn {
+                node: reader.read_u64()?,
+                type_index: reader.read_u64()?,
+                reply_to: reader.read_u64()?,
+                data: reader.read_sized()?.to_vec(),
+            },
+            MESSAGE => {
+                let receiver = reader.read_u64()?;
+                let reply_to = Some(reader.read_u64()?).filter(|future| *future != NONE);
+                Frame::Message { receiver, reply_to, data: reader.read_sized()?.to_vec() }
+            }
+            REPLY => {
+                let future = reader.read_u64()?;
+                let value = match reader.read_bytes(1)?[0] {
+                    0 => None,
+                    _ => Some(reader.read_sized()?.to_vec()),
+                };
+                Frame::Reply { future, value }
+            }
+            STOP => Frame::Stop { handle: reader.read_u64()? },
+            WAIT_STOPPED => {
+                Frame::WaitStopped { handle: reader.read_u64()?, reply_to: reader.read_u64()? }
+            }
+            WAIT_FUTURE => {
+                Frame::WaitFuture { future: reader.read_u64()?, reply_to: reader.read_u64()? }
+            }
+            MESSAGE_SENT => Frame::MessageSent,
+            MESSAGE_PROCESSED => Frame::MessageProcessed,
+            MESSAGE_FAILED => Frame::MessageFailed,
+            EXIT => Frame::Exit(reader.read_u64()? as i64),
+            SHUTDOWN => Frame::Shutdown,
+            kind => return Err(invalid_data(&format!("unknown frame kind {}", kind))),
+        };
+        if reader.position != bytes.len() {
+            return Err(invalid_data("unexpected bytes after the end of frame"));
+        }
+        Ok(frame)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct FrameReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    fn read_bytes(&mut self, amount: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() - self.position < amount {
+            return Err(invalid_data("frame ends unexpectedly"));
+        }
+        self.position += amount;
+        Ok(&self.bytes[self.position - amount..self.position])
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    fn read_sized(&mut self) -> io::Result<&'a [u8]> {
+        let length = u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap());
+        self.read_bytes(length as usize)
+    }
+}
+
+pub fn write_frame<W: Write>(stream: &mut W, frame: &Frame) -> io::Result<()> {
+    let bytes = frame.encode();
+    if bytes.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame is too big to be sent",
+        ));
+    }
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Frame> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(invalid_data("frame is too big"));
+    }
+    let mut bytes = vec![0; length];
+    stream.read_exact(&mut bytes)?;
+    Frame::decode(&bytes)
+}
+
+/// Connections of this node, VM without them runs the whole program by itself
+pub fn connect(address: &str) -> io::Result<TcpStream> {
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect(address) {
+            Err(_) if attempt < CONNECT_ATTEMPTS => {
+                attempt += 1;
+                thread::sleep(CONNECT_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Network {
+    node_id: AtomicU64,
+    names: RwLock<HashMap<String, u64>>,
+    links: RwLock<HashMap<u64, Mutex<TcpStream>>>, // node id -> stream to write frames to
+    shutting_down: AtomicBool,
+}
+
+impl Network {
+    pub fn node_id(&self) -> u64 {
+        self.node_id.load(Ordering::Relaxed)
+    }
+
+    /// True if this VM serves the coordinator, so it reports messages instead of counting them
+    pub fn is_node(&self) -> bool {
+        self.node_id() != COORDINATOR_NODE
+    }
+
+    pub fn is_local(&self, id: u64) -> bool {
+        node_of(id) == self.node_id()
+    }
+
+    pub fn join(&self, node_id: u64, names: Vec<(String, u64)>) {
+        self.node_id.store(node_id, Ordering::Relaxed);
+        self.names.write().unwrap().extend(names);
+    }
+
+    pub fn add_link(&self, node_id: u64, stream: TcpStream) {
+        self.links.write().unwrap().insert(node_id, Mutex::new(stream));
+    }
+
+    pub fn find_node(&self, name: &str) -> Option<u64> {
+        self.names.read().unwrap().get(name).copied()
+    }
+
+    pub fn get_node_name(&self, node_id: u64) -> String {
+        let names = self.names.read().unwrap();
+        let name = names
+            .iter()
+            .find(|(_, id)| **id == node_id)
+            .map(|(name, _)| name.clone());
+        name.unwrap_or_else(|| format!("#{}", node_id))
+    }
+
+    /// Sends frame to its destination, through the coordinator if this is a node
+    pub fn send(&self, frame: Frame) -> io::Result<()> {
+        let link = match self.is_node() {
+            true => COORDINATOR_NODE,
+            false => frame.destination(),
+        };
+        let links = self.links.read().unwrap();
+        let stream = links
+            .get(&link)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no connection to node"))?;
+        let result = write_frame(&mut *stream.lock().unwrap(), &frame);
+        result
+    }
+
+    /// Tells all nodes, that the program is finished
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        for stream in self.links.read().unwrap().values() {
+            // Node might be gone already, and there is nothing to do about it
+            let _ = write_frame(&mut *stream.lock().unwrap(), &Frame::Shutdown);
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = vec![
+            Frame::Hello {
+                node_id: 2,
+                bytecode_hash: 0xdeadbeef,
+                nodes: vec![("first".into(), 1), ("second".into(), 2)],
+            },
+            Frame::Welcome { bytecode_hash: 0xdeadbeef },
+            Frame::Spawn { node: 1, type_index: 3, reply_to: global_id(2, 5), data: vec![1, 2] },
+            Frame::Message { receiver: global_id(1, 7), reply_to: None, data: vec![] },
+            Frame::Message { receiver: 4, reply_to: Some(0), data: vec![9; 300] },
+            Frame::Reply { future: 3, value: Some(vec![]) },
+            Frame::Reply { future: 3, value: None },
+            Frame::Stop { handle: global_id(2, 4) },
+            Frame::WaitStopped { handle: global_id(2, 4), reply_to: 6 },
+            Frame::WaitFuture { future: global_id(1, 8), reply_to: 6 },
+            Frame::MessageSent,
+            Frame::MessageProcessed,
+            Frame::MessageFailed,
+            Frame::Exit(-3),
+            Frame::Shutdown,
+        ];
+
+        let mut stream = vec![];
+        for frame in frames.iter() {
+            write_frame(&mut stream, frame).unwrap();
+        }
+        let mut reader = stream.as_slice();
+        for frame in frames.iter() {
+            assert_eq!(&read_frame(&mut reader).unwrap(), frame);
+        }
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn too_big_frames_are_refused() {
+        let mut stream = u32::MAX.to_be_bytes().to_vec();
+        stream.push(SHUTDOWN);
+        let error = read_frame(&mut stream.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let frame = Frame::Message { receiver: 1, reply_to: None, data: vec![0; MAX_FRAME_SIZE] };
+        let error = write_frame(&mut vec![], &frame).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn ids_keep_their_node() {
+        let handle = global_id(3, 42);
+        assert_eq!(node_of(handle), 3);
+        assert_eq!(local_part(handle), 42);
+        assert_eq!(global_id(COORDINATOR_NODE, 42), 42);
+
+        let frame = Frame::Reply { future: handle, value: None };
+        assert_eq!(frame.destination(), 3);
+        assert_eq!(Frame::Stop { handle }.destination(), 3);
+        let frame = Frame::WaitFuture { future: global_id(1, 2), reply_to: handle };
+        assert_eq!(frame.destination(), 1);
+        assert_eq!(Frame::MessageSent.destination(), COORDINATOR_NODE);
+    }
+
+    #[test]
+    fn bytecode_hash_differs_for_different_programs() {
+        assert_eq!(
+            bytecode_hash(&[0xff, 0xff, 1]),
+            bytecode_hash(&[0xff, 0xff, 1])
+        );
+        assert_ne!(
+            bytecode_hash(&[0xff, 0xff, 1]),
+            bytecode_hash(&[0xff, 0xff, 2])
+        );
+    }
+}
@@ -30,6 +30,7 @@ pub mod op {
         LOAD_TRUE(0),
         LOAD_FALSE(0),
         LOAD_CONST(1),  // constant index
+        LOAD_CONST_WIDE(2),  // constant index as u16, for constants after the first 256
         LOAD_SMALL_INT(1),  // small int value (0-255)
 
         // Integer operators
@@ -70,32 +71,41 @@ pub mod op {
         // args: total tuple size, offset to extract, size to extract
         GET_TUPLE_ITEM(3),
 
-        JUMP(2),  // relative position as u16
-        JUMP_BACK(2),  // relative position as u16
-        JUMP_IF_FALSE(2),  // relative position as u16
+        JUMP(4),  // relative position as u32
+        JUMP_BACK(4),  // relative position as u32
+        JUMP_IF_FALSE(4),  // relative position as u32
 
-        CALL(3),  // locals size, call position as u16
-        CALL_STD(3),  // locals size, call position as u16
+        CALL(5),  // locals size, call position as u32
+        CALL_STD(3),  // locals size, return size, index of std function
         RETURN(1),  // size of return value
 
-        ALLOCATE(1),  // object type index
-        SET_OBJ_FIELD(2),  // offset from pointer, size
-        GET_OBJ_FIELD(2), // offset from pointer, size
+        ALLOCATE(2),  // object type index as u16
+        SET_OBJ_FIELD(3),  // offset from pointer as u16, size
+        GET_OBJ_FIELD(3), // offset from pointer as u16, size
 
-        ALLOCATE_LIST(2),  // list_item_type, initial_list_size
-        // no operands, because list pointer is on the stack,
-        // and list index value is calculated on stack as well
-        GET_LIST_ITEM(0),
+        ALLOCATE_LIST(4),  // list_item_type as u16, initial_list_size as u16
+        // list pointer and list index are on the stack, so only size of the item is needed
+        GET_LIST_ITEM(1),
         SET_LIST_ITEM(2),  // offset from pointer, size of value to set
 
         // ACTIVE-RELATED OPCODES
-        SPAWN(3),  // type index, call position (u16)
+        SPAWN(6),  // type index (u16), call position (u32)
+        SPAWN_REMOTE(6),  // same as SPAWN, name of the node is on the stack
         CURRENT_ACTIVE(0),
-        GET_CURRENT_ACTIVE_FIELD(2), // offset from pointer, size
-        SET_CURRENT_ACTIVE_FIELD(2), // offset from pointer, size
-        SEND_MESSAGE(2),  // function_pos (u16),   active object ptr is on the stack
+        GET_CURRENT_ACTIVE_FIELD(3), // offset from pointer as u16, size
+        SET_CURRENT_ACTIVE_FIELD(3), // offset from pointer as u16, size
+        SEND_MESSAGE(4),  // function_pos (u32),   active object ptr is on the stack
+        ASK_MESSAGE(4),  // same as SEND_MESSAGE, but pushes future for the return value
+        SEND_MESSAGE_AFTER(4),  // same as SEND_MESSAGE, delay in milliseconds is on top of the stack
+        WAIT_FUTURE(1),  // size of the value, future is on the stack, replaced with its value when it is ready
+        STOP_ACTIVE(0),  // active object is on the stack, it stops after messages sent before
+        WAIT_ACTIVE(0),  // active object is on the stack, blocks until it is stopped
     );
 
+    pub fn is_known(opcode: u8) -> bool {
+        (opcode as usize) < OPCODES_INFO.len()
+    }
+
     pub fn get_args_num(opcode: u8) -> usize {
         OPCODES_INFO[opcode as usize].0 as usize
     }
@@ -108,4 +118,7 @@ pub mod op {
     pub const CONST_INT_FLAG: u8 = 1;    
     pub const CONST_FLOAT_FLAG: u8 = 2;
     pub const CONST_STRING_FLAG: u8 = 3;
+
+    // Pointer map entry with this bit is an active object handle, not a heap pointer
+    pub const ACTIVE_HANDLE_FLAG: u16 = 1 << 15;
 }
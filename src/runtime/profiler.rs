@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use super::heap::{Allocations, Heap};
+use super::metadata::Metadata;
+use super::opcodes::op;
+use super::stdlib_runners::STD_RAW_FUNCTION_RUNNERS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Callee {
+    Function(usize), // position of the function in bytecode
+    Std(usize),      // index of std function runner
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct FunctionStats {
+    calls: u64,
+    inclusive: Duration, // time of the function with all functions it calls, once for recursion
+    exclusive: Duration, // time spent in the function itself
+}
+
+struct OpenCall {
+    callee: Callee,
+    started: Instant,
+    children: Duration,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ActorStats {
+    item_type: Option<usize>, // None for the entry
+    messages: u64,
+    peak_heap: usize,
+}
+
+/// Statistics of executed code. Each active object collects its own profile while it runs
+/// messages, and the VM merges them into the one reported on exit
+pub struct Profile {
+    opcodes: Vec<u64>,
+    functions: HashMap<Callee, FunctionStats>,
+    open_calls: Vec<OpenCall>,
+    open_depths: HashMap<Callee, usize>, // amount of open calls of each function
+    allocations: Allocations,
+    actors: BTreeMap<Option<u64>, ActorStats>, // entry is None, so it goes first
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            opcodes: vec![0; 256],
+            functions: HashMap::new(),
+            open_calls: vec![],
+            open_depths: HashMap::new(),
+            allocations: Allocations::default(),
+            actors: BTreeMap::new(),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Profile {
+    pub fn count_opcode(&mut self, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+    }
+
+    pub fn enter(&mut self, callee: Callee, now: Instant) {
+        self.open_calls
+            .push(OpenCall { callee, started: now, children: Duration::ZERO });
+        *self.open_depths.entry(callee).or_default() += 1;
+    }
+
+    pub fn exit(&mut self, now: Instant) {
+        let Some(call) = self.open_calls.pop() else {
+            return;
+        };
+        let elapsed = now.duration_since(call.started);
+        let depth = self.open_depths.get_mut(&call.callee).unwrap();
+        *depth -= 1;
+        let stats = self.functions.entry(call.callee).or_default();
+        stats.calls += 1;
+        stats.exclusive += elapsed.saturating_sub(call.children);
+        // Outer call of the recursive function already includes time of inner ones
+        if *depth == 0 {
+            stats.inclusive += elapsed;
+        }
+        if let Some(caller) = self.open_calls.last_mut() {
+            caller.children += elapsed;
+        }
+    }
+
+    /// Closes calls of the failed message, that never return
+    pub fn exit_all(&mut self, now: Instant) {
+        while !self.open_calls.is_empty() {
+            self.exit(now);
+        }
+    }
+
+    /// Counts processed message and takes allocations made by the active object since
+    /// the previous one
+    pub fn record_message(
+        &mut self,
+        actor: Option<u64>,
+        item_type: Option<usize>,
+        heap: &mut Heap,
+    ) {
+        self.allocations.add(heap.take_allocations());
+        let stats = self.actors.entry(actor).or_default();
+        stats.item_type = item_type;
+        stats.messages += 1;
+        stats.peak_heap = stats.peak_heap.max(heap.peak_size());
+    }
+
+    /// Moves all statistics of the other profile into this one
+    pub fn merge(&mut self, other: &mut Profile) {
+        for (total, count) in self.opcodes.iter_mut().zip(other.opcodes.iter_mut()) {
+            *total += std::mem::take(count);
+        }
+        for (callee, other_stats) in other.functions.drain() {
+            let stats = self.functions.entry(callee).or_default();
+            stats.calls += other_stats.calls;
+            stats.inclusive += other_stats.inclusive;
+            stats.exclusive += other_stats.exclusive;
+        }
+        self.allocations.add(std::mem::take(&mut other.allocations));
+        for (actor, other_stats) in std::mem::take(&mut other.actors) {
+            let stats = self.actors.entry(actor).or_default();
+            stats.item_type = other_stats.item_type;
+            stats.messages += other_stats.messages;
+            stats.peak_heap = stats.peak_heap.max(other_stats.peak_heap);
+        }
+    }
+
+    fn callee_name(callee: Callee, metadata: &Metadata) -> &str {
+        match callee {
+            Callee::Function(pos) => metadata
+                .function_positions
+                .get(&pos)
+                .map_or("<unknown function>", |i| &metadata.function_names[*i]),
+            Callee::Std(index) => STD_RAW_FUNCTION_RUNNERS[index].0,
+        }
+    }
+
+    fn actor_name(actor: Option<u64>, stats: &ActorStats, metadata: &Metadata) -> String {
+        match (actor, stats.item_type) {
+            (Some(actor), Some(item_type)) => {
+                format!("{} #{}", metadata.types_names[item_type], actor)
+            }
+            _ => "entry".to_string(),
+        }
+    }
+
+    pub fn report(&self, metadata: &Metadata) -> String {
+        let mut functions: Vec<(&str, &FunctionStats)> = (self.functions.iter())
+            .map(|(callee, stats)| (Profile::callee_name(*callee, metadata), stats))
+            .collect();
+        functions.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(b.0)));
+        let actors: Vec<(String, &ActorStats)> = (self.actors.iter())
+            .map(|(actor, stats)| (Profile::actor_name(*actor, stats, metadata), stats))
+            .collect();
+
+        // Names are padded to the longest one, so columns of all tables are aligned
+        let width = (functions.iter().map(|(name, _)| name.len()))
+            .chain(actors.iter().map(|(name, _)| name.len()))
+            .fold(24, usize::max);
+
+        let mut report = String::new();
+        let total: u64 = self.opcodes.iter().sum();
+        writeln!(report, "Instructions executed: {}", total).unwrap();
+        let mut opcodes: Vec<(u8, u64)> = (0..=u8::MAX)
+            .map(|opcode| (opcode, self.opcodes[opcode as usize]))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (opcode, count) in opcodes {
+            let share = count as f64 * 100.0 / total as f64;
+            let name = op::get_display_name(opcode);
+            writeln!(
+                report,
+                "    {:<width$} {:>10} {:>13.2}%",
+                name, count, share
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            report,
+            "{:<width$}     {:>10} {:>14} {:>14}",
+            "Functions:", "calls", "inclusive ms", "exclusive ms"
+        )
+        .unwrap();
+        for (name, stats) in functions {
+            writeln!(
+                report,
+                "    {:<width$} {:>10} {:>14.3} {:>14.3}",
+                name,
+                stats.calls,
+                millis(stats.inclusive),
+                millis(stats.exclusive)
+            )
+            .unwrap();
+        }
+
+        let Allocations { strings, lists, objects } = self.allocations;
+        writeln!(
+            report,
+            "Allocations: {} strings, {} lists, {} objects",
+            strings, lists, objects
+        )
+        .unwrap();
+        let peak_heap = self.actors.values().map(|stats| stats.peak_heap).max();
+        writeln!(report, "Peak heap size: {} objects", peak_heap.unwrap_or(0)).unwrap();
+
+        writeln!(
+            report,
+            "{:<width$}     {:>10} {:>14}",
+            "Active objects:", "messages", "peak heap"
+        )
+        .unwrap();
+        for (name, stats) in actors {
+            writeln!(
+                report,
+                "    {:<width$} {:>10} {:>14}",
+                name, stats.messages, stats.peak_heap
+            )
+            .unwrap();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exclusive_time_excludes_calls() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut profile = Profile::default();
+        profile.enter(Callee::Function(10), at(0));
+        profile.enter(Callee::Function(20), at(2));
+        profile.enter(Callee::Std(0), at(3));
+        profile.exit(at(4));
+        profile.exit(at(7));
+        profile.enter(Callee::Function(20), at(8));
+        profile.exit_all(at(10));
+
+        let stats = |callee| profile.functions[&callee];
+        let ms = Duration::from_millis;
+        assert_eq!(
+            stats(Callee::Function(10)),
+            FunctionStats { calls: 1, inclusive: ms(10), exclusive: ms(3) }
+        );
+        assert_eq!(
+            stats(Callee::Function(20)),
+            FunctionStats { calls: 2, inclusive: ms(7), exclusive: ms(6) }
+        );
+        assert_eq!(
+            stats(Callee::Std(0)),
+            FunctionStats { calls: 1, inclusive: ms(1), exclusive: ms(1) }
+        );
+        assert!(profile.open_calls.is_empty());
+    }
+
+    #[test]
+    fn recursive_calls_are_included_once() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut profile = Profile::default();
+        profile.enter(Callee::Function(10), at(0));
+        profile.enter(Callee::Function(10), at(1));
+        profile.enter(Callee::Function(10), at(2));
+        profile.exit_all(at(4));
+
+        let stats = profile.functions[&Callee::Function(10)];
+        assert_eq!(stats.calls, 3);
+        assert_eq!(stats.inclusive, Duration::from_millis(4));
+        assert_eq!(stats.exclusive, Duration::from_millis(4));
+    }
+
+    #[test]
+    fn merged_profiles_are_summed() {
+        let metadata = Metadata {
+            types_names: vec!["main::Worker".into()],
+            types_sizes: vec![0],
+            function_names: vec!["main::main".into()],
+            function_positions: HashMap::from([(5, 0)]),
+            ..Default::default()
+        };
+        let mut total = Profile::default();
+
+        let mut heap = Heap::default();
+        heap.move_string("first".into());
+        heap.move_string("second".into());
+        heap.allocate_custom(0, &metadata);
+        let mut entry = Profile::default();
+        entry.count_opcode(op::RETURN);
+        entry.enter(Callee::Function(5), Instant::now());
+        entry.exit_all(Instant::now());
+        entry.record_message(None, None, &mut heap);
+        total.merge(&mut entry);
+
+        let mut worker = Profile::default();
+        let mut heap = Heap::default();
+        for _ in 0..2 {
+            worker.count_opcode(op::RETURN);
+            worker.record_message(Some(3), Some(0), &mut heap);
+        }
+        total.merge(&mut worker);
+        total.merge(&mut entry);
+
+        assert_eq!(total.opcodes[op::RETURN as usize], 3);
+        assert_eq!(total.functions[&Callee::Function(5)].calls, 1);
+        assert_eq!(
+            total.allocations,
+            Allocations { strings: 2, lists: 0, objects: 1 }
+        );
+
+        let report = total.report(&metadata);
+        assert!(report.contains("Instructions executed: 3\n"));
+        assert!(report.contains("\n    main::main "));
+        assert!(report.contains("Allocations: 2 strings, 0 lists, 1 objects\n"));
+        assert!(report.contains("Peak heap size: 3 objects\n"));
+        let actors: Vec<Vec<&str>> = report
+            .lines()
+            .skip_while(|line| !line.starts_with("Active objects:"))
+            .skip(1)
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            actors,
+            vec![vec!["entry", "1", "3"], vec!["main::Worker", "#3", "2", "0"]]
+        );
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub data: Vec<u8>,         // serialized function call
+    pub reply_to: Option<u64>, // future, that waits for the return value
+}
+
+/// What happens with a message sent to a full mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Block,      // sender is suspended until there is space, other objects run meanwhile
+    DropNewest, // sent message is dropped
+    DropOldest, // the oldest message in the mailbox is dropped to make space for the new one
+}
+
+impl OverflowPolicy {
+    pub const NAMES: [&'static str; 3] = ["block", "drop_newest", "drop_oldest"];
+
+    /// Names are the same in sources and in options, except that options use dashes
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.replace('-', "_").as_str() {
+            "block" => Some(OverflowPolicy::Block),
+            "drop_newest" => Some(OverflowPolicy::DropNewest),
+            "drop_oldest" => Some(OverflowPolicy::DropOldest),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OverflowPolicy::Block => "block",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::DropOldest => "drop_oldest",
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            OverflowPolicy::Block => 1,
+            OverflowPolicy::DropNewest => 2,
+            OverflowPolicy::DropOldest => 3,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(OverflowPolicy::Block),
+            2 => Some(OverflowPolicy::DropNewest),
+            3 => Some(OverflowPolicy::DropOldest),
+            _ => None,
+        }
+    }
+}
+
+/// Mailbox settings declared by active type, missing ones are taken from VM options
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MailboxConfig {
+    pub capacity: Option<usize>,
+    pub overflow: Option<OverflowPolicy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxLimit {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+/// Result of putting a message into the mailbox
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+    Schedule,          // active object has to be put into the run queue
+    Queued,            // active object is already in the run queue or is being run
+    Dropped(Message),  // mailbox is full, so this message is dropped by the overflow policy
+    Rejected(Message), // active object is stopped, so it takes no more messages
+}
+
+/// Messages of a single active object, waiting to be processed
+#[derive(Default)]
+pub struct Mailbox {
+    messages: VecDeque<Message>,
+    scheduled: bool, // active object is in the run queue or is being run right now
+
+    limit: Option<MailboxLimit>, // mailbox is unbounded if there is no limit
+    dropped: u64,
+
+    // Closed mailbox takes no more messages, the last one stops the active object
+    closed: bool,
+    finished: bool,
+
+    // Message, that waits for space in a full mailbox of other object, and whether it is the
+    // last one. Object with suspended message is not scheduled until it is woken up
+    suspended: Option<(Message, bool)>,
+    woken: bool, // woken up before the message got suspended, so it is retried right away
+
+    // Active objects, suspended until a message is taken from this mailbox
+    blocked_senders: Vec<u64>,
+
+    // Futures of other nodes, resolved once the active object is stopped
+    stop_waiters: Vec<u64>,
+}
+
+impl Mailbox {
+    pub fn bounded(limit: Option<MailboxLimit>) -> Self {
+        Self { limit, ..Self::default() }
+    }
+
+    /// Full mailbox with blocking policy must not be pushed to, until a message is popped.
+    /// Pushing anyway puts the message over capacity
+    pub fn push(&mut self, message: Message) -> Delivery {
+        if self.closed {
+            return Delivery::Rejected(message);
+        }
+        if let Some(limit) = self.limit.filter(|limit| self.messages.len() >= limit.capacity) {
+            match limit.overflow {
+                OverflowPolicy::Block => {}
+                OverflowPolicy::DropNewest => {
+                    self.dropped += 1;
+                    return Delivery::Dropped(message);
+                }
+                OverflowPolicy::DropOldest => {
+                    // Full mailbox is never empty, so it is already scheduled
+                    self.dropped += 1;
+                    self.messages.push_back(message);
+                    return Delivery::Dropped(self.messages.pop_front().unwrap());
+                }
+            }
+        }
+
+        self.push_unchecked(message)
+    }
+
+    /// Puts the last message, that stops the active object after messages already in the mailbox.
+    /// It is pushed even to a full mailbox, so stopping never blocks or gets dropped
+    pub fn close(&mut self, last_message: Message) -> Delivery {
+        if self.closed {
+            return Delivery::Rejected(last_message);
+        }
+        self.closed = true;
+        self.push_unchecked(last_message)
+    }
+
+    fn push_unchecked(&mut self, message: Message) -> Delivery {
+        self.messages.push_back(message);
+        if self.scheduled || self.suspended.is_some() {
+            return Delivery::Queued;
+        }
+        self.scheduled = true;
+        Delivery::Schedule
+    }
+
+    /// True if sender has to wait before pushing a message
+    pub fn blocks_sender(&self) -> bool {
+        self.limit.is_some_and(|limit| {
+            limit.overflow == OverflowPolicy::Block && self.messages.len() >= limit.capacity
+        })
+    }
+
+    /// Sender waits until a message is taken from this mailbox
+    pub fn add_blocked_sender(&mut self, sender: u64) {
+        self.blocked_senders.push(sender);
+    }
+
+    /// Senders to wake up, called after a message is taken from this mailbox
+    pub fn take_blocked_senders(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.blocked_senders)
+    }
+
+    pub fn pop(&mut self) -> Option<Message> {
+        self.messages.pop_front()
+    }
+
+    /// Message is continued by the next turn, instead of taking a new one.
+    /// Returns true if the active object is already woken up, so it has to be scheduled again
+    pub fn suspend(&mut self, message: Message, is_stopping: bool) -> bool {
+        self.suspended = Some((message, is_stopping));
+        self.scheduled = std::mem::take(&mut self.woken);
+        self.scheduled
+    }
+
+    /// Suspended message and whether it is the last one
+    pub fn take_suspended(&mut self) -> Option<(Message, bool)> {
+        self.suspended.take()
+    }
+
+    /// Returns true if the active object has to be put into the run queue to retry its message.
+    /// Object, that is not suspended yet, retries it as soon as it gets suspended
+    pub fn wake(&mut self) -> bool {
+        if self.suspended.is_none() {
+            self.woken = true;
+            return false;
+        }
+        self.scheduled = true;
+        true
+    }
+
+    /// True if the last popped message stops the active object
+    pub fn is_stopping(&self) -> bool {
+        self.closed && self.messages.is_empty()
+    }
+
+    pub fn finish(&mut self) {
+        self.finished = true;
+        self.scheduled = false;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns false if the active object is already stopped, so there is nothing to wait for
+    pub fn add_stop_waiter(&mut self, future: u64) -> bool {
+        if !self.finished {
+            self.stop_waiters.push(future);
+        }
+        !self.finished
+    }
+
+    /// Futures to resolve, called after the active object is stopped
+    pub fn take_stop_waiters(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.stop_waiters)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Called after a message is processed, returns true if the active object has to be
+    /// put back into the run queue to process the rest of messages
+    pub fn finish_turn(&mut self) -> bool {
+        self.scheduled = !self.messages.is_empty();
+        self.scheduled
+    }
+}
+
+/// Small PRNG (splitmix64), so the same seed always gives the same order of active objects
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number in `0..bound`, bound must not be 0
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+#[derive(Default)]
+struct RunQueue {
+    active_objects: VecDeque<u64>,
+    rng: Option<SeededRng>, // picks active objects in random order instead of FIFO
+
+    // Program is finished when every sent message is processed, as only messages produce new ones
+    messages_sent: u64,
+    messages_processed: u64,
+
+    stopped: bool, // no more messages are run after program is stopped
+}
+
+impl RunQueue {
+    fn is_idle(&self) -> bool {
+        self.messages_processed == self.messages_sent
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        match &mut self.rng {
+            Some(rng) if !self.active_objects.is_empty() => {
+                let index = rng.below(self.active_objects.len());
+                self.active_objects.remove(index)
+            }
+            _ => self.active_objects.pop_front(),
+        }
+    }
+}
+
+/// Queue of active objects with non-empty mailboxes, shared between worker threads.
+/// Active object appears in the queue at most once, so only one of its messages runs at a time
+#[derive(Default)]
+pub struct Scheduler {
+    queue: Mutex<RunQueue>,
+    has_work: Condvar,
+    became_idle: Condvar,
+}
+
+impl Scheduler {
+    /// Scheduler for a single thread, that picks the next active object with seeded PRNG
+    pub fn seeded(seed: u64) -> Self {
+        let scheduler = Self::default();
+        scheduler.queue.lock().unwrap().rng = Some(SeededRng::new(seed));
+        scheduler
+    }
+
+    pub fn schedule(&self, active_index: u64) {
+        self.queue.lock().unwrap().active_objects.push_back(active_index);
+        self.has_work.notify_one();
+    }
+
+    /// Blocks until there is an active object to run, or returns None if program is stopped
+    pub fn next(&self) -> Option<u64> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.stopped {
+                return None;
+            }
+            if let Some(active_index) = queue.pop() {
+                return Some(active_index);
+            }
+            queue = self.has_work.wait(queue).unwrap();
+        }
+    }
+
+    /// Same as `next`, but returns None instead of waiting for work
+    pub fn try_next(&self) -> Option<u64> {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.stopped {
+            true => None,
+            false => queue.pop(),
+        }
+    }
+
+    /// Must be called before the message is put into the mailbox
+    pub fn message_sent(&self) {
+        self.queue.lock().unwrap().messages_sent += 1;
+    }
+
+    /// Must be called after the message is run, and all messages it sent are counted
+    pub fn message_processed(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.messages_processed += 1;
+        if queue.is_idle() {
+            self.became_idle.notify_all();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.queue.lock().unwrap().stopped
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queue.lock().unwrap().is_idle()
+    }
+
+    pub fn stop(&self) {
+        self.queue.lock().unwrap().stopped = true;
+        self.has_work.notify_all();
+        self.became_idle.notify_all();
+    }
+
+    /// Blocks until all messages are processed, or program is stopped
+    pub fn wait_until_idle(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.is_idle() && !queue.stopped {
+            queue = self.became_idle.wait(queue).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(value: u8) -> Message {
+        Message { data: vec![value], reply_to: None }
+    }
+
+    #[test]
+    fn mailbox_is_scheduled_once() {
+        let mut mailbox = Mailbox::default();
+        assert_eq!(mailbox.push(message(1)), Delivery::Schedule);
+        assert_eq!(mailbox.push(message(2)), Delivery::Queued);
+
+        assert_eq!(mailbox.pop(), Some(message(1)));
+        assert!(mailbox.finish_turn());
+        assert_eq!(mailbox.push(message(3)), Delivery::Queued);
+
+        assert_eq!(mailbox.pop(), Some(message(2)));
+        assert!(mailbox.finish_turn());
+        assert_eq!(mailbox.pop(), Some(message(3)));
+        assert!(!mailbox.finish_turn());
+
+        assert_eq!(mailbox.push(message(4)), Delivery::Schedule);
+    }
+
+    #[test]
+    fn full_mailbox_drops_by_policy() {
+        let limit = |overflow| Some(MailboxLimit { capacity: 2, overflow });
+
+        let mut newest = Mailbox::bounded(limit(OverflowPolicy::DropNewest));
+        newest.push(message(1));
+        newest.push(message(2));
+        assert_eq!(newest.push(message(3)), Delivery::Dropped(message(3)));
+        assert_eq!(newest.pop(), Some(message(1)));
+        assert_eq!(newest.push(message(4)), Delivery::Queued);
+        assert_eq!(newest.dropped(), 1);
+
+        let mut oldest = Mailbox::bounded(limit(OverflowPolicy::DropOldest));
+        oldest.push(message(1));
+        oldest.push(message(2));
+        assert_eq!(oldest.push(message(3)), Delivery::Dropped(message(1)));
+        assert_eq!(oldest.push(message(4)), Delivery::Dropped(message(2)));
+        assert_eq!(oldest.pop(), Some(message(3)));
+        assert_eq!(oldest.pop(), Some(message(4)));
+        assert_eq!(oldest.dropped(), 2);
+
+        let mut blocking = Mailbox::bounded(limit(OverflowPolicy::Block));
+        blocking.push(message(1));
+        assert!(!blocking.blocks_sender());
+        blocking.push(message(2));
+        assert!(blocking.blocks_sender());
+        assert_eq!(blocking.push(message(3)), Delivery::Queued);
+        assert_eq!(blocking.dropped(), 0);
+        blocking.pop();
+        blocking.pop();
+        assert!(!blocking.blocks_sender());
+    }
+
+    #[test]
+    fn suspended_mailbox_waits_to_be_woken() {
+        let mut mailbox = Mailbox::default();
+        assert_eq!(mailbox.push(message(1)), Delivery::Schedule);
+        let first = mailbox.pop().unwrap();
+        assert!(!mailbox.suspend(first, false));
+
+        // New messages wait for the suspended one
+        assert_eq!(mailbox.push(message(2)), Delivery::Queued);
+        assert!(mailbox.wake());
+        assert_eq!(mailbox.take_suspended(), Some((message(1), false)));
+        assert!(mailbox.finish_turn());
+
+        // Object woken up while it still runs retries its message right away
+        assert_eq!(mailbox.pop(), Some(message(2)));
+        assert!(!mailbox.wake());
+        assert!(mailbox.suspend(message(2), false));
+        assert_eq!(mailbox.take_suspended(), Some((message(2), false)));
+        assert!(!mailbox.finish_turn());
+
+        mailbox.add_blocked_sender(4);
+        mailbox.add_blocked_sender(5);
+        assert_eq!(mailbox.take_blocked_senders(), vec![4, 5]);
+        assert_eq!(mailbox.take_blocked_senders(), vec![]);
+    }
+
+    #[test]
+    fn closed_mailbox_drains_and_rejects() {
+        let limit = Some(MailboxLimit { capacity: 1, overflow: OverflowPolicy::DropNewest });
+        let mut mailbox = Mailbox::bounded(limit);
+        assert_eq!(mailbox.push(message(1)), Delivery::Schedule);
+
+        // Last message is taken even by a full mailbox
+        assert_eq!(mailbox.close(message(2)), Delivery::Queued);
+        assert_eq!(mailbox.push(message(3)), Delivery::Rejected(message(3)));
+        assert_eq!(mailbox.close(message(4)), Delivery::Rejected(message(4)));
+        assert_eq!(mailbox.dropped(), 0);
+
+        assert_eq!(mailbox.pop(), Some(message(1)));
+        assert!(!mailbox.is_stopping());
+        assert!(mailbox.finish_turn());
+        assert_eq!(mailbox.pop(), Some(message(2)));
+        assert!(mailbox.is_stopping());
+        assert!(mailbox.add_stop_waiter(7));
+
+        mailbox.finish();
+        assert!(mailbox.is_finished());
+        assert!(!mailbox.finish_turn());
+        assert_eq!(mailbox.take_stop_waiters(), vec![7]);
+        assert!(!mailbox.add_stop_waiter(8));
+    }
+
+    #[test]
+    fn overflow_policy_names() {
+        for name in OverflowPolicy::NAMES {
+            let policy = OverflowPolicy::from_name(name).unwrap();
+            assert_eq!(policy.name(), name);
+            assert_eq!(OverflowPolicy::from_byte(policy.to_byte()), Some(policy));
+        }
+        assert_eq!(
+            OverflowPolicy::from_name("drop-oldest"),
+            Some(OverflowPolicy::DropOldest)
+        );
+        assert_eq!(OverflowPolicy::from_name("drop"), None);
+        assert_eq!(OverflowPolicy::from_byte(0), None);
+    }
+
+    #[test]
+    fn scheduler_is_idle_when_all_messages_processed() {
+        let scheduler = Scheduler::default();
+        assert!(scheduler.is_idle());
+
+        scheduler.message_sent();
+        scheduler.schedule(3);
+        scheduler.message_sent();
+        scheduler.schedule(5);
+        assert!(!scheduler.is_idle());
+
+        assert_eq!(scheduler.next(), Some(3));
+        assert_eq!(scheduler.next(), Some(5));
+        scheduler.message_processed();
+        assert!(!scheduler.is_idle());
+
+        // Message in progress sends another one, so the program goes on
+        scheduler.message_sent();
+        scheduler.message_processed();
+        assert!(!scheduler.is_idle());
+
+        scheduler.message_processed();
+        assert!(scheduler.is_idle());
+        scheduler.wait_until_idle();
+    }
+
+    #[test]
+    fn waiting_for_messages_from_other_threads() {
+        let scheduler = std::sync::Arc::new(Scheduler::default());
+        for _ in 0..100 {
+            scheduler.message_sent();
+        }
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let scheduler = scheduler.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        scheduler.message_processed();
+                    }
+                })
+            })
+            .collect();
+
+        scheduler.wait_until_idle();
+        assert!(scheduler.is_idle());
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+    }
+
+    #[test]
+    fn stopped_scheduler_gives_no_work() {
+        let scheduler = std::sync::Arc::new(Scheduler::default());
+        let worker = {
+            let scheduler = scheduler.clone();
+            std::thread::spawn(move || scheduler.next())
+        };
+
+        scheduler.message_sent();
+        scheduler.stop();
+        scheduler.schedule(1);
+
+        // Pending message does not prevent stopped program from finishing
+        scheduler.wait_until_idle();
+        assert!(!scheduler.is_idle());
+        assert_eq!(worker.join().unwrap(), None);
+    }
+
+    #[test]
+    fn seeded_scheduler_order_is_reproducible() {
+        let order = |seed| {
+            let scheduler = Scheduler::seeded(seed);
+            (0..10).for_each(|i| scheduler.schedule(i));
+            (0..10).map(|_| scheduler.try_next().unwrap()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(order(7), order(7));
+        assert_ne!(order(7), order(8));
+        assert_ne!(order(7), (0..10).collect::<Vec<_>>());
+
+        let mut sorted = order(7);
+        sorted.sort();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+        assert_eq!(Scheduler::seeded(7).try_next(), None);
+    }
+}
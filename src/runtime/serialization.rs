@@ -1,13 +1,168 @@
 use std::collections::HashMap;
-
-use crate::runtime::heap::{CustomObject, List};
+use std::fmt;
 
 use super::heap::{Heap, HeapObject};
-use super::metadata::Metadata;
+use super::metadata::{Metadata, PointerKind};
+
+/*
+Message wire format, sizes are unsigned LEB128 varints:
+ - format version (1 byte)
+ - function position
+ - values, each word is a zigzag varint, so small negative ints stay short as well.
+   Pointers are replaced with 1-based indexes of heap objects in the message, 0 is nil
+ - amount of heap objects, then each object starts with a tag byte:
+    - string: length in bytes + UTF-8 bytes
+    - list: list kind, amount of items + words of all items
+    - custom object: type index + words of its fields
+Heap object reachable from several places is packed only once, so shared sub-objects
+and cycles are restored as they were.
+*/
+
+pub const WIRE_FORMAT_VERSION: u8 = 1;
+
+const STRING_TAG: u8 = 1;
+const LIST_TAG: u8 = 2;
+const CUSTOM_OBJECT_TAG: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum WireFormatError {
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    TrailingBytes,
+    VarintOverflow,
+    InvalidUtf8,
+    UnknownObjectTag(u8),
+    UnknownFunction(usize),
+    UnexpectedFunction(usize),
+    UnknownType(u64),
+    UnknownListKind(u64),
+    InvalidReference(u64),
+    MismatchedObject(u64),
+}
+
+impl fmt::Display for WireFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(v) => write!(f, "unsupported wire format version {}", v),
+            Self::UnexpectedEnd => write!(f, "message ends unexpectedly"),
+            Self::TrailingBytes => write!(f, "unexpected bytes after the end of message"),
+            Self::VarintOverflow => write!(f, "varint does not fit into 64 bits"),
+            Self::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            Self::UnknownObjectTag(tag) => write!(f, "unknown heap object tag {}", tag),
+            Self::UnknownFunction(pos) => write!(f, "no function at position {:#x}", pos),
+            Self::UnexpectedFunction(pos) => {
+                write!(f, "message is for another function at {:#x}", pos)
+            }
+            Self::UnknownType(t) => write!(f, "unknown type {}", t),
+            Self::UnknownListKind(k) => write!(f, "unknown list kind {}", k),
+            Self::InvalidReference(r) => write!(f, "reference to missing heap object {}", r),
+            Self::MismatchedObject(r) => {
+                write!(
+                    f,
+                    "heap object {} does not match the type of its reference",
+                    r
+                )
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct WireWriter {
+    bytes: Vec<u8>,
+}
+
+impl WireWriter {
+    fn push_byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn push_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    fn push_size(&mut self, size: usize) {
+        self.push_varint(size as u64);
+    }
+
+    fn push_words(&mut self, words: &[u64]) {
+        for word in words {
+            self.push_varint((word << 1) ^ ((*word as i64 >> 63) as u64));
+        }
+    }
+}
 
-pub const STRING_FLAG: u64 = 1 << 56;
-pub const LIST_FLAG: u64 = 2 << 56;
-pub const CUSTOM_OBJECT_FLAG: u64 = 4 << 56;
+struct WireReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> WireReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, WireFormatError> {
+        let byte = *self.bytes.get(self.position).ok_or(WireFormatError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, amount: usize) -> Result<&'a [u8], WireFormatError> {
+        if self.bytes.len() - self.position < amount {
+            return Err(WireFormatError::UnexpectedEnd);
+        }
+        self.position += amount;
+        Ok(&self.bytes[self.position - amount..self.position])
+    }
+
+    fn read_varint(&mut self) -> Result<u64, WireFormatError> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte()?;
+            if shift == 63 && byte > 1 {
+                return Err(WireFormatError::VarintOverflow);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_size(&mut self) -> Result<usize, WireFormatError> {
+        Ok(self.read_varint()? as usize)
+    }
+
+    fn read_words(&mut self, amount: usize) -> Result<Vec<u64>, WireFormatError> {
+        // Amount is not trusted, so memory grows only while there is data to read
+        let mut words = vec![];
+        for _ in 0..amount {
+            let zigzag = self.read_varint()?;
+            words.push((zigzag >> 1) ^ 0u64.wrapping_sub(zigzag & 1));
+        }
+        Ok(words)
+    }
+
+    fn read_header(&mut self) -> Result<usize, WireFormatError> {
+        let version = self.read_byte()?;
+        if version != WIRE_FORMAT_VERSION {
+            return Err(WireFormatError::UnsupportedVersion(version));
+        }
+        self.read_size()
+    }
+}
+
+/// Function, that is called by the message
+pub fn read_function_position(chunk: &[u8]) -> Result<usize, WireFormatError> {
+    WireReader::new(chunk).read_header()
+}
 
 pub fn serialize_function_args(
     function_pos: usize,
@@ -15,217 +170,727 @@ pub fn serialize_function_args(
     stack_pointer: &mut usize,
     heap: &Heap,
     metadata: &Metadata,
-) -> Vec<u64> {
-    // TODO: active objects should not be copied when serializing
+) -> Vec<u8> {
     let func_index = metadata.function_positions[&function_pos];
     let args_size = metadata.function_args_sizes[func_index];
 
     *stack_pointer -= args_size;
-    // println!("Serializing {:?} for locals size {}", &stack[..10], locals_size);
-
-    let mut chunk: Vec<u64> = vec![function_pos as u64];
-    chunk.extend(stack.iter().skip(*stack_pointer).take(args_size));
-    let mut pointers_to_pack: HashMap<u64, usize> = HashMap::new();
-    let mut pointers_order: Vec<u64> = vec![];
-    let mut processed_amount = 0;
-    // println!("Initial chunk {} {:?}", args_size, chunk);
-
-    // Prepare initial pointers for packing
-    // Stack will not be used anymore as all the processing after this cycle is just
-    // heap-data packing
-    for pointer_index in metadata.functions_pointer_mapping[func_index].iter() {
-        let heap_pointer = chunk[*pointer_index + 1];
-        if heap_pointer == 0 {
-            continue;
-        }
-
-        let pos = match pointers_to_pack.get(&heap_pointer) {
-            Some(pos) => *pos,
-            None => {
-                let pos = pointers_to_pack.len() + 1;
-                pointers_to_pack.insert(heap_pointer, pos);
-                pointers_order.push(heap_pointer);
-                pos
-            }
-        };
-        chunk[*pointer_index + 1] = pos as u64;
+
+    let args = &stack[*stack_pointer..*stack_pointer + args_size];
+    let pointer_map = &metadata.functions_pointer_mapping[func_index];
+    serialize_values(function_pos, args, pointer_map, heap, metadata)
+}
+
+/// Message of a function without arguments, used to start the entry
+pub fn serialize_call_without_args(function_pos: usize) -> Vec<u8> {
+    serialize_values(
+        function_pos,
+        &[],
+        &[],
+        &Heap::default(),
+        &Metadata::default(),
+    )
+}
+
+/// Message of a function that takes only strings, used to notify supervisors
+pub fn serialize_call_with_strings(function_pos: usize, args: Vec<String>) -> Vec<u8> {
+    let mut heap = Heap::default();
+    let values: Vec<u64> = args.into_iter().map(|s| heap.move_string(s).0).collect();
+    let pointer_map: Vec<usize> = (0..values.len()).collect();
+    serialize_values(
+        function_pos,
+        &values,
+        &pointer_map,
+        &heap,
+        &Metadata::default(),
+    )
+}
+
+/// Return value is packed the same way as arguments, so it can be sent back to the caller
+pub fn serialize_return_value(
+    function_pos: usize,
+    value: &[u64],
+    heap: &Heap,
+    metadata: &Metadata,
+) -> Vec<u8> {
+    let func_index = metadata.function_positions[&function_pos];
+    let pointer_map = &metadata.functions_return_pointer_mapping[func_index];
+    serialize_values(function_pos, value, pointer_map, heap, metadata)
+}
+
+/// Offsets of pointers in the data of heap object
+fn get_object_pointers(object: &HeapObject, metadata: &Metadata) -> Vec<usize> {
+    match object {
+        HeapObject::String(_) => vec![],
+        HeapObject::List(l) => {
+            let item_map = &metadata.lists_pointer_mapping[l.list_item_type];
+            (0..l.items_amount)
+                .flat_map(|i| item_map.iter().map(move |pos| pos + l.item_size * i))
+                .collect()
+        }
+        HeapObject::CustomObject(obj) => {
+            metadata.types_pointer_mapping[obj.type_index as usize].clone()
+        }
     }
-    // println!("processed {} to pack {:?}", processed_amount, pointers_to_pack);
+}
 
-    while processed_amount < pointers_order.len() {
-        let pointer = pointers_order[processed_amount];
-        // println!("processing {}", pointer);
-        processed_amount += 1;
+/// Numbers heap objects in order of their discovery, each object gets a single index
+#[derive(Default)]
+struct ObjectsToPack {
+    indexes: HashMap<u64, u64>,
+    order: Vec<u64>,
+}
 
-        let heap_object = heap.get(pointer);
+impl ObjectsToPack {
+    fn replace_pointers(&mut self, data: &mut [u64], pointer_map: &[usize]) {
+        for offset in pointer_map {
+            let heap_pointer = data[*offset];
+            if heap_pointer == 0 {
+                continue;
+            }
 
-        chunk.push(serialize_heap_object_header(heap_object));
-        let object_start = chunk.len();
+            let next_index = self.order.len() as u64 + 1;
+            let index = *self.indexes.entry(heap_pointer).or_insert(next_index);
+            if index == next_index {
+                self.order.push(heap_pointer);
+            }
+            data[*offset] = index;
+        }
+    }
+}
+
+// Active object handles are not in pointer maps, so they are copied as is and the
+// receiver refers to the same active objects as the sender
+fn serialize_values(
+    function_pos: usize,
+    values: &[u64],
+    pointer_map: &[usize],
+    heap: &Heap,
+    metadata: &Metadata,
+) -> Vec<u8> {
+    let mut to_pack = ObjectsToPack::default();
+    let mut values = values.to_vec();
+    to_pack.replace_pointers(&mut values, pointer_map);
+
+    // Objects may discover new ones, so the list of objects to pack grows while packing
+    let mut objects = WireWriter::default();
+    let mut packed_amount = 0;
+    while packed_amount < to_pack.order.len() {
+        let heap_object = heap.get(to_pack.order[packed_amount]);
+        packed_amount += 1;
 
-        let pointer_map: Vec<usize> = match heap_object {
+        let mut data = match heap_object {
             HeapObject::String(s) => {
-                // TODO: this needs to be refactored to make chars "tighter"
-                chunk.extend(s.chars().map(|c| c as u64));
-                vec![]
+                objects.push_byte(STRING_TAG);
+                objects.push_size(s.len());
+                objects.bytes.extend(s.as_bytes());
+                continue;
             }
             HeapObject::List(l) => {
-                // TODO: make this faster
-                chunk.extend(l.data.iter());
-                let item_map = &metadata.lists_pointer_mapping[l.list_item_type];
-                let mut res = vec![];
-                for i in 0..l.items_amount {
-                    for pos in item_map {
-                        res.push(pos + l.item_size * i);
-                    }
-                }
-                res
+                objects.push_byte(LIST_TAG);
+                objects.push_size(l.list_item_type);
+                objects.push_size(l.items_amount);
+                l.data.clone()
             }
             HeapObject::CustomObject(obj) => {
-                chunk.extend(obj.data.iter());
-                metadata.types_pointer_mapping[obj.type_index as usize].clone()
+                objects.push_byte(CUSTOM_OBJECT_TAG);
+                objects.push_varint(obj.type_index);
+                obj.data.clone()
             }
         };
-        // println!("Pointer map {:?}", pointer_map);
+        to_pack.replace_pointers(&mut data, &get_object_pointers(heap_object, metadata));
+        objects.push_words(&data);
+    }
 
-        for offset in pointer_map.iter().map(|i| *i + object_start) {
-            if chunk[offset] == 0 {
-                continue;
-            }
-            let heap_pointer = chunk[offset];
+    let mut chunk = WireWriter::default();
+    chunk.push_byte(WIRE_FORMAT_VERSION);
+    chunk.push_size(function_pos);
+    chunk.push_words(&values);
+    chunk.push_size(packed_amount);
+    chunk.bytes.extend(objects.bytes);
+    chunk.bytes
+}
 
-            let pos = match pointers_to_pack.get(&heap_pointer) {
-                Some(pos) => *pos,
-                None => {
-                    let pos = pointers_to_pack.len() + 1;
-                    pointers_to_pack.insert(heap_pointer, pos);
-                    pointers_order.push(heap_pointer);
-                    pos
-                }
-            };
-            chunk[offset] = pos as u64;
-        }
+pub fn deserialize_function_args(
+    function_pos: usize,
+    stack: &mut [u64],
+    stack_pointer: &mut usize,
+    heap: &mut Heap,
+    metadata: &Metadata,
+    chunk: &[u8],
+) -> Result<(), WireFormatError> {
+    let mut reader = WireReader::new(chunk);
+    let message_function = reader.read_header()?;
+    if message_function != function_pos {
+        return Err(WireFormatError::UnexpectedFunction(message_function));
     }
-    // println!("  chunk is {:?}", chunk);
-    chunk
+
+    let func_index = *metadata
+        .function_positions
+        .get(&function_pos)
+        .ok_or(WireFormatError::UnknownFunction(function_pos))?;
+    let args_size = metadata.function_args_sizes[func_index];
+    let pointer_map = &metadata.functions_pointer_mapping[func_index];
+    let pointer_kinds = &metadata.functions_pointer_kinds[func_index];
+
+    let args = deserialize_values(
+        &mut reader,
+        args_size,
+        (pointer_map, pointer_kinds),
+        heap,
+        metadata,
+    )?;
+    stack[*stack_pointer..*stack_pointer + args_size].copy_from_slice(&args);
+    *stack_pointer += args_size;
+    Ok(())
 }
 
-fn serialize_heap_object_header(obj: &HeapObject) -> u64 {
-    let obj_header: u64;
+pub fn deserialize_return_value(
+    chunk: &[u8],
+    heap: &mut Heap,
+    metadata: &Metadata,
+) -> Result<Vec<u64>, WireFormatError> {
+    let mut reader = WireReader::new(chunk);
+    let function_pos = reader.read_header()?;
+    let func_index = *metadata
+        .function_positions
+        .get(&function_pos)
+        .ok_or(WireFormatError::UnknownFunction(function_pos))?;
+    let return_size = metadata.function_return_sizes[func_index];
+    let pointer_map = &metadata.functions_return_pointer_mapping[func_index];
+    let pointer_kinds = &metadata.functions_return_pointer_kinds[func_index];
+    deserialize_values(
+        &mut reader,
+        return_size,
+        (pointer_map, pointer_kinds),
+        heap,
+        metadata,
+    )
+}
 
-    match obj {
-        HeapObject::String(s) => {
-            obj_header = s.len() as u64 | STRING_FLAG;
-        }
+/// Kinds of objects, that pointers of the heap object refer to, in the order of its pointers
+fn get_object_pointer_kinds(object: &HeapObject, metadata: &Metadata) -> Vec<PointerKind> {
+    match object {
+        HeapObject::String(_) => vec![],
         HeapObject::List(l) => {
-            // println!("Serializing {} {}", l.list_item_type, l.items_amount);
-            obj_header = (l.list_item_type as u64) << 32 | (l.items_amount as u64) | LIST_FLAG;
+            metadata.lists_pointer_kinds[l.list_item_type].repeat(l.items_amount)
         }
         HeapObject::CustomObject(obj) => {
-            obj_header = obj.type_index | CUSTOM_OBJECT_FLAG;
+            metadata.types_pointer_kinds[obj.type_index as usize].clone()
         }
     }
-    obj_header
 }
 
-pub fn deserialize_function_args(
-    function_pos: usize,
-    stack: &mut [u64],
-    stack_pointer: &mut usize,
+// Each restored pointer must refer to the object of the kind its type expects, otherwise
+// the receiver would take e.g. a string for a list
+fn deserialize_values(
+    reader: &mut WireReader,
+    values_size: usize,
+    (pointer_map, pointer_kinds): (&[usize], &[PointerKind]),
     heap: &mut Heap,
     metadata: &Metadata,
-    chunk: &Vec<u64>,
-) {
-    assert_eq!(chunk[0], function_pos as u64, "wrong function extracted");
-    // println!("deserializing {:?}", chunk);
+) -> Result<Vec<u64>, WireFormatError> {
+    let mut values = reader.read_words(values_size)?;
 
-    let func_index = metadata.function_positions[&function_pos];
-    let args_size = metadata.function_args_sizes[func_index];
+    // Pointers inside of objects are filled after all of them are allocated
+    let objects_amount = reader.read_size()?;
+    let mut objects: Vec<(u64, PointerKind)> = vec![];
+    for _ in 0..objects_amount {
+        let object = match reader.read_byte()? {
+            STRING_TAG => {
+                let length = reader.read_size()?;
+                let bytes = reader.read_bytes(length)?;
+                let s = std::str::from_utf8(bytes).map_err(|_| WireFormatError::InvalidUtf8)?;
+                (heap.move_string(s.to_string()).0, PointerKind::String)
+            }
+            LIST_TAG => {
+                let list_kind = reader.read_varint()?;
+                let item_size = *metadata
+                    .list_types_sizes
+                    .get(list_kind as usize)
+                    .ok_or(WireFormatError::UnknownListKind(list_kind))?;
+                let items_amount = reader.read_size()?;
+                let data = reader.read_words(items_amount.saturating_mul(item_size))?;
+                let (pointer, _) =
+                    heap.allocate_list(list_kind as usize, items_amount, &data, metadata);
+                (pointer, PointerKind::List(list_kind as usize))
+            }
+            CUSTOM_OBJECT_TAG => {
+                let type_index = reader.read_varint()?;
+                let size = *metadata
+                    .types_sizes
+                    .get(type_index as usize)
+                    .ok_or(WireFormatError::UnknownType(type_index))?;
+                let data = reader.read_words(size)?;
+                let (pointer, obj) = heap.allocate_custom(type_index as usize, metadata);
+                obj.data.copy_from_slice(&data);
+                (pointer, PointerKind::Object(type_index as usize))
+            }
+            tag => return Err(WireFormatError::UnknownObjectTag(tag)),
+        };
+        objects.push(object);
+    }
+    if reader.position != reader.bytes.len() {
+        return Err(WireFormatError::TrailingBytes);
+    }
+
+    let restore_pointers = |data: &mut [u64], pointer_map: &[usize], kinds: &[PointerKind]| {
+        for (offset, expected_kind) in pointer_map.iter().zip(kinds) {
+            let index = data[*offset];
+            if index == 0 {
+                continue;
+            }
+            let (pointer, kind) = objects
+                .get(index as usize - 1)
+                .ok_or(WireFormatError::InvalidReference(index))?;
+            if kind != expected_kind {
+                return Err(WireFormatError::MismatchedObject(index));
+            }
+            data[*offset] = *pointer;
+        }
+        Ok(())
+    };
 
-    for i in 0..args_size {
-        stack[*stack_pointer + i] = chunk[i + 1];
+    restore_pointers(&mut values, pointer_map, pointer_kinds)?;
+    for (pointer, _) in objects.iter() {
+        let object = heap.get(*pointer);
+        let object_pointers = get_object_pointers(object, metadata);
+        let object_kinds = get_object_pointer_kinds(object, metadata);
+        match heap.get_mut(*pointer) {
+            HeapObject::String(_) => {}
+            HeapObject::List(l) => restore_pointers(&mut l.data, &object_pointers, &object_kinds)?,
+            HeapObject::CustomObject(obj) => {
+                restore_pointers(&mut obj.data, &object_pointers, &object_kinds)?
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    const FUNCTION_POS: usize = 16;
+
+    fn test_metadata() -> Metadata {
+        // type 0: (Int, String, Type1?) -> (int, pointer, flag, pointer), type 1: ([Int], Type0)
+        // list kind 0: [Int], list kind 1: [Type0], list kind 2: [(String, Int)]
+        // function at 16: (Int, Type0, [(String, Int)], String), returns (String, Int)
+        let mut metadata = Metadata {
+            types_sizes: vec![4, 2],
+            types_pointer_mapping: vec![vec![1, 3], vec![0, 1]],
+            types_pointer_kinds: vec![
+                vec![PointerKind::String, PointerKind::Object(1)],
+                vec![PointerKind::List(0), PointerKind::Object(0)],
+            ],
+            list_types_sizes: vec![1, 1, 2],
+            lists_pointer_mapping: vec![vec![], vec![0], vec![0]],
+            lists_pointer_kinds: vec![
+                vec![],
+                vec![PointerKind::Object(0)],
+                vec![PointerKind::String],
+            ],
+            function_args_sizes: vec![4],
+            functions_pointer_mapping: vec![vec![1, 2, 3]],
+            functions_pointer_kinds: vec![vec![
+                PointerKind::Object(0),
+                PointerKind::List(2),
+                PointerKind::String,
+            ]],
+            function_return_sizes: vec![2],
+            functions_return_pointer_mapping: vec![vec![0]],
+            functions_return_pointer_kinds: vec![vec![PointerKind::String]],
+            ..Default::default()
+        };
+        metadata.function_positions.insert(FUNCTION_POS, 0);
+        metadata
+    }
+
+    fn serialize_args(args: &[u64], heap: &Heap, metadata: &Metadata) -> Vec<u8> {
+        let mut stack_pointer = args.len();
+        serialize_function_args(FUNCTION_POS, args, &mut stack_pointer, heap, metadata)
+    }
+
+    fn deserialize_args(
+        chunk: &[u8],
+        heap: &mut Heap,
+        metadata: &Metadata,
+    ) -> Result<Vec<u64>, WireFormatError> {
+        let mut stack = vec![0; 4];
+        let mut stack_pointer = 0;
+        deserialize_function_args(
+            FUNCTION_POS,
+            &mut stack,
+            &mut stack_pointer,
+            heap,
+            metadata,
+            chunk,
+        )?;
+        Ok(stack)
+    }
+
+    #[derive(Debug, Clone)]
+    enum NodeKind {
+        String(String),
+        List { kind: usize, items_amount: usize },
+        Object { type_index: usize },
+    }
+
+    impl NodeKind {
+        fn pointer_kind(&self) -> PointerKind {
+            match self {
+                NodeKind::String(_) => PointerKind::String,
+                NodeKind::List { kind, .. } => PointerKind::List(*kind),
+                NodeKind::Object { type_index } => PointerKind::Object(*type_index),
+            }
+        }
+    }
+
+    // Pointer slots refer to random nodes of the expected kind, so graphs have shared objects
+    // and cycles
+    #[derive(Debug, Clone)]
+    struct Node {
+        kind: NodeKind,
+        words: Vec<u64>,
+        refs: Vec<Option<Index>>,
+    }
+
+    fn node_strategy() -> impl Strategy<Value = Node> {
+        let kind = prop_oneof![
+            any::<String>().prop_map(NodeKind::String),
+            (0..3usize, 0..4usize)
+                .prop_map(|(kind, items_amount)| NodeKind::List { kind, items_amount }),
+            (0..2usize).prop_map(|type_index| NodeKind::Object { type_index }),
+        ];
+        let words = prop::collection::vec(any::<u64>(), 8);
+        let refs = prop::collection::vec(any::<Option<Index>>(), 8);
+        (kind, words, refs).prop_map(|(kind, words, refs)| Node { kind, words, refs })
+    }
+
+    fn fill_slots(
+        data: &mut [u64],
+        (pointer_map, kinds): (&[usize], &[PointerKind]),
+        node: &Node,
+        pointers: &[(u64, PointerKind)],
+    ) {
+        for (i, value) in data.iter_mut().enumerate() {
+            *value = match pointer_map.iter().position(|offset| *offset == i) {
+                Some(slot) => {
+                    let candidates: Vec<u64> = (pointers.iter())
+                        .filter(|(_, kind)| *kind == kinds[slot])
+                        .map(|(pointer, _)| *pointer)
+                        .collect();
+                    node.refs[i]
+                        .filter(|_| !candidates.is_empty())
+                        .map_or(0, |index| candidates[index.index(candidates.len())])
+                }
+                None => node.words[i],
+            };
+        }
     }
-    *stack_pointer += args_size;
 
-    let mut heap_pointers_to_fill: Vec<(u64, &Vec<usize>)> = vec![];
-
-    let mut heap_objects_mapping: HashMap<usize, u64> = HashMap::new();
-
-    let mut current_start = args_size + 1;
-    while current_start < chunk.len() {
-        let obj_header = chunk[current_start];
-
-        if (obj_header & STRING_FLAG) != 0 {
-            let string_length = (obj_header & !STRING_FLAG) as usize;
-            // TODO: check utf-8 probably
-            let parsed_string = chunk[current_start + 1..current_start + 1 + string_length]
-                .iter()
-                .map(|c| char::from_u32(*c as u32).unwrap())
-                .collect::<String>();
-            let (pos, _) = heap.move_string(parsed_string);
-            heap_objects_mapping.insert(heap_objects_mapping.len() + 1, pos);
-            current_start += 1 + string_length;
-        } else if (obj_header & LIST_FLAG) != 0 {
-            let obj_header = obj_header & !LIST_FLAG;
-            let list_type = obj_header >> 32;
-            let list_items_amount = (obj_header ^ (list_type << 32)) as usize; // TODO: improve this, 0fff or smth like this
-                                                                               // println!("Found list of {} items of type {}", list_items_amount, list_type);
-
-            let (pos, l) = heap.allocate_list(
-                list_type as usize,
-                list_items_amount,
-                &chunk[current_start + 1..],
-                metadata,
-            );
-            heap_objects_mapping.insert(heap_objects_mapping.len() + 1, pos);
-            current_start += 1 + l.data.len();
-
-            heap_pointers_to_fill.push((pos, &metadata.lists_pointer_mapping[list_type as usize]));
-        } else if (obj_header & CUSTOM_OBJECT_FLAG) != 0 {
-            let obj_type = obj_header & !CUSTOM_OBJECT_FLAG;
-            let (pos, new_obj) = heap.allocate_custom(obj_type as usize, metadata);
-            let serialized_obj_data = &chunk[current_start + 1..][..new_obj.data.len()];
-            new_obj.data.clone_from_slice(serialized_obj_data);
-            heap_objects_mapping.insert(heap_objects_mapping.len() + 1, pos);
-            current_start += 1 + new_obj.data.len();
-
-            heap_pointers_to_fill.push((pos, &metadata.types_pointer_mapping[obj_type as usize]));
-        } else {
-            panic!("I have no idea what this {} flag is about..", obj_header);
-        }
-    }
-
-    // Now fill all the pointers
-    // Start with the stack
-    for pointer in metadata.functions_pointer_mapping[func_index].iter() {
-        let stack_value = stack[*pointer] as usize;
-        if stack_value != 0 {
-            stack[*pointer] = heap_objects_mapping[&stack_value];
-        }
-    }
-
-    // And proceed with a heap
-    for (heap_obj_pointer, pointers) in heap_pointers_to_fill {
-        let heap_obj = heap.get_mut(heap_obj_pointer);
-        match heap_obj {
-            HeapObject::String(_) => unreachable!(),
-            HeapObject::List(List { data, items_amount, item_size, .. }) => {
-                for i in 0..*items_amount {
-                    for pointer in pointers.iter() {
-                        let value = data[*pointer + (i * *item_size)] as usize;
-                        if value != 0 {
-                            data[*pointer + (i * *item_size)] = heap_objects_mapping[&value];
-                        }
+    /// Allocates nodes on the heap and returns arguments, that point into the graph
+    fn build_heap(nodes: &[Node], args: &Node, heap: &mut Heap, metadata: &Metadata) -> Vec<u64> {
+        let pointers: Vec<(u64, PointerKind)> = nodes
+            .iter()
+            .map(|node| {
+                let pointer = match &node.kind {
+                    NodeKind::String(s) => heap.move_string(s.clone()).0,
+                    NodeKind::List { kind, items_amount } => {
+                        let data = vec![0; items_amount * metadata.list_types_sizes[*kind]];
+                        heap.allocate_list(*kind, *items_amount, &data, metadata).0
                     }
+                    NodeKind::Object { type_index } => {
+                        heap.allocate_custom(*type_index, metadata).0
+                    }
+                };
+                (pointer, node.kind.pointer_kind())
+            })
+            .collect();
+
+        for (node, (pointer, _)) in nodes.iter().zip(pointers.iter()) {
+            let object_pointers = get_object_pointers(heap.get(*pointer), metadata);
+            let object_kinds = get_object_pointer_kinds(heap.get(*pointer), metadata);
+            let slots = (&object_pointers[..], &object_kinds[..]);
+            match heap.get_mut(*pointer) {
+                HeapObject::String(_) => {}
+                HeapObject::List(l) => fill_slots(&mut l.data, slots, node, &pointers),
+                HeapObject::CustomObject(obj) => fill_slots(&mut obj.data, slots, node, &pointers),
+            }
+        }
+
+        let mut values = vec![0; 4];
+        let slots = (
+            &metadata.functions_pointer_mapping[0][..],
+            &metadata.functions_pointer_kinds[0][..],
+        );
+        fill_slots(&mut values, slots, args, &pointers);
+        values
+    }
+
+    /// Every pointer reachable from the values refers to an object of the expected kind
+    fn assert_well_typed(heap: &Heap, values: &[u64], metadata: &Metadata) {
+        let mut to_visit = vec![];
+        let check_slots = |data: &[u64],
+                           slots: (&[usize], &[PointerKind]),
+                           to_visit: &mut Vec<_>| {
+            let (pointer_map, kinds) = slots;
+            for (offset, expected_kind) in pointer_map.iter().zip(kinds) {
+                let pointer = data[*offset];
+                if pointer == 0 {
+                    continue;
                 }
+                let kind = match heap.get(pointer) {
+                    HeapObject::String(_) => PointerKind::String,
+                    HeapObject::List(l) => PointerKind::List(l.list_item_type),
+                    HeapObject::CustomObject(obj) => PointerKind::Object(obj.type_index as usize),
+                };
+                assert_eq!(kind, *expected_kind);
+                to_visit.push(pointer);
             }
-            HeapObject::CustomObject(CustomObject { data, .. }) => {
-                for pointer in pointers.iter() {
-                    let value = data[*pointer] as usize;
-                    if value != 0 {
-                        data[*pointer] = heap_objects_mapping[&value];
+        };
+
+        let slots = (
+            &metadata.functions_pointer_mapping[0][..],
+            &metadata.functions_pointer_kinds[0][..],
+        );
+        check_slots(values, slots, &mut to_visit);
+        let mut visited = std::collections::HashSet::new();
+        while let Some(pointer) = to_visit.pop() {
+            if !visited.insert(pointer) {
+                continue;
+            }
+            let object = heap.get(pointer);
+            let pointer_map = get_object_pointers(object, metadata);
+            let kinds = get_object_pointer_kinds(object, metadata);
+            let slots = (&pointer_map[..], &kinds[..]);
+            match object {
+                HeapObject::String(_) => {}
+                HeapObject::List(l) => check_slots(&l.data, slots, &mut to_visit),
+                HeapObject::CustomObject(obj) => check_slots(&obj.data, slots, &mut to_visit),
+            }
+        }
+    }
+
+    /// Walks both graphs at once, objects must match one to one
+    fn assert_same_graphs(
+        (heap_a, values_a): (&Heap, &[u64]),
+        (heap_b, values_b): (&Heap, &[u64]),
+        metadata: &Metadata,
+    ) {
+        let mut a_to_b: HashMap<u64, u64> = HashMap::new();
+        let mut b_to_a: HashMap<u64, u64> = HashMap::new();
+        let mut to_visit = vec![];
+
+        let mut compare_slots =
+            |a: &[u64], b: &[u64], pointer_map: &[usize], to_visit: &mut Vec<_>| {
+                assert_eq!(a.len(), b.len());
+                for i in 0..a.len() {
+                    if !pointer_map.contains(&i) || a[i] == 0 || b[i] == 0 {
+                        assert_eq!(a[i], b[i]);
+                        continue;
                     }
+                    let mapped = *a_to_b.entry(a[i]).or_insert(b[i]);
+                    assert_eq!(mapped, b[i], "shared object is duplicated");
+                    assert_eq!(
+                        *b_to_a.entry(b[i]).or_insert(a[i]),
+                        a[i],
+                        "objects are merged"
+                    );
+                    to_visit.push((a[i], b[i]));
                 }
+            };
+
+        compare_slots(
+            values_a,
+            values_b,
+            &metadata.functions_pointer_mapping[0],
+            &mut to_visit,
+        );
+        let mut visited = std::collections::HashSet::new();
+        while let Some((a, b)) = to_visit.pop() {
+            if !visited.insert(a) {
+                continue;
             }
+            let pointer_map = get_object_pointers(heap_a.get(a), metadata);
+            match (heap_a.get(a), heap_b.get(b)) {
+                (HeapObject::String(a), HeapObject::String(b)) => assert_eq!(a, b),
+                (HeapObject::List(a), HeapObject::List(b)) => {
+                    assert_eq!(a.list_item_type, b.list_item_type);
+                    assert_eq!(a.items_amount, b.items_amount);
+                    compare_slots(&a.data, &b.data, &pointer_map, &mut to_visit);
+                }
+                (HeapObject::CustomObject(a), HeapObject::CustomObject(b)) => {
+                    assert_eq!(a.type_index, b.type_index);
+                    compare_slots(&a.data, &b.data, &pointer_map, &mut to_visit);
+                }
+                (a, b) => panic!("Different objects {:?} and {:?}", a, b),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn random_heap_graph_round_trip(
+            nodes in prop::collection::vec(node_strategy(), 1..20),
+            args in node_strategy(),
+        ) {
+            let metadata = test_metadata();
+            let mut heap = Heap::default();
+            let values = build_heap(&nodes, &args, &mut heap, &metadata);
+
+            let chunk = serialize_args(&values, &heap, &metadata);
+            let mut other_heap = Heap::default();
+            let restored = deserialize_args(&chunk, &mut other_heap, &metadata).unwrap();
+            assert_same_graphs((&heap, &values), (&other_heap, &restored), &metadata);
+
+            // Only reachable objects are packed, each of them once
+            prop_assert!(other_heap.len() <= heap.len());
+            prop_assert_eq!(serialize_args(&restored, &other_heap, &metadata), chunk);
+        }
+
+        #[test]
+        fn corrupted_message_is_error_not_panic(
+            nodes in prop::collection::vec(node_strategy(), 1..8),
+            args in node_strategy(),
+            position in any::<Index>(),
+            byte in any::<u8>(),
+            cut in any::<Index>(),
+        ) {
+            let metadata = test_metadata();
+            let mut heap = Heap::default();
+            let values = build_heap(&nodes, &args, &mut heap, &metadata);
+            let mut chunk = serialize_args(&values, &heap, &metadata);
+
+            let position = position.index(chunk.len());
+            chunk[position] = byte;
+            chunk.truncate(cut.index(chunk.len() + 1));
+
+            // Message that is still decoded never gives objects of wrong kinds to the receiver
+            let mut other_heap = Heap::default();
+            if let Ok(restored) = deserialize_args(&chunk, &mut other_heap, &metadata) {
+                assert_well_typed(&other_heap, &restored, &metadata);
+            }
+        }
+    }
+
+    #[test]
+    fn strings_are_utf8_and_sizes_are_varints() {
+        let metadata = test_metadata();
+        let mut heap = Heap::default();
+        let (string, _) = heap.move_string("Привет, 🌍".into());
+        let (obj, obj_data) = heap.allocate_custom(0, &metadata);
+        obj_data.data.copy_from_slice(&[-1i64 as u64, string, 0, 0]);
+        let (list, _) = heap.allocate_list(2, 2, &[string, 300, string, 5], &metadata);
+
+        let chunk = serialize_args(&[7, obj, list, string], &heap, &metadata);
+
+        #[rustfmt::skip]
+        let mut expected = vec![
+            WIRE_FORMAT_VERSION, 16,
+            14, 2, 4, 6,                      // values: 7 and indexes of 3 objects
+            3,                                // amount of objects
+            CUSTOM_OBJECT_TAG, 0, 1, 6, 0, 0, // -1, string and nil
+            LIST_TAG, 2, 2, 6, 0xd8, 0x04, 6, 10,
+            STRING_TAG, 18,
+        ];
+        expected.extend("Привет, 🌍".as_bytes());
+        assert_eq!(chunk, expected);
+
+        let mut other_heap = Heap::default();
+        let restored = deserialize_args(&chunk, &mut other_heap, &metadata).unwrap();
+        assert_eq!(restored[0], 7);
+        match other_heap.get(restored[1]) {
+            // String is shared by the object and the arguments
+            HeapObject::CustomObject(obj) => assert_eq!(obj.data[..2], [-1i64 as u64, restored[3]]),
+            other => panic!("Object is not restored, got {:?}", other),
         }
+        assert_eq!(other_heap.get(restored[3]).extract_string(), "Привет, 🌍");
+        assert_eq!(other_heap.len(), 3);
+    }
+
+    #[test]
+    fn return_value_round_trip() {
+        let metadata = test_metadata();
+        let mut heap = Heap::default();
+        let (string, _) = heap.move_string("answer".into());
+
+        let chunk = serialize_return_value(FUNCTION_POS, &[string, 42], &heap, &metadata);
+        let mut other_heap = Heap::default();
+        let value = deserialize_return_value(&chunk, &mut other_heap, &metadata).unwrap();
+        assert_eq!(value[1], 42);
+        assert_eq!(other_heap.get(value[0]).extract_string(), "answer");
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let metadata = test_metadata();
+        let heap = Heap::default();
+        let chunk = serialize_args(&[1, 0, 0, 0], &heap, &metadata);
+        assert_eq!(chunk, vec![WIRE_FORMAT_VERSION, 16, 2, 0, 0, 0, 0]);
+
+        let decode = |chunk: &[u8]| deserialize_args(chunk, &mut Heap::default(), &metadata);
+        assert_eq!(decode(&chunk[..5]), Err(WireFormatError::UnexpectedEnd));
+        assert_eq!(
+            decode(&[&chunk[..], &[0]].concat()),
+            Err(WireFormatError::TrailingBytes)
+        );
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 16, 2, 2, 0, 0, 0]),
+            Err(WireFormatError::InvalidReference(1))
+        );
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 16, 2, 0, 0, 0, 1, 9]),
+            Err(WireFormatError::UnknownObjectTag(9))
+        );
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 17, 2, 0, 0, 0, 0]),
+            Err(WireFormatError::UnexpectedFunction(17))
+        );
+        // String is given, where the object of type 0 is expected
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 16, 2, 2, 0, 0, 1, STRING_TAG, 0]),
+            Err(WireFormatError::MismatchedObject(1))
+        );
+        // List of objects is given, where the list of tuples is expected
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 16, 2, 0, 2, 0, 1, LIST_TAG, 1, 0]),
+            Err(WireFormatError::MismatchedObject(1))
+        );
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 16, 2, 0, 0, 0, 1, STRING_TAG, 1, 0xff]),
+            Err(WireFormatError::InvalidUtf8)
+        );
+        assert_eq!(
+            decode(&[WIRE_FORMAT_VERSION, 16, 2, 0, 0, 0, 1, LIST_TAG, 5, 0]),
+            Err(WireFormatError::UnknownListKind(5))
+        );
+        assert_eq!(
+            decode(&[
+                WIRE_FORMAT_VERSION,
+                16,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0x7f
+            ]),
+            Err(WireFormatError::VarintOverflow)
+        );
+
+        assert_eq!(
+            read_function_position(&[WIRE_FORMAT_VERSION + 1, 16]),
+            Err(WireFormatError::UnsupportedVersion(WIRE_FORMAT_VERSION + 1))
+        );
+        assert_eq!(read_function_position(&chunk), Ok(FUNCTION_POS));
     }
 }
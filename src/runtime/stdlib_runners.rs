@@ -1,68 +1,119 @@
+use super::errors::RuntimeErrorKind;
 use super::heap::Heap;
 use super::metadata::Metadata;
 use super::utils::{f64_to_u64, u64_to_f64};
 use std::io::{self, Write};
+use std::sync::Mutex;
 
-pub type RawStdRunner = for<'r, 's> fn(&'r mut [u64], &'s mut Heap, &'s Metadata) -> Vec<u64>;
+pub type StdRunnerResult = Result<Vec<u64>, RuntimeErrorKind>;
+pub type RawStdRunner =
+    for<'r, 's> fn(&'r mut [u64], &'s mut Heap, &'s Metadata, &'s Output) -> StdRunnerResult;
 
 pub const LIST_OF_INTS_META_FLAG: usize = 0;
 
-fn std_println(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+/// Index of `std::sleep` among the runners, it suspends the message instead of being run
+pub const STD_SLEEP: usize = 7;
+
+/// Where std print functions write to, stdout of the process unless the host gives its own
+pub struct Output {
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Output {
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+        Output { sink: Mutex::new(sink) }
+    }
+
+    /// Text is written at once, so prints of different active objects are not mixed up
+    fn write(&self, text: &str, flush: bool) -> Result<(), RuntimeErrorKind> {
+        let mut sink = self.sink.lock().unwrap();
+        sink.write_all(text.as_bytes())
+            .and_then(|_| if flush { sink.flush() } else { Ok(()) })
+            .map_err(|error| RuntimeErrorKind::OutputFailed(error.to_string()))
+    }
+
+    pub fn flush(&self) {
+        // Program is already finished, so there is no one to report the error to
+        let _ = self.sink.lock().unwrap().flush();
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::new(Box::new(io::stdout()))
+    }
+}
+
+fn std_println(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    out: &Output,
+) -> StdRunnerResult {
     let obj = memory.get_mut(stack[0]);
-    println!("{}", obj.extract_string());
-    vec![]
+    out.write(&format!("{}\n", obj.extract_string()), false)?;
+    Ok(vec![])
 }
 
-fn std_print(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_print(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    out: &Output,
+) -> StdRunnerResult {
     let obj = memory.get_mut(stack[0]);
-    print!("{}", obj.extract_string());
-    io::stdout().flush().unwrap();
-    vec![]
+    out.write(obj.extract_string(), true)?;
+    Ok(vec![])
 }
 
-fn std_fprintln(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+// Each `%` of the format is replaced with the next string of the list
+fn format_with_args(stack: &[u64], memory: &Heap) -> String {
     let str_with_format = memory.get(stack[0]);
     let parts = str_with_format.extract_string().split('%').collect::<Vec<_>>();
 
     let args = memory.get(stack[1]).extract_list();
+    let mut result = String::new();
     for (i, part) in parts.iter().enumerate() {
-        print!("{}", part);
+        result.push_str(part);
         if i == parts.len() - 1 {
             break;
         }
         if i < args.items_amount {
             let s_ptr = args.data[i];
-            print!("{}", memory.get(s_ptr).extract_string());
+            result.push_str(memory.get(s_ptr).extract_string());
         } else {
-            print!("%");
+            result.push('%');
         }
     }
-    println!();
-    vec![]
+    result
 }
 
-fn std_fprint(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
-    let str_with_format = memory.get(stack[0]);
-    let parts = str_with_format.extract_string().split('%').collect::<Vec<_>>();
+fn std_fprintln(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    out: &Output,
+) -> StdRunnerResult {
+    out.write(&format!("{}\n", format_with_args(stack, memory)), false)?;
+    Ok(vec![])
+}
 
-    let args = memory.get(stack[1]).extract_list();
-    for (i, part) in parts.iter().enumerate() {
-        print!("{}", part);
-        if i == parts.len() - 1 {
-            break;
-        }
-        if i < args.items_amount {
-            let s_ptr = args.data[i];
-            print!("{}", memory.get(s_ptr).extract_string());
-        } else {
-            print!("%");
-        }
-    }
-    io::stdout().flush().unwrap();
-    vec![]
+fn std_fprint(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    out: &Output,
+) -> StdRunnerResult {
+    out.write(&format_with_args(stack, memory), true)?;
+    Ok(vec![])
 }
 
-fn std_range(stack: &mut [u64], memory: &mut Heap, meta: &Metadata) -> Vec<u64> {
+fn std_range(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let start = stack[0] as i64;
     let end = stack[1] as i64;
 
@@ -74,20 +125,30 @@ fn std_range(stack: &mut [u64], memory: &mut Heap, meta: &Metadata) -> Vec<u64>
         list_object.data[i as usize - start as usize] = i as u64;
     }
 
-    vec![list_pos]
+    Ok(vec![list_pos])
 }
 
-fn std_get_input(_stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_get_input(
+    _stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let (pos, inner) = memory.allocate_string(0);
     io::stdin().read_line(inner).expect("Failed to read line");
 
     // Remove all trailing newlines in place
     inner.truncate(inner.trim_end().len());
 
-    vec![pos]
+    Ok(vec![pos])
 }
 
-fn std_bool_to_string(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_bool_to_string(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     // Reserve for 5 chars, so both false and true fits
     // (true will have 4 of 5 chars filled, which is fine)
     let (pos, inner) = memory.allocate_string(5);
@@ -100,38 +161,73 @@ fn std_bool_to_string(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) ->
         panic!("Bool value is {}, must be 0 or 1", stack[0]);
     }
 
-    vec![pos]
+    Ok(vec![pos])
 }
 
-fn std_int_to_string(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_int_to_string(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let s = (stack[0] as i64).to_string();
 
-    vec![memory.move_string(s).0]
+    Ok(vec![memory.move_string(s).0])
 }
 
-fn std_int_to_float(stack: &mut [u64], _memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
-    vec![f64_to_u64((stack[0] as i64) as f64)]
+fn std_int_to_float(
+    stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    Ok(vec![f64_to_u64((stack[0] as i64) as f64)])
 }
 
-fn std_int_abs(stack: &mut [u64], _memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
-    vec![(stack[0] as i64).abs() as u64]
+fn std_int_abs(
+    stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    Ok(vec![(stack[0] as i64).unsigned_abs()])
 }
 
-fn std_float_round(stack: &mut [u64], _memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
-    vec![(u64_to_f64(stack[0]).round() as i64) as u64]
+fn std_float_round(
+    stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    Ok(vec![(u64_to_f64(stack[0]).round() as i64) as u64])
 }
 
-fn std_float_to_string(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_float_to_string(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let s = u64_to_f64(stack[0]).to_string();
 
-    vec![memory.move_string(s).0]
+    Ok(vec![memory.move_string(s).0])
 }
 
-fn std_float_abs(stack: &mut [u64], _memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
-    vec![f64_to_u64(u64_to_f64(stack[0]).abs())]
+fn std_float_abs(
+    stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    Ok(vec![f64_to_u64(u64_to_f64(stack[0]).abs())])
 }
 
-fn std_list_push(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_list_push(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let list_obj = memory.get_mut(stack[0]);
     let list = list_obj.extract_list_mut();
 
@@ -141,46 +237,85 @@ fn std_list_push(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<
     for i in 0..item_size {
         list.data.push(stack[1 + i]);
     }
-    vec![]
+    Ok(vec![])
 }
 
-fn std_list_pop(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_list_pop(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let list_obj = memory.get_mut(stack[0]);
     let list = list_obj.extract_list_mut();
+    if list.items_amount == 0 {
+        return Err(RuntimeErrorKind::PopFromEmptyList);
+    }
 
     list.items_amount -= 1;
     let item_size = list.item_size;
 
     let mut res = vec![];
     for _ in 0..item_size {
-        res.push(list.data.pop().expect("Popped from empty list!"));
+        res.push(list.data.pop().unwrap());
     }
 
     // Pop are returning items in reverse order, so we need to reverse them for saving to stack
     res.reverse();
 
-    res
+    Ok(res)
 }
 
-fn std_list_len(stack: &mut [u64], memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
+fn std_list_len(
+    stack: &mut [u64],
+    memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
     let list_obj = memory.get_mut(stack[0]);
     let list = list_obj.extract_list_mut();
 
-    vec![list.items_amount as u64]
+    Ok(vec![list.items_amount as u64])
 }
 
-fn noop(_stack: &mut [u64], _memory: &mut Heap, _meta: &Metadata) -> Vec<u64> {
-    panic!("not implemented yet");
+fn std_exit(
+    stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    Err(RuntimeErrorKind::Exit(stack[0] as i64))
+}
+
+// Active object runs the sleep itself, so that its message gives up the worker thread
+fn std_sleep(
+    _stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    unreachable!("std::sleep is run by the active object")
+}
+
+fn noop(
+    _stack: &mut [u64],
+    _memory: &mut Heap,
+    _meta: &Metadata,
+    _out: &Output,
+) -> StdRunnerResult {
+    Err(RuntimeErrorKind::NotImplemented("standard function"))
 }
 
 #[rustfmt::skip]
-pub static STD_RAW_FUNCTION_RUNNERS: [(&str, RawStdRunner); 23] = [
+pub static STD_RAW_FUNCTION_RUNNERS: [(&str, RawStdRunner); 25] = [
     ("std::print", std_print),
     ("std::println", std_println),
     ("std::fprint", std_fprint),
     ("std::fprintln", std_fprintln),
     ("std::range", std_range),
     ("std::get_input", std_get_input),
+    ("std::exit", std_exit),
+    ("std::sleep", std_sleep),
 
     ("std::Bool::to_string", std_bool_to_string),
 
@@ -204,3 +339,13 @@ pub static STD_RAW_FUNCTION_RUNNERS: [(&str, RawStdRunner); 23] = [
     ("std::List::len", std_list_len),
     ("std::List::is_empty", noop),
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sleep_index_points_to_its_runner() {
+        assert_eq!(STD_RAW_FUNCTION_RUNNERS[STD_SLEEP].0, "std::sleep");
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Which children are restarted, when one of them fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartStrategy {
+    OneForOne, // only the failed child is restarted
+    OneForAll, // all children of the supervisor are restarted
+}
+
+impl RestartStrategy {
+    pub const NAMES: [&'static str; 2] = ["one_for_one", "one_for_all"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "one_for_one" => Some(RestartStrategy::OneForOne),
+            "one_for_all" => Some(RestartStrategy::OneForAll),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RestartStrategy::OneForOne => "one_for_one",
+            RestartStrategy::OneForAll => "one_for_all",
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            RestartStrategy::OneForOne => 1,
+            RestartStrategy::OneForAll => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(RestartStrategy::OneForOne),
+            2 => Some(RestartStrategy::OneForAll),
+            _ => None,
+        }
+    }
+}
+
+/// Supervision declared by active type, children are objects spawned by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupervisorConfig {
+    pub strategy: RestartStrategy,
+    pub max_restarts: usize, // supervisor gives up after more restarts than that within the period
+    pub period: Duration,
+}
+
+impl SupervisorConfig {
+    pub const DEFAULT_MAX_RESTARTS: usize = 3;
+    pub const DEFAULT_PERIOD: Duration = Duration::from_millis(5000);
+}
+
+/// Children and recent restarts of a single supervisor
+pub struct Supervisor {
+    config: SupervisorConfig,
+    children: Vec<u64>,
+    restarts: VecDeque<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig) -> Self {
+        Supervisor { config, children: vec![], restarts: VecDeque::new() }
+    }
+
+    pub fn add_child(&mut self, child: u64) {
+        self.children.push(child);
+    }
+
+    /// Forgets children and restarts, returns children to stop
+    pub fn reset(&mut self) -> Vec<u64> {
+        self.restarts.clear();
+        std::mem::take(&mut self.children)
+    }
+
+    /// Children to restart after `failed` one has failed or None if restart intensity is
+    /// exceeded, so supervisor has to give up
+    pub fn on_failure(&mut self, failed: u64, now: Instant) -> Option<Vec<u64>> {
+        while let Some(restart) = self.restarts.front() {
+            if now.duration_since(*restart) < self.config.period {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.config.max_restarts {
+            return None;
+        }
+        self.restarts.push_back(now);
+
+        match self.config.strategy {
+            RestartStrategy::OneForOne => Some(vec![failed]),
+            RestartStrategy::OneForAll => Some(self.children.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn supervisor(strategy: RestartStrategy, max_restarts: usize) -> Supervisor {
+        let period = Duration::from_millis(100);
+        let mut supervisor = Supervisor::new(SupervisorConfig { strategy, max_restarts, period });
+        supervisor.add_child(1);
+        supervisor.add_child(2);
+        supervisor
+    }
+
+    #[test]
+    fn strategy_picks_children_to_restart() {
+        let now = Instant::now();
+        let mut one_for_one = supervisor(RestartStrategy::OneForOne, 3);
+        assert_eq!(one_for_one.on_failure(2, now), Some(vec![2]));
+
+        let mut one_for_all = supervisor(RestartStrategy::OneForAll, 3);
+        assert_eq!(one_for_all.on_failure(2, now), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn gives_up_after_too_many_restarts_within_period() {
+        let start = Instant::now();
+        let mut supervisor = supervisor(RestartStrategy::OneForOne, 2);
+
+        assert!(supervisor.on_failure(1, start).is_some());
+        assert!(supervisor.on_failure(1, start + Duration::from_millis(10)).is_some());
+        assert!(supervisor.on_failure(2, start + Duration::from_millis(20)).is_none());
+
+        // Restarts older than the period are forgotten
+        assert!(supervisor.on_failure(2, start + Duration::from_millis(105)).is_some());
+        assert!(supervisor.on_failure(2, start + Duration::from_millis(111)).is_some());
+        assert!(supervisor.on_failure(2, start + Duration::from_millis(112)).is_none());
+    }
+
+    #[test]
+    fn strategy_names() {
+        for name in RestartStrategy::NAMES {
+            let strategy = RestartStrategy::from_name(name).unwrap();
+            assert_eq!(strategy.name(), name);
+            assert_eq!(
+                RestartStrategy::from_byte(strategy.to_byte()),
+                Some(strategy)
+            );
+        }
+        assert_eq!(RestartStrategy::from_name("rest_for_one"), None);
+        assert_eq!(RestartStrategy::from_byte(0), None);
+    }
+}
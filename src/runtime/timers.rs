@@ -0,0 +1,196 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::scheduler::Message;
+
+/// What happens, when the timer is due
+#[derive(Debug, PartialEq)]
+pub enum Timeout {
+    Deliver(u64, Message), // delayed message to the receiver
+    Wake(u64),             // active object continues its sleeping message
+    Resolve(u64),          // future of the entry, that sleeps in a deterministic program
+}
+
+struct Timer {
+    due: Instant,
+    order: u64, // timers with the same due time fire in order they are added
+    timeout: Timeout,
+}
+
+impl Timer {
+    fn key(&self) -> (Instant, u64) {
+        (self.due, self.order)
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(Default)]
+struct TimerQueue {
+    timers: BinaryHeap<Reverse<Timer>>,
+    added: u64,
+    stopped: bool,
+    virtual_now: Option<Instant>, // time moves only when timers fire, if clock is virtual
+}
+
+/// Delayed messages and sleeps, waiting for their time
+#[derive(Default)]
+pub struct Timers {
+    queue: Mutex<TimerQueue>,
+    changed: Condvar, // notified when a timer is added or timers are stopped
+}
+
+impl Timers {
+    /// Timers of a single thread program, that does not wait for them in real time
+    pub fn virtual_clock() -> Self {
+        let timers = Self::default();
+        timers.queue.lock().unwrap().virtual_now = Some(Instant::now());
+        timers
+    }
+
+    pub fn now(&self) -> Instant {
+        self.queue.lock().unwrap().virtual_now.unwrap_or_else(Instant::now)
+    }
+
+    pub fn add(&self, due: Instant, timeout: Timeout) {
+        let mut queue = self.queue.lock().unwrap();
+        let order = queue.added;
+        queue.added += 1;
+        queue.timers.push(Reverse(Timer { due, order, timeout }));
+        self.changed.notify_all();
+    }
+
+    /// Blocks until the earliest timer is due, returns None after timers are stopped
+    pub fn next(&self) -> Option<Timeout> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.stopped {
+                return None;
+            }
+            let now = Instant::now();
+            queue = match queue.timers.peek() {
+                Some(Reverse(timer)) if timer.due <= now => {
+                    let Reverse(timer) = queue.timers.pop().unwrap();
+                    return Some(timer.timeout);
+                }
+                Some(Reverse(timer)) => {
+                    let timeout = timer.due - now;
+                    self.changed.wait_timeout(queue, timeout).unwrap().0
+                }
+                None => self.changed.wait(queue).unwrap(),
+            };
+        }
+    }
+
+    /// Fires the earliest timer without waiting for it, virtual clock moves to its time
+    pub fn fire_earliest(&self) -> Option<Timeout> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.stopped {
+            return None;
+        }
+        let Reverse(timer) = queue.timers.pop()?;
+        if let Some(now) = queue.virtual_now.as_mut() {
+            *now = (*now).max(timer.due);
+        }
+        Some(timer.timeout)
+    }
+
+    /// Blocks the thread for the delay, or only moves the clock forward, if it is virtual
+    pub fn sleep(&self, delay: Duration) {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.virtual_now.as_mut() {
+            Some(now) => *now += delay,
+            None => {
+                drop(queue);
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    /// Pending timers never fire after this
+    pub fn stop(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.stopped = true;
+        queue.timers.clear();
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    fn message(receiver: u64, n: u8) -> Timeout {
+        Timeout::Deliver(receiver, Message { data: vec![n], reply_to: None })
+    }
+
+    #[test]
+    fn timers_fire_in_order_of_due_time() {
+        let timers = Timers::default();
+        let now = Instant::now();
+        timers.add(now + Duration::from_millis(30), message(1, 3));
+        timers.add(now, message(2, 1));
+        timers.add(now + Duration::from_millis(10), message(3, 2));
+        timers.add(now + Duration::from_millis(10), Timeout::Wake(4));
+
+        let fired: Vec<_> = (0..4).map(|_| timers.next().unwrap()).collect();
+        assert_eq!(
+            fired,
+            vec![message(2, 1), message(3, 2), Timeout::Wake(4), message(1, 3)]
+        );
+        assert!(now.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn stopped_timers_never_fire() {
+        let timers = Arc::new(Timers::default());
+        timers.add(Instant::now() + Duration::from_secs(60), message(1, 1));
+
+        let waiting = {
+            let timers = timers.clone();
+            thread::spawn(move || timers.next())
+        };
+        timers.stop();
+        assert_eq!(waiting.join().unwrap(), None);
+    }
+
+    #[test]
+    fn virtual_clock_moves_with_fired_timers() {
+        let timers = Timers::virtual_clock();
+        let start = timers.now();
+        timers.add(start + Duration::from_secs(60), message(1, 2));
+        timers.add(start + Duration::from_secs(30), Timeout::Resolve(7));
+
+        assert_eq!(timers.fire_earliest(), Some(Timeout::Resolve(7)));
+        assert_eq!(timers.now(), start + Duration::from_secs(30));
+        assert_eq!(timers.fire_earliest(), Some(message(1, 2)));
+        assert_eq!(timers.now(), start + Duration::from_secs(60));
+        assert_eq!(timers.fire_earliest(), None);
+
+        // Sleep on virtual clock returns right away
+        timers.sleep(Duration::from_secs(3600));
+        assert_eq!(timers.now(), start + Duration::from_secs(3660));
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Value of an event argument
+#[derive(Clone, Copy)]
+pub enum TraceArg<'a> {
+    Str(&'a str),
+    Int(u64),
+}
+
+/// Writes events of active objects as JSON lines, one event per line.
+/// Events are in Chrome trace event format, so `jq -s . out.jsonl` gives a file for
+/// the trace viewer. Thread of the event is active object id + 1, entry is thread 0
+pub struct Tracer {
+    start: Instant,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+fn push_json_string(result: &mut String, s: &str) {
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(result, "\\u{:04x}", c as u32).unwrap(),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+}
+
+fn thread_id(actor: Option<u64>) -> u64 {
+    actor.map_or(0, |actor| actor + 1)
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Tracer { start: Instant::now(), out: Mutex::new(out) }
+    }
+
+    /// Event of the active object or of the entry, if actor is None
+    pub fn record(
+        &self,
+        phase: char,
+        category: &str,
+        name: &str,
+        actor: Option<u64>,
+        args: &[(&str, TraceArg)],
+    ) {
+        let timestamp = self.start.elapsed().as_micros();
+
+        let mut line = String::from("{\"name\":");
+        push_json_string(&mut line, name);
+        line.push_str(",\"cat\":");
+        push_json_string(&mut line, category);
+        write!(
+            line,
+            ",\"ph\":\"{}\",\"ts\":{},\"pid\":0,\"tid\":{},\"args\":{{",
+            phase,
+            timestamp,
+            thread_id(actor)
+        )
+        .unwrap();
+        for (i, (key, value)) in args.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            push_json_string(&mut line, key);
+            line.push(':');
+            match value {
+                TraceArg::Str(s) => push_json_string(&mut line, s),
+                TraceArg::Int(n) => write!(line, "{}", n).unwrap(),
+            }
+        }
+        line.push_str("}}\n");
+
+        // Tracing must not stop the program, so failed writes are ignored
+        let _ = self.out.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    /// Names the thread of the active object in the trace viewer
+    pub fn name_actor(&self, actor: Option<u64>, name: &str) {
+        self.record(
+            'M',
+            "__metadata",
+            "thread_name",
+            actor,
+            &[("name", TraceArg::Str(name))],
+        );
+    }
+
+    pub fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::helpers::SharedBuffer;
+
+    #[test]
+    fn events_are_json_lines() {
+        let buffer = SharedBuffer::default();
+        let tracer = Tracer::new(Box::new(buffer.clone()));
+        tracer.record(
+            'i',
+            "send",
+            "main::Worker::say",
+            None,
+            &[("receiver", TraceArg::Int(3)), ("size", TraceArg::Int(12))],
+        );
+        tracer.record(
+            'B',
+            "receive",
+            "main::Worker::say",
+            Some(3),
+            &[("error", TraceArg::Str("said \"hi\"\n\u{1}"))],
+        );
+
+        let output = buffer.text();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+
+        // Timestamps differ from run to run, so they are cut out
+        let without_timestamp = |line: &str| {
+            let start = line.find(",\"ts\":").unwrap();
+            let end = start + line[start..].find(",\"pid\"").unwrap();
+            format!("{}{}", &line[..start], &line[end..])
+        };
+        assert_eq!(
+            without_timestamp(lines[0]),
+            r#"{"name":"main::Worker::say","cat":"send","ph":"i","pid":0,"tid":0,"args":{"receiver":3,"size":12}}"#
+        );
+        assert_eq!(
+            without_timestamp(lines[1]),
+            r#"{"name":"main::Worker::say","cat":"receive","ph":"B","pid":0,"tid":4,"args":{"error":"said \"hi\"\n\u0001"}}"#
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use std::fmt;
+
+use super::debugger::ValueType;
+use super::heap::Heap;
+use super::metadata::Metadata;
+use super::serialization::{deserialize_return_value, serialize_function_args, WireFormatError};
+use super::utils::{f64_to_u64, u64_to_f64};
+
+/// Value passed between the program and the host application, that runs it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    List(Vec<Value>),
+    Tuple(Vec<Value>), // functions that return nothing give an empty tuple
+    Maybe(Option<Box<Value>>),
+    Active(ActiveRef),
+}
+
+/// Handle of an active object, along with its type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveRef {
+    pub handle: u64,
+    pub type_index: usize,
+}
+
+/// Types of arguments and of the return value of a function, used to call it from the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub args: Vec<ValueType>,
+    pub returns: ValueType,
+    pub active_type: Option<usize>, // type of the active object, if the function is its method
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ValueError {
+    TypeMismatch { expected: String, given: Value },
+    UnsupportedType(String),
+    MalformedReply(WireFormatError),
+}
+
+impl fmt::Display for ValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TypeMismatch { expected, given } => {
+                write!(
+                    f,
+                    "expected value of type {}, but {:?} is given",
+                    expected, given
+                )
+            }
+            Self::UnsupportedType(name) => {
+                write!(f, "values of type {} can not be passed to the host", name)
+            }
+            Self::MalformedReply(error) => write!(f, "malformed reply: {}", error),
+        }
+    }
+}
+
+/// Objects of classes and futures are bound to the heap of their active object, so only
+/// values of other types are copied in and out of the program
+pub fn check_supported(value_type: &ValueType, metadata: &Metadata) -> Result<(), ValueError> {
+    match value_type {
+        ValueType::List(inner) | ValueType::Maybe(inner) => check_supported(inner, metadata),
+        ValueType::Tuple(items) => {
+            (items.iter()).try_for_each(|item| check_supported(item, metadata))
+        }
+        ValueType::Object(_) | ValueType::Future(_) => Err(ValueError::UnsupportedType(
+            value_type.name(&metadata.types_names),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Message, that calls function at the position with given arguments
+pub fn pack_call(
+    function_pos: usize,
+    args: &[Value],
+    signature: &Signature,
+    metadata: &Metadata,
+) -> Result<Vec<u8>, ValueError> {
+    let mut heap = Heap::default();
+    let mut words = vec![];
+    for (value, value_type) in args.iter().zip(signature.args.iter()) {
+        push_value(value, value_type, &mut words, &mut heap, metadata)?;
+    }
+    let mut stack_pointer = words.len();
+    Ok(serialize_function_args(
+        function_pos,
+        &words,
+        &mut stack_pointer,
+        &heap,
+        metadata,
+    ))
+}
+
+/// Return value of the function, packed into the reply to the message that called it
+pub fn unpack_return(
+    reply: &[u8],
+    signature: &Signature,
+    metadata: &Metadata,
+) -> Result<Value, ValueError> {
+    let mut heap = Heap::default();
+    let words =
+        deserialize_return_value(reply, &mut heap, metadata).map_err(ValueError::MalformedReply)?;
+    read_value(&signature.returns, &words, &heap, metadata)
+}
+
+fn push_value(
+    value: &Value,
+    value_type: &ValueType,
+    words: &mut Vec<u64>,
+    heap: &mut Heap,
+    metadata: &Metadata,
+) -> Result<(), ValueError> {
+    match (value, value_type) {
+        (Value::Int(i), ValueType::Int) => words.push(*i as u64),
+        (Value::Float(f), ValueType::Float) => words.push(f64_to_u64(*f)),
+        (Value::Bool(b), ValueType::Bool) => words.push(*b as u64),
+        (Value::String(s), ValueType::String) => words.push(heap.move_string(s.clone()).0),
+        (Value::List(items), ValueType::List(item_type)) => {
+            let list_kind = get_list_kind(item_type, metadata)?;
+            let mut data = vec![];
+            for item in items {
+                push_value(item, item_type, &mut data, heap, metadata)?;
+            }
+            words.push(heap.allocate_list(list_kind, items.len(), &data, metadata).0);
+        }
+        (Value::Tuple(items), ValueType::Tuple(types)) if items.len() == types.len() => {
+            for (item, item_type) in items.iter().zip(types.iter()) {
+                push_value(item, item_type, words, heap, metadata)?;
+            }
+        }
+        (Value::Maybe(None), ValueType::Maybe(_)) => {
+            words.extend(std::iter::repeat_n(0, value_type.size()))
+        }
+        (Value::Maybe(Some(inner)), ValueType::Maybe(inner_type)) => {
+            words.push(1);
+            push_value(inner, inner_type, words, heap, metadata)?;
+        }
+        (Value::Active(active), ValueType::Active(type_index))
+            if active.type_index == *type_index =>
+        {
+            words.push(active.handle)
+        }
+        (_, ValueType::Object(_) | ValueType::Future(_)) => {
+            return Err(ValueError::UnsupportedType(
+                value_type.name(&metadata.types_names),
+            ))
+        }
+        _ => {
+            return Err(ValueError::TypeMismatch {
+                expected: value_type.name(&metadata.types_names),
+                given: value.clone(),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn get_list_kind(item_type: &ValueType, metadata: &Metadata) -> Result<usize, ValueError> {
+    metadata.find_list_kind(item_type).ok_or_else(|| {
+        ValueError::UnsupportedType(format!("[{}]", item_type.name(&metadata.types_names)))
+    })
+}
+
+fn read_value(
+    value_type: &ValueType,
+    slots: &[u64],
+    heap: &Heap,
+    metadata: &Metadata,
+) -> Result<Value, ValueError> {
+    // Empty tuple takes no slots at all
+    let value = slots.first().copied().unwrap_or(0);
+    Ok(match value_type {
+        ValueType::Int => Value::Int(value as i64),
+        ValueType::Float => Value::Float(u64_to_f64(value)),
+        ValueType::Bool => Value::Bool(value != 0),
+        ValueType::String => Value::String(heap.get(value).extract_string().clone()),
+        ValueType::List(item_type) => {
+            let list = heap.get(value).extract_list();
+            let size = item_type.size();
+            let items: Result<Vec<Value>, ValueError> = (0..list.items_amount)
+                .map(|i| read_value(item_type, &list.data[i * size..], heap, metadata))
+                .collect();
+            Value::List(items?)
+        }
+        ValueType::Tuple(items) => {
+            let mut offset = 0;
+            let mut values = vec![];
+            for item in items {
+                values.push(read_value(item, &slots[offset..], heap, metadata)?);
+                offset += item.size();
+            }
+            Value::Tuple(values)
+        }
+        ValueType::Maybe(_) if value == 0 => Value::Maybe(None),
+        ValueType::Maybe(inner) => Value::Maybe(Some(Box::new(read_value(
+            inner,
+            &slots[1..],
+            heap,
+            metadata,
+        )?))),
+        ValueType::Active(type_index) => {
+            Value::Active(ActiveRef { handle: value, type_index: *type_index })
+        }
+        ValueType::Object(_) | ValueType::Future(_) => {
+            return Err(ValueError::UnsupportedType(
+                value_type.name(&metadata.types_names),
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::runtime::serialization::{deserialize_function_args, serialize_return_value};
+
+    const FUNCTION_POS: usize = 16;
+
+    // Function takes ([String], Int?) and Float, and returns the same tuple
+    fn test_metadata() -> (Metadata, Signature) {
+        let tuple = ValueType::Tuple(vec![
+            ValueType::List(Box::new(ValueType::String)),
+            ValueType::Maybe(Box::new(ValueType::Int)),
+        ]);
+        let mut metadata = Metadata {
+            types_names: vec!["main::Point".into()],
+            types_sizes: vec![2],
+            types_pointer_mapping: vec![vec![]],
+            list_types_names: vec!["Int".into(), "String".into()],
+            list_types_sizes: vec![1, 1],
+            lists_pointer_mapping: vec![vec![], vec![0]],
+            function_positions: HashMap::from([(FUNCTION_POS, 0)]),
+            ..Default::default()
+        };
+        metadata.fill_function_metadata(vec![("main::check".into(), 4, vec![0])]);
+        metadata.fill_function_return_metadata(vec![("main::check".into(), 3, vec![0])]);
+        let signature = Signature {
+            args: vec![tuple.clone(), ValueType::Float],
+            returns: tuple,
+            active_type: None,
+        };
+        metadata.function_signatures.insert(FUNCTION_POS, signature.clone());
+        let point = ValueType::Tuple(vec![ValueType::Int, ValueType::Int]);
+        metadata
+            .fill_pointer_kinds(&[point], &[ValueType::Int, ValueType::String])
+            .unwrap();
+        (metadata, signature)
+    }
+
+    fn strings(items: &[&str]) -> Value {
+        Value::List(items.iter().map(|s| Value::String(s.to_string())).collect())
+    }
+
+    // Arguments are unpacked as the function would get them, and the tuple is sent back
+    fn call_and_return_tuple(tuple: Value) -> Result<Value, ValueError> {
+        let (metadata, signature) = test_metadata();
+        let args = [tuple, Value::Float(0.5)];
+        let message = pack_call(FUNCTION_POS, &args, &signature, &metadata)?;
+
+        let mut heap = Heap::default();
+        let mut stack = vec![0; 4];
+        let mut stack_pointer = 0;
+        deserialize_function_args(
+            FUNCTION_POS,
+            &mut stack,
+            &mut stack_pointer,
+            &mut heap,
+            &metadata,
+            &message,
+        )
+        .unwrap();
+        assert_eq!(u64_to_f64(stack[3]), 0.5);
+        let reply = serialize_return_value(FUNCTION_POS, &stack[..3], &heap, &metadata);
+        unpack_return(&reply, &signature, &metadata)
+    }
+
+    #[test]
+    fn packed_arguments_are_returned_back() {
+        let tuple = Value::Tuple(vec![
+            strings(&["a", "bc"]),
+            Value::Maybe(Some(Box::new(Value::Int(-3)))),
+        ]);
+        assert_eq!(call_and_return_tuple(tuple.clone()), Ok(tuple));
+
+        let tuple = Value::Tuple(vec![strings(&[]), Value::Maybe(None)]);
+        assert_eq!(call_and_return_tuple(tuple.clone()), Ok(tuple));
+    }
+
+    #[test]
+    fn wrong_values_are_refused() {
+        let (metadata, signature) = test_metadata();
+        let call = |args: &[Value]| pack_call(FUNCTION_POS, args, &signature, &metadata);
+
+        let tuple = Value::Tuple(vec![strings(&["a"]), Value::Int(1)]);
+        assert_eq!(
+            call(&[tuple, Value::Float(1.0)]),
+            Err(ValueError::TypeMismatch { expected: "Int?".into(), given: Value::Int(1) })
+        );
+        let tuple = Value::Tuple(vec![strings(&[]), Value::Maybe(None)]);
+        assert_eq!(
+            call(&[tuple, Value::Int(1)]),
+            Err(ValueError::TypeMismatch { expected: "Float".into(), given: Value::Int(1) })
+        );
+
+        let object = ValueType::List(Box::new(ValueType::Object(0)));
+        assert_eq!(
+            check_supported(&ValueType::Tuple(vec![ValueType::Int, object]), &metadata),
+            Err(ValueError::UnsupportedType("main::Point".into()))
+        );
+    }
+}
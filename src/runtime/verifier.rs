@@ -0,0 +1,923 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use super::errors::{RuntimeError, RuntimeErrorKind};
+use super::metadata::Metadata;
+use super::opcodes::op;
+use super::stdlib_runners::STD_RAW_FUNCTION_RUNNERS;
+
+// Instructions, that might collect garbage, so stack maps are needed right after them
+const SAFEPOINTS: [u8; 5] =
+    [op::ADD_STRINGS, op::ALLOCATE, op::ALLOCATE_LIST, op::CALL, op::CALL_STD];
+
+/// What is known about a value on the stack, so that numbers are never used as pointers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueKind {
+    Reserved, // zero, that is not written yet
+    Scalar,
+    Pointer,
+    Active,
+    Unknown, // different on different paths, or not tracked
+}
+
+impl ValueKind {
+    fn merge(self, other: ValueKind) -> ValueKind {
+        if self == other {
+            self
+        } else {
+            ValueKind::Unknown
+        }
+    }
+
+    // Zero is a valid nil value, but it can not be dereferenced
+    fn fits(self, expected: ValueKind) -> bool {
+        matches!(self, ValueKind::Reserved | ValueKind::Unknown) || self == expected
+    }
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueKind::Reserved => "reserved value",
+            ValueKind::Scalar => "scalar",
+            ValueKind::Pointer => "pointer",
+            ValueKind::Active => "active object",
+            ValueKind::Unknown => "unknown value",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+struct Instruction<'a> {
+    opcode: u8,
+    operands: &'a [u8],
+}
+
+/// Checks the whole program before any of it runs, so that truncated or edited bytecode
+/// is reported, instead of reading outside of the stack or the program during execution.
+/// Code section starts at `code_start` and contains only functions, one after another
+pub struct Verifier<'a> {
+    program: &'a [u8],
+    code_start: usize,
+    constants: &'a [ValueKind],
+    metadata: &'a Metadata,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(
+        program: &'a [u8],
+        code_start: usize,
+        constants: &'a [ValueKind],
+        metadata: &'a Metadata,
+    ) -> Self {
+        Verifier { program, code_start, constants, metadata }
+    }
+
+    pub fn verify(&self, entry: usize) -> Result<(), RuntimeError> {
+        let starts = self.verify_metadata(entry)?;
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(self.program.len());
+            self.verify_function(*start, end)?;
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, position: usize, message: String) -> RuntimeError {
+        RuntimeError::new(
+            RuntimeErrorKind::InvalidBytecode(message),
+            position,
+            self.metadata,
+        )
+    }
+
+    /// Returns sorted starts of functions
+    fn verify_metadata(&self, entry: usize) -> Result<Vec<usize>, RuntimeError> {
+        let metadata = self.metadata;
+        let at_start = |message| RuntimeError::invalid_bytecode(self.code_start, message);
+
+        if metadata.function_positions.len() != metadata.function_names.len() {
+            return Err(at_start("several functions have the same position".into()));
+        }
+        let mut starts: Vec<usize> = metadata.function_positions.keys().copied().collect();
+        starts.sort_unstable();
+        match (starts.first(), starts.last()) {
+            (Some(first), Some(last))
+                if *first == self.code_start && *last < self.program.len() => {}
+            (None, _) => return Err(at_start("program has no functions".into())),
+            _ => return Err(at_start("functions are outside of the code".into())),
+        }
+        if !metadata.function_positions.contains_key(&entry) {
+            return Err(at_start(format!("entry {:#x} is not a function", entry)));
+        }
+
+        let hooks = metadata
+            .types_on_stop
+            .iter()
+            .chain(metadata.types_on_child_failed.iter());
+        for (i, hook) in hooks.enumerate() {
+            match hook {
+                Some(position) if !metadata.function_positions.contains_key(position) => {
+                    let type_name = &metadata.types_names[i % metadata.types_names.len()];
+                    return Err(at_start(format!(
+                        "hook of `{}` at {:#x} is not a function",
+                        type_name, position
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        // Garbage collector and messages read values at these offsets
+        let m = metadata;
+        let mappings = [
+            (
+                "type",
+                &m.types_sizes,
+                &m.types_pointer_mapping,
+                &m.types_active_mapping,
+            ),
+            (
+                "list kind",
+                &m.list_types_sizes,
+                &m.lists_pointer_mapping,
+                &m.lists_active_mapping,
+            ),
+            (
+                "arguments of function",
+                &m.function_args_sizes,
+                &m.functions_pointer_mapping,
+                &m.functions_active_mapping,
+            ),
+            (
+                "return value of function",
+                &m.function_return_sizes,
+                &m.functions_return_pointer_mapping,
+                &m.functions_return_active_mapping,
+            ),
+        ];
+        for (kind, sizes, pointers, actives) in mappings {
+            for (i, size) in sizes.iter().enumerate() {
+                let mut offsets = pointers[i].iter().chain(actives[i].iter());
+                if let Some(offset) = offsets.find(|offset| **offset >= *size) {
+                    return Err(at_start(format!(
+                        "{} #{} has reference at offset {}, but its size is {}",
+                        kind, i, offset, size
+                    )));
+                }
+            }
+        }
+        Ok(starts)
+    }
+
+    fn verify_function(&self, start: usize, end: usize) -> Result<(), RuntimeError> {
+        let mut instructions = BTreeMap::new();
+        let mut position = start;
+        while position < end {
+            let opcode = self.program[position];
+            if !op::is_known(opcode) {
+                return Err(self.invalid(position, format!("unknown opcode {:02x}", opcode)));
+            }
+            let operands_end = position + 1 + op::get_args_num(opcode);
+            if operands_end > end {
+                return Err(self.invalid(
+                    position,
+                    format!(
+                        "operands of {} are cut off by the end of function",
+                        op::get_display_name(opcode)
+                    ),
+                ));
+            }
+            let operands = &self.program[position + 1..operands_end];
+            instructions.insert(position, Instruction { opcode, operands });
+            position = operands_end;
+        }
+
+        // Each instruction must be reached with the same stack depth on all paths,
+        // kinds of values are merged, until nothing changes
+        let function_index = self.metadata.function_positions[&start];
+        let active_type = self.active_type_of(start)?;
+        let args = self.expected_kinds(
+            self.metadata.function_args_sizes[function_index],
+            &self.metadata.functions_pointer_mapping[function_index],
+            &self.metadata.functions_active_mapping[function_index],
+        );
+        let mut states: HashMap<usize, Vec<ValueKind>> = HashMap::new();
+        let mut pending = vec![(start, args)];
+        while let Some((position, mut stack)) = pending.pop() {
+            if let Some(known) = states.get(&position) {
+                if known.len() != stack.len() {
+                    return Err(self.invalid(
+                        position,
+                        format!(
+                            "stack depth is {} on one path and {} on another",
+                            known.len(),
+                            stack.len()
+                        ),
+                    ));
+                }
+                let merged: Vec<ValueKind> =
+                    known.iter().zip(&stack).map(|(a, b)| a.merge(*b)).collect();
+                if merged == *known {
+                    continue;
+                }
+                stack = merged;
+            }
+            states.insert(position, stack.clone());
+            let depth = stack.len();
+
+            let instruction = &instructions[&position];
+            let name = op::get_display_name(instruction.opcode);
+            let (pops, pushes) = self.stack_effect(position, instruction, function_index)?;
+            if pops > depth {
+                return Err(self.invalid(
+                    position,
+                    format!("{} takes {} values, but stack has {}", name, pops, depth),
+                ));
+            }
+            let new_depth = depth - pops + pushes;
+            self.check_frame_access(position, instruction, depth, new_depth)?;
+            self.apply_kinds(
+                position,
+                instruction,
+                function_index,
+                active_type,
+                &mut stack,
+                pops,
+            )?;
+
+            let next = position + 1 + instruction.operands.len();
+            self.check_stack_map(position, instruction, next, depth)?;
+
+            let mut successors = vec![];
+            match instruction.opcode {
+                op::RETURN => {}
+                op::JUMP => successors.push(next + read_u32(instruction.operands, 0)),
+                op::JUMP_IF_FALSE => {
+                    successors.push(next);
+                    successors.push(next + read_u32(instruction.operands, 0));
+                }
+                op::JUMP_BACK => match next.checked_sub(read_u32(instruction.operands, 0)) {
+                    Some(target) => successors.push(target),
+                    None => return Err(self.invalid(position, "jump before the program".into())),
+                },
+                _ => successors.push(next),
+            }
+            for successor in successors {
+                if successor == end {
+                    return Err(
+                        self.invalid(position, "execution runs past the end of function".into())
+                    );
+                }
+                if !instructions.contains_key(&successor) {
+                    return Err(self.invalid(
+                        position,
+                        format!(
+                            "jump to {:#x}, which is not an instruction of the function",
+                            successor
+                        ),
+                    ));
+                }
+                pending.push((successor, stack.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks indexes in operands, and returns amounts of values taken from the stack
+    /// and put on it
+    fn stack_effect(
+        &self,
+        position: usize,
+        instruction: &Instruction,
+        function_index: usize,
+    ) -> Result<(usize, usize), RuntimeError> {
+        let metadata = self.metadata;
+        let operands = instruction.operands;
+        let operand = |i: usize| operands[i] as usize;
+        let check_index = |index: usize, amount: usize, kind: &str| {
+            if index < amount {
+                Ok(index)
+            } else {
+                Err(self.invalid(position, format!("{} #{} does not exist", kind, index)))
+            }
+        };
+        let function_at =
+            |callee: usize| {
+                metadata.function_positions.get(&callee).copied().ok_or_else(|| {
+                    self.invalid(position, format!("{:#x} is not a function", callee))
+                })
+            };
+
+        let effect = match instruction.opcode {
+            op::LOAD_TRUE | op::LOAD_FALSE | op::LOAD_SMALL_INT | op::CURRENT_ACTIVE => (0, 1),
+            op::LOAD_CONST => {
+                check_index(operand(0), self.constants.len(), "constant")?;
+                (0, 1)
+            }
+            op::LOAD_CONST_WIDE => {
+                check_index(read_u16(operands, 0), self.constants.len(), "constant")?;
+                (0, 1)
+            }
+            op::NEGATE_INT | op::NEGATE_FLOAT | op::NEGATE_BOOL => (1, 1),
+            op::ADD_INT
+            | op::SUB_INT
+            | op::MUL_INT
+            | op::DIV_INT
+            | op::GREATER_INT
+            | op::LESS_INT
+            | op::EQ_INT
+            | op::ADD_FLOAT
+            | op::SUB_FLOAT
+            | op::MUL_FLOAT
+            | op::DIV_FLOAT
+            | op::GREATER_FLOAT
+            | op::LESS_FLOAT
+            | op::EQ_FLOAT
+            | op::EQ_BOOL
+            | op::AND_BOOL
+            | op::OR_BOOL
+            | op::ADD_STRINGS
+            | op::EQ_STRINGS => (2, 1),
+
+            op::RESERVE => (0, operand(0)),
+            op::POP => (operand(0), 0),
+            op::SET_LOCAL => (operand(1), 0),
+            op::GET_LOCAL => (0, operand(1)),
+            op::GET_TUPLE_ITEM => {
+                let (tuple_size, offset, size) = (operand(0), operand(1), operand(2));
+                if offset + size > tuple_size {
+                    return Err(self.invalid(
+                        position,
+                        format!("tuple of size {} has no item at {}", tuple_size, offset),
+                    ));
+                }
+                // Item is copied to the space reserved right before the tuple
+                (tuple_size + size, size)
+            }
+
+            op::JUMP | op::JUMP_BACK => (0, 0),
+            op::JUMP_IF_FALSE => (1, 0),
+
+            op::CALL => {
+                let callee = function_at(read_u32(operands, 1))?;
+                let args_size = metadata.function_args_sizes[callee];
+                if operand(0) != args_size {
+                    return Err(self.invalid(
+                        position,
+                        format!(
+                            "`{}` takes arguments of size {}, but {} is passed",
+                            metadata.function_names[callee],
+                            args_size,
+                            operand(0)
+                        ),
+                    ));
+                }
+                // Space for the return value is reserved before the arguments
+                let return_size = metadata.function_return_sizes[callee];
+                (args_size + return_size, return_size)
+            }
+            op::CALL_STD => {
+                check_index(operand(2), STD_RAW_FUNCTION_RUNNERS.len(), "std function")?;
+                (operand(0), operand(1))
+            }
+            op::RETURN => {
+                let return_size = metadata.function_return_sizes[function_index];
+                if operand(0) != return_size {
+                    return Err(self.invalid(
+                        position,
+                        format!(
+                            "value of size {} is returned, but function returns {}",
+                            operand(0),
+                            return_size
+                        ),
+                    ));
+                }
+                (return_size, 0)
+            }
+
+            op::ALLOCATE => {
+                check_index(read_u16(operands, 0), metadata.types_sizes.len(), "type")?;
+                (0, 1)
+            }
+            op::SET_OBJ_FIELD => (1 + operand(2), 0),
+            op::GET_OBJ_FIELD => (1, operand(2)),
+
+            op::ALLOCATE_LIST => {
+                let kinds_amount = metadata.list_types_sizes.len();
+                let kind = check_index(read_u16(operands, 0), kinds_amount, "list kind")?;
+                (metadata.list_types_sizes[kind] * read_u16(operands, 2), 1)
+            }
+            op::GET_LIST_ITEM => (2, operand(0)),
+            op::SET_LIST_ITEM => (2 + operand(1), 0),
+
+            op::SPAWN | op::SPAWN_REMOTE => {
+                check_index(read_u16(operands, 0), metadata.types_sizes.len(), "type")?;
+                let constructor = function_at(read_u32(operands, 2))?;
+                let args_size = metadata.function_args_sizes[constructor];
+                match instruction.opcode {
+                    op::SPAWN_REMOTE => (args_size + 1, 1), // name of the node is on top
+                    _ => (args_size, 1),
+                }
+            }
+            op::GET_CURRENT_ACTIVE_FIELD => (0, operand(2)),
+            op::SET_CURRENT_ACTIVE_FIELD => (operand(2), 0),
+
+            // Active object is on the stack right before the arguments
+            op::SEND_MESSAGE | op::SEND_MESSAGE_AFTER | op::ASK_MESSAGE => {
+                let receiver = function_at(read_u32(operands, 0))?;
+                let args_size = metadata.function_args_sizes[receiver];
+                match instruction.opcode {
+                    op::SEND_MESSAGE => (args_size + 1, 0),
+                    op::SEND_MESSAGE_AFTER => (args_size + 2, 0), // delay is on top
+                    _ => (args_size + 1, 1),
+                }
+            }
+            op::WAIT_FUTURE => (1, operand(0)),
+            op::STOP_ACTIVE | op::WAIT_ACTIVE => (1, 0),
+            opcode => unreachable!("opcode {:02x} is not handled by verifier", opcode),
+        };
+        Ok(effect)
+    }
+
+    fn active_type_of(&self, start: usize) -> Result<Option<usize>, RuntimeError> {
+        let signature = self.metadata.function_signatures.get(&start);
+        match signature.and_then(|signature| signature.active_type) {
+            Some(index) if index >= self.metadata.types_sizes.len() => Err(self.invalid(
+                start,
+                format!("function runs in type #{}, which does not exist", index),
+            )),
+            active_type => Ok(active_type),
+        }
+    }
+
+    fn expected_kinds(&self, size: usize, pointers: &[usize], actives: &[usize]) -> Vec<ValueKind> {
+        let mut kinds = vec![ValueKind::Scalar; size];
+        for offset in pointers {
+            kinds[*offset] = ValueKind::Pointer;
+        }
+        for offset in actives {
+            kinds[*offset] = ValueKind::Active;
+        }
+        kinds
+    }
+
+    /// Replaces kinds of values taken by the instruction with kinds of values it puts,
+    /// values that can not be used by the instruction are refused
+    fn apply_kinds(
+        &self,
+        position: usize,
+        instruction: &Instruction,
+        function_index: usize,
+        active_type: Option<usize>,
+        stack: &mut Vec<ValueKind>,
+        pops: usize,
+    ) -> Result<(), RuntimeError> {
+        let metadata = self.metadata;
+        let operands = instruction.operands;
+        let operand = |i: usize| operands[i] as usize;
+        let name = op::get_display_name(instruction.opcode);
+        let taken = stack.split_off(stack.len() - pops);
+
+        let expect = |values: &[ValueKind], expected: &[ValueKind]| {
+            let mut pairs = values.iter().zip(expected).enumerate();
+            match pairs.find(|(_, (value, expected))| !value.fits(**expected)) {
+                Some((i, (value, expected))) => Err(self.invalid(
+                    position,
+                    format!(
+                        "{} expects {} as value #{}, but gets {}",
+                        name, expected, i, value
+                    ),
+                )),
+                None => Ok(()),
+            }
+        };
+        let dereference = |value: ValueKind| match value {
+            ValueKind::Pointer | ValueKind::Unknown => Ok(()),
+            _ => Err(self.invalid(position, format!("{} dereferences {}", name, value))),
+        };
+        let args_of = |function: usize| {
+            self.expected_kinds(
+                metadata.function_args_sizes[function],
+                &metadata.functions_pointer_mapping[function],
+                &metadata.functions_active_mapping[function],
+            )
+        };
+        let returns_of = |function: usize| {
+            self.expected_kinds(
+                metadata.function_return_sizes[function],
+                &metadata.functions_return_pointer_mapping[function],
+                &metadata.functions_return_active_mapping[function],
+            )
+        };
+        let active_fields = |offset: usize, size: usize| match active_type {
+            None => Err(self.invalid(
+                position,
+                format!("{} is used outside of methods of active objects", name),
+            )),
+            Some(index) if offset + size > metadata.types_sizes[index] => Err(self.invalid(
+                position,
+                format!(
+                    "`{}` has no field of size {} at offset {}",
+                    metadata.types_names[index], size, offset
+                ),
+            )),
+            Some(index) => {
+                let fields = self.expected_kinds(
+                    metadata.types_sizes[index],
+                    &metadata.types_pointer_mapping[index],
+                    &metadata.types_active_mapping[index],
+                );
+                Ok(fields[offset..offset + size].to_vec())
+            }
+        };
+
+        let put = match instruction.opcode {
+            op::LOAD_TRUE | op::LOAD_FALSE | op::LOAD_SMALL_INT => vec![ValueKind::Scalar],
+            op::CURRENT_ACTIVE => vec![ValueKind::Active],
+            op::LOAD_CONST => vec![self.constants[operand(0)]],
+            op::LOAD_CONST_WIDE => vec![self.constants[read_u16(operands, 0)]],
+            op::ADD_STRINGS => {
+                dereference(taken[0])?;
+                dereference(taken[1])?;
+                vec![ValueKind::Pointer]
+            }
+            op::EQ_STRINGS => {
+                dereference(taken[0])?;
+                dereference(taken[1])?;
+                vec![ValueKind::Scalar]
+            }
+
+            op::RESERVE => vec![ValueKind::Reserved; operand(0)],
+            op::SET_LOCAL => {
+                stack[operand(0)..operand(0) + operand(1)].copy_from_slice(&taken);
+                vec![]
+            }
+            op::GET_LOCAL => stack[operand(0)..operand(0) + operand(1)].to_vec(),
+            op::GET_TUPLE_ITEM => {
+                let item_start = operand(2) + operand(1);
+                taken[item_start..item_start + operand(2)].to_vec()
+            }
+            op::POP | op::JUMP | op::JUMP_BACK | op::JUMP_IF_FALSE => vec![],
+
+            op::CALL => {
+                let callee = metadata.function_positions[&read_u32(operands, 1)];
+                let return_size = metadata.function_return_sizes[callee];
+                expect(&taken[return_size..], &args_of(callee))?;
+                returns_of(callee)
+            }
+            op::CALL_STD => vec![ValueKind::Unknown; operand(1)],
+            op::RETURN => {
+                expect(&taken, &returns_of(function_index))?;
+                vec![]
+            }
+
+            // Types of objects and lists are not tracked, so their fields are unknown
+            op::ALLOCATE | op::ALLOCATE_LIST => vec![ValueKind::Pointer],
+            op::GET_OBJ_FIELD => {
+                dereference(taken[0])?;
+                vec![ValueKind::Unknown; operand(2)]
+            }
+            op::SET_OBJ_FIELD => {
+                dereference(taken[pops - 1])?;
+                vec![]
+            }
+            op::GET_LIST_ITEM => {
+                expect(&taken[..1], &[ValueKind::Scalar])?;
+                dereference(taken[1])?;
+                vec![ValueKind::Unknown; operand(0)]
+            }
+            op::SET_LIST_ITEM => {
+                expect(&taken[pops - 2..pops - 1], &[ValueKind::Scalar])?;
+                dereference(taken[pops - 1])?;
+                vec![]
+            }
+
+            op::SPAWN | op::SPAWN_REMOTE => {
+                let constructor = metadata.function_positions[&read_u32(operands, 2)];
+                let args_size = metadata.function_args_sizes[constructor];
+                expect(&taken[..args_size], &args_of(constructor))?;
+                if instruction.opcode == op::SPAWN_REMOTE {
+                    dereference(taken[args_size])?;
+                }
+                vec![ValueKind::Active]
+            }
+            op::GET_CURRENT_ACTIVE_FIELD => active_fields(read_u16(operands, 0), operand(2))?,
+            op::SET_CURRENT_ACTIVE_FIELD => {
+                expect(&taken, &active_fields(read_u16(operands, 0), operand(2))?)?;
+                vec![]
+            }
+            op::SEND_MESSAGE | op::SEND_MESSAGE_AFTER | op::ASK_MESSAGE => {
+                let receiver = metadata.function_positions[&read_u32(operands, 0)];
+                let args_size = metadata.function_args_sizes[receiver];
+                expect(&taken[..1], &[ValueKind::Active])?;
+                expect(&taken[1..args_size + 1], &args_of(receiver))?;
+                match instruction.opcode {
+                    op::SEND_MESSAGE_AFTER => {
+                        expect(&taken[args_size + 1..], &[ValueKind::Scalar])?;
+                        vec![]
+                    }
+                    op::ASK_MESSAGE => vec![ValueKind::Scalar], // future
+                    _ => vec![],
+                }
+            }
+            op::WAIT_FUTURE => {
+                expect(&taken, &[ValueKind::Scalar])?;
+                vec![ValueKind::Unknown; operand(0)]
+            }
+            op::STOP_ACTIVE | op::WAIT_ACTIVE => {
+                expect(&taken, &[ValueKind::Active])?;
+                vec![]
+            }
+            // Arithmetic, comparisons and negations
+            _ => vec![ValueKind::Scalar],
+        };
+        stack.extend(put);
+        Ok(())
+    }
+
+    // Locals are accessed by offset from the start of the frame
+    fn check_frame_access(
+        &self,
+        position: usize,
+        instruction: &Instruction,
+        depth: usize,
+        new_depth: usize,
+    ) -> Result<(), RuntimeError> {
+        let frame_size = match instruction.opcode {
+            op::GET_LOCAL => depth,
+            op::SET_LOCAL => new_depth,
+            _ => return Ok(()),
+        };
+        let (offset, size) = (instruction.operands[0], instruction.operands[1]);
+        if offset as usize + size as usize > frame_size {
+            return Err(self.invalid(
+                position,
+                format!(
+                    "local at offset {} of size {} is outside of the frame of size {}",
+                    offset, size, frame_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Garbage collector reads pointers of the frame by the stack map
+    fn check_stack_map(
+        &self,
+        position: usize,
+        instruction: &Instruction,
+        next: usize,
+        depth: usize,
+    ) -> Result<(), RuntimeError> {
+        let stack_map = match self.metadata.stack_maps.get(&next) {
+            Some(stack_map) => stack_map,
+            None if SAFEPOINTS.contains(&instruction.opcode) => {
+                return Err(self.invalid(
+                    position,
+                    format!(
+                        "{} has no stack map",
+                        op::get_display_name(instruction.opcode)
+                    ),
+                ))
+            }
+            None => return Ok(()),
+        };
+        // Arguments of the called function belong to its own frame
+        let frame_size = match instruction.opcode {
+            op::CALL => depth - instruction.operands[0] as usize,
+            _ => depth,
+        };
+        if let Some(slot) = stack_map.iter().find(|slot| **slot >= frame_size) {
+            return Err(self.invalid(
+                position,
+                format!(
+                    "stack map has pointer at {}, but frame has {} values",
+                    slot, frame_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn read_u16(operands: &[u8], from: usize) -> usize {
+    u16::from_be_bytes([operands[from], operands[from + 1]]) as usize
+}
+
+fn read_u32(operands: &[u8], from: usize) -> usize {
+    let bytes = [operands[from], operands[from + 1], operands[from + 2], operands[from + 3]];
+    u32::from_be_bytes(bytes) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::runtime::debugger::ValueType;
+    use crate::runtime::values::Signature;
+
+    // Program of a single function at position 0, that takes one value and returns nothing
+    fn function_metadata() -> Metadata {
+        let mut metadata = Metadata::default();
+        metadata.fill_function_metadata(vec![("main::f".into(), 1, vec![])]);
+        metadata.fill_function_return_metadata(vec![("main::f".into(), 0, vec![])]);
+        metadata.function_positions.insert(0, 0);
+        metadata
+    }
+
+    fn verify(code: &[u8], constants: &[ValueKind], metadata: &Metadata) -> Result<(), String> {
+        let verifier = Verifier::new(code, 0, constants, metadata);
+        verifier.verify(0).map_err(|error| error.to_string())
+    }
+
+    fn verify_function(code: &[u8], constants_amount: usize) -> Result<(), String> {
+        let constants = vec![ValueKind::Scalar; constants_amount];
+        verify(code, &constants, &function_metadata())
+    }
+
+    // Same function, but it is a method of `main::A`, that has a pointer field after a scalar one
+    fn verify_method(code: &[u8]) -> Result<(), String> {
+        let mut metadata = function_metadata();
+        metadata.fill_types_metadata(vec![("main::A".into(), 2, vec![1])]);
+        let signature = Signature {
+            args: vec![ValueType::Int],
+            returns: ValueType::Tuple(vec![]),
+            active_type: Some(0),
+        };
+        metadata.function_signatures.insert(0, signature);
+        verify(code, &[], &metadata)
+    }
+
+    #[test]
+    fn loops_and_branches_are_fine() {
+        #[rustfmt::skip]
+        let code = [
+            op::RESERVE, 1,
+            op::GET_LOCAL, 0, 1, // 0x02, start of the loop
+            op::JUMP_IF_FALSE, 0, 0, 0, 14,
+            op::LOAD_CONST, 0,
+            op::GET_LOCAL, 1, 1,
+            op::ADD_INT,
+            op::SET_LOCAL, 1, 1,
+            op::JUMP_BACK, 0, 0, 0, 22,
+            op::RETURN, 0, // 0x18
+        ];
+        assert_eq!(verify_function(&code, 1), Ok(()));
+    }
+
+    #[test]
+    fn malformed_instructions_found() {
+        assert_eq!(
+            verify_function(&[op::RESERVE, 0, 0xee, op::RETURN, 0], 0),
+            Err("invalid bytecode: unknown opcode ee in main::f (ip 0x2)".into())
+        );
+        assert_eq!(
+            verify_function(&[op::RESERVE, 0, op::GET_LOCAL, 0], 0),
+            Err("invalid bytecode: operands of GET_LOCAL are cut off by the end of function in main::f (ip 0x2)".into())
+        );
+        assert_eq!(
+            verify_function(&[op::LOAD_CONST, 2, op::POP, 1, op::RETURN, 0], 2),
+            Err("invalid bytecode: constant #2 does not exist in main::f (ip 0x0)".into())
+        );
+        assert_eq!(
+            verify_function(&[op::GET_LOCAL, 1, 1, op::POP, 1, op::RETURN, 0], 0),
+            Err("invalid bytecode: local at offset 1 of size 1 is outside of the frame of size 1 in main::f (ip 0x0)".into())
+        );
+    }
+
+    #[test]
+    fn wrong_control_flow_found() {
+        // Jump to the operand of LOAD_SMALL_INT
+        assert_eq!(
+            verify_function(&[op::JUMP, 0, 0, 0, 1, op::LOAD_SMALL_INT, 1, op::RETURN, 0], 0),
+            Err("invalid bytecode: jump to 0x6, which is not an instruction of the function in main::f (ip 0x0)".into())
+        );
+        assert_eq!(
+            verify_function(&[op::RESERVE, 0], 0),
+            Err(
+                "invalid bytecode: execution runs past the end of function in main::f (ip 0x0)"
+                    .into()
+            )
+        );
+    }
+
+    #[test]
+    fn unbalanced_stack_found() {
+        assert_eq!(
+            verify_function(&[op::POP, 2, op::RETURN, 0], 0),
+            Err("invalid bytecode: POP takes 2 values, but stack has 1 in main::f (ip 0x0)".into())
+        );
+        // Each iteration of the loop leaves one more value on the stack
+        assert_eq!(
+            verify_function(&[op::LOAD_TRUE, op::JUMP_BACK, 0, 0, 0, 6], 0),
+            Err("invalid bytecode: stack depth is 1 on one path and 2 on another in main::f (ip 0x0)".into())
+        );
+    }
+
+    #[test]
+    fn scalars_are_not_dereferenced() {
+        assert_eq!(
+            verify_function(
+                &[op::LOAD_SMALL_INT, 5, op::GET_OBJ_FIELD, 0, 0, 1, op::POP, 1, op::RETURN, 0],
+                0
+            ),
+            Err("invalid bytecode: GET_OBJ_FIELD dereferences scalar in main::f (ip 0x2)".into())
+        );
+        // Argument of the function is not a pointer by its mapping
+        assert_eq!(
+            verify_function(
+                &[op::LOAD_TRUE, op::GET_LOCAL, 0, 1, op::SET_OBJ_FIELD, 0, 0, 1, op::RETURN, 0],
+                0
+            ),
+            Err("invalid bytecode: SET_OBJ_FIELD dereferences scalar in main::f (ip 0x4)".into())
+        );
+        assert_eq!(
+            verify_function(
+                &[
+                    op::LOAD_SMALL_INT,
+                    0,
+                    op::LOAD_CONST,
+                    0,
+                    op::GET_LIST_ITEM,
+                    1,
+                    op::POP,
+                    1,
+                    op::RETURN,
+                    0
+                ],
+                1
+            ),
+            Err("invalid bytecode: GET_LIST_ITEM dereferences scalar in main::f (ip 0x4)".into())
+        );
+        assert_eq!(
+            verify_function(&[op::RESERVE, 2, op::SET_LIST_ITEM, 0, 1, op::RETURN, 0], 0),
+            Err(
+                "invalid bytecode: SET_LIST_ITEM dereferences reserved value in main::f (ip 0x2)"
+                    .into()
+            )
+        );
+        assert_eq!(
+            verify_function(&[op::LOAD_SMALL_INT, 1, op::STOP_ACTIVE, op::RETURN, 0], 0),
+            Err("invalid bytecode: STOP_ACTIVE expects active object as value #0, but gets scalar in main::f (ip 0x2)".into())
+        );
+    }
+
+    #[test]
+    fn pointers_are_tracked() {
+        // String constant is stored to a local, and read from it on both paths of the branch
+        #[rustfmt::skip]
+        let code = [
+            op::RESERVE, 1,
+            op::LOAD_CONST, 0,
+            op::SET_LOCAL, 1, 1,
+            op::GET_LOCAL, 0, 1,
+            op::JUMP_IF_FALSE, 0, 0, 0, 0,
+            op::GET_LOCAL, 1, 1,
+            op::GET_OBJ_FIELD, 0, 0, 1,
+            op::POP, 1,
+            op::RETURN, 0,
+        ];
+        assert_eq!(
+            verify(&code, &[ValueKind::Pointer], &function_metadata()),
+            Ok(())
+        );
+
+        // Local is a pointer on one path and a scalar on another, so it is not known
+        #[rustfmt::skip]
+        let code = [
+            op::LOAD_CONST, 0,
+            op::GET_LOCAL, 0, 1,
+            op::JUMP_IF_FALSE, 0, 0, 0, 4,
+            op::POP, 1,
+            op::LOAD_CONST, 1,
+            op::GET_OBJ_FIELD, 0, 0, 1,
+            op::POP, 2,
+            op::RETURN, 0,
+        ];
+        let constants = [ValueKind::Pointer, ValueKind::Scalar];
+        assert_eq!(verify(&code, &constants, &function_metadata()), Ok(()));
+        assert_eq!(
+            verify(&code, &[ValueKind::Scalar; 2], &function_metadata()),
+            Err("invalid bytecode: GET_OBJ_FIELD dereferences scalar in main::f (ip 0xe)".into())
+        );
+    }
+
+    #[test]
+    fn current_active_fields_are_checked() {
+        assert_eq!(
+            verify_method(&[op::GET_CURRENT_ACTIVE_FIELD, 0, 0, 2, op::POP, 2, op::RETURN, 0]),
+            Ok(())
+        );
+        assert_eq!(
+            verify_method(&[op::GET_CURRENT_ACTIVE_FIELD, 0, 1, 2, op::POP, 2, op::RETURN, 0]),
+            Err("invalid bytecode: `main::A` has no field of size 2 at offset 1 in main::f (ip 0x0)".into())
+        );
+        assert_eq!(
+            verify_method(&[op::GET_LOCAL, 0, 1, op::SET_CURRENT_ACTIVE_FIELD, 0, 1, 1, op::RETURN, 0]),
+            Err("invalid bytecode: SET_CURRENT_ACTIVE_FIELD expects pointer as value #0, but gets scalar in main::f (ip 0x3)".into())
+        );
+        assert_eq!(
+            verify_function(&[op::GET_CURRENT_ACTIVE_FIELD, 0, 0, 1, op::POP, 1, op::RETURN, 0], 0),
+            Err("invalid bytecode: GET_CURRENT_ACTIVE_FIELD is used outside of methods of active objects in main::f (ip 0x0)".into())
+        );
+    }
+}
@@ -1,19 +1,141 @@
+use super::debugger::{Debugger, LocalInfo, ValueType};
+use super::errors::{RuntimeError, RuntimeErrorKind};
+use super::futures::Futures;
+use super::header::Header;
 use super::heap::HeapObject;
-use super::metadata::{Metadata, MetadataBlock};
+use super::metadata::{Metadata, MetadataBlock, SourcePosition};
+use super::network::{
+    bytecode_hash, connect, global_id, local_part, read_frame, write_frame, Frame, Network,
+    COORDINATOR_NODE,
+};
 use super::opcodes::op;
-use super::worker::ActiveObject;
+use super::profiler::Profile;
+use super::scheduler::{
+    Delivery, Mailbox, MailboxConfig, MailboxLimit, Message, OverflowPolicy, Scheduler,
+};
+use super::serialization::{
+    read_function_position, serialize_call_with_strings, serialize_call_without_args,
+};
+use super::stdlib_runners::Output;
+use super::supervision::{RestartStrategy, Supervisor, SupervisorConfig};
+use super::timers::{Timeout, Timers};
+use super::tracing::{TraceArg, Tracer};
+use super::values::Signature;
+use super::verifier::{ValueKind, Verifier};
+use super::worker::{ActiveObject, Turn};
 
-use std::sync::{atomic, mpsc};
-use std::sync::{Arc, RwLock};
+use std::convert::TryInto;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use owo_colors::OwoColorize;
 
+use crate::errors::exit_codes;
+
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1 << 20;
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+// Entry waiting for an active object checks this often, that it might be stopped at all
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct DeadLetter {
+    pub receiver: u64,
+    pub message: Message,
+    pub error: RuntimeError,
+}
+
+pub fn default_threads_amount() -> usize {
+    thread::available_parallelism().map_or(1, |amount| amount.get())
+}
+
+/// How a sender waits for space in a full mailbox with blocking policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wait {
+    Never,        // message is put over capacity
+    Thread,       // thread of the sender waits, only entry has a thread of its own
+    Suspend(u64), // message of the sender is suspended, and its worker runs other objects
+}
+
 pub struct StoredActiveObject {
-    // pub active_object: Arc<ActiveObject>,
-    pub inbox: mpsc::Sender<Vec<u64>>,
-    pub is_running: Arc<atomic::AtomicBool>,
+    pub item_type: usize,
+    pub active_object: Mutex<ActiveObject>,
+    pub mailbox: Mutex<Mailbox>,
+    pub has_space: Condvar, // notified when a message is taken from the mailbox, for the entry
+    pub finished: Condvar,  // notified when the active object is stopped
+
+    // Serialized constructor call, that is run again when the object is restarted
+    pub constructor: Vec<u8>,
+    pub supervisor: Option<u64>,
+    pub supervision: Option<Mutex<Supervisor>>, // only for active types with supervision
+}
+
+pub struct VmOptions {
+    pub show_debug: bool,
+
+    // Limits for each active object, exceeding any of them is a stack overflow
+    pub max_stack_size: usize,
+    pub max_call_depth: usize,
+
+    // Roll back state of the active object if message fails, and move message to dead letters
+    pub transactional: bool,
+
+    // Amount of worker threads, that run messages of all active objects
+    pub threads: usize,
+
+    // Directory with sources of the program, used to show code lines in stack traces
+    pub source_root: Option<PathBuf>,
+
+    // Address to wait for the coordinator on, to run active objects spawned on this node
+    pub listen: Option<String>,
+
+    // Names and addresses of nodes, that active objects can be spawned on
+    pub nodes: Vec<(String, String)>,
+
+    // Used by active types, that do not declare their own mailbox capacity or policy
+    pub mailbox_capacity: Option<usize>,
+    pub mailbox_overflow: OverflowPolicy,
+
+    // Run all active objects on the entry thread, picking the next one with PRNG seeded by this.
+    // Senders never block and delayed messages are delivered without waiting for them
+    pub deterministic_seed: Option<u64>,
+
+    // Records spawns, sends and processing of messages
+    pub trace: Option<Tracer>,
+
+    // Count executed instructions, calls, allocations and messages, and report them on exit
+    pub profile: bool,
+
+    // Pauses actors on breakpoints and steps, to inspect them interactively
+    pub debugger: Option<Debugger>,
+
+    // Receives everything the program prints with std functions
+    pub stdout: Output,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        Self {
+            show_debug: false,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            transactional: false,
+            threads: default_threads_amount(),
+            source_root: None,
+            listen: None,
+            nodes: vec![],
+            mailbox_capacity: None,
+            mailbox_overflow: OverflowPolicy::Block,
+            deterministic_seed: None,
+            trace: None,
+            profile: false,
+            debugger: None,
+            stdout: Output::default(),
+        }
+    }
 }
 
 pub struct Vm {
@@ -24,209 +146,1390 @@ pub struct Vm {
     pub metadata: Metadata,
     pub entry: usize,
 
-    gateways_for_active: mpsc::Sender<(u64, Vec<u64>)>,
-    receiver: mpsc::Receiver<(u64, Vec<u64>)>,
+    pub options: VmOptions,
 
-    pub step_by_step: bool,
-    pub show_debug: bool,
+    active_objects: RwLock<Vec<Arc<StoredActiveObject>>>,
+    scheduler: Scheduler,
+    futures: Futures,
+    network: Network,
+    timers: Timers,
+    pub dead_letters: Mutex<Vec<DeadLetter>>,
 
-    active_objects: RwLock<Vec<StoredActiveObject>>,
+    exit_code: Mutex<Option<i32>>, // set by std `exit`, first call wins
+    has_failed_messages: AtomicBool,
+    profile: Option<Mutex<Profile>>,
 }
 
 unsafe impl Sync for Vm {}
 
 impl Vm {
-    pub fn setup(program: Vec<u8>, step_by_step: bool, show_debug: bool) -> Arc<Self> {
-        let (sender, receiver) = mpsc::channel();
+    pub fn setup(program: Vec<u8>, options: VmOptions) -> Result<Arc<Self>, RuntimeError> {
+        let (scheduler, timers) = match options.deterministic_seed {
+            Some(seed) => (Scheduler::seeded(seed), Timers::virtual_clock()),
+            None => (Scheduler::default(), Timers::default()),
+        };
+        let profile = options.profile.then(|| Mutex::new(Profile::default()));
         let mut new_vm = Self {
             ip: 0,
             program,
             constants: vec![],
             metadata: Metadata::default(),
             entry: 0,
-            step_by_step,
-            show_debug,
+            options,
             active_objects: RwLock::new(vec![]),
-
-            gateways_for_active: sender,
-            receiver,
+            dead_letters: Mutex::new(vec![]),
+            scheduler,
+            futures: Futures::default(),
+            network: Network::default(),
+            timers,
+            exit_code: Mutex::new(None),
+            has_failed_messages: AtomicBool::new(false),
+            profile,
         };
 
-        new_vm.check_header("Initial header");
+        new_vm.check_version_header()?;
+
+        let constants = new_vm.load_consts()?;
+        new_vm.load_metadata()?;
+        new_vm.load_entry()?;
+
+        // Functions take the rest of the program, so nothing runs until all of them are checked
+        let verifier = Verifier::new(&new_vm.program, new_vm.ip, &constants, &new_vm.metadata);
+        verifier.verify(new_vm.entry)?;
+        Ok(Arc::new(new_vm))
+    }
 
-        new_vm.load_consts();
-        new_vm.load_metadata();
-        new_vm.load_entry();
-        Arc::new(new_vm)
+    /// Program is refused before anything else is read, if this VM can not run it
+    fn check_version_header(&mut self) -> Result<(), RuntimeError> {
+        let (header, length) = Header::read(&self.program)
+            .map_err(|kind| RuntimeError::new(kind, 0, &self.metadata))?;
+        header
+            .check(&self.program[length..])
+            .map_err(|kind| RuntimeError::new(kind, 0, &self.metadata))?;
+        self.ip = length;
+        Ok(())
     }
 
-    fn check_header(&mut self, header_name: &'static str) {
-        let header = self.read_several::<2>();
+    fn check_header(&mut self, header_name: &'static str) -> Result<(), RuntimeError> {
+        let header = self.read_several::<2>()?;
         if header != [0xff, 0xff] {
-            panic!("Cannot find header: {}", header_name);
+            return Err(self.invalid_bytecode(format!("cannot find header: {}", header_name)));
         }
+        Ok(())
     }
 
-    fn read_metadata_block(&mut self, info_name: &'static str) -> MetadataBlock {
-        let amount = self.read_opcode();
+    fn read_metadata_block(
+        &mut self,
+        info_name: &'static str,
+    ) -> Result<MetadataBlock, RuntimeError> {
+        let amount = u16::from_be_bytes(self.read_several::<2>()?);
         let mut res = vec![];
         for _ in 0..amount {
-            // Read string repr, do not save it - we have no use for it
-            let symbol_name_len = u16::from_be_bytes(self.read_several::<2>());
-            self.read_bytes(symbol_name_len as usize);
+            let symbol_name_len = u16::from_be_bytes(self.read_several::<2>()?);
+            let symbol_name = self.read_bytes(symbol_name_len as usize)?;
+            let symbol_name = String::from_utf8(symbol_name)
+                .map_err(|_| self.invalid_bytecode("symbol name is not utf-8".into()))?;
 
-            let flag = u16::from_be_bytes(self.read_several::<2>()) as usize;
+            let flag = u16::from_be_bytes(self.read_several::<2>()?) as usize;
 
-            let pointers_amount = self.read_opcode();
-            let pointer_mapping = self.read_bytes(pointers_amount as usize);
-            res.push((flag, pointer_mapping));
+            let pointers_amount = u16::from_be_bytes(self.read_several::<2>()?);
+            let mut pointer_mapping = vec![];
+            for _ in 0..pointers_amount {
+                pointer_mapping.push(u16::from_be_bytes(self.read_several::<2>()?));
+            }
+            res.push((symbol_name, flag, pointer_mapping));
         }
-        self.check_header(info_name);
-        res
+        self.check_header(info_name)?;
+        Ok(res)
     }
 
-    fn load_entry(&mut self) {
-        self.entry = u16::from_be_bytes(self.read_several::<2>()) as usize;
-        self.check_header("Entry loaded, start of functions");
+    fn load_entry(&mut self) -> Result<(), RuntimeError> {
+        self.entry = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+        self.check_header("Entry loaded, start of functions")
     }
 
-    fn load_metadata(&mut self) {
-        let tm = self.read_metadata_block("Types metadata");
+    fn load_metadata(&mut self) -> Result<(), RuntimeError> {
+        let tm = self.read_metadata_block("Types metadata")?;
+        let types_count = tm.len();
         self.metadata.fill_types_metadata(tm);
 
-        let lm = self.read_metadata_block("Lists metadata");
+        for _ in 0..types_count {
+            let capacity = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            let overflow = match self.read_opcode()? {
+                0 => None,
+                byte => Some(OverflowPolicy::from_byte(byte).ok_or_else(|| {
+                    self.invalid_bytecode(format!("unknown overflow policy {}", byte))
+                })?),
+            };
+            let capacity = if capacity == 0 { None } else { Some(capacity) };
+            self.metadata
+                .types_mailboxes
+                .push(MailboxConfig { capacity, overflow });
+
+            let on_stop = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            self.metadata
+                .types_on_stop
+                .push(if on_stop == 0 { None } else { Some(on_stop) });
+
+            let strategy = self.read_opcode()?;
+            let max_restarts = u16::from_be_bytes(self.read_several::<2>()?) as usize;
+            let period = u32::from_be_bytes(self.read_several::<4>()?) as u64;
+            let supervision = match strategy {
+                0 => None,
+                byte => Some(SupervisorConfig {
+                    strategy: RestartStrategy::from_byte(byte).ok_or_else(|| {
+                        self.invalid_bytecode(format!("unknown supervision strategy {}", byte))
+                    })?,
+                    max_restarts,
+                    period: Duration::from_millis(period),
+                }),
+            };
+            self.metadata.types_supervision.push(supervision);
+
+            let on_child_failed = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            self.metadata.types_on_child_failed.push(match on_child_failed {
+                0 => None,
+                position => Some(position),
+            });
+        }
+        self.check_header("Mailboxes metadata")?;
+
+        let lm = self.read_metadata_block("Lists metadata")?;
+        let list_kinds_count = lm.len();
         self.metadata.fill_lists_metadata(lm);
 
-        let fm = self.read_metadata_block("Functions metadata");
+        let mut types_layouts = vec![];
+        for _ in 0..types_count {
+            types_layouts.push(self.read_value_type("unknown layout of type")?);
+        }
+        let mut list_item_types = vec![];
+        for _ in 0..list_kinds_count {
+            list_item_types.push(self.read_value_type("unknown type of list items")?);
+        }
+        self.check_header("End of layouts")?;
+
+        let fm = self.read_metadata_block("Functions metadata")?;
         let functions_count = fm.len();
         self.metadata.fill_function_metadata(fm);
 
+        let rm = self.read_metadata_block("Functions return metadata")?;
+        self.metadata.fill_function_return_metadata(rm);
+
         for i in 0..functions_count {
-            let pos = u16::from_be_bytes(self.read_several::<2>()) as usize;
+            let pos = u32::from_be_bytes(self.read_several::<4>()?) as usize;
             self.metadata.function_positions.insert(pos, i);
         }
-        self.check_header("End of function positions");
+        self.check_header("End of function positions")?;
+
+        for _ in 0..functions_count {
+            let function_start = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            let safepoints_amount = u32::from_be_bytes(self.read_several::<4>()?);
+            for _ in 0..safepoints_amount {
+                let offset = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+                let slots_amount = u16::from_be_bytes(self.read_several::<2>()?) as usize;
+                let bitmap = self.read_bytes(slots_amount.div_ceil(8))?;
+                self.metadata
+                    .add_stack_map(function_start + offset, slots_amount, &bitmap);
+            }
+        }
+        self.check_header("End of stack maps")?;
+
+        for _ in 0..functions_count {
+            let function_start = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            let module_len = u16::from_be_bytes(self.read_several::<2>()?);
+            let module = String::from_utf8(self.read_bytes(module_len as usize)?)
+                .map_err(|_| self.invalid_bytecode("module name is not utf-8".into()))?;
+            let entries_amount = u32::from_be_bytes(self.read_several::<4>()?);
+            for _ in 0..entries_amount {
+                let offset = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+                let line = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+                let column = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+                let source = SourcePosition { module: module.clone(), line, column };
+                self.metadata
+                    .line_table
+                    .insert(function_start + offset, (function_start, source));
+            }
+        }
+        self.check_header("End of line table")?;
+
+        for _ in 0..functions_count {
+            let function_start = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            let locals_amount = u16::from_be_bytes(self.read_several::<2>()?);
+            let mut locals = vec![];
+            for _ in 0..locals_amount {
+                let name_len = u16::from_be_bytes(self.read_several::<2>()?);
+                let name = String::from_utf8(self.read_bytes(name_len as usize)?)
+                    .map_err(|_| self.invalid_bytecode("local name is not utf-8".into()))?;
+                let offset = u16::from_be_bytes(self.read_several::<2>()?) as usize;
+                let type_len = u16::from_be_bytes(self.read_several::<2>()?);
+                let value_type = ValueType::from_bytes(&self.read_bytes(type_len as usize)?)
+                    .ok_or_else(|| self.invalid_bytecode(format!("unknown type of `{}`", name)))?;
+                locals.push(LocalInfo { name, offset, value_type });
+            }
+            self.metadata.function_locals.insert(function_start, locals);
+        }
+        self.check_header("End of locals")?;
+
+        for _ in 0..functions_count {
+            let function_start = u32::from_be_bytes(self.read_several::<4>()?) as usize;
+            let active_type = match u16::from_be_bytes(self.read_several::<2>()?) {
+                u16::MAX => None,
+                index => Some(index as usize),
+            };
+            let args_amount = u16::from_be_bytes(self.read_several::<2>()?);
+            let mut types = vec![];
+            for _ in 0..=args_amount {
+                let type_len = u16::from_be_bytes(self.read_several::<2>()?);
+                let value_type = ValueType::from_bytes(&self.read_bytes(type_len as usize)?)
+                    .ok_or_else(|| self.invalid_bytecode("unknown type in signature".into()))?;
+                types.push(value_type);
+            }
+            let returns = types.pop().unwrap();
+            let signature = Signature { args: types, returns, active_type };
+            self.metadata.function_signatures.insert(function_start, signature);
+        }
+        self.check_header("End of signatures")?;
+
+        (self.metadata)
+            .fill_pointer_kinds(&types_layouts, &list_item_types)
+            .map_err(|message| self.invalid_bytecode(message))
     }
 
-    fn load_consts(&mut self) {
+    fn read_value_type(&mut self, error_message: &str) -> Result<ValueType, RuntimeError> {
+        let type_len = u16::from_be_bytes(self.read_several::<2>()?);
+        ValueType::from_bytes(&self.read_bytes(type_len as usize)?)
+            .ok_or_else(|| self.invalid_bytecode(error_message.into()))
+    }
+
+    /// Returns kinds of the constants, strings are the only pointers among them
+    fn load_consts(&mut self) -> Result<Vec<ValueKind>, RuntimeError> {
         let mut string_repr: Vec<String> = vec![];
+        let mut kinds = vec![];
 
         loop {
-            let const_type = self.read_opcode();
+            let const_type = self.read_opcode()?;
             match const_type {
                 op::CONST_INT_FLAG => {
-                    let i = i64::from_be_bytes(self.read_several::<8>());
+                    let i = i64::from_be_bytes(self.read_several::<8>()?);
                     self.constants.push(i as u64);
+                    kinds.push(ValueKind::Scalar);
                     string_repr.push(i.to_string());
                 }
                 op::CONST_FLOAT_FLAG => {
-                    let f = u64::from_be_bytes(self.read_several::<8>());
+                    let f = u64::from_be_bytes(self.read_several::<8>()?);
                     self.constants.push(f);
+                    kinds.push(ValueKind::Scalar);
                     string_repr.push(f.to_string());
                 }
                 op::CONST_STRING_FLAG => {
-                    let str_len = u16::from_be_bytes(self.read_several::<2>());
-                    let str_bytes = self.read_bytes(str_len as usize);
+                    let str_len = u32::from_be_bytes(self.read_several::<4>()?);
+                    let str_bytes = self.read_bytes(str_len as usize)?;
 
-                    let q = std::str::from_utf8(&str_bytes).unwrap();
+                    let q = std::str::from_utf8(&str_bytes).map_err(|_| {
+                        self.invalid_bytecode("string constant is not utf-8".into())
+                    })?;
                     let s = Box::new(HeapObject::String(q.to_owned()));
                     let pointer = Box::into_raw(s);
                     string_repr.push(format!("string {:x}: \"{}\"", pointer as u64, q));
 
                     self.constants.push(pointer as u64);
+                    kinds.push(ValueKind::Pointer);
                 }
                 op::CONST_END_FLAG => break,
-                c => panic!("Unknown const flag: {:02x}", c),
+                c => return Err(self.invalid_bytecode(format!("unknown const flag {:02x}", c))),
             };
         }
-        self.check_header("End of constants table");
-        if self.show_debug {
+        self.check_header("End of constants table")?;
+        if self.options.show_debug {
             println!("Loaded constants:");
             for (i, s) in string_repr.iter().enumerate() {
                 println!("# {}  --  {}", i, s);
             }
         }
+        Ok(kinds)
     }
 
-    fn read_bytes(&mut self, num: usize) -> Vec<u8> {
+    fn read_bytes(&mut self, num: usize) -> Result<Vec<u8>, RuntimeError> {
         let mut bytes: Vec<u8> = vec![];
         for _ in 0..num {
-            bytes.push(self.read_opcode());
+            bytes.push(self.read_opcode()?);
+        }
+        Ok(bytes)
+    }
+
+    fn read_opcode(&mut self) -> Result<u8, RuntimeError> {
+        match self.program.get(self.ip) {
+            Some(byte) => {
+                self.ip += 1;
+                Ok(*byte)
+            }
+            None => Err(self.invalid_bytecode("unexpected end of bytecode".into())),
         }
-        bytes
     }
 
-    fn read_opcode(&mut self) -> u8 {
-        let byte = self.program[self.ip];
-        self.ip += 1;
-        byte
+    fn invalid_bytecode(&self, message: String) -> RuntimeError {
+        RuntimeError::invalid_bytecode(self.ip, message)
     }
 
-    fn read_several<const N: usize>(&mut self) -> [u8; N] {
+    fn read_several<const N: usize>(&mut self) -> Result<[u8; N], RuntimeError> {
         let mut bytes: [u8; N] = [0; N];
         for byte in bytes.iter_mut() {
-            *byte = self.read_opcode();
+            *byte = self.read_opcode()?;
         }
-        bytes
+        Ok(bytes)
     }
 
-    pub fn spawn_new_active(vm: Arc<Vm>, item_type: usize, constructor_args: Vec<u64>) -> u64 {
-        let active_index: u64;
+    /// Active object becomes a child of the spawner, if the spawner is a supervisor
+    pub fn spawn_new_active(
+        vm: Arc<Vm>,
+        spawner: Option<u64>,
+        item_type: usize,
+        constructor_args: Vec<u8>,
+    ) -> u64 {
+        let supervisor = spawner.filter(|spawner| vm.get_active(*spawner).supervision.is_some());
+        let handle = Vm::create_active(&vm, item_type, &constructor_args, supervisor);
+        vm.trace_spawn(spawner, handle);
+        vm.send_message(spawner, handle, constructor_args);
+        handle
+    }
+
+    /// Spawns active object on the node with given name, blocks until it is created there
+    pub fn spawn_remote(
+        vm: Arc<Vm>,
+        node_name: &str,
+        item_type: usize,
+        constructor_args: Vec<u8>,
+    ) -> Result<u64, RuntimeErrorKind> {
+        let node = vm
+            .network
+            .find_node(node_name)
+            .ok_or_else(|| RuntimeErrorKind::UnknownNode(node_name.into()))?;
+        // Objects spawned on nodes are not supervised, even if they end up on this one
+        if node == vm.network.node_id() {
+            return Ok(Vm::spawn_new_active(vm, None, item_type, constructor_args));
+        }
+
+        // Constructor message is counted as sent right away, node creates the object and
+        // replies with its handle
+        let future = vm.futures.create();
+        vm.message_sent();
+        vm.send_frame(Frame::Spawn {
+            node,
+            type_index: item_type as u64,
+            reply_to: global_id(vm.network.node_id(), future),
+            data: constructor_args,
+        });
+        let handle = vm.futures.wait(future).map_err(|error| match error {
+            RuntimeErrorKind::FailedFuture => RuntimeErrorKind::InvalidBytecode(format!(
+                "node `{}` has no active object type #{}",
+                node_name, item_type
+            )),
+            error => error,
+        })?;
+        let handle = handle.as_slice().try_into().map_err(|_| {
+            RuntimeErrorKind::InvalidBytecode(format!("node `{}` sent malformed handle", node_name))
+        })?;
+        Ok(u64::from_be_bytes(handle))
+    }
+
+    fn create_active(
+        vm: &Arc<Vm>,
+        item_type: usize,
+        constructor: &[u8],
+        supervisor: Option<u64>,
+    ) -> u64 {
+        let mut active_object =
+            ActiveObject::new(item_type, vm.metadata.types_sizes[item_type], vm.clone());
+
+        let handle = {
+            let mut locked_list = vm.active_objects.write().unwrap();
+            let handle = global_id(vm.network.node_id(), locked_list.len() as u64);
+            active_object.set_id(handle);
+
+            locked_list.push(Arc::new(StoredActiveObject {
+                item_type,
+                active_object: Mutex::new(active_object),
+                mailbox: Mutex::new(Mailbox::bounded(vm.get_mailbox_limit(item_type))),
+                has_space: Condvar::new(),
+                finished: Condvar::new(),
+                constructor: constructor.to_vec(),
+                supervisor,
+                supervision: vm.metadata.types_supervision[item_type]
+                    .map(|config| Mutex::new(Supervisor::new(config))),
+            }));
+            handle
+        };
 
-        let (send, recv) = mpsc::channel();
-        send.send(constructor_args).unwrap();
+        if let Some(supervisor) = supervisor {
+            let stored = vm.get_active(supervisor);
+            stored.supervision.as_ref().unwrap().lock().unwrap().add_child(handle);
+        }
+        handle
+    }
+
+    // Fresh object runs its constructor right away, so messages still in the mailbox are
+    // processed by the restarted object
+    fn restart_active(vm: &Arc<Vm>, handle: u64) {
+        let stored = vm.get_active(handle);
+        if stored.mailbox.lock().unwrap().is_finished() {
+            return;
+        }
 
-        let is_running = Arc::new(atomic::AtomicBool::new(true));
-        let mut active_object = ActiveObject::new(
-            item_type,
-            vm.metadata.types_sizes[item_type],
+        // Children of restarted supervisor are stopped, as its constructor spawns new ones
+        if let Some(supervision) = &stored.supervision {
+            for child in supervision.lock().unwrap().reset() {
+                vm.stop_active(child);
+            }
+        }
+
+        let mut active_object = stored.active_object.lock().unwrap();
+        // Suspended message is interrupted, but the message it sends is already counted
+        if let Some((receiver, message)) = active_object.take_blocked_send() {
+            vm.deliver_message(receiver, message, Wait::Never);
+        }
+        *active_object = ActiveObject::new(
+            stored.item_type,
+            vm.metadata.types_sizes[stored.item_type],
             vm.clone(),
-            vm.gateways_for_active.clone(),
         );
+        active_object.set_id(handle);
+        if let Err(error) = active_object.run(stored.constructor.clone(), false) {
+            vm.handle_runtime_error(error, handle, None);
+        }
+    }
 
-        {
-            let mut locked_list = vm.active_objects.write().unwrap();
-            active_index = locked_list.len() as u64;
-            active_object.set_id(active_index);
+    /// Supervisor restarts its failed child, and possibly other children, then it is
+    /// notified about the failure. Supervisor gives up if its children fail too often:
+    /// it stops them and fails itself, so its own supervisor restarts it or gives up in turn.
+    /// Supervisor at the top of the tree stops itself
+    fn supervise_failure(vm: &Arc<Vm>, failed: u64, error: String) {
+        let Some(supervisor) = vm.get_active(failed).supervisor else {
+            return;
+        };
+        let stored = vm.get_active(supervisor);
+        let to_restart = stored
+            .supervision
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .on_failure(failed, Instant::now());
 
-            locked_list
-                .push(StoredActiveObject { is_running: Arc::clone(&is_running), inbox: send });
+        match to_restart {
+            Some(children) => {
+                for child in children {
+                    Vm::restart_active(vm, child);
+                }
+                if let Some(on_child_failed) = vm.metadata.types_on_child_failed[stored.item_type] {
+                    let child_type = vm.get_active(failed).item_type;
+                    let child_name = vm.metadata.types_names[child_type].clone();
+                    let data =
+                        serialize_call_with_strings(on_child_failed, vec![child_name, error]);
+                    // Notification must not wait for space, as the failed object still holds
+                    // its worker thread
+                    let message = Message { data, reply_to: None };
+                    vm.send_without_waiting(Some(failed), supervisor, message);
+                }
+            }
+            None => {
+                eprintln!(
+                    "{} active object #{} gave up, its children fail too often",
+                    "Supervisor:".red(),
+                    supervisor
+                );
+                let children = stored.supervision.as_ref().unwrap().lock().unwrap().reset();
+                for child in children {
+                    vm.stop_active(child);
+                }
+                match stored.supervisor {
+                    Some(_) => {
+                        let error = "children fail too often".to_string();
+                        Vm::supervise_failure(vm, supervisor, error);
+                    }
+                    None => {
+                        vm.stop_active(supervisor);
+                    }
+                }
+            }
         }
+    }
 
-        thread::spawn(move || loop {
-            let msg = recv.recv().unwrap();
-            is_running.store(true, atomic::Ordering::Relaxed);
-            active_object.run(msg);
-            is_running.store(false, atomic::Ordering::Relaxed);
-        });
+    fn get_mailbox_limit(&self, item_type: usize) -> Option<MailboxLimit> {
+        let MailboxConfig { capacity, overflow } = self.metadata.types_mailboxes[item_type];
+        Some(MailboxLimit {
+            capacity: capacity.or(self.options.mailbox_capacity)?,
+            overflow: overflow.unwrap_or(self.options.mailbox_overflow),
+        })
+    }
 
-        active_index
+    /// Sender is None for the entry, that is not an active object
+    pub fn send_message(&self, sender: Option<u64>, receiver: u64, data: Vec<u8>) {
+        self.push_message(sender, receiver, Message { data, reply_to: None });
     }
 
-    pub fn setup_entry_and_run(vm: Arc<Vm>) {
-        let mut active_object = ActiveObject::new(0, 0, vm.clone(), vm.gateways_for_active.clone());
-        active_object.run(vec![vm.entry as u64]);
+    /// Message is put even into a full mailbox, so the sender never waits
+    pub fn send_without_waiting(&self, sender: Option<u64>, receiver: u64, message: Message) {
+        self.count_message(sender, receiver, &message);
+        self.deliver_message(receiver, message, Wait::Never);
+    }
 
-        if vm.show_debug {
-            println!("{}", "## ENTRY FINISHED!".red());
+    /// Message is counted as sent right away, so program does not finish while it waits.
+    /// It does not block when delivered, so it might put the mailbox over capacity
+    pub fn send_message_after(
+        &self,
+        sender: Option<u64>,
+        receiver: u64,
+        data: Vec<u8>,
+        delay: Duration,
+    ) {
+        self.message_sent();
+        let message = Message { data, reply_to: None };
+        let delay_ms = delay.as_millis() as u64;
+        let args = [("receiver", TraceArg::Int(receiver)), ("delay_ms", TraceArg::Int(delay_ms))];
+        self.trace_message('i', "send", sender, &message, &args);
+        self.timers.add(
+            self.timers.now() + delay,
+            Timeout::Deliver(receiver, message),
+        );
+    }
+
+    /// Time of timers, that is virtual if program is deterministic
+    pub fn now(&self) -> Instant {
+        self.timers.now()
+    }
+
+    /// Sleeping message of the active object is suspended, and continued once it is due
+    pub fn wake_after(&self, sender: u64, delay: Duration) -> Instant {
+        let due = self.timers.now() + delay;
+        self.timers.add(due, Timeout::Wake(sender));
+        due
+    }
+
+    /// Entry sleeps on its own thread. Deterministic program has no other threads, so the entry
+    /// runs messages meanwhile, until its timer fires on the virtual clock
+    pub fn sleep(vm: &Arc<Vm>, delay: Duration) -> Result<(), RuntimeErrorKind> {
+        if !vm.is_deterministic() {
+            vm.timers.sleep(delay);
+            return Ok(());
+        }
+        let future = vm.futures.create();
+        vm.timers.add(vm.timers.now() + delay, Timeout::Resolve(future));
+        while !vm.futures.is_ready(future) && Vm::run_step(vm) {}
+        vm.futures.wait(future).map(|_| ())
+    }
+
+    /// Active object, that can not give up its thread, blocks it for the delay
+    pub fn block_for(&self, delay: Duration) {
+        self.timers.sleep(delay);
+    }
+
+    /// Sends a message and returns a future, that is resolved with its return value
+    pub fn ask(&self, sender: Option<u64>, receiver: u64, data: Vec<u8>) -> u64 {
+        let future = self.create_future();
+        self.push_message(sender, receiver, Message { data, reply_to: Some(future) });
+        future
+    }
+
+    pub fn create_future(&self) -> u64 {
+        global_id(self.network.node_id(), self.futures.create())
+    }
+
+    pub fn wait_future(vm: &Arc<Vm>, future: u64) -> Result<Vec<u8>, RuntimeErrorKind> {
+        let local_future = match vm.network.is_local(future) {
+            true => local_part(future),
+            // Node of the future sends its value to a future of this node, once it is resolved
+            false => {
+                let forwarded = vm.futures.create();
+                let reply_to = global_id(vm.network.node_id(), forwarded);
+                vm.send_frame(Frame::WaitFuture { future, reply_to });
+                forwarded
+            }
+        };
+        // Asked message is processed by the same thread, if program is deterministic
+        while vm.is_deterministic() && !vm.futures.is_ready(local_future) && Vm::run_step(vm) {}
+        vm.futures.wait(local_future)
+    }
+
+    fn resolve_future(&self, future: u64, value: Option<Vec<u8>>) {
+        match self.network.is_local(future) {
+            true => self.futures.resolve(local_part(future), value),
+            false => self.send_frame(Frame::Reply { future, value }),
+        }
+    }
+
+    /// Entry waits for space in a full mailbox on its own thread
+    pub fn push_message(&self, sender: Option<u64>, receiver: u64, message: Message) {
+        self.count_message(sender, receiver, &message);
+        // Active object can't take messages from its mailbox while it waits for space in it
+        let wait = match sender == Some(receiver) {
+            true => Wait::Never,
+            false => Wait::Thread,
+        };
+        self.deliver_message(receiver, message, wait);
+    }
+
+    /// Counts the message as sent, it must be delivered afterwards
+    pub fn count_message(&self, sender: Option<u64>, receiver: u64, message: &Message) {
+        self.message_sent();
+        let args = [("receiver", TraceArg::Int(receiver))];
+        self.trace_message('i', "send", sender, message, &args);
+    }
+
+    /// Delivers counted message of the active object, that runs on a worker thread.
+    /// If the mailbox is full, message is returned back and the sender is scheduled again,
+    /// once a message is taken from that mailbox
+    pub fn deliver_or_suspend(
+        &self,
+        sender: u64,
+        receiver: u64,
+        message: Message,
+    ) -> Option<Message> {
+        let wait = match sender == receiver {
+            true => Wait::Never,
+            false => Wait::Suspend(sender),
+        };
+        self.deliver_message(receiver, message, wait)
+    }
+
+    // Message is already counted as sent, either by this VM or by the node that sent it.
+    // Messages from other nodes do not block, so they might put the mailbox over capacity.
+    // Returns the message back, if its sender has to be suspended
+    fn deliver_message(&self, receiver: u64, message: Message, wait: Wait) -> Option<Message> {
+        if !self.network.is_local(receiver) {
+            let Message { data, reply_to } = message;
+            self.send_frame(Frame::Message { receiver, reply_to, data });
+            return None;
+        }
+
+        // Single thread of deterministic program runs receivers only after the sender
+        let wait = match self.is_deterministic() {
+            true => Wait::Never,
+            false => wait,
+        };
+        let stored = self.get_active(receiver);
+        let delivery = {
+            let mut mailbox = stored.mailbox.lock().unwrap();
+            match wait {
+                Wait::Never => {}
+                Wait::Thread => {
+                    while mailbox.blocks_sender() && !self.scheduler.is_stopped() {
+                        mailbox = stored.has_space.wait(mailbox).unwrap();
+                    }
+                }
+                Wait::Suspend(sender) if mailbox.blocks_sender() => {
+                    mailbox.add_blocked_sender(sender);
+                    return Some(message);
+                }
+                Wait::Suspend(_) => {}
+            }
+            mailbox.push(message)
+        };
+        match delivery {
+            Delivery::Schedule => self.scheduler.schedule(receiver),
+            Delivery::Queued => {}
+            Delivery::Dropped(message) => self.discard_message(message),
+            Delivery::Rejected(message) => {
+                eprintln!(
+                    "{} {} to stopped active object #{} is discarded",
+                    "Warning:".yellow(),
+                    self.get_message_function_name(&message),
+                    receiver
+                );
+                self.discard_message(message);
+            }
+        }
+        None
+    }
+
+    // Suspended senders retry their messages, after a message is taken from the mailbox
+    fn wake_blocked_senders(&self, senders: Vec<u64>) {
+        for sender in senders {
+            self.wake_suspended(sender);
+        }
+    }
+
+    fn wake_suspended(&self, handle: u64) {
+        let stored = self.get_active(handle);
+        let woken = stored.mailbox.lock().unwrap().wake();
+        if woken {
+            self.scheduler.schedule(handle);
+        }
+    }
+
+    fn fire_timeout(&self, timeout: Timeout) {
+        match timeout {
+            Timeout::Deliver(receiver, message) => {
+                self.deliver_message(receiver, message, Wait::Never);
+            }
+            Timeout::Wake(handle) => self.wake_suspended(handle),
+            Timeout::Resolve(future) => self.futures.resolve(future, Some(vec![])),
+        }
+    }
+
+    // Discarded message will never run, so it is done with
+    fn discard_message(&self, message: Message) {
+        if let Some(future) = message.reply_to {
+            self.resolve_future(future, None);
+        }
+        self.message_processed();
+    }
+
+    /// Active object processes messages sent before, runs its `on_stop` method and stops
+    pub fn stop_active(&self, handle: u64) {
+        self.message_sent();
+        match self.network.is_local(handle) {
+            true => self.close_mailbox(handle),
+            false => self.send_frame(Frame::Stop { handle }),
+        }
+    }
+
+    // Last message is already counted as sent, either by this VM or by the node that sent it
+    fn close_mailbox(&self, handle: u64) {
+        let stored = self.get_active(handle);
+        // Last message is empty, if there is no method to run
+        let data = match self.metadata.types_on_stop[stored.item_type] {
+            Some(on_stop) => serialize_call_without_args(on_stop),
+            None => vec![],
+        };
+
+        let delivery = stored.mailbox.lock().unwrap().close(Message { data, reply_to: None });
+        match delivery {
+            Delivery::Schedule => self.scheduler.schedule(handle),
+            Delivery::Queued => {}
+            // Stopping an active object again does nothing
+            Delivery::Dropped(_) | Delivery::Rejected(_) => self.message_processed(),
+        }
+    }
+
+    /// Blocks until the active object is stopped
+    pub fn wait_stopped(vm: &Arc<Vm>, handle: u64) -> Result<(), RuntimeErrorKind> {
+        if !vm.network.is_local(handle) {
+            return Vm::wait_remote_stopped(vm, handle);
+        }
+        let stored = vm.get_active(handle);
+        if vm.is_deterministic() {
+            while !stored.mailbox.lock().unwrap().is_finished() {
+                if let Some(code) = *vm.exit_code.lock().unwrap() {
+                    return Err(RuntimeErrorKind::Exit(code as i64));
+                }
+                if !Vm::run_step(vm) {
+                    return Err(RuntimeErrorKind::ActiveNeverStops(handle));
+                }
+            }
+            return Ok(());
         }
 
+        let mut mailbox = stored.mailbox.lock().unwrap();
+        while !mailbox.is_finished() {
+            if let Some(code) = *vm.exit_code.lock().unwrap() {
+                return Err(RuntimeErrorKind::Exit(code as i64));
+            }
+            // Only messages stop active objects, so none of them is stopped without messages
+            if vm.scheduler.is_idle() {
+                return Err(RuntimeErrorKind::ActiveNeverStops(handle));
+            }
+            mailbox = stored.finished.wait_timeout(mailbox, STOP_CHECK_INTERVAL).unwrap().0;
+        }
+        Ok(())
+    }
+
+    // Request is counted as a message, and the node replies before it reports the request
+    // or the last message of the active object as processed. So the program never gets idle,
+    // while the object is stopped but the reply is on its way
+    fn wait_remote_stopped(vm: &Arc<Vm>, handle: u64) -> Result<(), RuntimeErrorKind> {
+        let future = vm.futures.create();
+        let reply_to = global_id(vm.network.node_id(), future);
+        vm.message_sent();
+        vm.send_frame(Frame::WaitStopped { handle, reply_to });
         loop {
-            match vm.receiver.recv_timeout(Duration::from_secs(1)) {
-                Ok((target, msg)) => {
-                    let sink = &vm.active_objects.read().unwrap()[target as usize];
-                    sink.inbox.send(msg).unwrap();
-                }
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    // Check if there are any running actors, exit if not
-                    let actives = vm.active_objects.read().unwrap();
-                    if actives.iter().all(|e| !e.is_running.load(atomic::Ordering::Relaxed)) {
-                        // println!("All messages processed!");
-                        return;
+            let is_idle = vm.scheduler.is_idle();
+            if vm.futures.wait_ready(future, STOP_CHECK_INTERVAL) {
+                break;
+            }
+            if is_idle {
+                return Err(RuntimeErrorKind::ActiveNeverStops(handle));
+            }
+        }
+        match vm.futures.wait(future) {
+            Ok(_) => Ok(()),
+            // Node does not know the active object, so it never stops
+            Err(RuntimeErrorKind::FailedFuture) => Err(RuntimeErrorKind::ActiveNeverStops(handle)),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Future of other node is resolved once the active object is stopped
+    fn add_stop_waiter(&self, handle: u64, future: u64) {
+        let stored = self.get_active(handle);
+        let is_waiting = stored.mailbox.lock().unwrap().add_stop_waiter(future);
+        if !is_waiting {
+            self.resolve_future(future, Some(vec![]));
+        }
+    }
+
+    fn is_deterministic(&self) -> bool {
+        self.options.deterministic_seed.is_some()
+    }
+
+    /// Runs a single message or delivers the earliest delayed one, returns false if there is
+    /// nothing to do. Used instead of worker threads, if program is deterministic
+    fn run_step(vm: &Arc<Vm>) -> bool {
+        if let Some(active_index) = vm.scheduler.try_next() {
+            Vm::run_message(vm, active_index);
+            return true;
+        }
+        match vm.timers.fire_earliest() {
+            Some(timeout) => {
+                vm.fire_timeout(timeout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Whole program is counted by the coordinator, nodes only report their messages
+    fn message_sent(&self) {
+        match self.network.is_node() {
+            true => self.send_frame(Frame::MessageSent),
+            false => self.scheduler.message_sent(),
+        }
+    }
+
+    fn message_processed(&self) {
+        match self.network.is_node() {
+            true => self.send_frame(Frame::MessageProcessed),
+            false => self.scheduler.message_processed(),
+        }
+    }
+
+    fn send_frame(&self, frame: Frame) {
+        if let Err(error) = self.network.send(frame) {
+            self.lose_connection(&error.to_string());
+        }
+    }
+
+    fn lose_connection(&self, reason: &str) {
+        if self.network.is_shutting_down() {
+            return;
+        }
+        eprintln!("{} {}", "Lost connection to other node:".red(), reason);
+        self.exit(exit_codes::RUNTIME_ERROR);
+    }
+
+    fn get_active(&self, handle: u64) -> Arc<StoredActiveObject> {
+        // Lock on the list is released right away, so running messages could spawn new objects
+        Arc::clone(&self.active_objects.read().unwrap()[local_part(handle) as usize])
+    }
+
+    fn run_worker_thread(vm: Arc<Vm>) {
+        while let Some(active_index) = vm.scheduler.next() {
+            Vm::run_message(&vm, active_index);
+        }
+    }
+
+    /// Runs the next message of scheduled active object, or continues the suspended one
+    fn run_message(vm: &Arc<Vm>, active_index: u64) {
+        let stored = vm.get_active(active_index);
+
+        // Active object is scheduled only with pending messages, and only once
+        let (message, is_stopping, resumed) = {
+            let mut mailbox = stored.mailbox.lock().unwrap();
+            match mailbox.take_suspended() {
+                Some((message, is_stopping)) => (message, is_stopping, true),
+                None => {
+                    let message = mailbox.pop().unwrap();
+                    let is_stopping = mailbox.is_stopping();
+                    let senders = mailbox.take_blocked_senders();
+                    drop(mailbox);
+                    vm.wake_blocked_senders(senders);
+                    (message, is_stopping, false)
+                }
+            }
+        };
+        stored.has_space.notify_all();
+
+        // Last message of stopped active object is empty, if it has no `on_stop` method
+        let mut failure = None;
+        if !message.data.is_empty() {
+            let mut active_object = stored.active_object.lock().unwrap();
+            let traced = vm.options.trace.as_ref().map(|_| &message);
+            if let Some(message) = traced.filter(|_| !resumed) {
+                vm.trace_message('B', "receive", Some(active_index), message, &[]);
+            }
+
+            let needs_reply = message.reply_to.is_some();
+            let result = match resumed {
+                true => active_object.resume(),
+                false => active_object.run_suspendable(&message.data, needs_reply),
+            };
+            let result = match result {
+                Ok(Turn::Suspended) => {
+                    drop(active_object);
+                    let woken = stored.mailbox.lock().unwrap().suspend(message, is_stopping);
+                    if woken {
+                        vm.scheduler.schedule(active_index);
+                    }
+                    return;
+                }
+                Ok(Turn::Finished(reply)) => Ok(reply),
+                Err(error) => Err(error),
+            };
+            if let Some(future) = message.reply_to {
+                vm.resolve_future(future, result.as_ref().ok().cloned().flatten());
+            }
+            if let Err(error) = result {
+                if !matches!(error.kind, RuntimeErrorKind::Exit(_)) {
+                    failure = Some(error.kind.to_string());
+                }
+                // State is rolled back on failure, so message might be safely retried later
+                let msg_copy = vm.options.transactional.then(|| message.clone());
+                vm.handle_runtime_error(error, active_index, msg_copy);
+            }
+            if let Some(message) = traced {
+                if let Some(error) = &failure {
+                    let args = [("error", TraceArg::Str(error))];
+                    vm.trace_message('i', "failure", Some(active_index), message, &args);
+                }
+                vm.trace_message('E', "receive", Some(active_index), message, &[]);
+            }
+        }
+
+        // Supervisor might restart the failed object, so its lock has to be released
+        if let Some(error) = failure {
+            Vm::supervise_failure(vm, active_index, error);
+        }
+
+        let stop_waiters = {
+            let mut mailbox = stored.mailbox.lock().unwrap();
+            if is_stopping {
+                mailbox.finish();
+                stored.finished.notify_all();
+            } else if mailbox.finish_turn() {
+                vm.scheduler.schedule(active_index);
+            }
+            mailbox.take_stop_waiters()
+        };
+        for future in stop_waiters {
+            vm.resolve_future(future, Some(vec![]));
+        }
+        vm.message_processed();
+    }
+
+    /// Type of the active object, None if it is not spawned by this program
+    pub fn get_active_type(&self, handle: u64) -> Option<usize> {
+        let active_objects = self.active_objects.read().unwrap();
+        let stored = active_objects.get(local_part(handle) as usize)?;
+        self.network.is_local(handle).then_some(stored.item_type)
+    }
+
+    /// Exit code, given to std `exit` by the program
+    pub fn exit_code(&self) -> Option<i32> {
+        *self.exit_code.lock().unwrap()
+    }
+
+    /// Name of the active object to show to the user, None is the entry
+    pub fn actor_name(&self, actor: Option<u64>) -> String {
+        match actor {
+            Some(handle) => {
+                let item_type = self.get_active(handle).item_type;
+                format!("{} #{}", self.metadata.types_names[item_type], handle)
+            }
+            None => "entry".to_string(),
+        }
+    }
+
+    /// Handles and names of active objects of this node
+    pub fn actors_names(&self) -> Vec<(u64, String)> {
+        let amount = self.active_objects.read().unwrap().len() as u64;
+        (0..amount)
+            .map(|index| global_id(self.network.node_id(), index))
+            .map(|handle| (handle, self.actor_name(Some(handle))))
+            .collect()
+    }
+
+    fn trace_spawn(&self, spawner: Option<u64>, handle: u64) {
+        let Some(tracer) = &self.options.trace else {
+            return;
+        };
+        let type_name = &self.metadata.types_names[self.get_active(handle).item_type];
+        tracer.name_actor(Some(handle), &format!("{} #{}", type_name, handle));
+
+        let mut args = vec![("actor", TraceArg::Int(handle)), ("type", TraceArg::Str(type_name))];
+        if let Some(spawner) = spawner {
+            args.push(("spawner", TraceArg::Int(spawner)));
+        }
+        tracer.record('i', "spawn", type_name, Some(handle), &args);
+    }
+
+    // Event of the active object, named by the function that message calls
+    fn trace_message(
+        &self,
+        phase: char,
+        category: &str,
+        actor: Option<u64>,
+        message: &Message,
+        extra: &[(&str, TraceArg)],
+    ) {
+        let Some(tracer) = &self.options.trace else {
+            return;
+        };
+        let mut args = vec![("size", TraceArg::Int(message.data.len() as u64))];
+        if let Some(actor) = actor {
+            args.push(("actor", TraceArg::Int(actor)));
+            // Other nodes know types of their own active objects
+            if self.network.is_local(actor) {
+                let item_type = self.get_active(actor).item_type;
+                args.push(("type", TraceArg::Str(&self.metadata.types_names[item_type])));
+            }
+        }
+        args.extend(extra.iter().map(|(key, value)| (*key, *value)));
+        let function = self.get_message_function_name(message);
+        tracer.record(phase, category, function, actor, &args);
+    }
+
+    fn run_timer_thread(vm: Arc<Vm>) {
+        while let Some(timeout) = vm.timers.next() {
+            vm.fire_timeout(timeout);
+        }
+    }
+
+    fn handle_runtime_error(&self, error: RuntimeError, receiver: u64, message: Option<Message>) {
+        if let RuntimeErrorKind::Exit(code) = error.kind {
+            match self.network.is_node() {
+                true => self.send_frame(Frame::Exit(code)),
+                false => self.exit(code as i32),
+            }
+            return;
+        }
+
+        match self.network.is_node() {
+            true => self.send_frame(Frame::MessageFailed),
+            false => self.has_failed_messages.store(true, Ordering::Relaxed),
+        }
+        self.log_runtime_error(&error);
+        if let Some(message) = message {
+            self.add_dead_letter(DeadLetter { receiver, message, error });
+        }
+    }
+
+    fn exit(&self, code: i32) {
+        let code = *self.exit_code.lock().unwrap().get_or_insert(code);
+        self.scheduler.stop();
+        self.timers.stop();
+        self.futures.cancel_all(code as i64);
+
+        // Senders, blocked by full mailboxes, check that program is stopped
+        for stored in self.active_objects.read().unwrap().iter() {
+            let _mailbox = stored.mailbox.lock().unwrap();
+            stored.has_space.notify_all();
+            stored.finished.notify_all();
+        }
+    }
+
+    fn report_dropped_messages(&self) {
+        for (index, stored) in self.active_objects.read().unwrap().iter().enumerate() {
+            let dropped = stored.mailbox.lock().unwrap().dropped();
+            if dropped > 0 {
+                eprintln!(
+                    "{} {} messages to active object #{}, its mailbox was full",
+                    "Dropped".yellow(),
+                    dropped,
+                    global_id(self.network.node_id(), index as u64)
+                );
+            }
+        }
+    }
+
+    pub fn merge_profile(&self, profile: &mut Profile) {
+        if let Some(total) = &self.profile {
+            total.lock().unwrap().merge(profile);
+        }
+    }
+
+    fn report_profile(&self) {
+        if let Some(profile) = &self.profile {
+            eprintln!("{}", "Profile:".bold());
+            eprint!("{}", profile.lock().unwrap().report(&self.metadata));
+        }
+    }
+
+    // Failed message is discarded, but the active object continues processing others
+    fn log_runtime_error(&self, error: &RuntimeError) {
+        eprintln!("{} {}", "Runtime error:".red(), error);
+        eprintln!(
+            "{}",
+            error.render_stack_trace(&self.metadata, self.options.source_root.as_deref())
+        );
+    }
+
+    fn add_dead_letter(&self, dead_letter: DeadLetter) {
+        eprintln!(
+            "{} {} to active object #{}",
+            "Moved to dead letters:".red(),
+            self.get_message_function_name(&dead_letter.message),
+            dead_letter.receiver
+        );
+        self.dead_letters.lock().unwrap().push(dead_letter);
+    }
+
+    fn get_message_function_name(&self, message: &Message) -> &str {
+        read_function_position(&message.data)
+            .ok()
+            .and_then(|pos| self.metadata.function_positions.get(&pos))
+            .map_or("<malformed message>", |i| &self.metadata.function_names[*i])
+    }
+
+    /// Handles in frames are not trusted, so unknown active objects are reported and skipped
+    fn is_known_active(&self, handle: u64) -> bool {
+        let is_known = !self.network.is_local(handle)
+            || (local_part(handle) as usize) < self.active_objects.read().unwrap().len();
+        if !is_known {
+            eprintln!(
+                "{} frame for unknown active object #{} is dropped",
+                "Warning:".yellow(),
+                handle
+            );
+        }
+        is_known
+    }
+
+    fn receive_frame(vm: &Arc<Vm>, frame: Frame) {
+        match frame {
+            Frame::Message { receiver, reply_to, data } => {
+                let message = Message { data, reply_to };
+                match vm.is_known_active(receiver) {
+                    true => {
+                        vm.deliver_message(receiver, message, Wait::Never);
                     }
+                    false => vm.discard_message(message),
+                }
+            }
+            Frame::Spawn { node, type_index, reply_to, data } if node == vm.network.node_id() => {
+                // Bytecode is the same on all nodes, but frames might still be malformed
+                let handle = match (type_index as usize) < vm.metadata.types_sizes.len() {
+                    true => Some(Vm::create_active(vm, type_index as usize, &data, None)),
+                    false => None,
+                };
+                if let Some(handle) = handle {
+                    vm.trace_spawn(None, handle);
+                    vm.deliver_message(handle, Message { data, reply_to: None }, Wait::Never);
+                }
+                vm.resolve_future(reply_to, handle.map(|h| h.to_be_bytes().to_vec()));
+            }
+            Frame::Stop { handle } if vm.network.is_local(handle) => {
+                match vm.is_known_active(handle) {
+                    true => vm.close_mailbox(handle),
+                    false => vm.message_processed(),
+                }
+            }
+            Frame::WaitStopped { handle, reply_to } if vm.network.is_local(handle) => {
+                match vm.is_known_active(handle) {
+                    true => vm.add_stop_waiter(handle, reply_to),
+                    false => vm.resolve_future(reply_to, None),
+                }
+                vm.message_processed();
+            }
+            // Frames are read by a single thread, so it must not block until future is resolved
+            Frame::WaitFuture { future, reply_to } if vm.network.is_local(future) => {
+                let vm = vm.clone();
+                thread::spawn(move || {
+                    let value = vm.futures.wait(local_part(future));
+                    vm.resolve_future(reply_to, value.ok());
+                });
+            }
+            frame @ (Frame::Spawn { .. }
+            | Frame::Stop { .. }
+            | Frame::WaitStopped { .. }
+            | Frame::WaitFuture { .. }) => vm.send_frame(frame),
+            Frame::Reply { future, value } => vm.resolve_future(future, value),
+            Frame::MessageSent => vm.scheduler.message_sent(),
+            Frame::MessageProcessed => vm.scheduler.message_processed(),
+            Frame::MessageFailed => vm.has_failed_messages.store(true, Ordering::Relaxed),
+            Frame::Exit(code) => vm.exit(code as i32),
+            Frame::Shutdown => {
+                vm.scheduler.stop();
+                vm.timers.stop();
+            }
+            Frame::Hello { .. } | Frame::Welcome { .. } => {
+                vm.lose_connection("unexpected handshake frame")
+            }
+        }
+    }
+
+    fn read_frames(vm: Arc<Vm>, mut stream: TcpStream, node_id: u64) {
+        loop {
+            match read_frame(&mut stream) {
+                Ok(Frame::Shutdown) => {
+                    Vm::receive_frame(&vm, Frame::Shutdown);
+                    return;
+                }
+                Ok(frame) => Vm::receive_frame(&vm, frame),
+                Err(error) => {
+                    let node_name = vm.network.get_node_name(node_id);
+                    vm.lose_connection(&format!("node `{}`: {}", node_name, error));
+                    return;
+                }
+            }
+        }
+    }
+
+    // Coordinator gives ids to nodes in order of `--node` options, and tells them all names
+    fn connect_nodes(vm: &Arc<Vm>) -> Result<(), String> {
+        let names: Vec<(String, u64)> = (vm.options.nodes.iter().enumerate())
+            .map(|(i, (name, _))| (name.clone(), i as u64 + 1))
+            .collect();
+        vm.network.join(COORDINATOR_NODE, names.clone());
+
+        let hash = bytecode_hash(&vm.program);
+        for ((name, address), (_, node_id)) in vm.options.nodes.iter().zip(names.iter()) {
+            let error = |e: std::io::Error| format!("cannot connect to node `{}`: {}", name, e);
+            let mut stream = connect(address).map_err(error)?;
+            let hello =
+                Frame::Hello { node_id: *node_id, bytecode_hash: hash, nodes: names.clone() };
+            write_frame(&mut stream, &hello).map_err(error)?;
+            match read_frame(&mut stream).map_err(error)? {
+                Frame::Welcome { bytecode_hash } if bytecode_hash == hash => {}
+                Frame::Welcome { .. } => {
+                    return Err(format!("node `{}` runs different bytecode", name))
+                }
+                _ => return Err(format!("node `{}` is not a frisbee node", name)),
+            }
+
+            let reader = stream.try_clone().map_err(error)?;
+            vm.network.add_link(*node_id, stream);
+            let vm = vm.clone();
+            let node_id = *node_id;
+            thread::spawn(move || Vm::read_frames(vm, reader, node_id));
+        }
+        Ok(())
+    }
+
+    /// Waits for the coordinator and runs active objects it spawns, until it shuts down
+    pub fn serve_node(vm: Arc<Vm>, address: &str) -> i32 {
+        let mut stream = match TcpListener::bind(address).and_then(|l| l.accept()) {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                eprintln!("{} {}: {}", "Cannot listen on".red(), address, error);
+                return exit_codes::LOAD_ERROR;
+            }
+        };
+
+        let hash = bytecode_hash(&vm.program);
+        let (node_id, names) = match read_frame(&mut stream) {
+            Ok(Frame::Hello { node_id, bytecode_hash, nodes }) => {
+                // Coordinator reports the mismatch, this node just stops
+                let _ = write_frame(&mut stream, &Frame::Welcome { bytecode_hash: hash });
+                if bytecode_hash != hash {
+                    eprintln!("{}", "Coordinator runs different bytecode".red());
+                    return exit_codes::LOAD_ERROR;
                 }
-                Err(e) => panic!("Error! {}", e),
+                (node_id, nodes)
             }
+            _ => {
+                eprintln!("{}", "Coordinator did not introduce itself".red());
+                return exit_codes::LOAD_ERROR;
+            }
+        };
+        vm.network.join(node_id, names);
+        match stream.try_clone() {
+            Ok(link) => vm.network.add_link(COORDINATOR_NODE, link),
+            Err(error) => {
+                eprintln!("{} {}", "Cannot use connection:".red(), error);
+                return exit_codes::LOAD_ERROR;
+            }
+        }
+
+        Vm::start_worker_threads(&vm);
+        Vm::read_frames(vm.clone(), stream, COORDINATOR_NODE);
+        vm.report_dropped_messages();
+        vm.report_profile();
+        if let Some(tracer) = &vm.options.trace {
+            tracer.flush();
+        }
+        0
+    }
+
+    fn start_worker_threads(vm: &Arc<Vm>) {
+        for _ in 0..vm.options.threads.max(1) {
+            let vm = vm.clone();
+            thread::spawn(move || Vm::run_worker_thread(vm));
+        }
+        let vm = vm.clone();
+        thread::spawn(move || Vm::run_timer_thread(vm));
+    }
+
+    /// Connects to other nodes and starts threads, that run active objects
+    pub fn start(vm: &Arc<Vm>) -> Result<(), String> {
+        if let Err(error) = Vm::connect_nodes(vm) {
+            vm.network.shutdown();
+            return Err(error);
+        }
+        if !vm.is_deterministic() {
+            Vm::start_worker_threads(vm);
+        }
+        if let Some(tracer) = &vm.options.trace {
+            tracer.name_actor(None, "entry");
+        }
+        Ok(())
+    }
+
+    /// Runs the function on the thread of the caller, outside of any active object, and
+    /// returns its packed return value. Exit from the function stops the whole program
+    pub fn run_on_entry(vm: &Arc<Vm>, message: Vec<u8>) -> Result<Vec<u8>, RuntimeError> {
+        let mut active_object = ActiveObject::new_entry(vm.clone());
+        let result = active_object.run(message, true);
+        if let Err(RuntimeError { kind: RuntimeErrorKind::Exit(code), .. }) = &result {
+            vm.exit(*code as i32);
+        }
+        result.map(Option::unwrap_or_default)
+    }
+
+    /// Blocks until all messages are processed, or program is stopped
+    pub fn wait_until_idle(vm: &Arc<Vm>) {
+        while vm.is_deterministic() && Vm::run_step(vm) {}
+        vm.scheduler.wait_until_idle();
+    }
+
+    /// Waits for all messages, stops the program and returns its exit code
+    pub fn finish(vm: &Arc<Vm>) -> i32 {
+        Vm::wait_until_idle(vm);
+        Vm::stop(vm);
+        vm.report_dropped_messages();
+        vm.report_profile();
+        if let Some(tracer) = &vm.options.trace {
+            tracer.flush();
+        }
+
+        match vm.exit_code() {
+            Some(code) => code,
+            None if vm.has_failed_messages.load(Ordering::Relaxed) => exit_codes::RUNTIME_ERROR,
+            None => 0,
+        }
+    }
+
+    /// Stops threads of the program without waiting for its messages
+    pub fn stop(vm: &Arc<Vm>) {
+        vm.scheduler.stop();
+        vm.timers.stop();
+        vm.network.shutdown();
+        vm.options.stdout.flush();
+    }
+
+    /// Runs the program until all messages are processed, and returns its exit code
+    pub fn setup_entry_and_run(vm: Arc<Vm>) -> i32 {
+        if let Err(error) = Vm::start(&vm) {
+            eprintln!("{} {}", "Cannot start:".red(), error);
+            return exit_codes::LOAD_ERROR;
+        }
+
+        let mut active_object = ActiveObject::new_entry(vm.clone());
+        if let Err(error) = active_object.run(serialize_call_without_args(vm.entry), false) {
+            vm.handle_runtime_error(error, 0, None);
+        }
+
+        if vm.options.show_debug {
+            println!("{}", "## ENTRY FINISHED!".red());
         }
+        Vm::finish(&vm)
     }
 }
@@ -1,23 +1,112 @@
-use std::io;
-use std::sync::{mpsc, Arc};
-
-use crate::runtime::serialization::serialize_function_args;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::debugger::{DebugAction, FrameView, PausedActor};
+use super::errors::{RuntimeError, RuntimeErrorKind};
 use super::heap;
 use super::opcodes::op;
-use super::serialization::deserialize_function_args;
-use super::stdlib_runners::STD_RAW_FUNCTION_RUNNERS;
+use super::profiler::{Callee, Profile};
+use super::scheduler::Message;
+use super::serialization::{
+    deserialize_function_args, deserialize_return_value, read_function_position,
+    serialize_function_args, serialize_return_value,
+};
+use super::stdlib_runners::{STD_RAW_FUNCTION_RUNNERS, STD_SLEEP};
 use super::utils::{f64_to_u64, u64_to_f64};
 use super::vm::Vm;
+use crate::errors::exit_codes;
+
+// Macros below access only fields, so they can be used while heap objects are borrowed
+macro_rules! runtime_error {
+    ($worker:ident, $kind:expr) => {
+        RuntimeError::new($kind, $worker.op_position, &$worker.vm.metadata)
+    };
+}
+
+macro_rules! try_op {
+    ($worker:ident, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(kind) => return Err(runtime_error!($worker, kind)),
+        }
+    };
+}
 
 macro_rules! push {
     ($worker:ident, $value:expr) => {
+        if $worker.stack_pointer == $worker.stack.len()
+            && !grow_stack(&mut $worker.stack, $worker.max_stack_size)
+        {
+            return Err(runtime_error!($worker, RuntimeErrorKind::StackOverflow));
+        }
         $worker.stack[$worker.stack_pointer] = $value;
         $worker.stack_pointer += 1;
     };
 }
 
-const STACK_SIZE: usize = 512; // TODO: maybe grow??
+const INITIAL_STACK_SIZE: usize = 512;
+
+/// Doubles the stack without exceeding the limit, returns false if it is already at the limit
+fn grow_stack(stack: &mut Vec<u64>, max_stack_size: usize) -> bool {
+    if stack.len() >= max_stack_size {
+        return false;
+    }
+    let new_size = (stack.len() * 2).clamp(1, max_stack_size);
+    stack.resize(new_size, 0);
+    true
+}
+
+/// Verifier does not know types of objects and receivers of messages,
+/// so fields are checked against the real object, when they are accessed
+fn field_range(
+    fields: &[u64],
+    offset: usize,
+    size: usize,
+) -> Result<std::ops::Range<usize>, RuntimeErrorKind> {
+    if offset + size > fields.len() {
+        return Err(RuntimeErrorKind::InvalidBytecode(format!(
+            "object of size {} has no field of size {} at offset {}",
+            fields.len(),
+            size,
+            offset
+        )));
+    }
+    Ok(offset..offset + size)
+}
+
+fn extract_object(
+    object: &mut heap::HeapObject,
+) -> Result<&mut heap::CustomObject, RuntimeErrorKind> {
+    match object {
+        heap::HeapObject::CustomObject(custom) => Ok(custom),
+        _ => Err(RuntimeErrorKind::InvalidBytecode(
+            "value is not an object".into(),
+        )),
+    }
+}
+
+fn extract_list(object: &mut heap::HeapObject) -> Result<&mut heap::List, RuntimeErrorKind> {
+    match object {
+        heap::HeapObject::List(list) => Ok(list),
+        _ => Err(RuntimeErrorKind::InvalidBytecode(
+            "value is not a list".into(),
+        )),
+    }
+}
+
+// State of the active object before the message, used to roll back failed messages
+struct Snapshot {
+    fields: Vec<u64>,
+    objects: HashMap<u64, heap::HeapObject>,
+}
+
+/// Message either runs to its end, or is suspended at a send to a full mailbox, that blocks
+/// senders, or at a sleep. Suspended message keeps its frames and is continued by `resume`
+pub enum Turn {
+    Finished(Option<Vec<u8>>), // serialized return value, if it is needed
+    Suspended,
+}
 
 #[derive(Debug)]
 struct CallFrame {
@@ -28,55 +117,137 @@ struct CallFrame {
 pub struct ActiveObject {
     program: Vec<u8>,
     ip: usize,
+    op_position: usize, // start of the instruction being executed, used for errors
 
     vm: Arc<Vm>,
-    step_by_step: bool,
+    debugging: bool,
     show_debug: bool,
-    gateway: mpsc::Sender<(u64, Vec<u64>)>,
 
     item_type: usize,
     worker_id: u64,
 
     memory: heap::Heap,
     current_active_fields: Vec<u64>,
-    stack: [u64; STACK_SIZE],
+    stack: Vec<u64>,
     stack_pointer: usize,
+    max_stack_size: usize,
+
+    frames: Vec<CallFrame>,
+    max_call_depth: usize,
+
+    transactional: bool,
+    snapshot: Option<Snapshot>,
+
+    blocking_allowed: bool, // only entry has its own thread, that can wait for futures
+    return_value: Vec<u64>, // of the last processed message
+
+    // Function of the running message and whether its return value is needed
+    message_call: (usize, bool),
+    // Only messages run by worker threads give up their thread, when blocked by a full mailbox
+    suspendable: bool,
+    blocked_send: Option<(u64, Message)>, // receiver and message, that waits for space
+    sleeps_until: Option<Instant>,        // message is suspended by a sleep
 
-    frames: Vec<CallFrame>, // TODO: limit size
+    profile: Option<Profile>, // merged into the profile of the VM after each message
 }
 
 impl ActiveObject {
-    pub fn new(
-        item_type: usize,
-        item_size: usize,
-        vm: Arc<Vm>,
-        gateway: mpsc::Sender<(u64, Vec<u64>)>,
-    ) -> Self {
+    pub fn new(item_type: usize, item_size: usize, vm: Arc<Vm>) -> Self {
         // TODO: do something with item_size for stdlib types
         ActiveObject {
             program: vm.program.clone(),
             ip: 0,
+            op_position: 0,
             memory: heap::Heap::default(),
 
-            step_by_step: vm.step_by_step,
-            show_debug: vm.show_debug,
+            debugging: vm.options.debugger.is_some(),
+            show_debug: vm.options.show_debug,
+            max_stack_size: vm.options.max_stack_size,
+            max_call_depth: vm.options.max_call_depth,
+            transactional: vm.options.transactional,
+            profile: vm.options.profile.then(Profile::default),
             vm,
-            gateway,
 
             item_type,
             worker_id: 0,
 
             current_active_fields: vec![0; item_size],
-            stack: [0; STACK_SIZE],
+            stack: vec![0; INITIAL_STACK_SIZE],
             stack_pointer: 0,
             frames: vec![],
+            snapshot: None,
+            blocking_allowed: false,
+            return_value: vec![],
+            message_call: (0, false),
+            suspendable: false,
+            blocked_send: None,
+            sleeps_until: None,
         }
     }
 
+    pub fn new_entry(vm: Arc<Vm>) -> Self {
+        let mut entry = Self::new(0, 0, vm);
+        entry.blocking_allowed = true;
+        entry
+    }
+
     pub fn set_id(&mut self, worker_id: u64) {
         self.worker_id = worker_id;
     }
 
+    // Entry is not an active object, so it never sends messages to itself
+    fn sender_id(&self) -> Option<u64> {
+        match self.blocking_allowed {
+            true => None,
+            false => Some(self.worker_id),
+        }
+    }
+
+    fn fields_pointers(&self) -> Vec<u64> {
+        // Entry object has no fields at all, so there is no type to take mapping from
+        if self.current_active_fields.is_empty() {
+            return vec![];
+        }
+        self.vm.metadata.types_pointer_mapping[self.item_type]
+            .iter()
+            .map(|i| self.current_active_fields[*i])
+            .collect()
+    }
+
+    // Stack maps are saved by position right after the safepoint instruction, which is
+    // current ip for the last frame and return ip of the next frame for all others
+    fn stack_pointers(&self) -> Vec<u64> {
+        let mut pointers = vec![];
+        for (i, frame) in self.frames.iter().enumerate() {
+            let safepoint_ip = match self.frames.get(i + 1) {
+                Some(next_frame) => next_frame.return_ip,
+                None => self.ip,
+            };
+            let stack_map = &self.vm.metadata.stack_maps[&safepoint_ip];
+            pointers.extend(stack_map.iter().map(|p| self.stack[frame.stack_start + p]));
+        }
+        pointers
+    }
+
+    // Called either between messages, when stack is empty, or at safepoints of the codegen
+    fn collect_garbage_if_needed(&mut self) {
+        if !self.memory.should_collect() {
+            return;
+        }
+        let mut roots = self.fields_pointers();
+        if !self.frames.is_empty() {
+            roots.extend(self.stack_pointers());
+        }
+        // Objects of the snapshot might be unreachable now, but are needed for rollback
+        if let Some(snapshot) = &self.snapshot {
+            roots.extend(snapshot.objects.keys());
+        }
+        let freed = self.memory.collect_garbage(roots, &self.vm.metadata);
+        if self.show_debug {
+            println!(" ## GC: freed {}, alive {}", freed, self.memory.len());
+        }
+    }
+
     fn pop(&mut self) -> u64 {
         self.stack_pointer -= 1;
         self.stack[self.stack_pointer]
@@ -88,30 +259,114 @@ impl ActiveObject {
         byte
     }
 
-    fn call_op(&mut self, func_pos: usize, locals_size: usize) {
+    fn ensure_stack_capacity(&mut self, amount: usize) -> Result<(), RuntimeError> {
+        while self.stack_pointer + amount > self.stack.len() {
+            if !grow_stack(&mut self.stack, self.max_stack_size) {
+                return Err(runtime_error!(self, RuntimeErrorKind::StackOverflow));
+            }
+        }
+        Ok(())
+    }
+
+    fn call_op(&mut self, func_pos: usize, locals_size: usize) -> Result<(), RuntimeError> {
+        if self.frames.len() >= self.max_call_depth {
+            return Err(runtime_error!(self, RuntimeErrorKind::StackOverflow));
+        }
         // This is a point of huge optimizations, probably worth tweaking callframe stack
         // to make it smaller
         self.frames
             .push(CallFrame { return_ip: self.ip, stack_start: self.stack_pointer - locals_size });
         self.ip = func_pos;
+        if let Some(profile) = &mut self.profile {
+            profile.enter(Callee::Function(func_pos), Instant::now());
+        }
+        Ok(())
     }
 
-    fn call_std(&mut self, func_index: usize, locals_size: usize) {
+    fn call_std(
+        &mut self,
+        func_index: usize,
+        locals_size: usize,
+        return_size: usize,
+    ) -> Result<(), RuntimeError> {
+        if let Some(profile) = &mut self.profile {
+            profile.enter(Callee::Std(func_index), Instant::now());
+        }
         self.stack_pointer -= locals_size;
-        let res = STD_RAW_FUNCTION_RUNNERS[func_index].1(
-            &mut self.stack[self.stack_pointer..self.stack_pointer + locals_size],
-            &mut self.memory,
-            &self.vm.metadata,
+        let res = try_op!(
+            self,
+            STD_RAW_FUNCTION_RUNNERS[func_index].1(
+                &mut self.stack[self.stack_pointer..self.stack_pointer + locals_size],
+                &mut self.memory,
+                &self.vm.metadata,
+                &self.vm.options.stdout,
+            )
         );
+        // Verifier relies on sizes of values in the bytecode, so they must be the real ones
+        if res.len() != return_size {
+            return Err(runtime_error!(
+                self,
+                RuntimeErrorKind::InvalidBytecode(format!(
+                    "{} returned value of size {}, but {} is expected",
+                    STD_RAW_FUNCTION_RUNNERS[func_index].0,
+                    res.len(),
+                    return_size
+                ))
+            ));
+        }
         for o in res {
             push!(self, o);
         }
+        if let Some(profile) = &mut self.profile {
+            profile.exit(Instant::now());
+        }
+        Ok(())
+    }
+
+    // Frames are shown by debugger innermost first, with positions of calls for outer ones
+    fn pause_if_needed(&self) -> Result<(), RuntimeError> {
+        let debugger = self.vm.options.debugger.as_ref().unwrap();
+        let metadata = &self.vm.metadata;
+        let actor = self.sender_id();
+        if !debugger.should_pause(actor, self.op_position, self.frames.len(), metadata) {
+            return Ok(());
+        }
+
+        let frames = (0..self.frames.len())
+            .rev()
+            .map(|i| FrameView {
+                position: match self.frames.get(i + 1) {
+                    Some(next_frame) => next_frame.return_ip - 1,
+                    None => self.op_position,
+                },
+                stack_start: self.frames[i].stack_start,
+            })
+            .collect();
+        let paused = PausedActor {
+            actor,
+            name: self.vm.actor_name(actor),
+            frames,
+            stack: &self.stack[..self.stack_pointer],
+            heap: &self.memory,
+            metadata,
+            actors: self.vm.actors_names(),
+        };
+        match debugger.pause(&paused) {
+            DebugAction::Resume => Ok(()),
+            DebugAction::Quit => Err(runtime_error!(
+                self,
+                RuntimeErrorKind::Exit(exit_codes::RUNTIME_ERROR as i64)
+            )),
+        }
     }
 
     fn drop_current_frame(&mut self) {
         let frame = self.frames.pop().unwrap();
         self.ip = frame.return_ip;
         self.stack_pointer = frame.stack_start;
+        if let Some(profile) = &mut self.profile {
+            profile.exit(Instant::now());
+        }
     }
 
     fn current_frame(&self) -> &CallFrame {
@@ -129,57 +384,250 @@ impl ActiveObject {
     // NOTE: items are pushed on stack in-order (from left to right)
     // which means that they are popped in reverse order (from right to left)
     // so first pop b, than pop a
-    fn exec_binaryop_i64(&mut self, op: fn(i64, i64) -> i64) {
+    fn exec_binaryop_i64(&mut self, op: fn(i64, i64) -> i64) -> Result<(), RuntimeError> {
         let b = self.pop() as i64;
         let a = self.pop() as i64;
         push!(self, op(a, b) as u64);
+        Ok(())
     }
 
-    fn exec_binaryop_f64(&mut self, op: fn(f64, f64) -> f64) {
+    fn exec_binaryop_f64(&mut self, op: fn(f64, f64) -> f64) -> Result<(), RuntimeError> {
         let b = u64_to_f64(self.pop());
         let a = u64_to_f64(self.pop());
         let res = op(a, b);
         push!(self, f64_to_u64(res));
+        Ok(())
     }
 
-    fn exec_binaryop(&mut self, op: fn(u64, u64) -> u64) {
+    fn exec_binaryop(&mut self, op: fn(u64, u64) -> u64) -> Result<(), RuntimeError> {
         let b = self.pop();
         let a = self.pop();
         push!(self, op(a, b));
+        Ok(())
     }
 
-    fn exec_unaryop(&mut self, op: fn(u64) -> u64) {
+    fn exec_unaryop(&mut self, op: fn(u64) -> u64) -> Result<(), RuntimeError> {
         let a = self.pop();
         push!(self, op(a));
+        Ok(())
+    }
+
+    /// Returns serialized return value of the message, if it is needed. Message never gets
+    /// suspended: entry waits for space in full mailboxes, and active objects overfill them
+    pub fn run(
+        &mut self,
+        data: Vec<u8>,
+        needs_reply: bool,
+    ) -> Result<Option<Vec<u8>>, RuntimeError> {
+        self.suspendable = false;
+        match self.run_turn(&data, needs_reply)? {
+            Turn::Finished(reply) => Ok(reply),
+            Turn::Suspended => unreachable!("Message is suspended only by worker threads"),
+        }
+    }
+
+    /// Runs the message on a worker thread, that is given up if the message gets blocked
+    pub fn run_suspendable(
+        &mut self,
+        data: &[u8],
+        needs_reply: bool,
+    ) -> Result<Turn, RuntimeError> {
+        self.suspendable = true;
+        self.run_turn(data, needs_reply)
+    }
+
+    /// Retries the send, that suspended the message, or wakes it from sleep, and continues it.
+    /// Message of the object, that is restarted meanwhile, is interrupted and finishes without
+    /// a value
+    pub fn resume(&mut self) -> Result<Turn, RuntimeError> {
+        if let Some(due) = self.sleeps_until {
+            // Object might be woken before its timer by a mailbox, that it was blocked by
+            if self.vm.now() < due {
+                return Ok(Turn::Suspended);
+            }
+            self.sleeps_until = None;
+            let result = self.execute();
+            return self.finish_turn(result);
+        }
+        let Some((receiver, message)) = self.blocked_send.take() else {
+            return Ok(Turn::Finished(None));
+        };
+        let result = match self.retry_send(receiver, message) {
+            true => self.execute(),
+            false => Ok(false),
+        };
+        self.finish_turn(result)
+    }
+
+    /// Send of the suspended message, that has to be delivered when the object is restarted
+    pub fn take_blocked_send(&mut self) -> Option<(u64, Message)> {
+        self.blocked_send.take()
+    }
+
+    fn run_turn(&mut self, data: &[u8], needs_reply: bool) -> Result<Turn, RuntimeError> {
+        if self.transactional {
+            self.snapshot = Some(Snapshot {
+                fields: self.current_active_fields.clone(),
+                objects: self.memory.snapshot(self.fields_pointers(), &self.vm.metadata),
+            });
+        }
+
+        let function_pos = match read_function_position(data) {
+            Ok(function_pos) => function_pos,
+            Err(error) => {
+                return Err(runtime_error!(
+                    self,
+                    RuntimeErrorKind::MalformedMessage(error)
+                ));
+            }
+        };
+        self.message_call = (function_pos, needs_reply);
+        let result = self.run_message(function_pos, data);
+        self.finish_turn(result)
+    }
+
+    // Result of the message is true if it is finished, and false if it is suspended
+    fn finish_turn(&mut self, result: Result<bool, RuntimeError>) -> Result<Turn, RuntimeError> {
+        let mut result = match result {
+            Ok(false) => return Ok(Turn::Suspended),
+            result => result.map(|_| ()),
+        };
+        if let Err(error) = &mut result {
+            // Return address of each frame points right after the call made from previous one
+            error.trace = (1..self.frames.len())
+                .rev()
+                .map(|i| self.frames[i].return_ip - 1)
+                .collect();
+
+            // Failed message is discarded, so its frames are of no use anymore
+            self.frames.clear();
+            self.stack_pointer = 0;
+            if let Some(profile) = &mut self.profile {
+                profile.exit_all(Instant::now());
+            }
+
+            if let Some(Snapshot { fields, objects }) = self.snapshot.take() {
+                self.current_active_fields = fields;
+                self.memory.restore(objects);
+            }
+        }
+        self.snapshot = None;
+
+        // Return value is not on the stack anymore, so it must be packed before collecting garbage
+        let (function_pos, needs_reply) = self.message_call;
+        let reply = match &result {
+            Ok(()) if needs_reply => Some(serialize_return_value(
+                function_pos,
+                &self.return_value,
+                &self.memory,
+                &self.vm.metadata,
+            )),
+            _ => None,
+        };
+        self.collect_garbage_if_needed();
+        let actor = self.sender_id();
+        let item_type = actor.map(|_| self.item_type);
+        if let Some(profile) = &mut self.profile {
+            profile.record_message(actor, item_type, &mut self.memory);
+            self.vm.merge_profile(profile);
+        }
+        result.map(|_| Turn::Finished(reply))
+    }
+
+    // Returns false if the message has to be suspended, until there is space for the sent one
+    fn send(&mut self, receiver: u64, message: Message) -> bool {
+        match self.sender_id() {
+            Some(sender) if self.suspendable => {
+                self.vm.count_message(Some(sender), receiver, &message);
+                self.retry_send(receiver, message)
+            }
+            // Constructor of restarted object has no worker thread to give up, so it never waits
+            Some(sender) => {
+                self.vm.send_without_waiting(Some(sender), receiver, message);
+                true
+            }
+            None => {
+                self.vm.push_message(None, receiver, message);
+                true
+            }
+        }
+    }
+
+    fn retry_send(&mut self, receiver: u64, message: Message) -> bool {
+        match self.vm.deliver_or_suspend(self.worker_id, receiver, message) {
+            None => true,
+            Some(message) => {
+                self.blocked_send = Some((receiver, message));
+                false
+            }
+        }
     }
 
-    pub fn run(&mut self, data: Vec<u64>) {
-        let func_pos = data[0] as usize;
-        deserialize_function_args(
-            func_pos,
-            &mut self.stack,
-            &mut self.stack_pointer,
-            &mut self.memory,
-            &self.vm.metadata,
-            &data,
+    // Returns false if the message is suspended until the sleep is over
+    fn sleep(&mut self, args_size: usize) -> Result<bool, RuntimeError> {
+        self.stack_pointer -= args_size;
+        // Negative delay is the same as no delay at all
+        let delay = Duration::from_millis((self.stack[self.stack_pointer] as i64).max(0) as u64);
+        match self.sender_id() {
+            Some(sender) if self.suspendable => {
+                self.sleeps_until = Some(self.vm.wake_after(sender, delay));
+                Ok(false)
+            }
+            // Constructor of restarted object has no worker thread to give up, so it blocks it
+            Some(_) => {
+                self.vm.block_for(delay);
+                Ok(true)
+            }
+            None => {
+                try_op!(self, Vm::sleep(&self.vm, delay));
+                Ok(true)
+            }
+        }
+    }
+
+    fn run_message(&mut self, func_pos: usize, data: &[u8]) -> Result<bool, RuntimeError> {
+        try_op!(
+            self,
+            deserialize_function_args(
+                func_pos,
+                &mut self.stack,
+                &mut self.stack_pointer,
+                &mut self.memory,
+                &self.vm.metadata,
+                data,
+            )
+            .map_err(RuntimeErrorKind::MalformedMessage)
         );
 
-        self.call_op(func_pos, self.stack_pointer);
+        self.op_position = func_pos;
+        self.call_op(func_pos, self.stack_pointer)?;
+        self.execute()
+    }
 
+    // Returns false if the message is suspended by a send or a sleep, and true once it is finished
+    fn execute(&mut self) -> Result<bool, RuntimeError> {
         while self.ip < self.program.len() {
             if self.show_debug {
                 println!(">> preparing to exec pc: {:02x?}", self.ip);
             }
-            if self.step_by_step {
-                io::stdin().read_line(&mut String::from("")).unwrap();
-            }
 
+            self.op_position = self.ip;
+            if self.debugging {
+                self.pause_if_needed()?;
+            }
             let opcode = self.read_opcode();
+            if let Some(profile) = &mut self.profile {
+                profile.count_opcode(opcode);
+            }
             match opcode {
                 op::LOAD_CONST => {
                     let index = self.read_opcode();
                     push!(self, self.vm.constants[index as usize]);
                 }
+                op::LOAD_CONST_WIDE => {
+                    let index = u16::from_be_bytes(self.read_several::<2>());
+                    push!(self, self.vm.constants[index as usize]);
+                }
                 op::LOAD_SMALL_INT => {
                     let value = self.read_opcode();
                     push!(self, value as u64);
@@ -192,33 +640,40 @@ impl ActiveObject {
                 }
 
                 // TODO: test div and suband compare for float and ints
-                op::NEGATE_INT => self.exec_unaryop(|x| (-(x as i64)) as u64),
-                op::ADD_INT => self.exec_binaryop_i64(|a, b| a + b),
-                op::MUL_INT => self.exec_binaryop_i64(|a, b| a * b),
-                op::SUB_INT => self.exec_binaryop_i64(|a, b| a - b),
-                op::DIV_INT => self.exec_binaryop_i64(|a, b| a / b),
-                op::GREATER_INT => self.exec_binaryop(|a, b| ((a as i64) > (b as i64)) as u64),
-                op::LESS_INT => self.exec_binaryop(|a, b| ((a as i64) < (b as i64)) as u64),
-                op::EQ_INT => self.exec_binaryop(|a, b| ((a as i64) == (b as i64)) as u64),
-
-                op::NEGATE_FLOAT => self.exec_unaryop(|x| f64_to_u64(-u64_to_f64(x))),
-                op::ADD_FLOAT => self.exec_binaryop_f64(|a, b| a + b),
-                op::MUL_FLOAT => self.exec_binaryop_f64(|a, b| a * b),
-                op::SUB_FLOAT => self.exec_binaryop_f64(|a, b| a - b),
-                op::DIV_FLOAT => self.exec_binaryop_f64(|a, b| a / b),
+                // Integers are wrapped on overflow, same as in release builds of Rust
+                op::NEGATE_INT => self.exec_unaryop(|x| (x as i64).wrapping_neg() as u64)?,
+                op::ADD_INT => self.exec_binaryop_i64(i64::wrapping_add)?,
+                op::MUL_INT => self.exec_binaryop_i64(i64::wrapping_mul)?,
+                op::SUB_INT => self.exec_binaryop_i64(i64::wrapping_sub)?,
+                op::DIV_INT => {
+                    if self.stack[self.stack_pointer - 1] == 0 {
+                        return Err(runtime_error!(self, RuntimeErrorKind::DivisionByZero));
+                    }
+                    self.exec_binaryop_i64(i64::wrapping_div)?
+                }
+                op::GREATER_INT => self.exec_binaryop(|a, b| ((a as i64) > (b as i64)) as u64)?,
+                op::LESS_INT => self.exec_binaryop(|a, b| ((a as i64) < (b as i64)) as u64)?,
+                op::EQ_INT => self.exec_binaryop(|a, b| ((a as i64) == (b as i64)) as u64)?,
+
+                op::NEGATE_FLOAT => self.exec_unaryop(|x| f64_to_u64(-u64_to_f64(x)))?,
+                op::ADD_FLOAT => self.exec_binaryop_f64(|a, b| a + b)?,
+                op::MUL_FLOAT => self.exec_binaryop_f64(|a, b| a * b)?,
+                op::SUB_FLOAT => self.exec_binaryop_f64(|a, b| a - b)?,
+                op::DIV_FLOAT => self.exec_binaryop_f64(|a, b| a / b)?,
                 op::GREATER_FLOAT => {
-                    self.exec_binaryop(|a, b| (u64_to_f64(a) > u64_to_f64(b)) as u64)
+                    self.exec_binaryop(|a, b| (u64_to_f64(a) > u64_to_f64(b)) as u64)?
                 }
-                op::LESS_FLOAT => self.exec_binaryop(|a, b| ((a as f64) < (b as f64)) as u64),
-                op::EQ_FLOAT => self.exec_binaryop(|a, b| ((a as f64) == (b as f64)) as u64),
+                op::LESS_FLOAT => self.exec_binaryop(|a, b| ((a as f64) < (b as f64)) as u64)?,
+                op::EQ_FLOAT => self.exec_binaryop(|a, b| ((a as f64) == (b as f64)) as u64)?,
 
                 // TODO: test bool operators
-                op::NEGATE_BOOL => self.exec_unaryop(|x| x ^ 1),
-                op::AND_BOOL => self.exec_binaryop(|a, b| a & b),
-                op::OR_BOOL => self.exec_binaryop(|a, b| a | b),
-                op::EQ_BOOL => self.exec_binaryop(|a, b| (a ^ b) ^ 1),
+                op::NEGATE_BOOL => self.exec_unaryop(|x| x ^ 1)?,
+                op::AND_BOOL => self.exec_binaryop(|a, b| a & b)?,
+                op::OR_BOOL => self.exec_binaryop(|a, b| a | b)?,
+                op::EQ_BOOL => self.exec_binaryop(|a, b| (a ^ b) ^ 1)?,
 
                 op::ADD_STRINGS => {
+                    self.collect_garbage_if_needed();
                     let (b, a) = (self.pop(), self.pop());
                     let s1 = self.memory.get(a).extract_string();
                     let s2 = self.memory.get(b).extract_string();
@@ -251,49 +706,55 @@ impl ActiveObject {
                 op::SET_LOCAL => {
                     let value_pos = self.read_opcode() as usize;
                     let value_size = self.read_opcode() as usize;
+                    let total_offset = self.current_frame().stack_start + value_pos;
                     // Go backwards because pop() returns items in a reversed order
                     for i in 0..value_size {
                         let value = self.pop();
-                        self.stack
-                            [self.current_frame().stack_start + value_pos + value_size - i - 1] =
-                            value;
+                        self.stack[total_offset + value_size - i - 1] = value;
                     }
                 }
                 op::GET_OBJ_FIELD => {
                     let pointer = self.pop();
-                    let offset = self.read_opcode() as usize;
+                    let offset = u16::from_be_bytes(self.read_several::<2>()) as usize;
                     let size = self.read_opcode() as usize;
 
-                    let heap_obj = self.memory.get_mut(pointer);
-                    let custom = heap_obj.extract_custom_object();
+                    let custom = try_op!(self, extract_object(self.memory.get_mut(pointer)));
+                    let field = try_op!(self, field_range(&custom.data, offset, size));
 
-                    for value in custom.data.iter().skip(offset).take(size) {
+                    for value in &custom.data[field] {
                         push!(self, *value);
                     }
                 }
                 op::SET_OBJ_FIELD => {
                     let pointer = self.pop();
-                    let offset = self.read_opcode() as usize;
+                    let offset = u16::from_be_bytes(self.read_several::<2>()) as usize;
                     let size = self.read_opcode() as usize;
 
-                    let heap_obj = self.memory.get_mut(pointer);
-                    let custom = heap_obj.extract_custom_object();
+                    let custom = try_op!(self, extract_object(self.memory.get_mut(pointer)));
+                    let field = try_op!(self, field_range(&custom.data, offset, size));
 
-                    for i in 0..size {
-                        let x = self.stack[self.stack_pointer - size + i];
-                        custom.data[offset + i] = x;
-                    }
                     self.stack_pointer -= size;
+                    custom.data[field].copy_from_slice(
+                        &self.stack[self.stack_pointer..self.stack_pointer + size],
+                    );
                 }
                 op::GET_LIST_ITEM => {
+                    let item_size = self.read_opcode() as usize;
                     let list_pointer = self.pop();
                     let index = self.pop() as i64;
 
-                    let heap_obj = self.memory.get_mut(list_pointer);
-                    let list = heap_obj.extract_list_mut();
+                    let list = try_op!(self, extract_list(self.memory.get_mut(list_pointer)));
+                    if list.item_size != item_size {
+                        return Err(runtime_error!(
+                            self,
+                            RuntimeErrorKind::InvalidBytecode(format!(
+                                "list item has size {}, but {} is expected",
+                                list.item_size, item_size
+                            ))
+                        ));
+                    }
 
-                    let index = list.normalize_index(index);
-                    let item_size = list.item_size;
+                    let index = try_op!(self, list.normalize_index(index));
                     let item_memory = list.get_item_mem(index);
 
                     for item in item_memory.iter().take(item_size) {
@@ -307,16 +768,24 @@ impl ActiveObject {
                     let list_pointer = self.pop();
                     let index = self.pop() as i64;
 
-                    let heap_obj = self.memory.get_mut(list_pointer);
-                    let list = heap_obj.extract_list_mut();
+                    let list = try_op!(self, extract_list(self.memory.get_mut(list_pointer)));
+                    if inner_offset + value_size > list.item_size {
+                        return Err(runtime_error!(
+                            self,
+                            RuntimeErrorKind::InvalidBytecode(format!(
+                                "list item has size {}, but value of size {} is written at {}",
+                                list.item_size, value_size, inner_offset
+                            ))
+                        ));
+                    }
 
-                    let index = list.normalize_index(index);
-                    let memory_to_write = list.get_item_mem(index as usize);
+                    let index = try_op!(self, list.normalize_index(index));
+                    let memory_to_write = list.get_item_mem(index);
 
                     self.stack_pointer -= value_size;
-                    for i in 0..value_size {
-                        memory_to_write[inner_offset + i] = self.stack[self.stack_pointer + i];
-                    }
+                    memory_to_write[inner_offset..inner_offset + value_size].copy_from_slice(
+                        &self.stack[self.stack_pointer..self.stack_pointer + value_size],
+                    );
                 }
                 op::GET_TUPLE_ITEM => {
                     let tuple_size = self.read_opcode() as usize;
@@ -329,15 +798,18 @@ impl ActiveObject {
                     }
                 }
                 op::ALLOCATE => {
-                    let type_index = self.read_opcode() as usize;
+                    let type_index = u16::from_be_bytes(self.read_several::<2>()) as usize;
+                    self.collect_garbage_if_needed();
                     let (new_obj_pos, _) =
                         self.memory.allocate_custom(type_index, &self.vm.metadata);
                     push!(self, new_obj_pos);
                 }
                 op::ALLOCATE_LIST => {
-                    let list_type_index = self.read_opcode() as usize;
-                    let item_size = self.vm.metadata.list_types_sizes[list_type_index] as usize;
-                    let initial_items_amount = self.read_opcode() as usize;
+                    let list_type_index = u16::from_be_bytes(self.read_several::<2>()) as usize;
+                    let item_size = self.vm.metadata.list_types_sizes[list_type_index];
+                    let initial_items_amount =
+                        u16::from_be_bytes(self.read_several::<2>()) as usize;
+                    self.collect_garbage_if_needed();
 
                     self.stack_pointer -= item_size * initial_items_amount;
 
@@ -355,17 +827,26 @@ impl ActiveObject {
                     // TODO: check if performance is increased when reserve does not fills with 0
                     // If yes, probably worth giving some kind of flag
                     // this will make GC less precise, but
+                    self.ensure_stack_capacity(value)?;
                     self.stack[self.stack_pointer..self.stack_pointer + value].fill(0);
                     self.stack_pointer += value;
                 }
-                op::CALL | op::CALL_STD => {
+                op::CALL => {
                     let args_size = self.read_opcode() as usize;
-                    let function_pos = u16::from_be_bytes(self.read_several::<2>()) as usize;
-
-                    match opcode {
-                        op::CALL => self.call_op(function_pos, args_size),
-                        op::CALL_STD => self.call_std(function_pos, args_size),
-                        _ => unreachable!(),
+                    let function_pos = u32::from_be_bytes(self.read_several::<4>()) as usize;
+                    self.call_op(function_pos, args_size)?;
+                }
+                op::CALL_STD => {
+                    let args_size = self.read_opcode() as usize;
+                    let return_size = self.read_opcode() as usize;
+                    let function_index = self.read_opcode() as usize;
+                    if function_index == STD_SLEEP {
+                        if !self.sleep(args_size)? {
+                            return Ok(false);
+                        }
+                    } else {
+                        self.collect_garbage_if_needed();
+                        self.call_std(function_index, args_size, return_size)?;
                     }
                 }
                 op::POP => {
@@ -379,8 +860,13 @@ impl ActiveObject {
                     let current_start = self.current_frame().stack_start;
 
                     // If function that returns some value is called via message send
-                    // then there is no place for return value on the stack, so just ignore writing return value
-                    if current_start >= return_size {
+                    // then there is no place for return value on the stack, so it is kept aside
+                    // in case the sender waits for it
+                    if self.frames.len() == 1 {
+                        self.return_value = self.stack
+                            [self.stack_pointer - return_size..self.stack_pointer]
+                            .to_vec();
+                    } else {
                         for i in 0..return_size {
                             self.stack[current_start - i - 1] =
                                 self.stack[self.stack_pointer - i - 1];
@@ -389,29 +875,30 @@ impl ActiveObject {
 
                     self.drop_current_frame();
                     if self.frames.is_empty() {
-                        break;
+                        return Ok(true);
                     }
                 }
                 op::JUMP => {
-                    let x = u16::from_be_bytes(self.read_several::<2>());
+                    let x = u32::from_be_bytes(self.read_several::<4>());
                     self.ip += x as usize;
                 }
                 op::JUMP_IF_FALSE => {
                     let c = self.pop();
-                    let x = u16::from_be_bytes(self.read_several::<2>());
+                    let x = u32::from_be_bytes(self.read_several::<4>());
                     if c == 0 {
                         self.ip += x as usize;
                     }
                 }
                 op::JUMP_BACK => {
-                    let x = u16::from_be_bytes(self.read_several::<2>());
+                    let x = u32::from_be_bytes(self.read_several::<4>());
                     self.ip -= x as usize;
                 }
                 op::SPAWN => {
-                    let item_type = self.read_opcode() as usize;
-                    let constructor_pos = u16::from_be_bytes(self.read_several::<2>());
+                    let item_type = u16::from_be_bytes(self.read_several::<2>()) as usize;
+                    let constructor_pos = u32::from_be_bytes(self.read_several::<4>());
                     let active_link = Vm::spawn_new_active(
                         self.vm.clone(),
+                        self.sender_id(),
                         item_type,
                         serialize_function_args(
                             constructor_pos as usize,
@@ -423,29 +910,51 @@ impl ActiveObject {
                     );
                     push!(self, active_link);
                 }
+                op::SPAWN_REMOTE => {
+                    let item_type = u16::from_be_bytes(self.read_several::<2>()) as usize;
+                    let constructor_pos = u32::from_be_bytes(self.read_several::<4>());
+                    let node_name_pointer = self.pop();
+                    let node_name = self.memory.get(node_name_pointer).extract_string().clone();
+                    let constructor_args = serialize_function_args(
+                        constructor_pos as usize,
+                        &self.stack,
+                        &mut self.stack_pointer,
+                        &self.memory,
+                        &self.vm.metadata,
+                    );
+                    let active_link = try_op!(
+                        self,
+                        Vm::spawn_remote(self.vm.clone(), &node_name, item_type, constructor_args)
+                    );
+                    push!(self, active_link);
+                }
                 op::CURRENT_ACTIVE => {
                     push!(self, self.worker_id);
                 }
                 op::GET_CURRENT_ACTIVE_FIELD => {
-                    let offset = self.read_opcode() as usize;
+                    let offset = u16::from_be_bytes(self.read_several::<2>()) as usize;
                     let size = self.read_opcode() as usize;
 
-                    for value in self.current_active_fields.iter().skip(offset).take(size) {
-                        push!(self, *value);
+                    let field =
+                        try_op!(self, field_range(&self.current_active_fields, offset, size));
+                    for i in field {
+                        push!(self, self.current_active_fields[i]);
                     }
                 }
                 op::SET_CURRENT_ACTIVE_FIELD => {
-                    let offset = self.read_opcode() as usize;
+                    let offset = u16::from_be_bytes(self.read_several::<2>()) as usize;
                     let size = self.read_opcode() as usize;
 
-                    for i in 0..size {
-                        let x = self.stack[self.stack_pointer - size + i];
-                        self.current_active_fields[offset + i] = x;
-                    }
+                    let field =
+                        try_op!(self, field_range(&self.current_active_fields, offset, size));
+
                     self.stack_pointer -= size;
+                    self.current_active_fields[field].copy_from_slice(
+                        &self.stack[self.stack_pointer..self.stack_pointer + size],
+                    );
                 }
                 op::SEND_MESSAGE => {
-                    let receiver_pos = u16::from_be_bytes(self.read_several::<2>());
+                    let receiver_pos = u32::from_be_bytes(self.read_several::<4>());
                     let msg = serialize_function_args(
                         receiver_pos as usize,
                         &self.stack,
@@ -455,9 +964,89 @@ impl ActiveObject {
                     );
                     // println!("Serialized for send {}: {:?}", receiver_pos, msg);
                     let active_obj = self.pop();
-                    self.gateway.send((active_obj, msg)).expect("Cant send message");
+                    if !self.send(active_obj, Message { data: msg, reply_to: None }) {
+                        return Ok(false);
+                    }
+                }
+                op::SEND_MESSAGE_AFTER => {
+                    let receiver_pos = u32::from_be_bytes(self.read_several::<4>());
+                    // Negative delay is the same as no delay at all
+                    let delay = Duration::from_millis((self.pop() as i64).max(0) as u64);
+                    let msg = serialize_function_args(
+                        receiver_pos as usize,
+                        &self.stack,
+                        &mut self.stack_pointer,
+                        &self.memory,
+                        &self.vm.metadata,
+                    );
+                    let active_obj = self.pop();
+                    self.vm.send_message_after(self.sender_id(), active_obj, msg, delay);
+                }
+                op::ASK_MESSAGE => {
+                    let receiver_pos = u32::from_be_bytes(self.read_several::<4>());
+                    let msg = serialize_function_args(
+                        receiver_pos as usize,
+                        &self.stack,
+                        &mut self.stack_pointer,
+                        &self.memory,
+                        &self.vm.metadata,
+                    );
+                    let active_obj = self.pop();
+                    let future = self.vm.create_future();
+                    push!(self, future);
+                    if !self.send(active_obj, Message { data: msg, reply_to: Some(future) }) {
+                        return Ok(false);
+                    }
+                }
+                op::STOP_ACTIVE => {
+                    let active_obj = self.pop();
+                    self.vm.stop_active(active_obj);
+                }
+                op::WAIT_ACTIVE => {
+                    if !self.blocking_allowed {
+                        return Err(runtime_error!(
+                            self,
+                            RuntimeErrorKind::BlockingInActiveObject
+                        ));
+                    }
+                    let active_obj = self.pop();
+                    try_op!(self, Vm::wait_stopped(&self.vm, active_obj));
+                }
+                op::WAIT_FUTURE => {
+                    if !self.blocking_allowed {
+                        return Err(runtime_error!(
+                            self,
+                            RuntimeErrorKind::BlockingInActiveObject
+                        ));
+                    }
+                    let value_size = self.read_opcode() as usize;
+                    let future = self.pop();
+                    let chunk = try_op!(self, Vm::wait_future(&self.vm, future));
+                    let value = try_op!(
+                        self,
+                        deserialize_return_value(&chunk, &mut self.memory, &self.vm.metadata)
+                            .map_err(RuntimeErrorKind::MalformedMessage)
+                    );
+                    if value.len() != value_size {
+                        return Err(runtime_error!(
+                            self,
+                            RuntimeErrorKind::InvalidBytecode(format!(
+                                "future has value of size {}, but {} is expected",
+                                value.len(),
+                                value_size
+                            ))
+                        ));
+                    }
+                    for v in value {
+                        push!(self, v);
+                    }
+                }
+                _ => {
+                    return Err(runtime_error!(
+                        self,
+                        RuntimeErrorKind::UnknownOpcode(opcode)
+                    ))
                 }
-                _ => panic!("Unknown opcode: {}", opcode),
             }
             if self.show_debug {
                 println!(" ## FRAME: {:?}", self.current_frame());
@@ -465,5 +1054,29 @@ impl ActiveObject {
                 println!(" ## {}", &self.memory.simple_debug_view());
             }
         }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fields_outside_of_objects_are_refused() {
+        let fields = [1, 2, 3];
+        assert_eq!(field_range(&fields, 1, 2), Ok(1..3));
+        assert_eq!(
+            field_range(&fields, 2, 2),
+            Err(RuntimeErrorKind::InvalidBytecode(
+                "object of size 3 has no field of size 2 at offset 2".into()
+            ))
+        );
+        assert_eq!(
+            extract_object(&mut heap::HeapObject::String("a".into())).err(),
+            Some(RuntimeErrorKind::InvalidBytecode(
+                "value is not an object".into()
+            ))
+        );
     }
 }
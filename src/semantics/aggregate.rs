@@ -1,9 +1,14 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use crate::alias::ModuleAlias;
 use crate::ast::parsed::{ClassDecl, FileAst, FunctionDecl, TypedItem};
 use crate::ast::verified::{CustomType, RawFunction, TypedFields};
-use crate::symbols::{SymbolFunc, SymbolType, MAIN_FUNCTION_NAME};
+use crate::runtime::scheduler::{MailboxConfig, OverflowPolicy};
+use crate::runtime::supervision::{RestartStrategy, SupervisorConfig};
+use crate::symbols::{
+    SymbolFunc, SymbolType, MAIN_FUNCTION_NAME, ON_CHILD_FAILED_METHOD_NAME, ON_STOP_METHOD_NAME,
+};
 use crate::types::{verify_parsed_type, Type};
 
 use super::errors::{top_level_with_module, SemanticError, SemanticErrorWithModule};
@@ -49,6 +54,8 @@ pub fn create_basic_aggregate(
                 CustomType {
                     name: full_name,
                     is_active: class_decl.is_active,
+                    mailbox: verify_mailbox(alias, class_decl)?,
+                    supervision: verify_supervision(alias, class_decl)?,
                     fields: annotate_typednamed_vec(&class_decl.fields, &file_resolver)
                         .or_else(|err| field_type_error(err, class_decl))?,
                 },
@@ -56,13 +63,112 @@ pub fn create_basic_aggregate(
         }
 
         if *alias == entry_module {
-            check_entry_module_has_main(*alias, *file_ast)?;
+            check_entry_module_has_main(alias, file_ast)?;
         }
     }
 
     Ok(aggregate)
 }
 
+fn verify_mailbox(
+    alias: &ModuleAlias,
+    class_decl: &ClassDecl,
+) -> Result<MailboxConfig, SemanticErrorWithModule> {
+    let capacity = match class_decl.options.mailbox {
+        Some(capacity) if capacity <= 0 || capacity > u32::MAX as i64 => {
+            return top_level_with_module!(
+                alias,
+                class_decl,
+                "Mailbox capacity of `{}` must be between 1 and {}, but got {}",
+                class_decl.name,
+                u32::MAX,
+                capacity
+            );
+        }
+        capacity => capacity.map(|c| c as usize),
+    };
+
+    let overflow = match &class_decl.options.overflow {
+        Some(name) => match OverflowPolicy::from_name(name) {
+            Some(policy) => Some(policy),
+            _ => {
+                return top_level_with_module!(
+                    alias,
+                    class_decl,
+                    "Unknown overflow policy `{}`, expected one of: {}",
+                    name,
+                    OverflowPolicy::NAMES.join(", ")
+                );
+            }
+        },
+        None => None,
+    };
+
+    Ok(MailboxConfig { capacity, overflow })
+}
+
+fn verify_supervision(
+    alias: &ModuleAlias,
+    class_decl: &ClassDecl,
+) -> Result<Option<SupervisorConfig>, SemanticErrorWithModule> {
+    let options = &class_decl.options;
+    let strategy = match &options.supervision {
+        Some(name) => match RestartStrategy::from_name(name) {
+            Some(strategy) => strategy,
+            _ => {
+                return top_level_with_module!(
+                    alias,
+                    class_decl,
+                    "Unknown supervision strategy `{}`, expected one of: {}",
+                    name,
+                    RestartStrategy::NAMES.join(", ")
+                );
+            }
+        },
+        None if options.max_restarts.is_some() || options.restart_period.is_some() => {
+            return top_level_with_module!(
+                alias,
+                class_decl,
+                "Restart limits of `{}` are set, but it has no `supervision` strategy",
+                class_decl.name
+            );
+        }
+        None => return Ok(None),
+    };
+
+    let max_restarts = match options.max_restarts {
+        Some(max_restarts) if !(0..=u16::MAX as i64).contains(&max_restarts) => {
+            return top_level_with_module!(
+                alias,
+                class_decl,
+                "Max restarts of `{}` must be between 0 and {}, but got {}",
+                class_decl.name,
+                u16::MAX,
+                max_restarts
+            );
+        }
+        max_restarts => max_restarts.map_or(SupervisorConfig::DEFAULT_MAX_RESTARTS, |m| m as usize),
+    };
+
+    let period = match options.restart_period {
+        Some(period) if period <= 0 || period > u32::MAX as i64 => {
+            return top_level_with_module!(
+                alias,
+                class_decl,
+                "Restart period of `{}` must be between 1 and {} milliseconds, but got {}",
+                class_decl.name,
+                u32::MAX,
+                period
+            );
+        }
+        period => period.map_or(SupervisorConfig::DEFAULT_PERIOD, |p| {
+            Duration::from_millis(p as u64)
+        }),
+    };
+
+    Ok(Some(SupervisorConfig { strategy, max_restarts, period }))
+}
+
 fn check_entry_module_has_main(
     main_module: &ModuleAlias,
     file_ast: &FileAst,
@@ -128,6 +234,31 @@ pub fn fill_aggregate_with_funcs<'a>(
                     );
                 }
 
+                let is_on_stop = class_decl.is_active && method.name == ON_STOP_METHOD_NAME;
+                if is_on_stop && (!method.args.is_empty() || method.rettype.is_some()) {
+                    return top_level_with_module!(
+                        *alias,
+                        method,
+                        "Method `{}` of `{}` must take no arguments and return nothing",
+                        method.name,
+                        class_decl.name
+                    );
+                }
+
+                let is_on_child_failed =
+                    class_decl.is_active && method.name == ON_CHILD_FAILED_METHOD_NAME;
+                let takes_two_strings = method.args.len() == 2
+                    && method.args.iter().all(|arg| arg.typename == Type::String);
+                if is_on_child_failed && (!takes_two_strings || method.rettype.is_some()) {
+                    return top_level_with_module!(
+                        *alias,
+                        method,
+                        "Method `{}` of `{}` must take name of the child and error, both `String`, and return nothing",
+                        method.name,
+                        class_decl.name
+                    );
+                }
+
                 let mut args = method.args.clone();
                 if !class_decl.is_active && method.name != class_decl.name {
                     // Add implicit `this` argument, but only for non-active classes
@@ -53,33 +53,37 @@ impl From<Box<SemanticError>> for Box<dyn ExprError> {
     }
 }
 
-fn get_temp(n: &str, t: &VerifiedType) -> VExprTyped {
+fn get_temp(n: &str, t: &VerifiedType, pos: usize) -> VExprTyped {
     VExprTyped {
         expr: VExpr::GetVar(n.into()),
         expr_type: Type::Maybe(Box::new(t.clone())),
+        pos,
     }
 }
-fn get_flag(n: &str, t: &VerifiedType) -> VExprTyped {
+fn get_flag(n: &str, t: &VerifiedType, pos: usize) -> VExprTyped {
     VExprTyped {
-        expr: VExpr::AccessTupleItem { tuple: Box::new(get_temp(n, t)), index: 0 },
+        expr: VExpr::AccessTupleItem { tuple: Box::new(get_temp(n, t, pos)), index: 0 },
         expr_type: Type::Bool,
+        pos,
     }
 }
-fn get_value(n: &str, t: &VerifiedType) -> VExprTyped {
+fn get_value(n: &str, t: &VerifiedType, pos: usize) -> VExprTyped {
     VExprTyped {
-        expr: VExpr::AccessTupleItem { tuple: Box::new(get_temp(n, t)), index: 1 },
+        expr: VExpr::AccessTupleItem { tuple: Box::new(get_temp(n, t, pos)), index: 1 },
         expr_type: t.clone(),
+        pos,
     }
 }
 
-fn dummy_maybe(inner: &VerifiedType) -> VExprTyped {
+fn dummy_maybe(inner: &VerifiedType, pos: usize) -> VExprTyped {
     let tuple_items = vec![
-        VExprTyped { expr: VExpr::Bool(false), expr_type: Type::Bool },
-        VExprTyped { expr: VExpr::Dummy(inner.clone()), expr_type: inner.clone() },
+        VExprTyped { expr: VExpr::Bool(false), expr_type: Type::Bool, pos },
+        VExprTyped { expr: VExpr::Dummy(inner.clone()), expr_type: inner.clone(), pos },
     ];
     VExprTyped {
         expr: VExpr::TupleValue(tuple_items),
         expr_type: Type::Maybe(Box::new(inner.clone())),
+        pos,
     }
 }
 
@@ -93,13 +97,17 @@ fn if_as_expected(
 ) -> Result<VExprTyped, String> {
     match expected {
         Some(t) if &calculated.expr_type == t => Ok(calculated),
-        Some(Type::Maybe(inner)) if &calculated.expr_type == inner.as_ref() => Ok(VExprTyped {
-            expr: VExpr::TupleValue(vec![
-                VExprTyped { expr: VExpr::Bool(true), expr_type: Type::Int },
-                calculated,
-            ]),
-            expr_type: expected.unwrap().clone(),
-        }),
+        Some(Type::Maybe(inner)) if &calculated.expr_type == inner.as_ref() => {
+            let pos = calculated.pos;
+            Ok(VExprTyped {
+                expr: VExpr::TupleValue(vec![
+                    VExprTyped { expr: VExpr::Bool(true), expr_type: Type::Int, pos },
+                    calculated,
+                ]),
+                expr_type: expected.unwrap().clone(),
+                pos,
+            })
+        }
         Some(_) => Err(format!(
             "Expected type `{}` but got `{}`",
             expected.unwrap(),
@@ -182,7 +190,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         let verified_expr = self
             .calculate(expr, expected)
             .map_err(|err| err.add_expr_info(expr))?;
-        if_as_expected(expected, verified_expr).map_err(|e| (&e).add_expr_info(expr))
+        if_as_expected(expected, verified_expr).map_err(|e| e.add_expr_info(expr))
     }
 
     fn calculate(
@@ -190,12 +198,15 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         expr: &ExprWithPos,
         expected: Option<&VerifiedType>,
     ) -> Result<VExprTyped, Box<dyn ExprError>> {
+        let pos = expr.pos_first;
         match &expr.expr {
-            Expr::Int(i) => Ok(VExprTyped { expr: VExpr::Int(*i), expr_type: Type::Int }),
-            Expr::Float(f) => Ok(VExprTyped { expr: VExpr::Float(*f), expr_type: Type::Float }),
-            Expr::Bool(b) => Ok(VExprTyped { expr: VExpr::Bool(*b), expr_type: Type::Bool }),
+            Expr::Int(i) => Ok(VExprTyped { expr: VExpr::Int(*i), expr_type: Type::Int, pos }),
+            Expr::Float(f) => {
+                Ok(VExprTyped { expr: VExpr::Float(*f), expr_type: Type::Float, pos })
+            }
+            Expr::Bool(b) => Ok(VExprTyped { expr: VExpr::Bool(*b), expr_type: Type::Bool, pos }),
             Expr::String(s) => {
-                Ok(VExprTyped { expr: VExpr::String(s.clone()), expr_type: Type::String })
+                Ok(VExprTyped { expr: VExpr::String(s.clone()), expr_type: Type::String, pos })
             }
 
             Expr::Identifier(i) => {
@@ -207,7 +218,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                         i
                     ));
                 }
-                Ok(VExprTyped { expr: VExpr::GetVar(real_name), expr_type: identifier_type })
+                Ok(VExprTyped { expr: VExpr::GetVar(real_name), expr_type: identifier_type, pos })
             }
             Expr::This => match &self.func.method_of {
                 Some(t) => {
@@ -216,7 +227,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     } else {
                         VExpr::GetVar("this".into())
                     };
-                    Ok(VExprTyped { expr: this_expr, expr_type: Type::Custom(t.clone()) })
+                    Ok(VExprTyped { expr: this_expr, expr_type: Type::Custom(t.clone()), pos })
                 }
                 None => to_dyn(expression_error!(
                     expr,
@@ -262,10 +273,10 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
             Expr::FunctionCall { function, args } => {
                 if is_std_function(function) {
                     let std_raw = get_std_function_raw(function);
-                    self.calculate_function_call(&std_raw, args, None)
+                    self.calculate_function_call(&std_raw, args, None, pos)
                 } else {
                     let raw_called = self.resolve_func(function)?;
-                    self.calculate_function_call(raw_called, args, None)
+                    self.calculate_function_call(raw_called, args, None, pos)
                 }
             }
             Expr::MethodCall { object, method, args } => {
@@ -288,7 +299,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                 let inner_type = inner_type.clone();
                 let temp = self.request_temp(ve_object, object.pos_last);
                 let method_call =
-                    self.calculate_method_call(get_value(&temp, &inner_type), method, args)?;
+                    self.calculate_method_call(get_value(&temp, &inner_type, pos), method, args)?;
 
                 let inner_type: VerifiedType;
                 let method_call_wrapped = match &method_call.expr_type {
@@ -301,20 +312,25 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                         inner_type = t.clone();
                         let boxed_type = Type::Maybe(Box::new(t.clone()));
                         let tuple_items = vec![
-                            VExprTyped { expr: VExpr::Bool(true), expr_type: Type::Bool },
+                            VExprTyped { expr: VExpr::Bool(true), expr_type: Type::Bool, pos },
                             method_call,
                         ];
-                        VExprTyped { expr: VExpr::TupleValue(tuple_items), expr_type: boxed_type }
+                        VExprTyped {
+                            expr: VExpr::TupleValue(tuple_items),
+                            expr_type: boxed_type,
+                            pos,
+                        }
                     }
                 };
 
                 Ok(VExprTyped {
                     expr: VExpr::TernaryOp {
-                        condition: Box::new(get_flag(&temp, &inner_type)),
+                        condition: Box::new(get_flag(&temp, &inner_type, pos)),
                         if_true: Box::new(method_call_wrapped),
-                        if_false: Box::new(dummy_maybe(&inner_type)),
+                        if_false: Box::new(dummy_maybe(&inner_type, pos)),
                     },
                     expr_type: Type::Maybe(Box::new(inner_type)),
+                    pos,
                 })
             }
             Expr::OwnMethodCall { method, args } => {
@@ -339,7 +355,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     Some(self.verify_expr(&implicit_this, None)?)
                 };
                 let raw_method = self.resolve_method(type_of_func, method)?;
-                self.calculate_function_call(raw_method, args, this_object)
+                self.calculate_function_call(raw_method, args, this_object, pos)
             }
             Expr::NewClassInstance { typename, args } => {
                 let symbol = &(self.type_resolver)(typename)?;
@@ -352,7 +368,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     ));
                 }
                 let raw_constructor = self.resolve_method(&raw_type.name, typename)?;
-                self.calculate_function_call(raw_constructor, args, None)
+                self.calculate_function_call(raw_constructor, args, None, pos)
             }
 
             Expr::TupleValue(items) => {
@@ -385,6 +401,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                 Ok(VExprTyped {
                     expr: VExpr::TupleValue(calculated),
                     expr_type: Type::Tuple(item_types),
+                    pos,
                 })
             }
             Expr::ListValue(items) if items.is_empty() => {
@@ -396,18 +413,15 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                         Ok(VExprTyped {
                             expr: VExpr::ListValue { item_type: item_type.clone(), items: vec![] },
                             expr_type: Type::List(Box::new(item_type)),
+                            pos,
                         })
                     }
-                    Some(_) => {
-                        return to_dyn(expression_error!(
-                            expr,
-                            "Unexpected list value (expected `{}`)",
-                            expected.unwrap()
-                        ))
-                    }
-                    None => {
-                        return to_dyn(expression_error!(expr, "Cannot derive empty list type!"))
-                    }
+                    Some(_) => to_dyn(expression_error!(
+                        expr,
+                        "Unexpected list value (expected `{}`)",
+                        expected.unwrap()
+                    )),
+                    None => to_dyn(expression_error!(expr, "Cannot derive empty list type!")),
                 }
             }
             Expr::ListValue(items) => {
@@ -451,6 +465,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                         items: calculated_items,
                     },
                     expr_type: Type::List(Box::new(item_type)),
+                    pos,
                 })
             }
             Expr::ListAccess { list, index } => self.calculate_access_by_index(list, index),
@@ -472,7 +487,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                             object: Box::new(object_calculated),
                             field: field.clone(),
                         };
-                        Ok(VExprTyped { expr, expr_type: field_type.clone() })
+                        Ok(VExprTyped { expr, expr_type: field_type.clone(), pos })
                     }
                     _ => to_dyn(expression_error!(
                         expr,
@@ -519,10 +534,10 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     VExpr::AccessField { object: Box::new(this_object), field: field.clone() }
                 };
 
-                Ok(VExprTyped { expr, expr_type: field_type.clone() })
+                Ok(VExprTyped { expr, expr_type: field_type.clone(), pos })
             }
 
-            Expr::SpawnActive { typename, args } => {
+            Expr::SpawnActive { typename, args, node } => {
                 let symbol = &(self.type_resolver)(typename)?;
                 let raw_type = &self.aggregate.types[symbol];
                 if !raw_type.is_active {
@@ -550,27 +565,69 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     .map(|(arg, expected_type)| self.verify_expr(arg, Some(expected_type)))
                     .collect();
 
-                let vexpr_spawn = VExpr::Spawn { typename: symbol.clone(), args: processed_args? };
+                let node = match node {
+                    Some(node) => Some(Box::new(self.verify_expr(node, Some(&Type::String))?)),
+                    None => None,
+                };
+
+                let vexpr_spawn =
+                    VExpr::Spawn { typename: symbol.clone(), args: processed_args?, node };
                 Ok(VExprTyped {
                     expr: vexpr_spawn,
                     expr_type: Type::Custom(raw_constructor.method_of.clone().unwrap()),
+                    pos,
                 })
             }
-            Expr::Nil => match expected {
-                Some(Type::Maybe(i)) => Ok(dummy_maybe(i.as_ref())),
-                Some(t) => {
-                    return to_dyn(expression_error!(
-                        expr,
-                        "`nil` is only allowed for maybe types (expected `{}`)",
-                        t
-                    ))
-                }
-                None => {
+            Expr::AskMessage { active, method, args } => {
+                let verified_active = self.verify_expr(active, None)?;
+                let method_raw = match &verified_active.expr_type {
+                    Type::Custom(typename) if self.aggregate.types[typename].is_active => self
+                        .aggregate
+                        .functions
+                        .get(&typename.method(method))
+                        .ok_or_else(|| format!("No method `{}` in type `{}`", method, typename))?,
+                    t => {
+                        return to_dyn(expression_error!(
+                            active,
+                            "Can only ask active objects, but `{}` is not active",
+                            t
+                        ));
+                    }
+                };
+                if method_raw.return_type == Type::Tuple(vec![]) {
                     return to_dyn(expression_error!(
                         expr,
-                        "`nil` is not allowed here (cannot derive type)"
-                    ))
+                        "Method `{}` returns nothing, use ! to send a message instead",
+                        method
+                    ));
                 }
+
+                let call = self.calculate_function_call(method_raw, args, None, pos)?;
+                let call_args = match call.expr {
+                    VExpr::CallFunction { args, .. } => args,
+                    _ => unreachable!(),
+                };
+                Ok(VExprTyped {
+                    expr: VExpr::AskMessage {
+                        active: Box::new(verified_active),
+                        receiver: method_raw.name.clone(),
+                        args: call_args,
+                    },
+                    expr_type: Type::Future(Box::new(method_raw.return_type.clone())),
+                    pos,
+                })
+            }
+            Expr::Nil => match expected {
+                Some(Type::Maybe(i)) => Ok(dummy_maybe(i.as_ref(), pos)),
+                Some(t) => to_dyn(expression_error!(
+                    expr,
+                    "`nil` is only allowed for maybe types (expected `{}`)",
+                    t
+                )),
+                None => to_dyn(expression_error!(
+                    expr,
+                    "`nil` is not allowed here (cannot derive type)"
+                )),
             },
         }
     }
@@ -581,6 +638,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         method: &str,
         args: &[ExprWithPos],
     ) -> Result<VExprTyped, Box<dyn ExprError>> {
+        let pos = object.pos;
         let std_method: Box<RawFunction>;
         let raw_method = match &object.expr_type {
             Type::Tuple(..) => {
@@ -591,10 +649,30 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     "Use ?. operator to access methods for Maybe type".to_string(),
                 ));
             }
+            Type::Future(inner) => {
+                if method != "wait" || !args.is_empty() {
+                    return Err(Box::new("Futures only have `wait()` method".to_string()));
+                }
+                // Worker threads are shared between active objects, so none of them can block
+                if self.func.is_active_method {
+                    return Err(Box::new(
+                        "Waiting for futures is not allowed inside of active objects".to_string(),
+                    ));
+                }
+                let expr_type = inner.as_ref().clone();
+                return Ok(VExprTyped {
+                    expr: VExpr::WaitFuture(Box::new(object)),
+                    expr_type,
+                    pos,
+                });
+            }
 
             Type::Custom(symbol_type) => {
                 let object_definition = &self.aggregate.types[symbol_type];
                 if object_definition.is_active {
+                    if let Some(lifecycle_call) = self.verify_lifecycle_call(object, method, args) {
+                        return lifecycle_call;
+                    }
                     return Err(Box::new(format!(
                         "Can't call methods of active objects directly (use ! to send message or @{} for access from inside)",
                         method
@@ -607,7 +685,31 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                 std_method.as_ref()
             }
         };
-        self.calculate_function_call(raw_method, args, Some(object))
+        self.calculate_function_call(raw_method, args, Some(object), pos)
+    }
+
+    /// `stop()` and `wait()` are built into every active object, other methods are messages
+    fn verify_lifecycle_call(
+        &self,
+        active: VExprTyped,
+        method: &str,
+        args: &[ExprWithPos],
+    ) -> Option<Result<VExprTyped, Box<dyn ExprError>>> {
+        let pos = active.pos;
+        let expr = match method {
+            "stop" => VExpr::StopActive(Box::new(active)),
+            "wait" => VExpr::WaitActive(Box::new(active)),
+            _ => return None,
+        };
+        if !args.is_empty() {
+            return Some(Err(Box::new(format!("`{}()` takes no arguments", method))));
+        }
+        if method == "wait" && self.func.is_active_method {
+            return Some(Err(Box::new(
+                "Waiting for active objects is not allowed inside of active objects".to_string(),
+            )));
+        }
+        Some(Ok(VExprTyped { expr, expr_type: Type::Tuple(vec![]), pos }))
     }
 
     fn calculate_function_call(
@@ -615,6 +717,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         raw_called: &'a RawFunction,
         given_args: &[ExprWithPos],
         implicit_this: Option<VExprTyped>,
+        pos: usize,
     ) -> Result<VExprTyped, Box<dyn ExprError>> {
         // TODO: mark called function as used, strip unused functions
         let expected_args: &[VerifiedType] = if implicit_this.is_some() {
@@ -648,7 +751,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
             return_type: raw_called.return_type.clone(),
             args: processed_args,
         };
-        Ok(VExprTyped { expr: vexpr_call, expr_type: raw_called.return_type.clone() })
+        Ok(VExprTyped { expr: vexpr_call, expr_type: raw_called.return_type.clone(), pos })
     }
 
     fn calculate_access_by_index(
@@ -656,6 +759,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         object: &ExprWithPos,
         index: &ExprWithPos,
     ) -> Result<VExprTyped, Box<dyn ExprError>> {
+        let pos = object.pos_first;
         let calculated_object = self.verify_expr(object, None)?;
 
         match calculated_object.expr_type.clone() {
@@ -670,7 +774,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                         tuple: Box::new(calculated_object),
                         index: i as usize,
                     };
-                    Ok(VExprTyped { expr: vexpr, expr_type: item_types[i as usize].clone() })
+                    Ok(VExprTyped { expr: vexpr, expr_type: item_types[i as usize].clone(), pos })
                 }
                 _ => to_dyn(expression_error!(
                     index,
@@ -683,7 +787,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                     list: Box::new(calculated_object),
                     index: Box::new(calculated_index),
                 };
-                Ok(VExprTyped { expr: new_expr, expr_type: inner.as_ref().clone() })
+                Ok(VExprTyped { expr: new_expr, expr_type: inner.as_ref().clone(), pos })
             }
             t => to_dyn(expression_error!(
                 object,
@@ -698,9 +802,10 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         left_og: &ExprWithPos,
         right_og: &ExprWithPos,
     ) -> Result<VExprTyped, Box<dyn ExprError>> {
+        let pos = left_og.pos_first;
         if left_og.expr == Expr::Nil {
             if right_og.expr == Expr::Nil {
-                return Ok(VExprTyped { expr: VExpr::Bool(true), expr_type: Type::Bool });
+                return Ok(VExprTyped { expr: VExpr::Bool(true), expr_type: Type::Bool, pos });
             } else {
                 return self.calculate_equality(right_og, left_og);
             }
@@ -720,6 +825,7 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
             let access_flag = VExprTyped {
                 expr: VExpr::AccessTupleItem { tuple: Box::new(left_calculated), index: 0 },
                 expr_type: Type::Bool,
+                pos,
             };
             // negate the flag, so that it is the same as `not obj[0]`
             // unwrap as there is type-related errors in there expected
@@ -761,21 +867,27 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                 let are_both_false = wrap_binary(
                     RawOperator::AndBools,
                     vec![
-                        calculate_unaryop(&UnaryOp::Not, get_flag(&left_temp, &left_inner))
+                        calculate_unaryop(&UnaryOp::Not, get_flag(&left_temp, &left_inner, pos))
                             .unwrap(),
-                        calculate_unaryop(&UnaryOp::Not, get_flag(&right_temp, &right_inner))
+                        calculate_unaryop(&UnaryOp::Not, get_flag(&right_temp, &right_inner, pos))
                             .unwrap(),
                     ],
                     Type::Bool,
                 );
                 let are_both_true = wrap_binary(
                     RawOperator::AndBools,
-                    vec![get_flag(&left_temp, &left_inner), get_flag(&right_temp, &right_inner)],
+                    vec![
+                        get_flag(&left_temp, &left_inner, pos),
+                        get_flag(&right_temp, &right_inner, pos),
+                    ],
                     Type::Bool,
                 );
                 let are_values_equal = wrap_binary(
                     op,
-                    vec![get_value(&left_temp, &left_inner), get_value(&right_temp, &right_inner)],
+                    vec![
+                        get_value(&left_temp, &left_inner, pos),
+                        get_value(&right_temp, &right_inner, pos),
+                    ],
                     left_inner.as_ref().clone(),
                 );
 
@@ -799,23 +911,21 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
                 let left_temp = self.request_temp(left, left_og.pos_first);
 
                 let are_values_equal =
-                    wrap_binary(op, vec![get_value(&left_temp, &left_inner), right], rt);
+                    wrap_binary(op, vec![get_value(&left_temp, &left_inner, pos), right], rt);
                 let if_both_true = wrap_binary(
                     RawOperator::AndBools,
-                    vec![get_flag(&left_temp, &left_inner), are_values_equal],
+                    vec![get_flag(&left_temp, &left_inner, pos), are_values_equal],
                     Type::Bool,
                 );
                 Ok(if_both_true)
             }
             (_, Type::Maybe(_)) => self.calculate_equality(right_og, left_og),
-            (t1, t2) if t1 != t2 => {
-                return to_dyn(expression_error!(
-                    left_og,
-                    "Types `{}` and `{}` cannot be checked for equality",
-                    t1,
-                    t2
-                ));
-            }
+            (t1, t2) if t1 != t2 => to_dyn(expression_error!(
+                left_og,
+                "Types `{}` and `{}` cannot be checked for equality",
+                t1,
+                t2
+            )),
             (_, _) => {
                 let op = get_eq_op(&left.expr_type, is_eq_error_msg)?;
                 Ok(wrap_binary(op, vec![left, right], Type::Bool))
@@ -830,15 +940,17 @@ impl<'a, 'i> ExpressionsVerifier<'a, 'i> {
         seed: usize,
     ) -> Result<VExprTyped, String> {
         let inner_type = right.expr_type.clone();
+        let pos = left.pos;
         let left_temp = self.request_temp(left, seed);
 
         Ok(VExprTyped {
             expr: VExpr::TernaryOp {
-                condition: Box::new(get_flag(&left_temp, &inner_type)),
-                if_true: Box::new(get_value(&left_temp, &inner_type)),
+                condition: Box::new(get_flag(&left_temp, &inner_type, pos)),
+                if_true: Box::new(get_value(&left_temp, &inner_type, pos)),
                 if_false: Box::new(right),
             },
             expr_type: inner_type.clone(),
+            pos,
         })
     }
 }
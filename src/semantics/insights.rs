@@ -36,8 +36,7 @@ impl Insights {
 
         // If variable я might be unitialized due to another insights -
         // then it can be initialized in this one
-        self.uninitialized_variables
-            .extend(uninitialized_variables.into_iter());
+        self.uninitialized_variables.extend(uninitialized_variables);
 
         self.initialized_own_fields
             .retain(|e| initialized_own_fields.contains(e));
@@ -12,6 +12,7 @@ pub fn calculate_unaryop(operator: &UnaryOp, operand: VExprTyped) -> Result<VExp
         (UnaryOp::Not, t) => return Err(format!("Cannot apply NOT to {} type", t)),
     };
     let expr_type = operand.expr_type.clone();
+    let pos = operand.pos;
 
     match operand.expr {
         VExpr::ApplyOp { operator, mut operands } if operator == exact_operator => {
@@ -22,6 +23,7 @@ pub fn calculate_unaryop(operator: &UnaryOp, operand: VExprTyped) -> Result<VExp
     Ok(VExprTyped {
         expr: VExpr::ApplyOp { operator: exact_operator, operands: vec![operand] },
         expr_type,
+        pos,
     })
 }
 
@@ -30,7 +32,12 @@ pub fn wrap_binary(
     operands: Vec<VExprTyped>,
     res_type: VerifiedType,
 ) -> VExprTyped {
-    VExprTyped { expr: VExpr::ApplyOp { operator: op, operands }, expr_type: res_type }
+    let pos = operands[0].pos;
+    VExprTyped {
+        expr: VExpr::ApplyOp { operator: op, operands },
+        expr_type: res_type,
+        pos,
+    }
 }
 
 pub fn calculate_binaryop(
@@ -44,9 +51,11 @@ pub fn calculate_binaryop(
     );
 
     let ensure_same_types = || {
-        (left.expr_type != right.expr_type)
-            .then(|| Err(binaryop_error.clone()))
-            .unwrap_or(Ok(()))
+        if left.expr_type != right.expr_type {
+            Err(binaryop_error.clone())
+        } else {
+            Ok(())
+        }
     };
     let ensure_int_or_float = |int_op: RawOperator, float_op: RawOperator| {
         ensure_same_types()?;
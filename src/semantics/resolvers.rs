@@ -12,11 +12,6 @@ type SingleFileMapping<T> = HashMap<String, T>;
 
 pub type SymbolResolver<'a, T> = Box<dyn Fn(&str) -> Result<T, String> + 'a>;
 
-trait Symbol {}
-
-impl Symbol for SymbolType {}
-impl Symbol for SymbolFunc {}
-
 pub struct NameResolver {
     // key is where the symbol lookup occures, value is target
     typenames: SymbolLookupMapping<SymbolType>,
@@ -31,8 +26,8 @@ impl NameResolver {
 
         for (alias, file_ast) in modules.iter() {
             resolver
-                .init_file_symbols(*alias, *file_ast)
-                .map_err(|err| err.with_module(*alias))?;
+                .init_file_symbols(alias, file_ast)
+                .map_err(|err| err.with_module(alias))?;
         }
 
         resolver.validate(modules)?;
@@ -237,23 +232,23 @@ mod test {
 
         let main_types_resolver = resolver.get_typenames_resolver(&main_alias);
         assert_eq!(
-            main_types_resolver(&String::from("SomeType")).unwrap(),
-            SymbolType::new(&main_alias, &String::from("SomeType"))
+            main_types_resolver("SomeType").unwrap(),
+            SymbolType::new(&main_alias, "SomeType")
         );
 
         let main_functions_resolver = resolver.get_functions_resolver(&main_alias);
         let mod_functions_resolver = resolver.get_functions_resolver(&mod_alias);
         assert_eq!(
-            main_functions_resolver(&String::from("somefun")).unwrap(),
-            SymbolFunc::new(&mod_alias, &String::from("somefun"))
+            main_functions_resolver("somefun").unwrap(),
+            SymbolFunc::new(&mod_alias, "somefun")
         );
         assert_eq!(
-            mod_functions_resolver(&String::from("somefun")).unwrap(),
-            SymbolFunc::new(&mod_alias, &String::from("somefun"))
+            mod_functions_resolver("somefun").unwrap(),
+            SymbolFunc::new(&mod_alias, "somefun")
         );
 
-        assert!(mod_functions_resolver(&String::from("wrong_name")).is_err());
-        assert!(main_types_resolver(&String::from("BadType")).is_err());
+        assert!(mod_functions_resolver("wrong_name").is_err());
+        assert!(main_types_resolver("BadType").is_err());
     }
 
     #[test]
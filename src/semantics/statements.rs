@@ -2,7 +2,9 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::ast::parsed::*;
-use crate::ast::verified::{RawFunction, RawOperator, VExpr, VExprTyped, VStatement};
+use crate::ast::verified::{
+    RawFunction, RawOperator, VExpr, VExprTyped, VStatement, VStatementWithPos,
+};
 use crate::symbols::SymbolFunc;
 use crate::types::{verify_parsed_type, ParsedType, Type, VerifiedType};
 
@@ -19,7 +21,7 @@ struct StatementsVerifier<'a, 'c> {
     pub resolver: &'c NameResolver,
     pub locals: Rc<RefCell<LocalVariables>>,
 
-    stmt_blocks: Vec<Vec<VStatement>>,
+    stmt_blocks: Vec<Vec<VStatementWithPos>>,
 }
 
 impl<'a, 'c> StatementsVerifier<'a, 'c> {
@@ -44,15 +46,18 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
         .map_err(SemanticError::add_statement(stmt))
     }
 
-    fn emit_stmt(&mut self, stmt: VStatement) {
-        self.stmt_blocks.last_mut().unwrap().push(stmt);
+    fn emit_stmt(&mut self, statement: VStatement, pos: usize) {
+        self.stmt_blocks
+            .last_mut()
+            .unwrap()
+            .push(VStatementWithPos { statement, pos });
     }
 
     pub fn generate_block(
         &mut self,
         statements: &[StatementWithPos],
         insights: &mut Insights,
-    ) -> SemanticResult<Vec<VStatement>> {
+    ) -> SemanticResult<Vec<VStatementWithPos>> {
         self.stmt_blocks.push(vec![]);
 
         let mut new_insights: Option<Insights> = None;
@@ -94,11 +99,14 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                 .borrow_mut()
                 .add_variable_exact(&temp_name, &temp_value.expr_type)
                 .map_err(SemanticError::add_expr(expr))?;
-            self.emit_stmt(VStatement::AssignLocal {
-                name: temp_name,
-                tuple_indexes: vec![],
-                value: temp_value,
-            });
+            self.emit_stmt(
+                VStatement::AssignLocal {
+                    name: temp_name,
+                    tuple_indexes: vec![],
+                    value: temp_value,
+                },
+                expr.pos_first,
+            );
         }
         Ok(calculated_expr)
     }
@@ -120,13 +128,17 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
         let else_body = match elif_bodies_input {
             [] => self.generate_block(else_body_input, insights)?,
             [(first_condition, first_body), other_elifs @ ..] => {
-                vec![self.generate_if_elif_else(
+                let elif_statement = self.generate_if_elif_else(
                     first_condition,
                     first_body,
                     other_elifs,
                     else_body_input,
                     insights,
-                )?]
+                )?;
+                vec![VStatementWithPos {
+                    statement: elif_statement,
+                    pos: first_condition.pos_first,
+                }]
             }
         };
         insights.merge_with(insights_of_if_branch);
@@ -149,7 +161,7 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
         match &statement.statement {
             Statement::Expr(e) => {
                 let expr = self.check_expr(e, None, insights)?;
-                self.emit_stmt(VStatement::Expression(expr));
+                self.emit_stmt(VStatement::Expression(expr), statement.pos);
             }
             Statement::VarDecl(var_type, name) => {
                 let var_type = self.annotate_type(var_type, statement)?;
@@ -168,11 +180,10 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                     .add_variable(name, &var_type)
                     .map_err(stmt_err)?;
 
-                self.emit_stmt(VStatement::AssignLocal {
-                    name: real_name,
-                    tuple_indexes: vec![],
-                    value,
-                });
+                self.emit_stmt(
+                    VStatement::AssignLocal { name: real_name, tuple_indexes: vec![], value },
+                    statement.pos,
+                );
             }
             Statement::Assign { left, right } => {
                 let mut temp_insights: Insights;
@@ -249,7 +260,7 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                         )
                     }
                 };
-                self.emit_stmt(assign_stmt);
+                self.emit_stmt(assign_stmt, statement.pos);
             }
 
             Statement::Return(option_e) => {
@@ -263,10 +274,11 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                     None => VExprTyped {
                         expr: VExpr::TupleValue(vec![]),
                         expr_type: Type::Tuple(vec![]),
+                        pos: statement.pos,
                     },
                 };
 
-                self.emit_stmt(VStatement::Return(value));
+                self.emit_stmt(VStatement::Return(value), statement.pos);
             }
             Statement::Break if !insights.is_in_loop => {
                 return statement_error!(statement, "`break` outside loop")
@@ -276,11 +288,11 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
             }
             Statement::Break => {
                 insights.break_or_continue_found = true;
-                self.emit_stmt(VStatement::Break);
+                self.emit_stmt(VStatement::Break, statement.pos);
             }
             Statement::Continue => {
                 insights.break_or_continue_found = true;
-                self.emit_stmt(VStatement::Continue);
+                self.emit_stmt(VStatement::Continue, statement.pos);
             }
             Statement::IfElse { condition, if_body, elif_bodies, else_body } => {
                 let if_else_stmt = self.generate_if_elif_else(
@@ -290,7 +302,7 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                     else_body,
                     insights,
                 )?;
-                self.emit_stmt(if_else_stmt);
+                self.emit_stmt(if_else_stmt, statement.pos);
             }
             Statement::While { condition, body } => {
                 let condition = self.check_expr(condition, Some(&Type::Bool), insights)?;
@@ -300,9 +312,10 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
 
                 let body = self.generate_block(body, &mut loop_insights)?;
 
-                self.emit_stmt(VStatement::While { condition, body });
+                self.emit_stmt(VStatement::While { condition, body }, statement.pos);
             }
             Statement::Foreach { item_name, iterable, body } => {
+                let statement_pos = statement.pos;
                 let iterable_calculated = self.check_expr(iterable, None, insights)?;
                 let iterable_type = iterable_calculated.expr_type.clone();
 
@@ -341,20 +354,30 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
 
                 let get_var = |locals: &RefCell<LocalVariables>, name: &str| {
                     let (t, n) = locals.borrow().get_variable(name).unwrap();
-                    VExprTyped { expr: VExpr::GetVar(n), expr_type: t }
+                    VExprTyped { expr: VExpr::GetVar(n), expr_type: t, pos: statement_pos }
+                };
+                let int_expr = |i: i64| VExprTyped {
+                    expr: VExpr::Int(i),
+                    expr_type: Type::Int,
+                    pos: statement_pos,
                 };
-                let int_expr = |i: i64| VExprTyped { expr: VExpr::Int(i), expr_type: Type::Int };
 
-                self.emit_stmt(VStatement::AssignLocal {
-                    name: real_index_name.clone(),
-                    tuple_indexes: vec![],
-                    value: int_expr(0),
-                });
-                self.emit_stmt(VStatement::AssignLocal {
-                    name: real_iterable_name,
-                    tuple_indexes: vec![],
-                    value: iterable_calculated,
-                });
+                self.emit_stmt(
+                    VStatement::AssignLocal {
+                        name: real_index_name.clone(),
+                        tuple_indexes: vec![],
+                        value: int_expr(0),
+                    },
+                    statement.pos,
+                );
+                self.emit_stmt(
+                    VStatement::AssignLocal {
+                        name: real_iterable_name,
+                        tuple_indexes: vec![],
+                        value: iterable_calculated,
+                    },
+                    statement.pos,
+                );
 
                 // Condition to check if all good
                 let condition = VExprTyped {
@@ -370,9 +393,11 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                                     return_type: Type::Int,
                                     args: vec![get_var(&self.locals, &iterable_name)],
                                 },
+                                pos: statement_pos,
                             },
                         ],
                     },
+                    pos: statement_pos,
                 };
                 let get_by_index_from_iterable = VExprTyped {
                     expr_type: item_type,
@@ -380,6 +405,7 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                         list: Box::new(get_var(&self.locals, &iterable_name)),
                         index: Box::new(get_var(&self.locals, &index_name)),
                     },
+                    pos: statement_pos,
                 };
                 let set_item_statement = VStatement::AssignLocal {
                     name: real_item_name,
@@ -396,6 +422,7 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                             operator: RawOperator::AddInts,
                             operands: vec![get_var(&self.locals, &index_name), int_expr(1)],
                         },
+                        pos: statement_pos,
                     },
                 };
 
@@ -405,14 +432,18 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
 
                 let mut calculated_body = self.generate_block(body, &mut loop_insights)?;
 
-                calculated_body.insert(0, increase_index_statement);
-                calculated_body.insert(0, set_item_statement);
-                self.emit_stmt(VStatement::While { condition, body: calculated_body });
+                let with_pos = |statement| VStatementWithPos { statement, pos: statement_pos };
+                calculated_body.insert(0, with_pos(increase_index_statement));
+                calculated_body.insert(0, with_pos(set_item_statement));
+                self.emit_stmt(
+                    VStatement::While { condition, body: calculated_body },
+                    statement.pos,
+                );
 
                 self.locals.borrow_mut().drop_current_scope();
             }
 
-            Statement::SendMessage { active, method, args } => {
+            Statement::SendMessage { active, method, args, delay } => {
                 let verified_active = self.check_expr(active, None, insights)?;
                 let method_raw = match &verified_active.expr_type {
                     Type::Custom(typename) if self.aggregate.types[typename].is_active => {
@@ -457,12 +488,20 @@ impl<'a, 'c> StatementsVerifier<'a, 'c> {
                     .zip(method_raw.args.types.iter())
                     .map(|(arg, expected_type)| self.check_expr(arg, Some(expected_type), insights))
                     .collect();
+                let verified_delay = match delay {
+                    Some(delay) => Some(self.check_expr(delay, Some(&Type::Int), insights)?),
+                    None => None,
+                };
 
-                self.emit_stmt(VStatement::SendMessage {
-                    active: verified_active,
-                    receiver: method_raw.name.clone(),
-                    args: verified_args?,
-                })
+                self.emit_stmt(
+                    VStatement::SendMessage {
+                        active: verified_active,
+                        receiver: method_raw.name.clone(),
+                        args: verified_args?,
+                        delay: verified_delay,
+                    },
+                    statement.pos,
+                )
             }
         };
         Ok(())
@@ -501,13 +540,18 @@ pub fn verify_raw_function(
         // so either add one if return is implicit (constructor and void functions)
         // or raise an error
 
+        // Implicit return belongs to the end of the function, which is not known here
+        let pos = og_function.pos;
         if func.is_constructor {
-            verified.push(return_statement_for_constructor(func))
+            let statement = return_statement_for_constructor(func, pos);
+            verified.push(VStatementWithPos { statement, pos });
         } else if func.return_type == Type::Tuple(vec![]) {
-            verified.push(VStatement::Return(VExprTyped {
+            let statement = VStatement::Return(VExprTyped {
                 expr: VExpr::TupleValue(vec![]),
                 expr_type: Type::Tuple(vec![]),
-            }));
+                pos,
+            });
+            verified.push(VStatementWithPos { statement, pos });
         } else {
             let error_msg = match &func.method_of {
                 Some(t) => format!(
@@ -535,7 +579,8 @@ pub fn verify_raw_function(
         }
 
         if !func.is_active_method {
-            verified.insert(0, allocate_object_for_constructor(func));
+            let statement = allocate_object_for_constructor(func, og_function.pos);
+            verified.insert(0, VStatementWithPos { statement, pos: og_function.pos });
         }
     }
 
@@ -564,23 +609,25 @@ fn split_left_part_of_assignment(vexpr: VExprTyped) -> (VExprTyped, Vec<usize>)
     }
 }
 
-pub fn return_statement_for_constructor(func: &RawFunction) -> VStatement {
+pub fn return_statement_for_constructor(func: &RawFunction, pos: usize) -> VStatement {
     let class_name = func.method_of.as_ref().unwrap();
 
     if func.is_active_method {
         VStatement::Return(VExprTyped {
             expr: VExpr::TupleValue(vec![]),
             expr_type: Type::Tuple(vec![]),
+            pos,
         })
     } else {
         VStatement::Return(VExprTyped {
             expr: VExpr::GetVar("this".into()),
             expr_type: Type::Custom(class_name.clone()),
+            pos,
         })
     }
 }
 
-fn allocate_object_for_constructor(func: &RawFunction) -> VStatement {
+fn allocate_object_for_constructor(func: &RawFunction, pos: usize) -> VStatement {
     let class_name = func.method_of.as_ref().unwrap();
 
     VStatement::AssignLocal {
@@ -589,6 +636,7 @@ fn allocate_object_for_constructor(func: &RawFunction) -> VStatement {
         value: VExprTyped {
             expr: VExpr::Allocate { typename: class_name.clone() },
             expr_type: Type::Custom(class_name.clone()),
+            pos,
         },
     }
 }
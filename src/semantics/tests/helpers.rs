@@ -6,7 +6,7 @@ use crate::loader::WholeProgram;
 use crate::semantics::aggregate::ProgramAggregate;
 use crate::tests::helpers::setup_and_load_program;
 
-fn extract_expected_err<'a>(wp: &'a WholeProgram) -> (&'a ModuleAlias, usize, &'a str) {
+fn extract_expected_err(wp: &WholeProgram) -> (&ModuleAlias, usize, &str) {
     let expected_error_regex = Regex::new(r"// ERR: (?P<errmsg>.+)").unwrap();
 
     for (module_alias, file) in wp.files.iter() {
@@ -54,9 +54,10 @@ pub(super) fn run_semantic_and_check_all_good(program: &str) {
 
     // BONUS: check that if the program is valid - it does not panic during codegen
     let ProgramAggregate { types, functions, entry } = res;
-    let types: Vec<_> = types.into_iter().map(|(_, v)| v).collect();
-    let functions: Vec<_> = functions.into_iter().map(|(_, v)| v).collect();
-    crate::codegen::generate(&types, &functions, &entry);
+    let types: Vec<_> = types.into_values().collect();
+    let functions: Vec<_> = functions.into_values().collect();
+    let sources = wp.files.iter().map(|(alias, f)| (alias.clone(), f.contents.as_str())).collect();
+    crate::codegen::generate(&types, &functions, &entry, &sources).unwrap();
 }
 
 macro_rules! assert_semantic_check_fails {
@@ -213,3 +213,241 @@ assert_semantic_check_fails!(
     }
     "#
 );
+
+assert_semantic_check_is_fine!(
+    ask_and_wait_in_main,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun Int double(Int i) {
+            return i * 2;
+        }
+    }
+
+    fun void main() {
+        Actor a = spawn Actor();
+        Future<Int> f = a ? double(21);
+        Int res = f.wait();
+    }
+    "#
+);
+
+assert_semantic_check_fails!(
+    ask_passive_not_allowed,
+    r#"
+    ===== file: main.frisbee
+    class Passive {
+        fun Int get_counter() {
+            return 1;
+        }
+    }
+
+    fun void main() {
+        Passive a = Passive();
+        Future<Int> f = a ? get_counter();    // ERR: Can only ask active objects, but `main::Passive` is not active
+    }
+    "#
+);
+
+assert_semantic_check_fails!(
+    ask_void_method_not_allowed,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun void run() {}
+    }
+
+    fun void main() {
+        Actor a = spawn Actor();
+        a ? run();    // ERR: Method `run` returns nothing, use ! to send a message instead
+    }
+    "#
+);
+
+assert_semantic_check_fails!(
+    wait_inside_active_not_allowed,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun Int double(Int i) {
+            return i * 2;
+        }
+
+        fun void ask_self() {
+            Int res = (this ? double(2)).wait();  // ERR: Waiting for futures is not allowed inside of active objects
+        }
+    }
+
+    fun void main() {}
+    "#
+);
+
+assert_semantic_check_fails!(
+    spawn_node_must_be_string,
+    r#"
+    ===== file: main.frisbee
+    active Actor {}
+
+    fun void main() {
+        Actor a = spawn Actor() on "worker";
+        Actor b = spawn Actor() on 1;  // ERR: Expected type `String` but got `Int`
+    }
+    "#
+);
+
+assert_semantic_check_is_fine!(
+    active_with_mailbox,
+    r#"
+    ===== file: main.frisbee
+    active Worker(mailbox = 10, overflow = drop_oldest) {}
+    active Blocking(overflow = block) {}
+
+    fun void main() {
+        Worker w = spawn Worker();
+        Blocking b = spawn Blocking();
+    }
+    "#
+);
+
+assert_semantic_check_fails!(
+    mailbox_capacity_must_be_positive,
+    r#"
+    ===== file: main.frisbee
+    active Worker(mailbox = 0) {}  // ERR: Mailbox capacity of `Worker` must be between 1 and 4294967295, but got 0
+    "#
+);
+
+assert_semantic_check_fails!(
+    unknown_overflow_policy,
+    r#"
+    ===== file: main.frisbee
+    active Worker(mailbox = 5, overflow = drop_all) {}  // ERR: Unknown overflow policy `drop_all`, expected one of: block, drop_newest, drop_oldest
+    "#
+);
+
+assert_semantic_check_is_fine!(
+    active_lifecycle,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun void on_stop() {
+            println("Stopped");
+        }
+
+        fun void finish() {
+            this.stop();
+        }
+    }
+
+    fun void main() {
+        Actor a = spawn Actor();
+        a.stop();
+        a.wait();
+    }
+    "#
+);
+
+assert_semantic_check_fails!(
+    on_stop_takes_no_arguments,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun void on_stop(Int code) {}  // ERR: Method `on_stop` of `Actor` must take no arguments and return nothing
+    }
+
+    fun void main() {}
+    "#
+);
+
+assert_semantic_check_fails!(
+    wait_for_active_inside_active_not_allowed,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun void wait_for(Actor other) {
+            other.wait();  // ERR: Waiting for active objects is not allowed inside of active objects
+        }
+    }
+
+    fun void main() {}
+    "#
+);
+
+assert_semantic_check_fails!(
+    stop_takes_no_arguments,
+    r#"
+    ===== file: main.frisbee
+    active Actor {}
+
+    fun void main() {
+        Actor a = spawn Actor();
+        a.stop(1);  // ERR: `stop()` takes no arguments
+    }
+    "#
+);
+
+assert_semantic_check_is_fine!(
+    active_supervisor,
+    r#"
+    ===== file: main.frisbee
+    active Child {}
+
+    active Pool(supervision = one_for_all, max_restarts = 2, restart_period = 100) {
+        Child child;
+
+        fun Pool() {
+            @child = spawn Child();
+        }
+
+        fun void on_child_failed(String child, String error) {
+            println(child + ": " + error);
+        }
+    }
+
+    fun void main() {
+        Pool pool = spawn Pool();
+    }
+    "#
+);
+
+assert_semantic_check_fails!(
+    unknown_supervision_strategy,
+    r#"
+    ===== file: main.frisbee
+    active Pool(supervision = rest_for_one) {}  // ERR: Unknown supervision strategy `rest_for_one`, expected one of: one_for_one, one_for_all
+    "#
+);
+
+assert_semantic_check_fails!(
+    restart_limits_need_supervision,
+    r#"
+    ===== file: main.frisbee
+    active Pool(max_restarts = 2) {}  // ERR: Restart limits of `Pool` are set, but it has no `supervision` strategy
+    "#
+);
+
+assert_semantic_check_fails!(
+    on_child_failed_takes_two_strings,
+    r#"
+    ===== file: main.frisbee
+    active Pool(supervision = one_for_one) {
+        fun void on_child_failed(String child) {}  // ERR: Method `on_child_failed` of `Pool` must take name of the child and error, both `String`, and return nothing
+    }
+
+    fun void main() {}
+    "#
+);
+
+assert_semantic_check_fails!(
+    delay_of_message_must_be_int,
+    r#"
+    ===== file: main.frisbee
+    active Actor {
+        fun void tick() {
+            this ! tick() after "soon";  // ERR: Expected type `Int` but got `String`
+        }
+    }
+
+    fun void main() {}
+    "#
+);
@@ -7,7 +7,7 @@ pub type StdMethod<'a> = (
 );
 const VOID_TYPE: VerifiedType = Type::Tuple(vec![]);
 
-pub const STD_FUNCTIONS: [StdFunction; 6] = [
+pub const STD_FUNCTIONS: [StdFunction; 8] = [
     ("print", || (vec![Type::String], VOID_TYPE)),
     ("println", || (vec![Type::String], VOID_TYPE)),
     ("fprint", || {
@@ -26,6 +26,8 @@ pub const STD_FUNCTIONS: [StdFunction; 6] = [
         (vec![Type::Int, Type::Int], Type::List(Box::new(Type::Int)))
     }),
     ("get_input", || (vec![], Type::String)),
+    ("exit", || (vec![Type::Int], VOID_TYPE)),
+    ("sleep", || (vec![Type::Int], VOID_TYPE)),
 ];
 
 pub const STD_BOOL_METHODS: [StdMethod; 1] = [("to_string", |_| (vec![], Type::String))];
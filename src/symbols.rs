@@ -4,6 +4,8 @@ use crate::alias::ModuleAlias;
 use crate::types::Type;
 
 pub static MAIN_FUNCTION_NAME: &str = "main";
+pub static ON_STOP_METHOD_NAME: &str = "on_stop"; // run by active object after it is stopped
+pub static ON_CHILD_FAILED_METHOD_NAME: &str = "on_child_failed"; // run by supervisor
 
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub struct SymbolType(String);
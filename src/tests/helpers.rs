@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::fs::{create_dir_all, remove_file, File};
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use tempfile::{tempdir, TempDir};
 
@@ -93,6 +94,26 @@ pub fn setup_and_load_program(s: &str) -> WholeProgram {
     t.load_program()
 }
 
+/// Writer for output of the program, that keeps everything written for the test to check
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::read_to_string;
@@ -16,6 +16,7 @@ pub enum Type<T> {
     List(Box<Type<T>>),
     Tuple(Vec<Type<T>>),
     Maybe(Box<Type<T>>),
+    Future(Box<Type<T>>), // result of asking an active object, see `?` operator
 
     // User-defined type
     Custom(T),
@@ -43,6 +44,7 @@ where
                 write!(f, "({})", items_str.join(", "))
             }
             Self::Maybe(inner) => write!(f, "{}?", inner),
+            Self::Future(inner) => write!(f, "Future<{}>", inner),
         }
     }
 }
@@ -70,6 +72,10 @@ where
             let real_inner = verify_parsed_type(inner, mapper)?;
             Type::Maybe(Box::new(real_inner))
         }
+        Type::Future(inner) => {
+            let real_inner = verify_parsed_type(inner, mapper)?;
+            Type::Future(Box::new(real_inner))
+        }
         Type::Custom(ident) => Type::Custom(mapper(ident)?),
     })
 }